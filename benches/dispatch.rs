@@ -0,0 +1,59 @@
+//! Benchmarks of the instruction dispatch over a tight loop. Run with `--features threaded` to
+//! compare the threaded-code interpreter with the classic one.
+
+#[macro_use]
+extern crate bencher;
+
+use bencher::Bencher;
+
+use rvemu::{bus::DRAM_BASE, emulator::Emulator};
+
+/// Count a2 down from 1000 while mixing integer instructions and a store.
+const TIGHT_LOOP: [u8; 40] = [
+    0x13, 0x06, 0x80, 0x3e, // addi a2, zero, 1000
+    0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+    0x13, 0x05, 0x35, 0x00, // addi a0, a0, 3
+    0x93, 0x16, 0x25, 0x00, // slli a3, a0, 2
+    0x33, 0xc7, 0xa6, 0x00, // xor a4, a3, a0
+    0xb3, 0x87, 0xe7, 0x00, // add a5, a5, a4
+    0x33, 0x88, 0xc7, 0x40, // sub a6, a5, a2
+    0x23, 0xb0, 0x02, 0x01, // sd a6, 0(t0)
+    0x13, 0x06, 0xf6, 0xff, // addi a2, a2, -1
+    0xe3, 0x12, 0x06, 0xfe, // bne a2, zero, -28
+];
+
+/// The address where the loop ends.
+const END: u64 = DRAM_BASE + TIGHT_LOOP.len() as u64;
+
+fn setup() -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(TIGHT_LOOP.to_vec());
+    emu
+}
+
+fn classic(b: &mut Bencher) {
+    let mut emu = setup();
+    b.iter(|| {
+        emu.initialize_pc(DRAM_BASE);
+        while emu.cpu.pc != END {
+            emu.cpu.execute().unwrap();
+        }
+    });
+}
+
+#[cfg(feature = "threaded")]
+fn threaded(b: &mut Bencher) {
+    let mut emu = setup();
+    b.iter(|| {
+        emu.initialize_pc(DRAM_BASE);
+        while emu.cpu.pc != END {
+            emu.cpu.execute_block().unwrap();
+        }
+    });
+}
+
+#[cfg(not(feature = "threaded"))]
+benchmark_group!(benches, classic);
+#[cfg(feature = "threaded")]
+benchmark_group!(benches, classic, threaded);
+benchmark_main!(benches);
@@ -3,10 +3,60 @@ use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::iter::FromIterator;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
 
 use rvemu_core::bus::DRAM_BASE;
 use rvemu_core::cpu::Cpu;
-use rvemu_core::emulator::Emulator;
+use rvemu_core::emulator::{Emulator, SigintBehavior};
+
+/// The flag of the emulator which the SIGINT handler sets.
+static SIGINT_FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// Handle SIGINT by setting the flag instead of killing the process. It only does an atomic
+/// store, which is async-signal-safe.
+#[cfg(unix)]
+extern "C" fn handle_sigint(_: libc::c_int) {
+    if let Some(flag) = SIGINT_FLAG.get() {
+        flag.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Install the SIGINT handler which passes the signal to the emulator.
+#[cfg(unix)]
+fn install_sigint_handler(flag: Arc<AtomicBool>) {
+    let _ = SIGINT_FLAG.set(flag);
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handle_sigint as *const () as libc::sighandler_t,
+        );
+    }
+}
+
+/// Signals can't be caught on the other platforms, so Ctrl-C still kills the process.
+#[cfg(not(unix))]
+fn install_sigint_handler(_: Arc<AtomicBool>) {}
+
+/// Parse the value of `--sigint`: `halt`, `byte=<hex>`, or `irq=<id>`.
+fn parse_sigint_behavior(value: &str) -> Result<SigintBehavior, String> {
+    match value.split_once('=') {
+        None if value == "halt" => Ok(SigintBehavior::Halt),
+        Some(("byte", byte)) => u8::from_str_radix(byte.trim_start_matches("0x"), 16)
+            .map(SigintBehavior::InjectByte)
+            .map_err(|e| format!("invalid byte of --sigint `{}`: {}", byte, e)),
+        Some(("irq", irq)) => irq
+            .parse()
+            .map(SigintBehavior::RaiseInterrupt)
+            .map_err(|e| format!("invalid irq of --sigint `{}`: {}", irq, e)),
+        _ => Err(format!("unknown --sigint behavior: {}", value)),
+    }
+}
+
+/// Print `message` as a usage error and exit with a non-zero status.
+fn usage_error(message: &str) -> ! {
+    clap::Error::with_description(message, clap::ErrorKind::InvalidValue).exit()
+}
 
 /// Output current registers to the console.
 fn dump_registers(cpu: &Cpu) {
@@ -24,8 +74,9 @@ fn dump_registers(cpu: &Cpu) {
 fn dump_count(cpu: &Cpu) {
     if cpu.is_count {
         println!("===========================================================================================");
-        let mut sorted_counter = Vec::from_iter(&cpu.inst_counter);
-        sorted_counter.sort_by(|&(_, a), &(_, b)| b.cmp(&a));
+        let histogram = cpu.instruction_histogram();
+        let mut sorted_counter = Vec::from_iter(&histogram);
+        sorted_counter.sort_by(|&(a_inst, a), &(b_inst, b)| b.cmp(a).then(a_inst.cmp(b_inst)));
         for (inst, count) in sorted_counter.iter() {
             println!("{}, {}", inst, count);
         }
@@ -53,6 +104,47 @@ fn main() -> io::Result<()> {
                 .takes_value(true)
                 .help("A raw disk image"),
         )
+        .arg(
+            Arg::with_name("initrd")
+                .long("initrd")
+                .takes_value(true)
+                .help("An initramfs image passed to the kernel via the device tree"),
+        )
+        .arg(
+            Arg::with_name("htif")
+                .long("htif")
+                .takes_value(true)
+                .help("Enables the HTIF device with the `tohost` address in hex"),
+        )
+        .arg(
+            Arg::with_name("sbi")
+                .long("sbi")
+                .help("Services SBI calls from S-mode in the emulator instead of firmware"),
+        )
+        .arg(
+            Arg::with_name("debug-call")
+                .long("debug-call")
+                .help("Dumps the registers to the console on an ecall with 0x0A000000 in a7"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .takes_value(true)
+                .conflicts_with("replay")
+                .help("Records inputs from the host to a file"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .takes_value(true)
+                .help("Replays inputs recorded by --record instead of reading them from the host"),
+        )
+        .arg(
+            Arg::with_name("sigint")
+                .long("sigint")
+                .takes_value(true)
+                .help("Handles Ctrl-C in the emulator: `halt`, `byte=<hex>` to type the byte to the UART, or `irq=<id>` to raise the PLIC interrupt"),
+        )
         .arg(
             Arg::with_name("debug")
                 .short("d")
@@ -86,6 +178,44 @@ fn main() -> io::Result<()> {
     emu.initialize_disk(img_data);
     emu.initialize_pc(DRAM_BASE);
 
+    if let Some(initrd_file) = matches.value_of("initrd") {
+        let mut initrd_data = Vec::new();
+        File::open(initrd_file)?.read_to_end(&mut initrd_data)?;
+        if let Err(e) = emu.load_initrd(initrd_data) {
+            usage_error(&format!("invalid --initrd: {}", e));
+        }
+    }
+
+    if let Some(tohost) = matches.value_of("htif") {
+        let tohost = u64::from_str_radix(tohost.trim_start_matches("0x"), 16)
+            .unwrap_or_else(|e| usage_error(&format!("invalid --htif address: {}", e)));
+        emu.enable_htif(tohost);
+    }
+
+    if matches.occurrences_of("sbi") == 1 {
+        emu.is_sbi = true;
+    }
+
+    if matches.occurrences_of("debug-call") == 1 {
+        emu.is_debug_call = true;
+    }
+
+    if let Some(path) = matches.value_of("record") {
+        emu.start_recording(path)?;
+    }
+
+    if let Some(path) = matches.value_of("replay") {
+        emu.replay(path)?;
+    }
+
+    if let Some(behavior) = matches.value_of("sigint") {
+        let behavior = parse_sigint_behavior(behavior).unwrap_or_else(|e| usage_error(&e));
+        if let Err(e) = emu.set_sigint_behavior(behavior) {
+            usage_error(&format!("invalid --sigint behavior: {}", e));
+        }
+        install_sigint_handler(emu.sigint_flag());
+    }
+
     if matches.occurrences_of("debug") == 1 {
         emu.is_debug = true;
     }
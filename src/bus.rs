@@ -1,9 +1,41 @@
 //! The bus module contains the system bus which can access the memroy or memory-mapped peripheral
 //! devices.
 
-use crate::devices::{clint::Clint, plic::Plic, uart::Uart, virtio_blk::Virtio};
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::fs::File;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::io;
+
+use log::trace;
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use crate::devices::entropy::OsEntropy;
+#[cfg(not(all(feature = "std", not(target_arch = "wasm32"))))]
+use crate::devices::entropy::SeededEntropy;
+use crate::devices::{
+    clint::Clint,
+    debug_ctl::DebugCtl,
+    delay::{Delay, DELAY_SIZE},
+    entropy::EntropySource,
+    htif::{Htif, FROMHOST_OFFSET},
+    mmio::{is_width_allowed, MmioDevice, MmioRegion, WidthRule},
+    perfcounters::PerfCounters,
+    plic::{check_source, IrqSource, Plic, PLIC_ACCESS_WIDTHS},
+    uart::{Uart, UART_ACCESS_WIDTHS},
+    virtio_blk::{Virtio, VIRTIO_ACCESS_WIDTHS},
+    virtio_console::{VirtioConsole, VIRTIO_CONSOLE_SIZE},
+    watchdog::Watchdog,
+};
 use crate::dram::{Dram, DRAM_SIZE};
+use crate::error::ConfigError;
 use crate::exception::Exception;
+use crate::reservation::ReservationMonitor;
 use crate::rom::Rom;
 
 // QEMU virt machine:
@@ -32,26 +64,81 @@ pub const UART_BASE: u64 = 0x1000_0000;
 /// The size of UART.
 pub const UART_SIZE: u64 = 0x100;
 /// The address which UART ends.
-const UART_END: u64 = UART_BASE + 0x100;
+const UART_END: u64 = UART_BASE + UART_SIZE - 1;
 
 /// The address which virtio starts.
 pub const VIRTIO_BASE: u64 = 0x1000_1000;
 /// The address which virtio ends.
-const VIRTIO_END: u64 = VIRTIO_BASE + 0x1000;
+const VIRTIO_END: u64 = VIRTIO_BASE + 0xfff;
+
+/// The address which the optional virtio console starts, which is the second virtio slot.
+pub const VIRTIO_CONSOLE_BASE: u64 = 0x1000_2000;
 
 /// The address which DRAM starts.
 pub const DRAM_BASE: u64 = 0x8000_0000;
 /// The address which DRAM ends.
-const DRAM_END: u64 = DRAM_BASE + DRAM_SIZE;
+const DRAM_END: u64 = DRAM_BASE + DRAM_SIZE - 1;
+
+/// A UART other than the console, which is at `base` and raises the PLIC interrupt source `irq`.
+pub struct UartPort {
+    pub base: u64,
+    pub irq: u32,
+    pub uart: Uart,
+}
+
+impl UartPort {
+    /// Return true if `addr` is in the registers of the UART.
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr - self.base < UART_SIZE
+    }
+
+    /// Load `size`-bit data from `addr`. The UART sees the address as if it were the console.
+    fn read(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
+        let addr = UART_BASE + (addr - self.base);
+        if !is_width_allowed(UART_ACCESS_WIDTHS, addr, size) {
+            return Err(Exception::LoadAccessFault);
+        }
+        self.uart.read(addr, size)
+    }
+
+    /// Store `size`-bit data to `addr`. The UART sees the address as if it were the console.
+    fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
+        let addr = UART_BASE + (addr - self.base);
+        if !is_width_allowed(UART_ACCESS_WIDTHS, addr, size) {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+        self.uart.write(addr, value as u8, size)
+    }
+}
 
 /// The system bus.
 pub struct Bus {
     pub clint: Clint,
-    plic: Plic,
+    pub plic: Plic,
     pub uart: Uart,
     pub virtio: Virtio,
+    /// The optional HTIF device. Its `tohost` word may overlap DRAM, so it takes precedence over
+    /// the other devices.
+    pub htif: Option<Htif>,
+    /// The optional delay device which stalls the hart until a deadline in `mcycle`.
+    pub delay: Option<Delay>,
+    /// The optional virtio console at `VIRTIO_CONSOLE_BASE`.
+    pub virtio_console: Option<VirtioConsole>,
+    /// The UARTs other than the console at `UART_BASE`, such as a debug port.
+    pub uarts: Vec<UartPort>,
+    /// Devices attached by `attach` and `attach_named`, such as the optional watchdog. They're
+    /// dispatched to only if no built-in device is at the address.
+    mmio: Vec<MmioRegion>,
+    /// The reservations made by LR instructions. Every store on the bus breaks the reservations on
+    /// the stored bytes, whichever hart or device it comes from.
+    pub reservations: ReservationMonitor,
     dram: Dram,
-    rom: Rom,
+    pub rom: Rom,
+    /// The source of all random bytes handed to the guest.
+    entropy: Box<dyn EntropySource>,
+    /// The ranges of addresses, `start..end`, where every access faults as if the memory had an
+    /// uncorrectable ECC error.
+    bus_errors: Vec<(u64, u64)>,
 }
 
 impl Bus {
@@ -62,43 +149,401 @@ impl Bus {
             plic: Plic::new(),
             uart: Uart::new(),
             virtio: Virtio::new(),
+            htif: None,
+            delay: None,
+            virtio_console: None,
+            uarts: Vec::new(),
+            mmio: Vec::new(),
+            reservations: ReservationMonitor::new(),
             dram: Dram::new(),
             rom: Rom::new(),
+            #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+            entropy: Box::new(OsEntropy),
+            #[cfg(not(all(feature = "std", not(target_arch = "wasm32"))))]
+            entropy: Box::new(SeededEntropy::new(0)),
+            bus_errors: Vec::new(),
         }
     }
 
+    /// Replace the source of random bytes, e.g., with a seeded one to make a run reproducible.
+    pub fn set_entropy_source(&mut self, source: Box<dyn EntropySource>) {
+        self.entropy = source;
+    }
+
+    /// Fill `buf` with random bytes from the entropy source.
+    pub fn fill_random(&mut self, buf: &mut [u8]) {
+        self.entropy.fill(buf);
+    }
+
+    /// Make every access to `addr..addr + len` fault until `clear_bus_errors` is called, e.g., to
+    /// model an uncorrectable ECC error and test the machine-check handling of a guest. Loads
+    /// raise a load access fault and stores raise a store access fault, and DMA fails as well.
+    pub fn inject_bus_error(&mut self, addr: u64, len: u64) {
+        self.bus_errors.push((addr, addr.saturating_add(len)));
+    }
+
+    /// Remove all the errors injected by `inject_bus_error`.
+    pub fn clear_bus_errors(&mut self) {
+        self.bus_errors.clear();
+    }
+
+    /// Return true if `addr..addr + len` overlaps an injected error.
+    fn is_bus_error(&self, addr: u64, len: u64) -> bool {
+        let end = addr.saturating_add(len);
+        self.bus_errors
+            .iter()
+            .any(|&(start, error_end)| addr < error_end && start < end)
+    }
+
     /// Set the binary data to the memory.
     pub fn initialize_dram(&mut self, data: Vec<u8>) {
         self.dram.initialize(data);
     }
 
+    /// Reset the memory and the devices to their state after initialization. The devices attached
+    /// by `attach` and the ROM are kept as they are, and the optional devices of the core attached
+    /// by `attach_named` are reset.
+    pub fn reset(&mut self) {
+        self.clint = Clint::new();
+        self.plic = Plic::new();
+        self.uart.reset();
+        for port in &mut self.uarts {
+            port.uart.reset();
+        }
+        self.virtio.reset();
+        if let Some(htif) = &mut self.htif {
+            htif.reset();
+        }
+        if let Some(delay) = &mut self.delay {
+            delay.reset();
+        }
+        if let Some(console) = &mut self.virtio_console {
+            console.reset();
+        }
+        if let Some(watchdog) = self.device_mut::<Watchdog>() {
+            watchdog.reset();
+        }
+        if let Some(counters) = self.device_mut::<PerfCounters>() {
+            counters.reset();
+        }
+        if let Some(debug_ctl) = self.device_mut::<DebugCtl>() {
+            debug_ctl.reset();
+        }
+        self.reservations = ReservationMonitor::new();
+        self.dram.reset();
+    }
+
+    /// Fill the memory with `pattern` repeatedly.
+    pub fn fill_dram(&mut self, pattern: u32) {
+        self.dram.fill(pattern);
+    }
+
     /// Set the binary data to the virtIO disk.
     pub fn initialize_disk(&mut self, data: Vec<u8>) {
         self.virtio.initialize(data);
     }
 
+    /// Set the read-only image shared with other buses to the virtIO disk.
+    pub fn initialize_shared_disk(&mut self, image: Arc<[u8]>) {
+        self.virtio.initialize_shared(image);
+    }
+
+    /// Set the image file of the host to the virtIO disk. With `locking`, each request which
+    /// writes to the disk holds an exclusive advisory lock on the file.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn initialize_file_disk(&mut self, file: File, locking: bool) -> io::Result<()> {
+        self.virtio.initialize_file(file, locking)
+    }
+
+    /// Forward the interrupt raised by `source` to the PLIC, which delivers the highest priority
+    /// one of the pending interrupts to a hart.
+    pub fn raise_irq(&mut self, source: IrqSource) {
+        self.plic.set_pending(source);
+    }
+
+    /// Add a UART at `base` besides the console. It raises the PLIC interrupt source `irq` and
+    /// writes to the standard output until its backend is replaced through `uart_at`. Fail if
+    /// `irq` isn't a valid interrupt source or the registers overlap another region.
+    pub fn add_uart(&mut self, base: u64, irq: u32) -> Result<(), ConfigError> {
+        check_source(irq)?;
+        self.check_overlap(base, UART_SIZE)?;
+        self.uarts.push(UartPort {
+            base,
+            irq,
+            uart: Uart::detached(),
+        });
+        Ok(())
+    }
+
+    /// Return an error if the region of `size` bytes at `base` wraps around the end of the address
+    /// space or overlaps a region on the bus.
+    fn check_overlap(&self, base: u64, size: u64) -> Result<(), ConfigError> {
+        let end = base
+            .checked_add(size)
+            .ok_or(ConfigError::Wraparound { base, size })?;
+        match self
+            .memory_map()
+            .into_iter()
+            .find(|&(_, start, len)| base < start.saturating_add(len) && start < end)
+        {
+            Some((name, _, _)) => Err(ConfigError::Overlap { base, size, name }),
+            None => Ok(()),
+        }
+    }
+
+    /// Return the UART at `base`, which is either the console or one added by `add_uart`.
+    pub fn uart_at(&mut self, base: u64) -> Option<&mut Uart> {
+        if base == UART_BASE {
+            return Some(&mut self.uart);
+        }
+        self.uarts
+            .iter_mut()
+            .find(|port| port.base == base)
+            .map(|port| &mut port.uart)
+    }
+
+    /// Attach `device` to the bus at `base..base + size`.
+    pub fn attach(&mut self, base: u64, size: u64, device: Box<dyn MmioDevice>) {
+        self.mmio.push(MmioRegion {
+            name: "mmio",
+            base,
+            size,
+            device,
+        });
+    }
+
+    /// Attach `device` to the bus at `base..base + size` as `name` in the memory map, such as an
+    /// optional device of the core. Fail if the registers overlap another region, including a
+    /// device attached before with the same name.
+    pub fn attach_named(
+        &mut self,
+        name: &'static str,
+        base: u64,
+        size: u64,
+        device: Box<dyn MmioDevice>,
+    ) -> Result<(), ConfigError> {
+        self.check_overlap(base, size)?;
+        self.mmio.push(MmioRegion {
+            name,
+            base,
+            size,
+            device,
+        });
+        Ok(())
+    }
+
+    /// Return the device of type `T` attached to the bus, such as the watchdog, if any.
+    pub fn device_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.mmio
+            .iter_mut()
+            .find_map(|region| region.device.as_any_mut()?.downcast_mut::<T>())
+    }
+
+    /// Return the name, the base address, and the size of each region on the bus in the order of
+    /// the addresses. Optional devices are only listed if they're enabled, and devices attached
+    /// from outside the core are named "mmio".
+    pub fn memory_map(&self) -> Vec<(String, u64, u64)> {
+        let mut map = vec![
+            ("rom".to_string(), MROM_BASE, MROM_END - MROM_BASE),
+            ("clint".to_string(), CLINT_BASE, CLINT_END - CLINT_BASE),
+            ("plic".to_string(), PLIC_BASE, PLIC_END - PLIC_BASE),
+            ("uart".to_string(), UART_BASE, UART_SIZE),
+            (
+                "virtio".to_string(),
+                VIRTIO_BASE,
+                VIRTIO_END + 1 - VIRTIO_BASE,
+            ),
+            ("dram".to_string(), DRAM_BASE, DRAM_SIZE),
+        ];
+        if let Some(htif) = &self.htif {
+            map.push(("htif".to_string(), htif.tohost_addr(), FROMHOST_OFFSET + 8));
+        }
+        if let Some(delay) = &self.delay {
+            map.push(("delay".to_string(), delay.base(), DELAY_SIZE));
+        }
+        if self.virtio_console.is_some() {
+            map.push((
+                "virtio_console".to_string(),
+                VIRTIO_CONSOLE_BASE,
+                VIRTIO_CONSOLE_SIZE,
+            ));
+        }
+        for port in &self.uarts {
+            map.push(("uart".to_string(), port.base, UART_SIZE));
+        }
+        for region in &self.mmio {
+            map.push((region.name.to_string(), region.base, region.size));
+        }
+        map.sort_by_key(|&(_, base, _)| base);
+        map
+    }
+
+    /// Return the whole DRAM, which starts at `DRAM_BASE`, to inspect it from the host.
+    pub fn dram(&self) -> &[u8] {
+        &self.dram.dram
+    }
+
+    /// Return the bytes of DRAM in `addr..addr + len` so that devices can access the memory
+    /// directly (DMA). Return `None` unless the whole range is backed by DRAM.
+    pub fn dma_slice(&mut self, addr: u64, len: u64) -> Option<&mut [u8]> {
+        let end = addr.checked_add(len)?;
+        if addr < DRAM_BASE || end > DRAM_BASE + DRAM_SIZE || self.is_bus_error(addr, len) {
+            return None;
+        }
+        // The device may write to the memory.
+        self.reservations.invalidate(addr, len);
+        let start = (addr - DRAM_BASE) as usize;
+        Some(&mut self.dram.dram[start..start + len as usize])
+    }
+
     /// Load a `size`-bit data from the device that connects to the system bus.
     pub fn read(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
-        match addr {
-            MROM_BASE..=MROM_END => self.rom.read(addr, size),
-            CLINT_BASE..=CLINT_END => self.clint.read(addr, size),
-            PLIC_BASE..=PLIC_END => self.plic.read(addr, size),
-            UART_BASE..=UART_END => self.uart.read(addr, size),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio.read(addr, size),
-            DRAM_BASE..=DRAM_END => self.dram.read(addr, size),
-            _ => Err(Exception::LoadAccessFault),
+        if !self.bus_errors.is_empty() && self.is_bus_error(addr, size as u64 / 8) {
+            return Err(Exception::LoadAccessFault);
         }
+        if let Some(htif) = &self.htif {
+            if htif.contains(addr) {
+                return htif.read(addr, size);
+            }
+        }
+        if let Some(delay) = &self.delay {
+            if delay.contains(addr) {
+                return delay.read(addr, size);
+            }
+        }
+        if let Some(console) = &mut self.virtio_console {
+            if VirtioConsole::contains(addr) {
+                return console.read(addr, size);
+            }
+        }
+
+        // DRAM accesses are far too frequent to be worth tracing.
+        if let DRAM_BASE..=DRAM_END = addr {
+            // The access may overhang the end of DRAM, which faults like the end of a device.
+            if addr > DRAM_END + 1 - size as u64 / 8 {
+                return Err(Exception::LoadAccessFault);
+            }
+            return self.dram.read(addr, size);
+        }
+
+        if !is_width_allowed(access_widths(addr), addr, size) {
+            return Err(Exception::LoadAccessFault);
+        }
+        // An access which straddles the end of a device faults as no device supports a split
+        // access.
+        let last = last_byte(addr, size);
+        let value = match (addr, last) {
+            (MROM_BASE..=MROM_END, MROM_BASE..=MROM_END) => self.rom.read(addr, size),
+            (CLINT_BASE..=CLINT_END, CLINT_BASE..=CLINT_END) => self.clint.read(addr, size),
+            (PLIC_BASE..=PLIC_END, PLIC_BASE..=PLIC_END) => self.plic.read(addr, size),
+            (UART_BASE..=UART_END, UART_BASE..=UART_END) => self.uart.read(addr, size),
+            (VIRTIO_BASE..=VIRTIO_END, VIRTIO_BASE..=VIRTIO_END) => self.virtio.read(addr, size),
+            _ => {
+                let mtime = self.clint.mtime();
+                let port = self
+                    .uarts
+                    .iter_mut()
+                    .find(|port| port.contains(addr) && port.contains(last));
+                let region = self
+                    .mmio
+                    .iter_mut()
+                    .find(|region| region.contains(addr) && region.contains(last));
+                match (port, region) {
+                    (Some(port), _) => port.read(addr, size),
+                    (None, Some(region)) => {
+                        region.device.set_mtime(mtime);
+                        region.read(addr, size)
+                    }
+                    (None, None) => Err(Exception::LoadAccessFault),
+                }
+            }
+        };
+        trace!("mmio read {:#x} ({} bits): {:x?}", addr, size, value);
+        value
     }
 
     /// Store a `size`-bit data to the device that connects to the system bus.
     pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
-        match addr {
-            CLINT_BASE..=CLINT_END => self.clint.write(addr, value, size),
-            PLIC_BASE..=PLIC_END => self.plic.write(addr, value, size),
-            UART_BASE..=UART_END => self.uart.write(addr, value as u8, size),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio.write(addr, value, size),
-            DRAM_BASE..=DRAM_END => self.dram.write(addr, value, size),
-            _ => Err(Exception::StoreAMOAccessFault),
+        if !self.bus_errors.is_empty() && self.is_bus_error(addr, size as u64 / 8) {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+
+        // "The SC must fail if a write from some other device to the bytes accessed by the LR can
+        // be observed to occur between the LR and SC."
+        self.reservations.invalidate(addr, size as u64 / 8);
+
+        if let Some(htif) = &mut self.htif {
+            if htif.contains(addr) {
+                return htif.write(addr, value, size, &mut self.dram, self.uart.backend());
+            }
+        }
+        if let Some(delay) = &mut self.delay {
+            if delay.contains(addr) {
+                return delay.write(addr, value, size);
+            }
+        }
+        if let Some(console) = &mut self.virtio_console {
+            if VirtioConsole::contains(addr) {
+                return console.write(addr, value, size);
+            }
+        }
+
+        if let DRAM_BASE..=DRAM_END = addr {
+            // The access may overhang the end of DRAM, which faults like the end of a device.
+            if addr > DRAM_END + 1 - size as u64 / 8 {
+                return Err(Exception::StoreAMOAccessFault);
+            }
+            return self.dram.write(addr, value, size);
+        }
+
+        trace!("mmio write {:#x} ({} bits): {:#x}", addr, size, value);
+        if !is_width_allowed(access_widths(addr), addr, size) {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+        let last = last_byte(addr, size);
+        match (addr, last) {
+            (CLINT_BASE..=CLINT_END, CLINT_BASE..=CLINT_END) => self.clint.write(addr, value, size),
+            (PLIC_BASE..=PLIC_END, PLIC_BASE..=PLIC_END) => self.plic.write(addr, value, size),
+            (UART_BASE..=UART_END, UART_BASE..=UART_END) => {
+                self.uart.write(addr, value as u8, size)
+            }
+            (VIRTIO_BASE..=VIRTIO_END, VIRTIO_BASE..=VIRTIO_END) => {
+                self.virtio.write(addr, value, size)
+            }
+            _ => {
+                let mtime = self.clint.mtime();
+                let port = self
+                    .uarts
+                    .iter_mut()
+                    .find(|port| port.contains(addr) && port.contains(last));
+                let region = self
+                    .mmio
+                    .iter_mut()
+                    .find(|region| region.contains(addr) && region.contains(last));
+                match (port, region) {
+                    (Some(port), _) => port.write(addr, value, size),
+                    (None, Some(region)) => {
+                        region.device.set_mtime(mtime);
+                        region.write(addr, value, size)
+                    }
+                    (None, None) => Err(Exception::StoreAMOAccessFault),
+                }
+            }
         }
     }
 }
+
+/// Return the access widths which the built-in device at `addr` declares.
+fn access_widths(addr: u64) -> &'static [WidthRule] {
+    match addr {
+        PLIC_BASE..=PLIC_END => PLIC_ACCESS_WIDTHS,
+        UART_BASE..=UART_END => UART_ACCESS_WIDTHS,
+        VIRTIO_BASE..=VIRTIO_END => VIRTIO_ACCESS_WIDTHS,
+        _ => &[],
+    }
+}
+
+/// Return the address of the last byte of the `size`-bit access at `addr`.
+fn last_byte(addr: u64, size: u8) -> u64 {
+    addr.wrapping_add(size as u64 / 8 - 1)
+}
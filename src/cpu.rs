@@ -1,24 +1,39 @@
 //! The cpu module contains the privileged mode, registers, and CPU.
 
-use std::cmp;
-use std::cmp::PartialEq;
-use std::collections::BTreeMap;
-use std::fmt;
-use std::num::FpCategory;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use core::cmp;
+use core::cmp::PartialEq;
+use core::fmt;
+use core::mem;
+use core::num::FpCategory;
+
+use log::{debug, warn};
 
 use crate::{
     bus::{Bus, DRAM_BASE},
     csr::*,
     devices::{
-        plic::PLIC_SCLAIM,
-        uart::UART_IRQ,
-        virtio_blk::{Virtio, VIRTIO_IRQ},
+        delay::Delay,
+        perfcounters::PerfCounters,
+        plic::{IrqSource, PLIC_MCONTEXT, PLIC_SCONTEXT},
+        virtio_blk::Virtio,
+        virtio_console::VirtioConsole,
+        watchdog::{Watchdog, WatchdogAction},
     },
     dram::DRAM_SIZE,
     exception::Exception,
     interrupt::Interrupt,
+    tlb::Tlb,
 };
 
+#[cfg(not(feature = "std"))]
+use crate::float::Float;
+#[cfg(feature = "threaded")]
+use crate::threaded::BlockCache;
+
 /// The stack pointer.
 const SP: u64 = 2;
 
@@ -28,6 +43,10 @@ const REGISTERS_COUNT: usize = 32;
 /// The page size (4 KiB) for the virtual memory system.
 const PAGE_SIZE: u64 = 4096;
 
+/// The size of a cache block which the cache-block operations of the Zicbom and Zicboz extensions
+/// work on.
+pub const CACHE_BLOCK_SIZE: u64 = 64;
+
 /// 8 bits. 1 byte.
 pub const BYTE: u8 = 8;
 /// 16 bits. 2 bytes.
@@ -40,14 +59,14 @@ pub const DOUBLEWORD: u8 = 64;
 macro_rules! inst_count {
     ($cpu:ident, $inst_name:expr) => {
         if $cpu.is_count {
-            *$cpu.inst_counter.entry($inst_name.to_string()).or_insert(0) += 1;
+            *$cpu.inst_counter.entry($inst_name).or_insert(0) += 1;
         }
     };
 }
 
 /// Access type that is used in the virtual address translation process. It decides which exception
 /// should raises (InstructionPageFault, LoadPageFault or StoreAMOPageFault).
-#[derive(Debug, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum AccessType {
     /// Raises the exception InstructionPageFault. It is used for an instruction fetch.
     Instruction,
@@ -57,6 +76,198 @@ pub enum AccessType {
     Store,
 }
 
+impl AccessType {
+    /// Return the page-fault exception for the access to the virtual address `addr`.
+    fn page_fault(self, addr: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionPageFault(addr),
+            AccessType::Load => Exception::LoadPageFault(addr),
+            AccessType::Store => Exception::StoreAMOPageFault(addr),
+        }
+    }
+}
+
+/// The rounding modes of floating-point instructions. They're encoded in the `rm` field of an
+/// instruction or in the `frm` field of `fcsr` when the `rm` field is dynamic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoundingMode {
+    /// Round to nearest, ties to even (RNE).
+    NearestEven,
+    /// Round towards zero (RTZ).
+    TowardZero,
+    /// Round down, towards negative infinity (RDN).
+    Down,
+    /// Round up, towards positive infinity (RUP).
+    Up,
+    /// Round to nearest, ties to max magnitude (RMM).
+    NearestMaxMagnitude,
+}
+
+impl RoundingMode {
+    /// Return the rounding mode encoded in `rm`, or `None` if `rm` is reserved or dynamic.
+    fn from_rm(rm: u64) -> Option<RoundingMode> {
+        match rm {
+            0b000 => Some(RoundingMode::NearestEven),
+            0b001 => Some(RoundingMode::TowardZero),
+            0b010 => Some(RoundingMode::Down),
+            0b011 => Some(RoundingMode::Up),
+            0b100 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+
+    /// Round `value` to an integral value in this rounding mode.
+    fn round(self, value: f64) -> f64 {
+        match self {
+            RoundingMode::NearestEven => value.round_ties_even(),
+            RoundingMode::TowardZero => value.trunc(),
+            RoundingMode::Down => value.floor(),
+            RoundingMode::Up => value.ceil(),
+            RoundingMode::NearestMaxMagnitude => value.round(),
+        }
+    }
+}
+
+/// The number of cycles each class of instructions takes. The cycles of an executed instruction
+/// are accumulated into the `mcycle` register, which allows a rough performance modeling of a
+/// guest program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CostModel {
+    /// Integer computational instructions and instructions not classified below.
+    pub alu: u64,
+    /// Load instructions including floating-point loads.
+    pub load: u64,
+    /// Store instructions including floating-point stores.
+    pub store: u64,
+    /// Conditional branch instructions.
+    pub branch: u64,
+    /// Unconditional jump instructions (jal and jalr).
+    pub jump: u64,
+    /// Multiplication instructions in the M extension.
+    pub mul: u64,
+    /// Division and remainder instructions in the M extension.
+    pub div: u64,
+    /// Atomic instructions in the A extension.
+    pub atomic: u64,
+    /// Floating-point computational instructions.
+    pub fp: u64,
+    /// CSR and privileged instructions.
+    pub system: u64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            alu: 1,
+            load: 2,
+            store: 1,
+            branch: 1,
+            jump: 1,
+            mul: 3,
+            div: 20,
+            atomic: 2,
+            fp: 4,
+            system: 1,
+        }
+    }
+}
+
+impl CostModel {
+    /// Return the number of cycles that `inst` takes. `inst` is either a 16-bit compressed
+    /// instruction or a 32-bit instruction.
+    pub fn cycles(&self, inst: u64) -> u64 {
+        self.class_cycles(InstClass::of(inst))
+    }
+
+    /// Return the number of cycles that an instruction of `class` takes.
+    fn class_cycles(&self, class: InstClass) -> u64 {
+        match class {
+            InstClass::Alu => self.alu,
+            InstClass::Load => self.load,
+            InstClass::Store => self.store,
+            InstClass::Branch => self.branch,
+            InstClass::Jump => self.jump,
+            InstClass::Mul => self.mul,
+            InstClass::Div => self.div,
+            InstClass::Atomic => self.atomic,
+            InstClass::Fp => self.fp,
+            InstClass::System => self.system,
+        }
+    }
+}
+
+/// The classes of instructions which the cost model and the performance counters distinguish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InstClass {
+    Alu,
+    Load,
+    Store,
+    Branch,
+    Jump,
+    Mul,
+    Div,
+    Atomic,
+    Fp,
+    System,
+}
+
+impl InstClass {
+    /// Classify `inst`, which is either a 16-bit compressed instruction or a 32-bit instruction.
+    fn of(inst: u64) -> Self {
+        let funct3 = (inst >> 13) & 0x7;
+        match inst & 0x3 {
+            // Quadrant 0.
+            0 => match funct3 {
+                0x1..=0x3 => Self::Load,
+                0x5..=0x7 => Self::Store,
+                _ => Self::Alu,
+            },
+            // Quadrant 1.
+            1 => match funct3 {
+                0x5 => Self::Jump,
+                0x6 | 0x7 => Self::Branch,
+                _ => Self::Alu,
+            },
+            // Quadrant 2.
+            2 => match funct3 {
+                0x1..=0x3 => Self::Load,
+                0x5..=0x7 => Self::Store,
+                // c.jr and c.jalr have no rs2 and a non-zero rs1.
+                0x4 if (inst >> 2) & 0x1f == 0 && (inst >> 7) & 0x1f != 0 => Self::Jump,
+                _ => Self::Alu,
+            },
+            _ => {
+                let funct3 = (inst >> 12) & 0x7;
+                let funct7 = (inst >> 25) & 0x7f;
+                match inst & 0x7f {
+                    0x03 | 0x07 => Self::Load,
+                    0x23 | 0x27 => Self::Store,
+                    0x2f => Self::Atomic,
+                    0x33 | 0x3b if funct7 == 0x01 => match funct3 {
+                        0x0..=0x3 => Self::Mul,
+                        _ => Self::Div,
+                    },
+                    0x43 | 0x47 | 0x4b | 0x4f | 0x53 => Self::Fp,
+                    0x63 => Self::Branch,
+                    0x67 | 0x6f => Self::Jump,
+                    0x73 => Self::System,
+                    _ => Self::Alu,
+                }
+            }
+        }
+    }
+
+    /// Return the event of the performance counters which an instruction of this class counts
+    /// towards, if any.
+    fn hpm_event(self) -> Option<u64> {
+        match self {
+            Self::Load => Some(HPM_EVENT_LOAD),
+            Self::Branch => Some(HPM_EVENT_BRANCH),
+            _ => None,
+        }
+    }
+}
+
 /// The privileged mode.
 #[derive(Debug, PartialEq, PartialOrd, Eq, Copy, Clone)]
 pub enum Mode {
@@ -67,7 +278,7 @@ pub enum Mode {
 }
 
 /// The integer registers.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct XRegisters {
     xregs: [u64; REGISTERS_COUNT],
 }
@@ -99,7 +310,8 @@ impl XRegisters {
         self.xregs[index as usize]
     }
 
-    /// Write the value to a register.
+    /// Write the value to a register. All instructions write their destination register through
+    /// this function so that writes to x0 are discarded in one place.
     pub fn write(&mut self, index: u64, value: u64) {
         // Register x0 is hardwired with all bits equal to 0.
         if index != 0 {
@@ -210,8 +422,50 @@ impl fmt::Display for FRegisters {
     }
 }
 
+/// The architectural state of a hart which isn't running. The emulator runs several harts on one
+/// `Cpu` by swapping their contexts in and out of it, so that all harts share the bus.
+pub struct HartContext {
+    pub hartid: u64,
+    pub xregs: XRegisters,
+    pub fregs: FRegisters,
+    pub pc: u64,
+    pub state: State,
+    pub mode: Mode,
+    pub prev_mode: Mode,
+    enable_paging: bool,
+    page_table: u64,
+    tlb: Tlb,
+    pub idle: bool,
+    pending_nmi: Option<u64>,
+}
+
+impl HartContext {
+    /// Create the context of the hart `hartid` which starts at `pc` in machine mode. Its a0
+    /// holds the hartid as a bootloader passes it.
+    pub fn new(hartid: u64, pc: u64) -> Self {
+        let mut xregs = XRegisters::new();
+        xregs.write(10, hartid);
+        Self {
+            hartid,
+            xregs,
+            fregs: FRegisters::new(),
+            pc,
+            state: State::new(),
+            mode: Mode::Machine,
+            prev_mode: Mode::Machine,
+            enable_paging: false,
+            page_table: 0,
+            tlb: Tlb::new(),
+            idle: false,
+            pending_nmi: None,
+        }
+    }
+}
+
 /// The CPU to contain registers, a program coutner, status, and a privileged mode.
 pub struct Cpu {
+    /// The hart ID which the read-only `mhartid` CSR returns.
+    pub hartid: u64,
     /// 64-bit integer registers.
     pub xregs: XRegisters,
     /// 64-bit floating-point registers.
@@ -230,21 +484,56 @@ pub struct Cpu {
     enable_paging: bool,
     /// Physical page number (PPN) × PAGE_SIZE (4096).
     page_table: u64,
-    /// A set of bytes that subsumes the bytes in the addressed word used in
-    /// load-reserved/store-conditional instructions.
-    reservation_set: Vec<u64>,
+    /// Translation lookaside buffer for the SV39 paging.
+    pub tlb: Tlb,
+    /// Predecoded blocks for the threaded-code interpreter.
+    #[cfg(feature = "threaded")]
+    pub block_cache: BlockCache,
     /// Idle state. True when WFI is called, and becomes false when an interrupt happens.
     pub idle: bool,
-    /// Counter of each instructions for debug.
-    pub inst_counter: BTreeMap<String, u64>,
-    /// The count flag. Count the number of each instruction executed.
+    /// The cause of the NMI which is waiting for `mnstatus.NMIE` to be taken.
+    pending_nmi: Option<u64>,
+    /// The address of the NMI handler. It's implementation-defined, and `DRAM_BASE` by default.
+    pub nmi_vector: u64,
+    /// Counter of each instructions for debug, keyed by mnemonic. It's a `BTreeMap` to work
+    /// without `std`, and it's read through `instruction_histogram`.
+    inst_counter: BTreeMap<&'static str, u64>,
+    /// The count flag. Count the number of each instruction executed. It's off by default
+    /// because it slows down every instruction.
     pub is_count: bool,
+    /// The Zicond flag. The conditional-zero instructions raise an illegal-instruction exception
+    /// unless it's true.
+    pub is_zicond: bool,
+    /// The Zbs flag. The single-bit instructions raise an illegal-instruction exception unless
+    /// it's true.
+    pub is_zbs: bool,
+    /// The Zbc flag. The carry-less multiplication instructions raise an illegal-instruction
+    /// exception unless it's true.
+    pub is_zbc: bool,
+    /// The Zbkb flag. The bit-manipulation instructions for cryptography raise an
+    /// illegal-instruction exception unless it's true.
+    pub is_zbkb: bool,
+    /// The Zbkc flag. `clmul` and `clmulh` are also legal when it's true, even without Zbc.
+    pub is_zbkc: bool,
+    /// The Zbkx flag. The crossbar permutation instructions raise an illegal-instruction exception
+    /// unless it's true.
+    pub is_zbkx: bool,
+    /// The Zicbom flag. The cache-block management instructions raise an illegal-instruction
+    /// exception unless it's true.
+    pub is_zicbom: bool,
+    /// The Zicboz flag. `cbo.zero` raises an illegal-instruction exception unless it's true.
+    pub is_zicboz: bool,
+    /// The number of cycles each class of instructions takes.
+    cost_model: CostModel,
+    /// Custom CSRs consulted before `state`.
+    csr_handlers: BTreeMap<CsrAddress, Box<dyn CsrDevice>>,
 }
 
 impl Cpu {
     /// Create a new `Cpu` object.
     pub fn new() -> Cpu {
         Cpu {
+            hartid: 0,
             xregs: XRegisters::new(),
             fregs: FRegisters::new(),
             pc: 0,
@@ -254,23 +543,230 @@ impl Cpu {
             bus: Bus::new(),
             enable_paging: false,
             page_table: 0,
-            reservation_set: Vec::new(),
+            tlb: Tlb::new(),
+            #[cfg(feature = "threaded")]
+            block_cache: BlockCache::new(),
             idle: false,
+            pending_nmi: None,
+            nmi_vector: DRAM_BASE,
             inst_counter: BTreeMap::new(),
             is_count: false,
+            is_zicond: true,
+            is_zbs: true,
+            is_zbc: true,
+            is_zbkb: true,
+            is_zbkc: true,
+            is_zbkx: true,
+            is_zicbom: true,
+            is_zicboz: true,
+            cost_model: CostModel::default(),
+            csr_handlers: BTreeMap::new(),
+        }
+    }
+
+    /// Return the number of times each instruction has been executed, keyed by mnemonic, e.g.,
+    /// to find which instructions dominate a workload. It's empty unless `is_count` is set. It
+    /// needs `std` for `HashMap`.
+    #[cfg(feature = "std")]
+    pub fn instruction_histogram(&self) -> std::collections::HashMap<&'static str, u64> {
+        self.inst_counter
+            .iter()
+            .map(|(&mnemonic, &count)| (mnemonic, count))
+            .collect()
+    }
+
+    /// Set the cost model which decides how many cycles each instruction takes.
+    pub fn set_cost_model(&mut self, cost_model: CostModel) {
+        self.cost_model = cost_model;
+    }
+
+    /// Register `handler` as the CSR at `addr`. Zicsr instructions access it instead of the CSR
+    /// in `state`.
+    pub fn register_csr_handler(&mut self, addr: CsrAddress, handler: Box<dyn CsrDevice>) {
+        self.csr_handlers.insert(addr, handler);
+    }
+
+    /// Read the CSR at `addr` on behalf of a Zicsr instruction.
+    fn read_csr(&mut self, addr: CsrAddress) -> u64 {
+        match self.csr_handlers.get_mut(&addr) {
+            Some(handler) => handler.read(),
+            None if addr == MHARTID => self.hartid,
+            None => self.state.read(addr),
+        }
+    }
+
+    /// Return the value which CSRRS and CSRRC modify for the CSR at `addr` read as `value`. It's
+    /// `value` itself except for `mip`, whose SEIP signal from the PLIC isn't written back.
+    fn csr_update_base(&self, addr: CsrAddress, value: u64) -> u64 {
+        match addr {
+            MIP if !self.csr_handlers.contains_key(&addr) => {
+                (value & !SEIP_BIT) | (self.state.read_mip_for_update() & SEIP_BIT)
+            }
+            _ => value,
+        }
+    }
+
+    /// Write `value` to the CSR at `addr` on behalf of a Zicsr instruction.
+    fn write_csr(&mut self, addr: CsrAddress, value: u64) {
+        match self.csr_handlers.get_mut(&addr) {
+            Some(handler) => handler.write(value),
+            None => {
+                self.state.write(addr, value);
+                match addr {
+                    SATP => self.update_paging(),
+                    FFLAGS | FRB | FCSR => self.set_fs_dirty(),
+                    _ => {}
+                }
+            }
         }
     }
 
-    /// Reset CPU states.
+    /// Mark the floating-point state as dirty in `mstatus.FS` so that a kernel saves it on a
+    /// context switch.
+    fn set_fs_dirty(&mut self) {
+        self.state
+            .write(MSTATUS, self.state.read(MSTATUS) | MSTATUS_FS);
+    }
+
+    /// Write `value` to the floating-point register `index` and mark the floating-point state as
+    /// dirty.
+    fn write_freg(&mut self, index: u64, value: f64) {
+        self.fregs.write(index, value);
+        self.set_fs_dirty();
+    }
+
+    /// Return true if ebreak in the current mode enters debug mode instead of raising a
+    /// breakpoint exception.
+    fn ebreak_enters_debug_mode(&self) -> bool {
+        let bit = match self.mode {
+            Mode::Machine => DCSR_EBREAKM,
+            Mode::Supervisor => DCSR_EBREAKS,
+            Mode::User => DCSR_EBREAKU,
+            Mode::Debug => return false,
+        };
+        self.state.read(DCSR) & bit != 0
+    }
+
+    /// Return true if the current privilege mode can execute the instructions enabled by `bit`.
+    /// M-mode always can, S-mode can if `bit` is set in `menvcfg`, and U-mode can if it's set in
+    /// both `menvcfg` and `senvcfg`.
+    fn is_envcfg_enabled(&self, bit: u64) -> bool {
+        let is_enabled = |addr| self.state.read(addr) & bit != 0;
+        match self.mode {
+            Mode::Machine | Mode::Debug => true,
+            Mode::Supervisor => is_enabled(MENVCFG),
+            Mode::User => is_enabled(MENVCFG) && is_enabled(SENVCFG),
+        }
+    }
+
+    /// Enter debug mode by ebreak at `pc`. The program counter and the privilege mode to resume
+    /// are saved in dpc and dcsr.
+    fn enter_debug_mode(&mut self, pc: u64) {
+        let prv = match self.mode {
+            Mode::User => 0b00,
+            Mode::Supervisor => 0b01,
+            _ => 0b11,
+        };
+        let cause_mask = 0b111 << DCSR_CAUSE_SHIFT;
+        let dcsr = self.state.read(DCSR) & !cause_mask & !DCSR_PRV;
+        self.state
+            .write(DCSR, dcsr | (DCSR_CAUSE_EBREAK << DCSR_CAUSE_SHIFT) | prv);
+        self.state.write(DPC, pc);
+        self.mode = Mode::Debug;
+        debug!("enter debug mode at {:#x}", pc);
+    }
+
+    /// Leave debug mode as dret does. Resume at dpc in the privilege mode saved in dcsr.
+    pub fn leave_debug_mode(&mut self) {
+        self.pc = self.state.read(DPC);
+        self.mode = match self.state.read(DCSR) & DCSR_PRV {
+            0b00 => Mode::User,
+            0b01 => Mode::Supervisor,
+            _ => Mode::Machine,
+        };
+        debug!("dret: return to {:?} mode at {:#x}", self.mode, self.pc);
+    }
+
+    /// Exchange the state of the running hart with `context`. The bus, the predecoded blocks, and
+    /// the configuration of the emulator stay.
+    pub fn swap_context(&mut self, context: &mut HartContext) {
+        mem::swap(&mut self.hartid, &mut context.hartid);
+        mem::swap(&mut self.xregs, &mut context.xregs);
+        mem::swap(&mut self.fregs, &mut context.fregs);
+        mem::swap(&mut self.pc, &mut context.pc);
+        mem::swap(&mut self.state, &mut context.state);
+        mem::swap(&mut self.mode, &mut context.mode);
+        mem::swap(&mut self.prev_mode, &mut context.prev_mode);
+        mem::swap(&mut self.enable_paging, &mut context.enable_paging);
+        mem::swap(&mut self.page_table, &mut context.page_table);
+        mem::swap(&mut self.tlb, &mut context.tlb);
+        mem::swap(&mut self.idle, &mut context.idle);
+        mem::swap(&mut self.pending_nmi, &mut context.pending_nmi);
+    }
+
+    /// Reset CPU states to the ones of a new CPU. The hartid, the bus, and the configuration,
+    /// such as the cost model and the CSR handlers, are kept.
     pub fn reset(&mut self) {
         self.pc = 0;
         self.mode = Mode::Machine;
         self.prev_mode = Mode::Machine;
         self.state.reset();
-        for i in 0..REGISTERS_COUNT {
-            self.xregs.write(i as u64, 0);
-            self.fregs.write(i as u64, 0.0);
+        self.enable_paging = false;
+        self.page_table = 0;
+        self.tlb.flush();
+        self.tlb.set_asid(0);
+        #[cfg(feature = "threaded")]
+        self.block_cache.flush();
+        self.idle = false;
+        self.pending_nmi = None;
+        self.xregs = XRegisters::new();
+        self.fregs = FRegisters::new();
+    }
+
+    /// Raise a resumable NMI with `cause`, such as a hardware error. It's taken before any
+    /// interrupt regardless of `mstatus.MIE`, but it waits while the hart is in an NMI handler.
+    /// Another NMI raised meanwhile replaces it.
+    pub fn raise_nmi(&mut self, cause: u64) {
+        self.pending_nmi = Some(cause);
+    }
+
+    /// Take the pending NMI if `mnstatus.NMIE` allows it, and return true if it's taken.
+    ///
+    /// The Smrnmi extension: "the hart saves the current pc in mnepc, the RNMI cause in mncause,
+    /// and the current privilege mode in mnstatus.MNPP. It then clears mnstatus.NMIE, sets the
+    /// privilege mode to M-mode, and jumps to the RNMI trap handler address."
+    pub fn take_pending_nmi(&mut self) -> bool {
+        // The debug specification: "All interrupts (including NMI) are masked" in Debug Mode, so
+        // the NMI stays pending until the hart leaves it.
+        if self.mode == Mode::Debug || self.state.read(MNSTATUS) & MNSTATUS_NMIE == 0 {
+            return false;
         }
+        let cause = match self.pending_nmi.take() {
+            Some(cause) => cause,
+            None => return false,
+        };
+        self.idle = false;
+        // "mncause[XLEN-1] is set to 1 on RNMI", as for an interrupt.
+        self.state.write(MNEPC, self.pc);
+        self.state.write(MNCAUSE, (1 << 63) | cause);
+        let mnpp = match self.mode {
+            Mode::User => 0b00,
+            Mode::Supervisor => 0b01,
+            Mode::Machine | Mode::Debug => 0b11,
+        };
+        let mnstatus = self.state.read(MNSTATUS) & !(MNSTATUS_NMIE | MNSTATUS_MNPP);
+        self.state.write(MNSTATUS, mnstatus | (mnpp << 11));
+        self.state.clear_nmie();
+        self.prev_mode = self.mode;
+        self.mode = Mode::Machine;
+        self.pc = self.nmi_vector;
+        debug!(
+            "NMI {:#x} at {:#x} from {:?} mode",
+            cause,
+            self.state.read(MNEPC),
+            self.prev_mode
+        );
+        true
     }
 
     /// Check interrupt flags for all devices that can interrupt.
@@ -280,47 +776,73 @@ impl Cpu {
         // local interrupt: CLINT (Core Local Interrupter) dispatches local interrupts to a hart
         //                  which directly connected to CLINT.
 
-        // 3.1.6.1 Privilege and Global Interrupt-Enable Stack in mstatus register
-        // "When a hart is executing in privilege mode x, interrupts are globally enabled when
-        // xIE=1 and globally disabled when xIE=0."
-        match self.mode {
-            Mode::Machine => {
-                // Check if the MIE bit is enabled.
-                if (self.state.read(MSTATUS) >> 3) & 1 == 0 {
-                    return None;
-                }
+        // Check external interrupt for uart and virtio. They stay pending in the PLIC while the
+        // hart masks them.
+        if self.bus.uart.is_interrupting() {
+            self.bus.raise_irq(IrqSource::Uart);
+        }
+        for port in &mut self.bus.uarts {
+            if port.uart.is_interrupting() {
+                self.bus.plic.set_pending_id(port.irq);
             }
-            Mode::Supervisor => {
-                // Check if the SIE bit is enabled.
-                if (self.state.read(SSTATUS) >> 1) & 1 == 0 {
-                    return None;
-                }
+        }
+        if self.bus.virtio.is_interrupting() {
+            // An interrupt is raised after a disk access is done.
+            // A malformed request is dropped rather than bringing down the emulator.
+            if let Err(exception) = Virtio::disk_access(self) {
+                warn!("virtio: failed to access the disk: {:?}", exception);
+            }
+            self.bus.raise_irq(IrqSource::Virtio);
+        }
+        let mtime = self.bus.clint.mtime();
+        let lapsed = self
+            .bus
+            .device_mut::<Watchdog>()
+            .and_then(|watchdog| watchdog.poll(mtime).then(|| watchdog.action()));
+        if let Some(WatchdogAction::Interrupt(irq)) = lapsed {
+            self.bus.plic.set_pending_id(irq);
+        }
+        if matches!(&self.bus.virtio_console, Some(console) if console.has_pending_queue()) {
+            match VirtioConsole::process_queues(&mut self.bus) {
+                Ok(true) => self.bus.raise_irq(IrqSource::VirtioConsole),
+                Ok(false) => {}
+                Err(exception) => warn!("virtio: failed to access the console: {:?}", exception),
             }
-            _ => {}
         }
 
-        // TODO: Take interrupts based on priorities.
-
-        // Check external interrupt for uart and virtio.
-        let irq;
-        if self.bus.uart.is_interrupting() {
-            irq = UART_IRQ;
-        } else if self.bus.virtio.is_interrupting() {
-            // An interrupt is raised after a disk access is done.
-            Virtio::disk_access(self).expect("failed to access the disk");
-            irq = VIRTIO_IRQ;
+        // The PLIC asserts the external interrupt of a context while the context has an interrupt
+        // to claim. MEIP is driven only by the PLIC, while SEIP is also set by software.
+        // TODO: assume that hart is 0
+        let mip = self.state.read_mip_for_update();
+        if self.bus.plic.is_interrupting(PLIC_MCONTEXT) {
+            self.state.write(MIP, mip | MEIP_BIT);
         } else {
-            irq = 0;
+            self.state.write(MIP, mip & !MEIP_BIT);
         }
+        let seip_line = self.bus.plic.is_interrupting(PLIC_SCONTEXT);
+        self.state.set_seip_line(seip_line);
 
-        if irq != 0 {
-            // TODO: assume that hart is 0
-            // TODO: write a value to MCLAIM if the mode is machine
-            self.bus
-                .write(PLIC_SCLAIM, irq, WORD)
-                .expect("failed to write an IRQ to the PLIC_SCLAIM");
-            self.state.write(MIP, self.state.read(MIP) | SEIP_BIT);
-        }
+        // 3.1.6.1 Privilege and Global Interrupt-Enable Stack in mstatus register
+        // "When a hart is executing in privilege mode x, interrupts are globally enabled when
+        // xIE=1 and globally disabled when xIE=0. Interrupts for lower-privilege modes, w<x, are
+        // always globally disabled regardless of the setting of any global wIE bit for the
+        // lower-privilege mode. Interrupts for higher-privilege modes, y>x, are always globally
+        // enabled regardless of the setting of the global yIE bit for the higher-privilege mode."
+        //
+        // The Smrnmi extension: "When NMIE=0, the hart behaves as though mstatus.MIE were clear".
+        let nmie = self.state.read(MNSTATUS) & MNSTATUS_NMIE != 0;
+        let machine_enabled = nmie
+            && match self.mode {
+                Mode::Machine => (self.state.read(MSTATUS) >> 3) & 1 == 1,
+                Mode::Debug => false,
+                _ => true,
+            };
+        let supervisor_enabled = nmie
+            && match self.mode {
+                Mode::User => true,
+                Mode::Supervisor => (self.state.read(SSTATUS) >> 1) & 1 == 1,
+                _ => false,
+            };
 
         // 3.1.9 Machine Interrupt Registers (mip and mie)
         // "An interrupt i will be taken if bit i is set in both mip and mie, and if interrupts are
@@ -332,43 +854,42 @@ impl Cpu {
         // mstatus) is set, or if the current privilege mode is less than the delegated privilege
         // mode."
         let pending = self.state.read(MIE) & self.state.read(MIP);
-
-        if (pending & MEIP_BIT) != 0 {
-            //println!("meip: check_pending_interrupt!");
-            self.state.write(MIP, self.state.read(MIP) & !MEIP_BIT);
-            return Some(Interrupt::MachineExternalInterrupt);
-        }
-        if (pending & MSIP_BIT) != 0 {
-            //println!("msip: check_pending_interrupt!");
-            self.state.write(MIP, self.state.read(MIP) & !MSIP_BIT);
-            return Some(Interrupt::MachineSoftwareInterrupt);
-        }
-        if (pending & MTIP_BIT) != 0 {
-            //println!("mtip: check_pending_interrupt!");
-            self.state.write(MIP, self.state.read(MIP) & !MTIP_BIT);
-            return Some(Interrupt::MachineTimerInterrupt);
+        let mideleg = self.state.read(MIDELEG);
+        let mut candidates = [0; 2];
+        if machine_enabled {
+            candidates[0] = pending & !mideleg;
         }
-        if (pending & SEIP_BIT) != 0 {
-            //println!("seip: check_pending_interrupt!");
-            self.state.write(MIP, self.state.read(MIP) & !SEIP_BIT);
-            return Some(Interrupt::SupervisorExternalInterrupt);
-        }
-        if (pending & SSIP_BIT) != 0 {
-            //println!("ssip: check_pending_interrupt!");
-            self.state.write(MIP, self.state.read(MIP) & !SSIP_BIT);
-            return Some(Interrupt::SupervisorSoftwareInterrupt);
-        }
-        if (pending & STIP_BIT) != 0 {
-            //println!("stip: check_pending_interrupt!");
-            self.state.write(MIP, self.state.read(MIP) & !STIP_BIT);
-            return Some(Interrupt::SupervisorTimerInterrupt);
+        if supervisor_enabled {
+            candidates[1] = pending & mideleg;
         }
 
-        return None;
+        // "Multiple simultaneous interrupts destined for M-mode are handled in the following
+        // decreasing priority order: MEI, MSI, MTI, SEI, SSI, STI." Interrupts destined for M-mode
+        // are handled before the ones delegated to S-mode, which are in the same order.
+        let priorities = [
+            (MEIP_BIT, Interrupt::MachineExternalInterrupt),
+            (MSIP_BIT, Interrupt::MachineSoftwareInterrupt),
+            (MTIP_BIT, Interrupt::MachineTimerInterrupt),
+            (SEIP_BIT, Interrupt::SupervisorExternalInterrupt),
+            (SSIP_BIT, Interrupt::SupervisorSoftwareInterrupt),
+            (STIP_BIT, Interrupt::SupervisorTimerInterrupt),
+        ];
+        for &candidate in &candidates {
+            if let Some(&(bit, interrupt)) = priorities.iter().find(|(bit, _)| candidate & bit != 0)
+            {
+                let mip = self.state.read_mip_for_update();
+                self.state.write(MIP, mip & !bit);
+                return Some(interrupt);
+            }
+        }
+        None
     }
 
     /// Update the physical page number (PPN) and the addressing mode.
     fn update_paging(&mut self) {
+        let page_table = self.page_table;
+        let enable_paging = self.enable_paging;
+
         // Read the physical page number (PPN) of the root page table, i.e., its
         // supervisor physical address divided by 4 KiB.
         self.page_table = self.state.read_bits(SATP, ..44) * PAGE_SIZE;
@@ -382,14 +903,77 @@ impl Cpu {
         } else {
             self.enable_paging = false;
         }
+
+        // Cached translations belong to the previous page table if the mode or the root has
+        // changed, even if the ASID has changed too, as an ASID may have been cached with another
+        // root. Changing only the ASID keeps the translations of the other address spaces.
+        let asid = self.state.read_bits(SATP, 44..60);
+        if self.enable_paging != enable_paging || self.page_table != page_table {
+            self.tlb.flush();
+        }
+        self.tlb.set_asid(asid);
     }
 
     /// Translate a virtual address to a physical address for the paged virtual-memory system.
     fn translate(&mut self, addr: u64, access_type: AccessType) -> Result<u64, Exception> {
+        // Bare mode and M-mode use the identity mapping, so neither a page-table walk nor a TLB
+        // lookup is needed.
         if !self.enable_paging || self.mode == Mode::Machine {
             return Ok(addr);
         }
 
+        if let Some((paddr, pte)) = self.tlb.lookup(addr) {
+            self.check_permission(addr, pte, access_type)?;
+            return Ok(paddr);
+        }
+        let (paddr, pte, level) = self.walk(addr, access_type)?;
+        self.check_permission(addr, pte, access_type)?;
+        self.tlb.insert(addr, paddr, pte, level);
+        Ok(paddr)
+    }
+
+    /// Check if the access to the virtual address `addr` is allowed by the leaf PTE, given the
+    /// current privilege mode and the value of the SUM and MXR fields of the mstatus register.
+    fn check_permission(
+        &self,
+        addr: u64,
+        pte: u64,
+        access_type: AccessType,
+    ) -> Result<(), Exception> {
+        let r = (pte >> 1) & 1;
+        let w = (pte >> 2) & 1;
+        let x = (pte >> 3) & 1;
+        let u = (pte >> 4) & 1;
+        let sum = self.state.read_bit(SSTATUS, 18);
+        let mxr = self.state.read_bit(SSTATUS, 19);
+
+        // 4.3.1 Addressing and Memory Protection
+        // "The U bit indicates whether the page is accessible to user mode. U-mode software may
+        // only access the page when U=1. If the SUM bit in the sstatus register is set, supervisor
+        // mode software may also access pages with U=1. However, supervisor code normally
+        // operates with the SUM bit clear, in which case, supervisor code will fault on accesses
+        // to user-mode pages. Irrespective of SUM, the supervisor may not execute code on pages
+        // with U=1."
+        let privileged = match self.mode {
+            Mode::User => u == 1,
+            _ => u == 0 || (sum == 1 && access_type != AccessType::Instruction),
+        };
+        let permitted = match access_type {
+            AccessType::Instruction => x == 1,
+            AccessType::Load => r == 1 || (mxr == 1 && x == 1),
+            AccessType::Store => w == 1,
+        };
+
+        if privileged && permitted {
+            return Ok(());
+        }
+        Err(access_type.page_fault(addr))
+    }
+
+    /// Walk the SV39 page table to translate a virtual address to a physical address. Return the
+    /// physical address, the leaf PTE, and the level of the page table where the leaf PTE is. The
+    /// permission of the leaf PTE isn't checked here.
+    fn walk(&mut self, addr: u64, access_type: AccessType) -> Result<(u64, u64, u64), Exception> {
         // 4.3.2 Virtual Address Translation Process
         // (The RISC-V Instruction Set Manual Volume II-Privileged Architecture_20190608)
         // A virtual address va is translated into a physical address pa as follows:
@@ -418,11 +1002,7 @@ impl Cpu {
             let w = (pte >> 2) & 1;
             let x = (pte >> 3) & 1;
             if v == 0 || (r == 0 && w == 1) {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                    AccessType::Load => return Err(Exception::LoadPageFault),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault),
-                }
+                return Err(access_type.page_fault(addr));
             }
 
             // 4. Otherwise, the PTE is valid. If pte.r = 1 or pte.x = 1, go to step 5.
@@ -437,14 +1017,9 @@ impl Cpu {
             let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
             a = ppn * PAGE_SIZE;
             if i < 0 {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                    AccessType::Load => return Err(Exception::LoadPageFault),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault),
-                }
+                return Err(access_type.page_fault(addr));
             }
         }
-        // TODO: implement step 5
         // 5. A leaf PTE has been found. Determine if the requested memory access is
         //    allowed by the pte.r, pte.w, pte.x, and pte.u bits, given the current
         //    privilege mode and the value of the SUM and MXR fields of the mstatus
@@ -476,11 +1051,7 @@ impl Cpu {
             for j in (0..i).rev() {
                 if ppn[j as usize] != 0 {
                     // A misaligned superpage.
-                    match access_type {
-                        AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                        AccessType::Load => return Err(Exception::LoadPageFault),
-                        AccessType::Store => return Err(Exception::StoreAMOPageFault),
-                    }
+                    return Err(access_type.page_fault(addr));
                 }
             }
         }
@@ -523,26 +1094,44 @@ impl Cpu {
         match i {
             0 => {
                 let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
-                Ok((ppn << 12) | offset)
+                Ok(((ppn << 12) | offset, pte, 0))
             }
             1 => {
                 // Superpage translation. A superpage is a memory page of larger size than an
                 // ordinary page (4 KiB). It reduces TLB misses and improves performance.
-                Ok((ppn[2] << 30) | (ppn[1] << 21) | (vpn[0] << 12) | offset)
+                Ok((
+                    (ppn[2] << 30) | (ppn[1] << 21) | (vpn[0] << 12) | offset,
+                    pte,
+                    1,
+                ))
             }
             2 => {
                 // Superpage translation. A superpage is a memory page of larger size than an
                 // ordinary page (4 KiB). It reduces TLB misses and improves performance.
-                Ok((ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset)
+                Ok((
+                    (ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset,
+                    pte,
+                    2,
+                ))
             }
-            _ => match access_type {
-                AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                AccessType::Load => return Err(Exception::LoadPageFault),
-                AccessType::Store => return Err(Exception::StoreAMOPageFault),
-            },
+            _ => return Err(access_type.page_fault(addr)),
         }
     }
 
+    /// Return the rounding mode of the floating-point instruction `inst`. The dynamic rounding
+    /// mode in the `rm` field selects `frm`, and a reserved one raises an illegal-instruction
+    /// exception.
+    ///
+    /// TODO: only the conversions from a float to an integer round in the returned mode. The
+    /// arithmetic instructions validate `rm` but always round to nearest, ties to even.
+    fn rounding_mode(&self, inst: u64) -> Result<RoundingMode, Exception> {
+        let rm = match (inst >> 12) & 0x7 {
+            0b111 => self.state.read_bits(FCSR, 5..8),
+            rm => rm,
+        };
+        RoundingMode::from_rm(rm).ok_or(Exception::IllegalInstruction(inst))
+    }
+
     /// Read `size`-bit data from the system bus with the translation a virtual address to a physical address
     /// if it is enabled.
     fn read(&mut self, v_addr: u64, size: u8) -> Result<u64, Exception> {
@@ -553,16 +1142,34 @@ impl Cpu {
     /// Write `size`-bit data to the system bus with the translation a virtual address to a physical address
     /// if it is enabled.
     fn write(&mut self, v_addr: u64, value: u64, size: u8) -> Result<(), Exception> {
-        // "The SC must fail if a write from some other device to the bytes accessed by the LR can
-        // be observed to occur between the LR and SC."
-        if self.reservation_set.contains(&v_addr) {
-            self.reservation_set.retain(|&x| x != v_addr);
-        }
-
-        let p_addr = self.translate(v_addr, AccessType::Load)?;
+        let p_addr = self.translate(v_addr, AccessType::Store)?;
+        // Self-modifying code invalidates the predecoded blocks.
+        #[cfg(feature = "threaded")]
+        self.block_cache.invalidate(p_addr);
         self.bus.write(p_addr, value, size)
     }
 
+    /// Read `size`-bit data at the virtual address `vaddr` through the current page tables and
+    /// privilege mode, e.g., to dereference a pointer held in a register. A fault is returned
+    /// as the exception a load would raise, and it isn't taken as a trap.
+    pub fn read_virt(&mut self, vaddr: u64, size: u8) -> Result<u64, Exception> {
+        self.read(vaddr, size)
+    }
+
+    /// Write `size`-bit `value` at the virtual address `vaddr` through the current page tables
+    /// and privilege mode. A fault is returned as the exception a store would raise, and it isn't
+    /// taken as a trap.
+    pub fn write_virt(&mut self, vaddr: u64, size: u8, value: u64) -> Result<(), Exception> {
+        self.write(vaddr, value, size)
+    }
+
+    /// Translate the virtual address `vaddr` of an instruction to a physical address through the
+    /// current page tables and privilege mode, e.g., to inspect the code at the program counter.
+    /// A fault is returned as the exception a fetch would raise, and it isn't taken as a trap.
+    pub fn translate_inst(&mut self, vaddr: u64) -> Result<u64, Exception> {
+        self.translate(vaddr, AccessType::Instruction)
+    }
+
     /// Fetch the `size`-bit next instruction from the memory at the current program counter.
     pub fn fetch(&mut self, size: u8) -> Result<u64, Exception> {
         let p_pc = self.translate(self.pc, AccessType::Instruction)?;
@@ -589,6 +1196,27 @@ impl Cpu {
         self.state.increment_time();
     }
 
+    /// Advance the timers of a hart waiting in `wfi` to just before the nearest `mtimecmp` or
+    /// `stimecmp` in the future, so that the next cycle on peripheral devices reaches it. Nothing
+    /// happens if no timer is set in the future.
+    pub fn fast_forward_timers(&mut self) {
+        if !self.idle {
+            return;
+        }
+        let cycles = [
+            self.bus.clint.cycles_until_interrupt(),
+            self.state.cycles_until_stimecmp(),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied();
+        if let Some(cycles) = cycles {
+            self.bus.clint.advance(cycles - 1);
+            self.state.advance_time(cycles - 1);
+        }
+    }
+
     /// Execute an instruction. Raises an exception if something is wrong, otherwise, returns
     /// the instruction executed in this cycle.
     pub fn execute(&mut self) -> Result<u64, Exception> {
@@ -597,22 +1225,115 @@ impl Cpu {
             return Ok(0);
         }
 
-        // Fetch.
-        let inst16 = self.fetch(HALFWORD)?;
+        // The delay device reads as the cycles before the instruction.
+        if let Some(delay) = &mut self.bus.delay {
+            delay.set_mcycle(self.state.read(MCYCLE));
+        }
+        self.sync_perf_counters();
+
+        // Fetch. A trap assumes that the program counter has already advanced past the faulting
+        // instruction, so advance it here too in order for the trap to point at the instruction.
+        let inst16 = match self.fetch(HALFWORD) {
+            Ok(inst16) => inst16,
+            Err(exception) => {
+                self.pc = self.pc.wrapping_add(4);
+                return Err(exception);
+            }
+        };
         let inst;
         match inst16 & 0b11 {
             0 | 1 | 2 => {
                 if inst16 == 0 {
                     // Unimplemented instruction, since all bits are 0.
-                    return Err(Exception::IllegalInstruction);
+                    self.pc = self.pc.wrapping_add(4);
+                    return Err(Exception::IllegalInstruction(inst16));
                 }
-                inst = self.execute_compressed()?
+                // A compressed instruction only advances the program counter by 2 bytes, so
+                // advance it as far as a 32-bit instruction does for a trap.
+                let pc = self.pc;
+                inst = match self.execute_compressed() {
+                    Ok(inst) => inst,
+                    Err(exception) => {
+                        self.pc = pc.wrapping_add(4);
+                        return Err(exception);
+                    }
+                };
             }
             _ => inst = self.execute_general()?,
         }
+
+        // Accumulate the cycles the instruction took into the MCYCLE register.
+        let cycles = self.account(inst);
+        let mut mcycle = self.state.read(MCYCLE).wrapping_add(cycles);
+        // A deadline written to the delay device stalls the hart until MCYCLE reaches it.
+        if let Some(deadline) = self.bus.delay.as_mut().and_then(Delay::take_deadline) {
+            mcycle = mcycle.max(deadline);
+        }
+        self.state.write(MCYCLE, mcycle);
+        Ok(inst)
+    }
+
+    /// Execute a basic block on the threaded-code interpreter. Raises an exception if something
+    /// is wrong, otherwise, returns the last instruction executed. Pending interrupts are only
+    /// taken between blocks.
+    #[cfg(feature = "threaded")]
+    pub fn execute_block(&mut self) -> Result<u64, Exception> {
+        // WFI is called and pending interrupts don't exist.
+        if self.idle {
+            return Ok(0);
+        }
+
+        // The classic interpreter raises the exception if the block can't be fetched.
+        let p_pc = match self.translate(self.pc, AccessType::Instruction) {
+            Ok(p_pc) => p_pc,
+            Err(_) => return self.execute(),
+        };
+
+        self.sync_perf_counters();
+        let block = self.block_cache.get_or_predecode(&mut self.bus, p_pc);
+        let generation = self.block_cache.generation();
+        let mut inst = 0;
+        for op in block.iter() {
+            inst = op.inst;
+            (op.handler)(self, op.inst)?;
+            // The rest of the block may have been overwritten.
+            if self.block_cache.generation() != generation {
+                break;
+            }
+        }
         Ok(inst)
     }
 
+    /// Finish a 32-bit instruction `inst` executed by a handler of the threaded-code interpreter.
+    #[cfg(feature = "threaded")]
+    pub(crate) fn retire(&mut self, inst: u64) {
+        self.pc = self.pc.wrapping_add(4);
+        let cycles = self.account(inst);
+        self.state
+            .write(MCYCLE, self.state.read(MCYCLE).wrapping_add(cycles));
+        // The next instruction of the block may read the counters through the device.
+        self.sync_perf_counters();
+    }
+
+    /// Let the performance-counter device read as the counters before the next instruction.
+    fn sync_perf_counters(&mut self) {
+        if let Some(counters) = self.bus.device_mut::<PerfCounters>() {
+            counters.set_counters(self.state.read(MCYCLE), self.state.read(MINSTRET));
+        }
+    }
+
+    /// Count the retired instruction `inst` in the performance counters and return the number
+    /// of cycles it took.
+    fn account(&mut self, inst: u64) -> u64 {
+        self.state
+            .write(MINSTRET, self.state.read(MINSTRET).wrapping_add(1));
+        let class = InstClass::of(inst);
+        if let Some(event) = class.hpm_event() {
+            self.state.count_event(event);
+        }
+        self.cost_model.class_cycles(class)
+    }
+
     /// Execute a compressed instruction. Raised an exception if something is wrong, otherwise,
     /// returns a fetched instruction. It also increments the program counter by 2 bytes.
     pub fn execute_compressed(&mut self) -> Result<u64, Exception> {
@@ -620,7 +1341,7 @@ impl Cpu {
         let inst = self.fetch(HALFWORD)?;
 
         // Add 2 bytes to the program counter.
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
 
         // 2. Decode.
         let opcode = inst & 0x3;
@@ -645,7 +1366,7 @@ impl Cpu {
                             | ((inst >> 2) & 0x8) // znuimm[3]
                             | ((inst >> 4) & 0x4); // znuimm[2]
                         if nzuimm == 0 {
-                            return Err(Exception::IllegalInstruction);
+                            return Err(Exception::IllegalInstruction(inst));
                         }
                         self.xregs
                             .write(rd, self.xregs.read(2).wrapping_add(nzuimm));
@@ -663,7 +1384,7 @@ impl Cpu {
                         let val = f64::from_bits(
                             self.read(self.xregs.read(rs1).wrapping_add(offset), DOUBLEWORD)?,
                         );
-                        self.fregs.write(rd, val);
+                        self.write_freg(rd, val);
                     }
                     0x2 => {
                         // c.lw
@@ -696,7 +1417,7 @@ impl Cpu {
                     }
                     0x4 => {
                         // Reserved.
-                        panic!("reserved");
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                     0x5 => {
                         // c.fsd
@@ -739,7 +1460,7 @@ impl Cpu {
                         self.write(addr, self.xregs.read(rs2), DOUBLEWORD)?;
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
@@ -959,12 +1680,12 @@ impl Cpu {
                                         );
                                     }
                                     _ => {
-                                        return Err(Exception::IllegalInstruction);
+                                        return Err(Exception::IllegalInstruction(inst));
                                     }
                                 }
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -1032,7 +1753,7 @@ impl Cpu {
                         }
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
@@ -1061,9 +1782,10 @@ impl Cpu {
                         let offset = ((inst << 4) & 0x1c0) // offset[8:6]
                             | ((inst >> 7) & 0x20) // offset[5]
                             | ((inst >> 2) & 0x18); // offset[4:3]
-                        let val =
-                            f64::from_bits(self.read(self.xregs.read(2) + offset, DOUBLEWORD)?);
-                        self.fregs.write(rd, val);
+                        let val = f64::from_bits(
+                            self.read(self.xregs.read(2).wrapping_add(offset), DOUBLEWORD)?,
+                        );
+                        self.write_freg(rd, val);
                     }
                     0x2 => {
                         // c.lwsp
@@ -1121,7 +1843,10 @@ impl Cpu {
                                     // Expands to ebreak.
                                     inst_count!(self, "c.ebreak");
 
-                                    return Err(Exception::Breakpoint);
+                                    if !self.ebreak_enters_debug_mode() {
+                                        return Err(Exception::Breakpoint);
+                                    }
+                                    self.enter_debug_mode(self.pc.wrapping_sub(2));
                                 } else {
                                     // c.jalr
                                     // Expands to jalr x1, 0(rs1).
@@ -1149,7 +1874,7 @@ impl Cpu {
                                 }
                             }
                             (_, _) => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -1190,12 +1915,12 @@ impl Cpu {
                         self.write(addr, self.xregs.read(rs2), DOUBLEWORD)?;
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
             _ => {
-                return Err(Exception::IllegalInstruction);
+                return Err(Exception::IllegalInstruction(inst));
             }
         }
         Ok(inst)
@@ -1208,7 +1933,7 @@ impl Cpu {
         let inst = self.fetch(WORD)?;
 
         // Add 4 bytes to the program counter.
-        self.pc += 4;
+        self.pc = self.pc.wrapping_add(4);
 
         // 2. Decode.
         let opcode = inst & 0x0000007f;
@@ -1276,7 +2001,7 @@ impl Cpu {
                         self.xregs.write(rd, val);
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
@@ -1291,36 +2016,115 @@ impl Cpu {
                         inst_count!(self, "flw");
 
                         let val = f32::from_bits(self.read(addr, WORD)? as u32);
-                        self.fregs.write(rd, val as f64);
+                        self.write_freg(rd, widen_f32(val));
                     }
                     0x3 => {
                         // fld
                         inst_count!(self, "fld");
 
                         let val = f64::from_bits(self.read(addr, DOUBLEWORD)?);
-                        self.fregs.write(rd, val);
+                        self.write_freg(rd, val);
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
             0x0f => {
                 // RV32I and RV64I
-                // fence instructions are not supportted yet because this emulator executes an
-                // instruction sequentially on a single thread.
                 // fence.i is a part of the Zifencei extension.
                 match funct3 {
                     0x0 => {
-                        // fence
-                        inst_count!(self, "fence");
+                        // Harts run one at a time on a single thread and each memory access
+                        // completes before the next one starts, so every access is already
+                        // ordered and fences don't need to do anything. The emulator may switch
+                        // harts at them instead.
+                        let fm = (inst >> 28) & 0xf;
+                        let pred = (inst >> 24) & 0xf;
+                        let succ = (inst >> 20) & 0xf;
+                        // The R and W bits of the predecessor and successor sets.
+                        const RW: u64 = 0b0011;
+                        if fm == 0b1000 && pred == RW && succ == RW {
+                            // fence.tso
+                            inst_count!(self, "fence.tso");
+                        } else {
+                            // fence
+                            // "Base implementations shall treat all such reserved configurations
+                            // as normal fences with FM=0000", so the other values of fm are
+                            // ignored.
+                            inst_count!(self, "fence");
+                        }
                     }
                     0x1 => {
                         // fence.i
                         inst_count!(self, "fence.i");
+
+                        #[cfg(feature = "threaded")]
+                        self.block_cache.flush();
+                    }
+                    0x2 if rd == 0 => {
+                        // The cache-block operations of the Zicbom and Zicboz extensions. There
+                        // are no caches, so the management instructions only check that the block
+                        // can be accessed.
+                        let addr = self.xregs.read(rs1);
+                        let block = addr & !(CACHE_BLOCK_SIZE - 1);
+                        // "If access to the cache block is not permitted, a cache-block
+                        // management instruction raises a store page fault", and every fault
+                        // reports the effective address rather than the start of the block. An
+                        // access fault, e.g., while walking the page table, is also raised as the
+                        // one of a store.
+                        let to_store_fault = |exception| match exception {
+                            Exception::LoadPageFault(_) | Exception::StoreAMOPageFault(_) => {
+                                Exception::StoreAMOPageFault(addr)
+                            }
+                            Exception::LoadAccessFault => Exception::StoreAMOAccessFault,
+                            exception => exception,
+                        };
+                        match inst >> 20 {
+                            0x0..=0x2 if self.is_zicbom => {
+                                let (name, is_enabled) = match inst >> 20 {
+                                    // CBIE isn't implemented, so cbo.inval is only allowed in
+                                    // M-mode, where it's performed as a flush.
+                                    0x0 => ("cbo.inval", self.mode == Mode::Machine),
+                                    0x1 => ("cbo.clean", self.is_envcfg_enabled(ENVCFG_CBCFE)),
+                                    _ => ("cbo.flush", self.is_envcfg_enabled(ENVCFG_CBCFE)),
+                                };
+                                if !is_enabled {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
+                                inst_count!(self, name);
+
+                                // Reading the block is enough to clean or flush it.
+                                self.translate(block, AccessType::Load)
+                                    .map_err(to_store_fault)?;
+                            }
+                            0x4 if self.is_zicboz => {
+                                // cbo.zero
+                                if !self.is_envcfg_enabled(ENVCFG_CBZE) {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
+                                inst_count!(self, "cbo.zero");
+
+                                // The block never crosses a page, so it's translated once. It's
+                                // zeroed as a whole only in DRAM, and any other memory faults
+                                // before a byte is written.
+                                let p_block = self
+                                    .translate(block, AccessType::Store)
+                                    .map_err(to_store_fault)?;
+                                match self.bus.dma_slice(p_block, CACHE_BLOCK_SIZE) {
+                                    Some(bytes) => bytes.fill(0),
+                                    None => return Err(Exception::StoreAMOAccessFault),
+                                }
+                                #[cfg(feature = "threaded")]
+                                self.block_cache.invalidate(p_block);
+                            }
+                            _ => {
+                                return Err(Exception::IllegalInstruction(inst));
+                            }
+                        }
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
@@ -1337,12 +2141,37 @@ impl Cpu {
                         self.xregs.write(rd, self.xregs.read(rs1).wrapping_add(imm));
                     }
                     0x1 => {
-                        // slli
-                        inst_count!(self, "slli");
-
                         // shamt size is 5 bits for RV32I and 6 bits for RV64I.
                         let shamt = (inst >> 20) & 0x3f;
-                        self.xregs.write(rd, self.xregs.read(rs1) << shamt);
+                        match funct6 {
+                            0x00 => {
+                                // slli
+                                inst_count!(self, "slli");
+
+                                self.xregs.write(rd, self.xregs.read(rs1) << shamt);
+                            }
+                            0x12 if self.is_zbs => {
+                                // bclri
+                                inst_count!(self, "bclri");
+
+                                self.xregs.write(rd, self.xregs.read(rs1) & !(1 << shamt));
+                            }
+                            0x1a if self.is_zbs => {
+                                // binvi
+                                inst_count!(self, "binvi");
+
+                                self.xregs.write(rd, self.xregs.read(rs1) ^ (1 << shamt));
+                            }
+                            0x0a if self.is_zbs => {
+                                // bseti
+                                inst_count!(self, "bseti");
+
+                                self.xregs.write(rd, self.xregs.read(rs1) | (1 << shamt));
+                            }
+                            _ => {
+                                return Err(Exception::IllegalInstruction(inst));
+                            }
+                        }
                     }
                     0x2 => {
                         // slti
@@ -1389,8 +2218,22 @@ impl Cpu {
                                 self.xregs
                                     .write(rd, ((self.xregs.read(rs1) as i64) >> shamt) as u64);
                             }
+                            0x12 if self.is_zbs => {
+                                // bexti
+                                inst_count!(self, "bexti");
+
+                                let shamt = (inst >> 20) & 0x3f;
+                                self.xregs.write(rd, (self.xregs.read(rs1) >> shamt) & 1);
+                            }
+                            0x1a if self.is_zbkb && (inst >> 20) & 0x3f == 0x07 => {
+                                // brev8
+                                inst_count!(self, "brev8");
+
+                                self.xregs.write(rd, brev8(self.xregs.read(rs1)));
+                            }
+                            // zip and unzip are only defined for RV32, so they stay illegal here.
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -1407,7 +2250,7 @@ impl Cpu {
                         self.xregs.write(rd, self.xregs.read(rs1) & imm);
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
@@ -1442,6 +2285,9 @@ impl Cpu {
                         inst_count!(self, "slliw");
 
                         // "SLLIW, SRLIW, and SRAIW encodings with imm[5] ̸= 0 are reserved."
+                        if funct7 != 0 {
+                            return Err(Exception::IllegalInstruction(inst));
+                        }
                         let shamt = (imm & 0x1f) as u32;
                         self.xregs
                             .write(rd, (self.xregs.read(rs1) << shamt) as i32 as i64 as u64);
@@ -1453,6 +2299,7 @@ impl Cpu {
                                 inst_count!(self, "srliw");
 
                                 // "SLLIW, SRLIW, and SRAIW encodings with imm[5] ̸= 0 are reserved."
+                                // imm[5] is the lowest bit of funct7, so they don't match here.
                                 let shamt = (imm & 0x1f) as u32;
                                 self.xregs.write(
                                     rd,
@@ -1464,6 +2311,7 @@ impl Cpu {
                                 inst_count!(self, "sraiw");
 
                                 // "SLLIW, SRLIW, and SRAIW encodings with imm[5] ̸= 0 are reserved."
+                                // imm[5] is the lowest bit of funct7, so they don't match here.
                                 let shamt = (imm & 0x1f) as u32;
                                 self.xregs.write(
                                     rd,
@@ -1471,12 +2319,12 @@ impl Cpu {
                                 );
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
@@ -1512,7 +2360,7 @@ impl Cpu {
                         self.write(addr, self.xregs.read(rs2), DOUBLEWORD)?
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
@@ -1526,7 +2374,11 @@ impl Cpu {
                         // fsw
                         inst_count!(self, "fsw");
 
-                        self.write(addr, (self.fregs.read(rs2) as f32).to_bits() as u64, WORD)?
+                        self.write(
+                            addr,
+                            narrow_f64(self.fregs.read(rs2)).to_bits() as u64,
+                            WORD,
+                        )?
                     }
                     0x3 => {
                         // fsd
@@ -1535,7 +2387,7 @@ impl Cpu {
                         self.write(addr, self.fregs.read(rs2).to_bits() as u64, DOUBLEWORD)?
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
@@ -1556,8 +2408,9 @@ impl Cpu {
                         // for 64-bit words and four-byte aligned for 32-bit words). If the
                         // address is not naturally aligned, an address-misaligned exception or
                         // an access-fault exception will be generated."
+                        // AMOs both read and write memory, so they raise the store/AMO variant.
                         if addr % 4 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, WORD)?;
                         self.write(addr, t.wrapping_add(self.xregs.read(rs2)), WORD)?;
@@ -1569,7 +2422,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 8 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, DOUBLEWORD)?;
                         self.write(addr, t.wrapping_add(self.xregs.read(rs2)), DOUBLEWORD)?;
@@ -1581,7 +2434,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 4 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, WORD)?;
                         self.write(addr, self.xregs.read(rs2), WORD)?;
@@ -1593,7 +2446,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 8 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, DOUBLEWORD)?;
                         self.write(addr, self.xregs.read(rs2), DOUBLEWORD)?;
@@ -1608,11 +2461,12 @@ impl Cpu {
                         // naturally aligned to the size of the operand (i.e., eight-byte aligned
                         // for 64-bit words and four-byte aligned for 32-bit words)."
                         if addr % 4 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::LoadAddressMisaligned(addr));
                         }
-                        let value = self.read(addr, WORD)?;
+                        let p_addr = self.translate(addr, AccessType::Load)?;
+                        let value = self.bus.read(p_addr, WORD)?;
                         self.xregs.write(rd, value as i32 as i64 as u64);
-                        self.reservation_set.push(addr);
+                        self.bus.reservations.reserve(self.hartid, p_addr);
                     }
                     (0x3, 0x02) => {
                         // lr.d
@@ -1623,11 +2477,12 @@ impl Cpu {
                         // naturally aligned to the size of the operand (i.e., eight-byte aligned for
                         // 64-bit words and four-byte aligned for 32-bit words)."
                         if addr % 8 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::LoadAddressMisaligned(addr));
                         }
-                        let value = self.read(addr, DOUBLEWORD)?;
+                        let p_addr = self.translate(addr, AccessType::Load)?;
+                        let value = self.bus.read(p_addr, DOUBLEWORD)?;
                         self.xregs.write(rd, value);
-                        self.reservation_set.push(addr);
+                        self.bus.reservations.reserve(self.hartid, p_addr);
                     }
                     (0x2, 0x03) => {
                         // sc.w
@@ -1638,16 +2493,13 @@ impl Cpu {
                         // naturally aligned to the size of the operand (i.e., eight-byte aligned for
                         // 64-bit words and four-byte aligned for 32-bit words)."
                         if addr % 4 != 0 {
-                            return Err(Exception::StoreAMOAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
-                        if self.reservation_set.contains(&addr) {
-                            // "Regardless of success or failure, executing an SC.W instruction
-                            // invalidates any reservation held by this hart. "
-                            self.reservation_set.retain(|&x| x != addr);
+                        let p_addr = self.translate(addr, AccessType::Store)?;
+                        if self.bus.reservations.release(self.hartid, p_addr) {
                             self.write(addr, self.xregs.read(rs2), WORD)?;
                             self.xregs.write(rd, 0);
                         } else {
-                            self.reservation_set.retain(|&x| x != addr);
                             self.xregs.write(rd, 1);
                         };
                     }
@@ -1660,14 +2512,13 @@ impl Cpu {
                         // naturally aligned to the size of the operand (i.e., eight-byte aligned for
                         // 64-bit words and four-byte aligned for 32-bit words)."
                         if addr % 8 != 0 {
-                            return Err(Exception::StoreAMOAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
-                        if self.reservation_set.contains(&addr) {
-                            self.reservation_set.retain(|&x| x != addr);
+                        let p_addr = self.translate(addr, AccessType::Store)?;
+                        if self.bus.reservations.release(self.hartid, p_addr) {
                             self.write(addr, self.xregs.read(rs2), DOUBLEWORD)?;
                             self.xregs.write(rd, 0);
                         } else {
-                            self.reservation_set.retain(|&x| x != addr);
                             self.xregs.write(rd, 1);
                         }
                     }
@@ -1677,7 +2528,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 4 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, WORD)?;
                         self.write(
@@ -1693,7 +2544,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 8 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, DOUBLEWORD)?;
                         self.write(addr, t ^ self.xregs.read(rs2), DOUBLEWORD)?;
@@ -1705,7 +2556,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 4 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, WORD)?;
                         self.write(
@@ -1721,7 +2572,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 8 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, DOUBLEWORD)?;
                         self.write(addr, t | self.xregs.read(rs2), DOUBLEWORD)?;
@@ -1733,7 +2584,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 4 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, WORD)?;
                         self.write(
@@ -1749,7 +2600,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 8 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, DOUBLEWORD)?;
                         self.write(addr, t & self.xregs.read(rs2), DOUBLEWORD)?;
@@ -1761,7 +2612,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 4 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, WORD)?;
                         self.write(
@@ -1777,7 +2628,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 8 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, DOUBLEWORD)?;
                         self.write(
@@ -1793,7 +2644,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 4 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, WORD)?;
                         self.write(
@@ -1809,7 +2660,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 8 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, DOUBLEWORD)?;
                         self.write(
@@ -1825,7 +2676,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 4 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, WORD)?;
                         self.write(
@@ -1841,7 +2692,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 8 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, DOUBLEWORD)?;
                         self.write(addr, cmp::min(t, self.xregs.read(rs2)), DOUBLEWORD)?;
@@ -1853,7 +2704,7 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 4 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, WORD)?;
                         self.write(
@@ -1869,14 +2720,14 @@ impl Cpu {
 
                         let addr = self.xregs.read(rs1);
                         if addr % 8 != 0 {
-                            return Err(Exception::LoadAddressMisaligned);
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
                         }
                         let t = self.read(addr, DOUBLEWORD)?;
                         self.write(addr, cmp::max(t, self.xregs.read(rs2)), DOUBLEWORD)?;
                         self.xregs.write(rd, t);
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
@@ -2111,8 +2962,117 @@ impl Cpu {
                             },
                         );
                     }
+                    (0x1, 0x24) if self.is_zbs => {
+                        // bclr
+                        inst_count!(self, "bclr");
+
+                        // "The index is taken modulo XLEN."
+                        let index = self.xregs.read(rs2) & 0x3f;
+                        self.xregs.write(rd, self.xregs.read(rs1) & !(1 << index));
+                    }
+                    (0x5, 0x24) if self.is_zbs => {
+                        // bext
+                        inst_count!(self, "bext");
+
+                        let index = self.xregs.read(rs2) & 0x3f;
+                        self.xregs.write(rd, (self.xregs.read(rs1) >> index) & 1);
+                    }
+                    (0x1, 0x34) if self.is_zbs => {
+                        // binv
+                        inst_count!(self, "binv");
+
+                        let index = self.xregs.read(rs2) & 0x3f;
+                        self.xregs.write(rd, self.xregs.read(rs1) ^ (1 << index));
+                    }
+                    (0x1, 0x14) if self.is_zbs => {
+                        // bset
+                        inst_count!(self, "bset");
+
+                        let index = self.xregs.read(rs2) & 0x3f;
+                        self.xregs.write(rd, self.xregs.read(rs1) | (1 << index));
+                    }
+                    (0x1, 0x05) if self.is_zbc || self.is_zbkc => {
+                        // clmul
+                        inst_count!(self, "clmul");
+
+                        // The lower half of the 128-bit carry-less product.
+                        let product = clmul(self.xregs.read(rs1), self.xregs.read(rs2));
+                        self.xregs.write(rd, product as u64);
+                    }
+                    (0x3, 0x05) if self.is_zbc || self.is_zbkc => {
+                        // clmulh
+                        inst_count!(self, "clmulh");
+
+                        // The upper half of the 128-bit carry-less product.
+                        let product = clmul(self.xregs.read(rs1), self.xregs.read(rs2));
+                        self.xregs.write(rd, (product >> 64) as u64);
+                    }
+                    (0x2, 0x05) if self.is_zbc => {
+                        // clmulr
+                        inst_count!(self, "clmulr");
+
+                        // Bits 2*XLEN-2 through XLEN-1 of the carry-less product, which is the
+                        // product of the bit-reversed operands reversed.
+                        let product = clmul(self.xregs.read(rs1), self.xregs.read(rs2));
+                        self.xregs.write(rd, (product >> 63) as u64);
+                    }
+                    (0x4, 0x04) if self.is_zbkb => {
+                        // pack
+                        inst_count!(self, "pack");
+
+                        // The lower halves of rs1 and rs2 go to the lower and upper halves of rd.
+                        let low = self.xregs.read(rs1) & 0xffff_ffff;
+                        self.xregs.write(rd, (self.xregs.read(rs2) << 32) | low);
+                    }
+                    (0x7, 0x04) if self.is_zbkb => {
+                        // packh
+                        inst_count!(self, "packh");
+
+                        let low = self.xregs.read(rs1) & 0xff;
+                        let high = self.xregs.read(rs2) & 0xff;
+                        self.xregs.write(rd, (high << 8) | low);
+                    }
+                    (0x2, 0x14) if self.is_zbkx => {
+                        // xperm4
+                        inst_count!(self, "xperm4");
+
+                        let value = xperm(self.xregs.read(rs1), self.xregs.read(rs2), 4);
+                        self.xregs.write(rd, value);
+                    }
+                    (0x4, 0x14) if self.is_zbkx => {
+                        // xperm8
+                        inst_count!(self, "xperm8");
+
+                        let value = xperm(self.xregs.read(rs1), self.xregs.read(rs2), 8);
+                        self.xregs.write(rd, value);
+                    }
+                    (0x5, 0x07) if self.is_zicond => {
+                        // czero.eqz
+                        inst_count!(self, "czero.eqz");
+
+                        // "If rs2 contains the value zero, this instruction writes the value zero
+                        // to rd. Otherwise, this instruction copies the contents of rs1 to rd."
+                        let value = match self.xregs.read(rs2) {
+                            0 => 0,
+                            _ => self.xregs.read(rs1),
+                        };
+                        self.xregs.write(rd, value);
+                    }
+                    (0x7, 0x07) if self.is_zicond => {
+                        // czero.nez
+                        inst_count!(self, "czero.nez");
+
+                        // "If rs2 contains a nonzero value, this instruction writes the value
+                        // zero to rd. Otherwise, this instruction copies the contents of rs1 to
+                        // rd."
+                        let value = match self.xregs.read(rs2) {
+                            0 => self.xregs.read(rs1),
+                            _ => 0,
+                        };
+                        self.xregs.write(rd, value);
+                    }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 };
             }
@@ -2122,7 +3082,8 @@ impl Cpu {
                 inst_count!(self, "lui");
 
                 // "LUI places the U-immediate value in the top 20 bits of the destination
-                // register rd, filling in the lowest 12 bits with zeros."
+                // register rd, filling in the lowest 12 bits with zeros." The 32-bit result is
+                // sign-extended to 64 bits on RV64.
                 self.xregs
                     .write(rd, (inst & 0xfffff000) as i32 as i64 as u64);
             }
@@ -2158,6 +3119,16 @@ impl Cpu {
                                 as u64,
                         );
                     }
+                    (0x4, 0x04) if self.is_zbkb => {
+                        // packw
+                        inst_count!(self, "packw");
+
+                        // The lower 16 bits of rs1 and rs2 form a word, which is sign-extended.
+                        let low = self.xregs.read(rs1) & 0xffff;
+                        let high = self.xregs.read(rs2) & 0xffff;
+                        self.xregs
+                            .write(rd, ((high << 16) | low) as i32 as i64 as u64);
+                    }
                     (0x1, 0x00) => {
                         // sllw
                         inst_count!(self, "sllw");
@@ -2275,21 +3246,21 @@ impl Cpu {
                         );
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
             0x43 => {
                 // RV32F and RV64F
-                // TODO: support the rounding mode encoding (rm).
+                self.rounding_mode(inst)?;
                 let rs3 = ((inst & 0xf8000000) >> 27) as u64;
-                let funct2 = (inst & 0x03000000) >> 25;
+                let funct2 = (inst & 0x06000000) >> 25;
                 match funct2 {
                     0x0 => {
                         // fmadd.s
                         inst_count!(self, "fmadd.s");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             (self.fregs.read(rs1) as f32)
                                 .mul_add(self.fregs.read(rs2) as f32, self.fregs.read(rs3) as f32)
@@ -2300,7 +3271,7 @@ impl Cpu {
                         // fmadd.d
                         inst_count!(self, "fmadd.d");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             self.fregs
                                 .read(rs1)
@@ -2308,21 +3279,21 @@ impl Cpu {
                         );
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
             0x47 => {
                 // RV32F and RV64F
-                // TODO: support the rounding mode encoding (rm).
+                self.rounding_mode(inst)?;
                 let rs3 = ((inst & 0xf8000000) >> 27) as u64;
-                let funct2 = (inst & 0x03000000) >> 25;
+                let funct2 = (inst & 0x06000000) >> 25;
                 match funct2 {
                     0x0 => {
                         // fmsub.s
                         inst_count!(self, "fmsub.s");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             (self.fregs.read(rs1) as f32)
                                 .mul_add(self.fregs.read(rs2) as f32, -self.fregs.read(rs3) as f32)
@@ -2333,7 +3304,7 @@ impl Cpu {
                         // fmsub.d
                         inst_count!(self, "fmsub.d");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             self.fregs
                                 .read(rs1)
@@ -2341,21 +3312,21 @@ impl Cpu {
                         );
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
             0x4b => {
                 // RV32F and RV64F
-                // TODO: support the rounding mode encoding (rm).
+                self.rounding_mode(inst)?;
                 let rs3 = ((inst & 0xf8000000) >> 27) as u64;
-                let funct2 = (inst & 0x03000000) >> 25;
+                let funct2 = (inst & 0x06000000) >> 25;
                 match funct2 {
                     0x0 => {
                         // fnmadd.s
                         inst_count!(self, "fnmadd.s");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             (-self.fregs.read(rs1) as f32)
                                 .mul_add(self.fregs.read(rs2) as f32, self.fregs.read(rs3) as f32)
@@ -2366,28 +3337,28 @@ impl Cpu {
                         // fnmadd.d
                         inst_count!(self, "fnmadd.d");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             (-self.fregs.read(rs1))
                                 .mul_add(self.fregs.read(rs2), self.fregs.read(rs3)),
                         );
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
             0x4f => {
                 // RV32F and RV64F
-                // TODO: support the rounding mode encoding (rm).
+                self.rounding_mode(inst)?;
                 let rs3 = ((inst & 0xf8000000) >> 27) as u64;
-                let funct2 = (inst & 0x03000000) >> 25;
+                let funct2 = (inst & 0x06000000) >> 25;
                 match funct2 {
                     0x0 => {
                         // fnmsub.s
                         inst_count!(self, "fnmsub.s");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             (-self.fregs.read(rs1) as f32)
                                 .mul_add(self.fregs.read(rs2) as f32, -self.fregs.read(rs3) as f32)
@@ -2398,20 +3369,19 @@ impl Cpu {
                         // fnmsub.d
                         inst_count!(self, "fnmsub.d");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             (-self.fregs.read(rs1))
                                 .mul_add(self.fregs.read(rs2), -self.fregs.read(rs3)),
                         );
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
             0x53 => {
                 // RV32F and RV64F
-                // TODO: support the rounding mode encoding (rm).
                 // TODO: NaN Boxing of Narrower Values (Spec 12.2).
                 // TODO: set exception flags.
 
@@ -2425,17 +3395,25 @@ impl Cpu {
                  *
                  */
 
-                // Check the frm field is valid.
-                match self.state.read_bits(FCSR, 5..8) {
-                    0b000 => {}
-                    0b001 => {}
-                    0b010 => {}
-                    0b011 => {}
-                    0b100 => {}
-                    0b111 => {}
-                    _ => {
-                        return Err(Exception::IllegalInstruction);
+                // fsgnj, fmin, fmax, fmv, fclass, and the comparisons use funct3 as a minor opcode
+                // instead of the rounding mode.
+                let rm = match funct7 {
+                    0x10 | 0x11 | 0x14 | 0x15 | 0x50 | 0x51 | 0x70 | 0x71 | 0x78 | 0x79 => {
+                        RoundingMode::NearestEven
                     }
+                    _ => self.rounding_mode(inst)?,
+                };
+
+                // The unary instructions use rs2 as a minor opcode, and the other values are
+                // reserved.
+                let is_reserved = match funct7 {
+                    0x20 => rs2 != 1,
+                    0x21 | 0x2c | 0x2d | 0x70 | 0x71 => rs2 != 0,
+                    0x78 | 0x79 => rs2 != 0 || funct3 != 0,
+                    _ => false,
+                };
+                if is_reserved {
+                    return Err(Exception::IllegalInstruction(inst));
                 }
 
                 match funct7 {
@@ -2443,7 +3421,7 @@ impl Cpu {
                         // fadd.s
                         inst_count!(self, "fadd.s");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             (self.fregs.read(rs1) as f32 + self.fregs.read(rs2) as f32) as f64,
                         )
@@ -2452,14 +3430,13 @@ impl Cpu {
                         // fadd.d
                         inst_count!(self, "fadd.d");
 
-                        self.fregs
-                            .write(rd, self.fregs.read(rs1) + self.fregs.read(rs2));
+                        self.write_freg(rd, self.fregs.read(rs1) + self.fregs.read(rs2));
                     }
                     0x04 => {
                         // fsub.s
                         inst_count!(self, "fsub.s");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             (self.fregs.read(rs1) as f32 - self.fregs.read(rs2) as f32) as f64,
                         )
@@ -2468,14 +3445,13 @@ impl Cpu {
                         // fsub.d
                         inst_count!(self, "fsub.d");
 
-                        self.fregs
-                            .write(rd, self.fregs.read(rs1) - self.fregs.read(rs2));
+                        self.write_freg(rd, self.fregs.read(rs1) - self.fregs.read(rs2));
                     }
                     0x08 => {
                         // fmul.s
                         inst_count!(self, "fmul.s");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             (self.fregs.read(rs1) as f32 * self.fregs.read(rs2) as f32) as f64,
                         )
@@ -2484,14 +3460,13 @@ impl Cpu {
                         // fmul.d
                         inst_count!(self, "fmul.d");
 
-                        self.fregs
-                            .write(rd, self.fregs.read(rs1) * self.fregs.read(rs2));
+                        self.write_freg(rd, self.fregs.read(rs1) * self.fregs.read(rs2));
                     }
                     0x0c => {
                         // fdiv.s
                         inst_count!(self, "fdiv.s");
 
-                        self.fregs.write(
+                        self.write_freg(
                             rd,
                             (self.fregs.read(rs1) as f32 / self.fregs.read(rs2) as f32) as f64,
                         )
@@ -2500,8 +3475,7 @@ impl Cpu {
                         // fdiv.d
                         inst_count!(self, "fdiv.d");
 
-                        self.fregs
-                            .write(rd, self.fregs.read(rs1) / self.fregs.read(rs2));
+                        self.write_freg(rd, self.fregs.read(rs1) / self.fregs.read(rs2));
                     }
                     0x10 => {
                         match funct3 {
@@ -2509,14 +3483,16 @@ impl Cpu {
                                 // fsgnj.s
                                 inst_count!(self, "fsgnj.s");
 
-                                self.fregs
-                                    .write(rd, self.fregs.read(rs1).copysign(self.fregs.read(rs2)));
+                                self.write_freg(
+                                    rd,
+                                    self.fregs.read(rs1).copysign(self.fregs.read(rs2)),
+                                );
                             }
                             0x1 => {
                                 // fsgnjn.s
                                 inst_count!(self, "fsgnjn.s");
 
-                                self.fregs.write(
+                                self.write_freg(
                                     rd,
                                     self.fregs.read(rs1).copysign(-self.fregs.read(rs2)),
                                 );
@@ -2528,11 +3504,10 @@ impl Cpu {
                                 let sign1 = (self.fregs.read(rs1) as f32).to_bits() & 0x80000000;
                                 let sign2 = (self.fregs.read(rs2) as f32).to_bits() & 0x80000000;
                                 let other = (self.fregs.read(rs1) as f32).to_bits() & 0x7fffffff;
-                                self.fregs
-                                    .write(rd, f32::from_bits((sign1 ^ sign2) | other) as f64);
+                                self.write_freg(rd, f32::from_bits((sign1 ^ sign2) | other) as f64);
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2542,14 +3517,16 @@ impl Cpu {
                                 // fsgnj.d
                                 inst_count!(self, "fsgnj.d");
 
-                                self.fregs
-                                    .write(rd, self.fregs.read(rs1).copysign(self.fregs.read(rs2)));
+                                self.write_freg(
+                                    rd,
+                                    self.fregs.read(rs1).copysign(self.fregs.read(rs2)),
+                                );
                             }
                             0x1 => {
                                 // fsgnjn.d
                                 inst_count!(self, "fsgnjn.d");
 
-                                self.fregs.write(
+                                self.write_freg(
                                     rd,
                                     self.fregs.read(rs1).copysign(-self.fregs.read(rs2)),
                                 );
@@ -2561,11 +3538,10 @@ impl Cpu {
                                 let sign1 = self.fregs.read(rs1).to_bits() & 0x80000000_00000000;
                                 let sign2 = self.fregs.read(rs2).to_bits() & 0x80000000_00000000;
                                 let other = self.fregs.read(rs1).to_bits() & 0x7fffffff_ffffffff;
-                                self.fregs
-                                    .write(rd, f64::from_bits((sign1 ^ sign2) | other));
+                                self.write_freg(rd, f64::from_bits((sign1 ^ sign2) | other));
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2575,18 +3551,16 @@ impl Cpu {
                                 // fmin.s
                                 inst_count!(self, "fmin.s");
 
-                                self.fregs
-                                    .write(rd, self.fregs.read(rs1).min(self.fregs.read(rs2)));
+                                self.write_freg(rd, self.fregs.read(rs1).min(self.fregs.read(rs2)));
                             }
                             0x1 => {
                                 // fmax.s
                                 inst_count!(self, "fmax.s");
 
-                                self.fregs
-                                    .write(rd, self.fregs.read(rs1).max(self.fregs.read(rs2)));
+                                self.write_freg(rd, self.fregs.read(rs1).max(self.fregs.read(rs2)));
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2596,18 +3570,16 @@ impl Cpu {
                                 // fmin.d
                                 inst_count!(self, "fmin.d");
 
-                                self.fregs
-                                    .write(rd, self.fregs.read(rs1).min(self.fregs.read(rs2)));
+                                self.write_freg(rd, self.fregs.read(rs1).min(self.fregs.read(rs2)));
                             }
                             0x1 => {
                                 // fmax.d
                                 inst_count!(self, "fmax.d");
 
-                                self.fregs
-                                    .write(rd, self.fregs.read(rs1).max(self.fregs.read(rs2)));
+                                self.write_freg(rd, self.fregs.read(rs1).max(self.fregs.read(rs2)));
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2615,26 +3587,25 @@ impl Cpu {
                         // fcvt.s.d
                         inst_count!(self, "fcvt.s.d");
 
-                        self.fregs.write(rd, self.fregs.read(rs1));
+                        self.write_freg(rd, self.fregs.read(rs1));
                     }
                     0x21 => {
                         // fcvt.d.s
                         inst_count!(self, "fcvt.d.s");
 
-                        self.fregs.write(rd, (self.fregs.read(rs1) as f32) as f64);
+                        self.write_freg(rd, (self.fregs.read(rs1) as f32) as f64);
                     }
                     0x2c => {
                         // fsqrt.s
                         inst_count!(self, "fsqrt.s");
 
-                        self.fregs
-                            .write(rd, (self.fregs.read(rs1) as f32).sqrt() as f64);
+                        self.write_freg(rd, (self.fregs.read(rs1) as f32).sqrt() as f64);
                     }
                     0x2d => {
                         // fsqrt.d
                         inst_count!(self, "fsqrt.d");
 
-                        self.fregs.write(rd, self.fregs.read(rs1).sqrt());
+                        self.write_freg(rd, self.fregs.read(rs1).sqrt());
                     }
                     0x50 => {
                         match funct3 {
@@ -2678,7 +3649,7 @@ impl Cpu {
                                 );
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2724,7 +3695,7 @@ impl Cpu {
                                 );
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2736,7 +3707,7 @@ impl Cpu {
 
                                 self.xregs.write(
                                     rd,
-                                    ((self.fregs.read(rs1) as f32).round() as i32) as u64,
+                                    (rm.round((self.fregs.read(rs1) as f32) as f64) as i32) as u64,
                                 );
                             }
                             0x1 => {
@@ -2745,25 +3716,30 @@ impl Cpu {
 
                                 self.xregs.write(
                                     rd,
-                                    (((self.fregs.read(rs1) as f32).round() as u32) as i32) as u64,
+                                    ((rm.round((self.fregs.read(rs1) as f32) as f64) as u32) as i32)
+                                        as u64,
                                 );
                             }
                             0x2 => {
                                 // fcvt.l.s
                                 inst_count!(self, "fcvt.l.s");
 
-                                self.xregs
-                                    .write(rd, (self.fregs.read(rs1) as f32).round() as u64);
+                                self.xregs.write(
+                                    rd,
+                                    rm.round((self.fregs.read(rs1) as f32) as f64) as i64 as u64,
+                                );
                             }
                             0x3 => {
                                 // fcvt.lu.s
                                 inst_count!(self, "fcvt.lu.s");
 
-                                self.xregs
-                                    .write(rd, (self.fregs.read(rs1) as f32).round() as u64);
+                                self.xregs.write(
+                                    rd,
+                                    rm.round((self.fregs.read(rs1) as f32) as f64) as u64,
+                                );
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2774,7 +3750,7 @@ impl Cpu {
                                 inst_count!(self, "fcvt.w.d");
 
                                 self.xregs
-                                    .write(rd, (self.fregs.read(rs1).round() as i32) as u64);
+                                    .write(rd, (rm.round(self.fregs.read(rs1)) as i32) as u64);
                             }
                             0x1 => {
                                 // fcvt.wu.d
@@ -2782,23 +3758,24 @@ impl Cpu {
 
                                 self.xregs.write(
                                     rd,
-                                    ((self.fregs.read(rs1).round() as u32) as i32) as u64,
+                                    ((rm.round(self.fregs.read(rs1)) as u32) as i32) as u64,
                                 );
                             }
                             0x2 => {
                                 // fcvt.l.d
                                 inst_count!(self, "fcvt.l.d");
 
-                                self.xregs.write(rd, self.fregs.read(rs1).round() as u64);
+                                self.xregs
+                                    .write(rd, rm.round(self.fregs.read(rs1)) as i64 as u64);
                             }
                             0x3 => {
                                 // fcvt.lu.d
                                 inst_count!(self, "fcvt.lu.d");
 
-                                self.xregs.write(rd, self.fregs.read(rs1).round() as u64);
+                                self.xregs.write(rd, rm.round(self.fregs.read(rs1)) as u64);
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2808,31 +3785,28 @@ impl Cpu {
                                 // fcvt.s.w
                                 inst_count!(self, "fcvt.s.w");
 
-                                self.fregs
-                                    .write(rd, ((self.xregs.read(rs1) as i32) as f32) as f64);
+                                self.write_freg(rd, ((self.xregs.read(rs1) as i32) as f32) as f64);
                             }
                             0x1 => {
                                 // fcvt.s.wu
                                 inst_count!(self, "fcvt.s.wu");
 
-                                self.fregs
-                                    .write(rd, ((self.xregs.read(rs1) as u32) as f32) as f64);
+                                self.write_freg(rd, ((self.xregs.read(rs1) as u32) as f32) as f64);
                             }
                             0x2 => {
                                 // fcvt.s.l
                                 inst_count!(self, "fcvt.s.l");
 
-                                self.fregs.write(rd, (self.xregs.read(rs1) as f32) as f64);
+                                self.write_freg(rd, (self.xregs.read(rs1) as f32) as f64);
                             }
                             0x3 => {
                                 // fcvt.s.lu
                                 inst_count!(self, "fcvt.s.lu");
 
-                                self.fregs
-                                    .write(rd, ((self.xregs.read(rs1) as u64) as f32) as f64);
+                                self.write_freg(rd, (self.xregs.read(rs1) as f32) as f64);
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2842,28 +3816,28 @@ impl Cpu {
                                 // fcvt.d.w
                                 inst_count!(self, "fcvt.d.w");
 
-                                self.fregs.write(rd, (self.xregs.read(rs1) as i32) as f64);
+                                self.write_freg(rd, (self.xregs.read(rs1) as i32) as f64);
                             }
                             0x1 => {
                                 // fcvt.d.wu
                                 inst_count!(self, "fcvt.d.wu");
 
-                                self.fregs.write(rd, (self.xregs.read(rs1) as u32) as f64);
+                                self.write_freg(rd, (self.xregs.read(rs1) as u32) as f64);
                             }
                             0x2 => {
                                 // fcvt.d.l
                                 inst_count!(self, "fcvt.d.l");
 
-                                self.fregs.write(rd, self.xregs.read(rs1) as f64);
+                                self.write_freg(rd, self.xregs.read(rs1) as f64);
                             }
                             0x3 => {
                                 // fcvt.d.lu
                                 inst_count!(self, "fcvt.d.lu");
 
-                                self.fregs.write(rd, self.xregs.read(rs1) as f64);
+                                self.write_freg(rd, self.xregs.read(rs1) as f64);
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2876,37 +3850,24 @@ impl Cpu {
                                 // "The bits are not modified in the transfer"
                                 self.xregs.write(
                                     rd,
-                                    (self.fregs.read(rs1).to_bits() as u32) as i32 as i64 as u64,
+                                    narrow_f64(self.fregs.read(rs1)).to_bits() as i32 as i64 as u64,
                                 );
                             }
                             0x1 => {
                                 // fclass.s
                                 inst_count!(self, "fclass.s");
 
-                                let f = self.fregs.read(rs1);
-                                match f.classify() {
-                                    FpCategory::Infinite => {
-                                        self.xregs
-                                            .write(rd, if f.is_sign_negative() { 0 } else { 7 });
-                                    }
-                                    FpCategory::Normal => {
-                                        self.xregs
-                                            .write(rd, if f.is_sign_negative() { 1 } else { 6 });
-                                    }
-                                    FpCategory::Subnormal => {
-                                        self.xregs
-                                            .write(rd, if f.is_sign_negative() { 2 } else { 5 });
-                                    }
-                                    FpCategory::Zero => {
-                                        self.xregs
-                                            .write(rd, if f.is_sign_negative() { 3 } else { 4 });
-                                    }
-                                    // don't support a signaling nan, only support a quiet nan.
-                                    FpCategory::Nan => self.xregs.write(rd, 9),
-                                }
+                                // A single-precision value is classified before it's widened, since
+                                // a subnormal one is normal in double precision.
+                                let f = narrow_f64(self.fregs.read(rs1));
+                                let is_quiet = f.to_bits() & (1 << 22) != 0;
+                                self.xregs.write(
+                                    rd,
+                                    fclass(f.classify(), f.is_sign_negative(), is_quiet),
+                                );
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2924,29 +3885,14 @@ impl Cpu {
                                 inst_count!(self, "fclass.d");
 
                                 let f = self.fregs.read(rs1);
-                                match f.classify() {
-                                    FpCategory::Infinite => {
-                                        self.xregs
-                                            .write(rd, if f.is_sign_negative() { 0 } else { 7 });
-                                    }
-                                    FpCategory::Normal => {
-                                        self.xregs
-                                            .write(rd, if f.is_sign_negative() { 1 } else { 6 });
-                                    }
-                                    FpCategory::Subnormal => {
-                                        self.xregs
-                                            .write(rd, if f.is_sign_negative() { 2 } else { 5 });
-                                    }
-                                    FpCategory::Zero => {
-                                        self.xregs
-                                            .write(rd, if f.is_sign_negative() { 3 } else { 4 });
-                                    }
-                                    // don't support a signaling nan, only support a quiet nan.
-                                    FpCategory::Nan => self.xregs.write(rd, 9),
-                                }
+                                let is_quiet = f.to_bits() & (1 << 51) != 0;
+                                self.xregs.write(
+                                    rd,
+                                    fclass(f.classify(), f.is_sign_negative(), is_quiet),
+                                );
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
@@ -2955,23 +3901,24 @@ impl Cpu {
                         inst_count!(self, "fmv.w.x");
 
                         // "The bits are not modified in the transfer"
-                        self.fregs
-                            .write(rd, f32::from_bits(self.xregs.read(rs1) as u32) as f64);
+                        self.write_freg(rd, widen_f32(f32::from_bits(self.xregs.read(rs1) as u32)));
                     }
                     0x79 => {
                         // fmv.d.x
                         inst_count!(self, "fmv.d.x");
 
                         // "FMV.X.D and FMV.D.X do not modify the bits being transferred"
-                        self.fregs.write(rd, f64::from_bits(self.xregs.read(rs1)));
+                        self.write_freg(rd, f64::from_bits(self.xregs.read(rs1)));
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
             0x63 => {
                 // RV32I
+                // The pc already points to the next instruction, so a branch which isn't taken
+                // leaves it, and a taken one moves it back by 4 before adding the offset.
                 // imm[12|10:5|4:1|11] = inst[31|30:25|11:8|7]
                 let imm = (((inst & 0x80000000) as i32 as i64 >> 19) as u64)
                     | ((inst & 0x80) << 4) // imm[11]
@@ -3028,7 +3975,7 @@ impl Cpu {
                         }
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
@@ -3036,13 +3983,28 @@ impl Cpu {
                 // jalr
                 inst_count!(self, "jalr");
 
+                if funct3 != 0 {
+                    return Err(Exception::IllegalInstruction(inst));
+                }
+
                 // Don't add 4 because the pc already moved on.
                 let t = self.pc;
 
+                // "The target address is obtained by adding the sign-extended 12-bit I-immediate
+                // to the register rs1, then setting the least-significant bit of the result to
+                // zero."
                 let offset = (inst as i32 as i64) >> 20;
-                let target = ((self.xregs.read(rs1) as i64).wrapping_add(offset)) & !1;
+                let target = (((self.xregs.read(rs1) as i64).wrapping_add(offset)) & !1) as u64;
+
+                // "The JAL and JALR instructions will generate an instruction-address-misaligned
+                // exception if the target address is not aligned to a four-byte boundary."
+                // Without the C extension, the exception is reported on the jalr and rd isn't
+                // written.
+                if target & 0b10 != 0 && self.state.read(MISA) & MISA_C == 0 {
+                    return Err(Exception::InstructionAddressMisaligned(target));
+                }
 
-                self.pc = target as u64;
+                self.pc = target;
                 self.xregs.write(rd, t);
             }
             0x6F => {
@@ -3062,8 +4024,32 @@ impl Cpu {
             0x73 => {
                 // RV32I, RVZicsr, and supervisor ISA
                 let csr_addr = ((inst >> 20) & 0xfff) as u16;
+                // Sstc: "When STCE is 0, an attempt to access stimecmp in a mode other than
+                // M-mode raises an illegal instruction exception".
+                if funct3 != 0x0
+                    && csr_addr == STIMECMP
+                    && self.mode != Mode::Machine
+                    && !self.state.is_sstc_enabled()
+                {
+                    return Err(Exception::IllegalInstruction(inst));
+                }
+                // The N extension isn't implemented, so its CSRs don't exist, and neither do
+                // sedeleg and sideleg, which only delegate traps to U-mode handlers.
+                if funct3 != 0x0
+                    && matches!(
+                        csr_addr,
+                        USTATUS | UIE | UTVEC | USCRATCH..=UIP | SEDELEG | SIDELEG
+                    )
+                {
+                    return Err(Exception::IllegalInstruction(inst));
+                }
                 match funct3 {
                     0x0 => {
+                        // None of these instructions writes rd, and only the fences take rs1.
+                        let is_fence = matches!(funct7, 0x9 | 0x11 | 0x51);
+                        if rd != 0 || (rs1 != 0 && !is_fence) {
+                            return Err(Exception::IllegalInstruction(inst));
+                        }
                         match (rs2, funct7) {
                             (0x0, 0x0) => {
                                 // ecall
@@ -3082,7 +4068,7 @@ impl Cpu {
                                         return Err(Exception::EnvironmentCallFromMMode);
                                     }
                                     _ => {
-                                        return Err(Exception::IllegalInstruction);
+                                        return Err(Exception::IllegalInstruction(inst));
                                     }
                                 }
                             }
@@ -3091,13 +4077,26 @@ impl Cpu {
                                 inst_count!(self, "ebreak");
 
                                 // Makes a request of the debugger bu raising a Breakpoint
-                                // exception.
-                                return Err(Exception::Breakpoint);
+                                // exception, or enters debug mode if dcsr asks for it.
+                                if !self.ebreak_enters_debug_mode() {
+                                    return Err(Exception::Breakpoint);
+                                }
+                                self.enter_debug_mode(self.pc.wrapping_sub(4));
+                            }
+                            (0x12, 0x3d) => {
+                                // dret
+                                inst_count!(self, "dret");
+
+                                if self.mode != Mode::Debug {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
+                                self.leave_debug_mode();
                             }
                             (0x2, 0x0) => {
                                 // uret
+                                // The N extension isn't implemented.
                                 inst_count!(self, "uret");
-                                panic!("uret: not implemented yet. pc {}", self.pc);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                             (0x2, 0x8) => {
                                 // sret
@@ -3110,18 +4109,29 @@ impl Cpu {
                                 // 1, and CSRs[sstatus].SPP to 0.", but the implementation in QEMU
                                 // and Spike use `mstatus` instead of `sstatus`.
 
+                                // `sret` isn't available in U-mode. "When TSR=1, attempts to
+                                // execute SRET while executing in S-mode will raise an illegal
+                                // instruction exception."
+                                match self.mode {
+                                    Mode::User => return Err(Exception::IllegalInstruction(inst)),
+                                    Mode::Supervisor
+                                        if self.state.read(MSTATUS) & MSTATUS_TSR != 0 =>
+                                    {
+                                        return Err(Exception::IllegalInstruction(inst))
+                                    }
+                                    _ => {}
+                                }
+
                                 // Set the program coutner to the supervisor exception program
                                 // counter (SEPC).
                                 self.pc = self.state.read(SEPC);
 
-                                // TODO: Check TSR field
-
                                 // Set the current privileged mode depending on a privious
-                                // privilege mode for supervisor mode (SPP, 8).
+                                // privilege mode for supervisor mode (SPP, 8). SPP is a single
+                                // bit, so `sret` never returns to M-mode.
                                 self.mode = match self.state.read_bit(SSTATUS, 8) {
                                     0 => Mode::User,
-                                    1 => Mode::Supervisor,
-                                    _ => Mode::Debug,
+                                    _ => Mode::Supervisor,
                                 };
                                 // Read a privious interrupt-enable bit for supervisor mode (SPIE,
                                 // 5), and set a global interrupt-enable bit for supervisor mode
@@ -3134,6 +4144,8 @@ impl Cpu {
                                 self.state.write_bit(SSTATUS, 5, 1);
                                 // Set a privious privilege mode for supervisor mode (SPP, 8) to 0.
                                 self.state.write_bit(SSTATUS, 8, 0);
+
+                                debug!("sret: return to {:?} mode at {:#x}", self.mode, self.pc);
                             }
                             (0x2, 0x18) => {
                                 // mret
@@ -3172,10 +4184,50 @@ impl Cpu {
                                 // Set a privious privilege mode for machine mode (MPP, 11..13) to
                                 // 0.
                                 self.state.write_bits(MSTATUS, 11..13, 0b00);
+
+                                debug!("mret: return to {:?} mode at {:#x}", self.mode, self.pc);
+                            }
+                            (0x2, 0x38) => {
+                                // mnret
+                                inst_count!(self, "mnret");
+
+                                // The Smrnmi extension: "MNRET is an M-mode-only instruction
+                                // that uses the values in mnepc and mnstatus to return to the
+                                // program counter, privilege mode, and virtualization mode of the
+                                // interrupted context. This instruction also sets mnstatus.NMIE."
+                                if self.mode != Mode::Machine {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
+                                self.pc = self.state.read(MNEPC);
+                                self.mode = match self.state.read(MNSTATUS) & MNSTATUS_MNPP {
+                                    0 => Mode::User,
+                                    MNSTATUS_MNPP => Mode::Machine,
+                                    _ => Mode::Supervisor,
+                                };
+                                self.state
+                                    .write(MNSTATUS, self.state.read(MNSTATUS) | MNSTATUS_NMIE);
+                                // The privileged specification: "If the xPP != M, xRET also sets
+                                // MPRV=0", which applies to MNRET as well.
+                                if self.mode != Mode::Machine {
+                                    self.state
+                                        .write(MSTATUS, self.state.read(MSTATUS) & !MSTATUS_MPRV);
+                                }
+
+                                debug!("mnret: return to {:?} mode at {:#x}", self.mode, self.pc);
                             }
                             (0x5, 0x8) => {
                                 // wfi
                                 inst_count!(self, "wfi");
+                                // "When TW=1, then if WFI is executed in any less-privileged
+                                // mode, and it does not complete within an
+                                // implementation-specific, bounded time limit, the WFI
+                                // instruction causes an illegal instruction exception." The time
+                                // limit is 0 here.
+                                if self.mode != Mode::Machine
+                                    && self.state.read(MSTATUS) & MSTATUS_TW != 0
+                                {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
                                 // "provides a hint to the implementation that the current
                                 // hart can be stalled until an interrupt might need servicing."
                                 self.idle = true;
@@ -3185,6 +4237,21 @@ impl Cpu {
                                 inst_count!(self, "sfence.vma");
                                 // "SFENCE.VMA is used to synchronize updates to in-memory
                                 // memory-management data structures with current execution"
+                                // "If rs1=x0, the fence orders all reads and writes made to any
+                                // level of the page tables. If rs2=x0, the fence orders them
+                                // for all address spaces, including global mappings.
+                                // Otherwise, it orders them only for the address space
+                                // identified by integer register rs2, except for global
+                                // mappings."
+                                let vaddr = match rs1 {
+                                    0 => None,
+                                    _ => Some(self.xregs.read(rs1)),
+                                };
+                                let asid = match rs2 {
+                                    0 => None,
+                                    _ => Some(self.xregs.read(rs2) & 0xffff),
+                                };
+                                self.tlb.invalidate(vaddr, asid);
                             }
                             (_, 0x11) => {
                                 // hfence.bvma
@@ -3195,93 +4262,164 @@ impl Cpu {
                                 inst_count!(self, "hfence.gvma");
                             }
                             _ => {
-                                return Err(Exception::IllegalInstruction);
+                                return Err(Exception::IllegalInstruction(inst));
                             }
                         }
                     }
+                    // 9.1 CSR Instructions
+                    // "If rd=x0, then the instruction shall not read the CSR and shall not cause
+                    // any of the side effects that might occur on a CSR read."
+                    // "For both CSRRS and CSRRC, if rs1=x0, then the instruction will not write to
+                    // the CSR at all, and so shall not cause any of the side effects that might
+                    // otherwise occur on a CSR write"
                     0x1 => {
                         // csrrw
                         inst_count!(self, "csrrw");
 
-                        let t = self.state.read(csr_addr);
-                        self.state.write(csr_addr, self.xregs.read(rs1));
-                        self.xregs.write(rd, t);
-
-                        if csr_addr == SATP {
-                            self.update_paging();
+                        let value = self.xregs.read(rs1);
+                        if rd != 0 {
+                            let t = self.read_csr(csr_addr);
+                            self.xregs.write(rd, t);
                         }
+                        self.write_csr(csr_addr, value);
                     }
                     0x2 => {
                         // csrrs
                         inst_count!(self, "csrrs");
 
-                        let t = self.state.read(csr_addr);
-                        self.state.write(csr_addr, t | self.xregs.read(rs1));
-                        self.xregs.write(rd, t);
-
-                        if csr_addr == SATP {
-                            self.update_paging();
+                        let t = self.read_csr(csr_addr);
+                        if rs1 != 0 {
+                            let old = self.csr_update_base(csr_addr, t);
+                            self.write_csr(csr_addr, old | self.xregs.read(rs1));
                         }
+                        self.xregs.write(rd, t);
                     }
                     0x3 => {
                         // csrrc
                         inst_count!(self, "csrrc");
 
-                        let t = self.state.read(csr_addr);
-                        self.state.write(csr_addr, t & (!self.xregs.read(rs1)));
-                        self.xregs.write(rd, t);
-
-                        if csr_addr == SATP {
-                            self.update_paging();
+                        let t = self.read_csr(csr_addr);
+                        if rs1 != 0 {
+                            let old = self.csr_update_base(csr_addr, t);
+                            self.write_csr(csr_addr, old & (!self.xregs.read(rs1)));
                         }
+                        self.xregs.write(rd, t);
                     }
                     0x5 => {
                         // csrrwi
                         inst_count!(self, "csrrwi");
 
+                        // "CSRRWI, CSRRSI, and CSRRCI ... update the CSR using an XLEN-bit value
+                        // obtained by zero-extending a 5-bit unsigned immediate (uimm[4:0]) field
+                        // encoded in the rs1 field." The same no-write rule as rs1=x0 applies to
+                        // uimm=0 of CSRRSI and CSRRCI.
                         let zimm = rs1;
-                        self.xregs.write(rd, self.state.read(csr_addr));
-                        self.state.write(csr_addr, zimm);
-
-                        if csr_addr == SATP {
-                            self.update_paging();
+                        if rd != 0 {
+                            let t = self.read_csr(csr_addr);
+                            self.xregs.write(rd, t);
                         }
+                        self.write_csr(csr_addr, zimm);
                     }
                     0x6 => {
                         // csrrsi
                         inst_count!(self, "csrrsi");
 
                         let zimm = rs1;
-                        let t = self.state.read(csr_addr);
-                        self.state.write(csr_addr, t | zimm);
-                        self.xregs.write(rd, t);
-
-                        if csr_addr == SATP {
-                            self.update_paging();
+                        let t = self.read_csr(csr_addr);
+                        if zimm != 0 {
+                            self.write_csr(csr_addr, t | zimm);
                         }
+                        self.xregs.write(rd, t);
                     }
                     0x7 => {
                         // csrrci
                         inst_count!(self, "csrrci");
 
                         let zimm = rs1;
-                        let t = self.state.read(csr_addr);
-                        self.state.write(csr_addr, t & (!zimm));
-                        self.xregs.write(rd, t);
-
-                        if csr_addr == SATP {
-                            self.update_paging();
+                        let t = self.read_csr(csr_addr);
+                        if zimm != 0 {
+                            self.write_csr(csr_addr, t & (!zimm));
                         }
+                        self.xregs.write(rd, t);
                     }
                     _ => {
-                        return Err(Exception::IllegalInstruction);
+                        return Err(Exception::IllegalInstruction(inst));
                     }
                 }
             }
             _ => {
-                return Err(Exception::IllegalInstruction);
+                return Err(Exception::IllegalInstruction(inst));
             }
         }
         Ok(inst)
     }
 }
+
+/// Return the 128-bit carry-less product of `a` and `b`, i.e., the product of the polynomials
+/// over GF(2) whose coefficients are their bits.
+fn clmul(a: u64, b: u64) -> u128 {
+    (0..64)
+        .filter(|i| (b >> i) & 1 == 1)
+        .fold(0, |product, i| product ^ ((a as u128) << i))
+}
+
+/// Reverse the order of the bits in each byte of `value`.
+fn brev8(value: u64) -> u64 {
+    u64::from_le_bytes(value.to_le_bytes().map(u8::reverse_bits))
+}
+
+/// Return the crossbar permutation of `xperm4` or `xperm8` for elements of `width` bits. Each
+/// element of `indices` selects an element of `table`, and an out-of-range index selects zero.
+fn xperm(table: u64, indices: u64, width: u32) -> u64 {
+    let mask = (1 << width) - 1;
+    (0..64).step_by(width as usize).fold(0, |result, i| {
+        let index = (indices >> i) & mask;
+        let element = match index.checked_mul(width as u64) {
+            Some(shift) if shift < 64 => (table >> shift) & mask,
+            _ => 0,
+        };
+        result | (element << i)
+    })
+}
+
+/// Return the result of `fclass`, a mask in which only the bit for the class of the operand is set.
+/// `is_quiet` tells a quiet NaN from a signaling one.
+fn fclass(category: FpCategory, is_negative: bool, is_quiet: bool) -> u64 {
+    let bit = match (category, is_negative) {
+        (FpCategory::Infinite, true) => 0,
+        (FpCategory::Normal, true) => 1,
+        (FpCategory::Subnormal, true) => 2,
+        (FpCategory::Zero, true) => 3,
+        (FpCategory::Zero, false) => 4,
+        (FpCategory::Subnormal, false) => 5,
+        (FpCategory::Normal, false) => 6,
+        (FpCategory::Infinite, false) => 7,
+        (FpCategory::Nan, _) if is_quiet => 9,
+        (FpCategory::Nan, _) => 8,
+    };
+    1 << bit
+}
+
+/// Widen a single-precision value to the double-precision register file. The conversion of the
+/// host quiets a signaling NaN, so a NaN is widened by hand to keep its payload.
+fn widen_f32(f: f32) -> f64 {
+    if !f.is_nan() {
+        return f as f64;
+    }
+    let bits = f.to_bits() as u64;
+    f64::from_bits(((bits >> 31) << 63) | (0x7ff << 52) | ((bits & 0x7f_ffff) << 29))
+}
+
+/// Narrow a value in the double-precision register file to single precision. It's the inverse of
+/// `widen_f32`, and a NaN whose payload doesn't fit in single precision becomes a quiet NaN.
+fn narrow_f64(f: f64) -> f32 {
+    if !f.is_nan() {
+        return f as f32;
+    }
+    let bits = f.to_bits();
+    let mut payload = ((bits >> 29) & 0x7f_ffff) as u32;
+    if payload == 0 {
+        payload = 1 << 22;
+    }
+    f32::from_bits((((bits >> 63) as u32) << 31) | (0xff << 23) | payload)
+}
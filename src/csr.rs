@@ -1,7 +1,8 @@
 //! The csr module contains all the control and status registers.
 
-use std::fmt;
-use std::ops::{Bound, Range, RangeBounds};
+use alloc::format;
+use core::fmt;
+use core::ops::{Bound, Range, RangeBounds};
 
 pub type CsrAddress = u16;
 
@@ -12,19 +13,25 @@ pub const CSR_SIZE: usize = 4096;
 //////////////////////////////
 // User-level CSR addresses //
 //////////////////////////////
-// User trap setup.
+// User trap setup of the N extension, which isn't implemented.
 /// User status register.
 pub const USTATUS: CsrAddress = 0x000;
+/// User interrupt-enable register.
+pub const UIE: CsrAddress = 0x004;
 /// User trap handler base address.
 pub const UTVEC: CsrAddress = 0x005;
 
-// User trap handling.
+// User trap handling of the N extension, which isn't implemented.
+/// Scratch register for user trap handlers.
+pub const USCRATCH: CsrAddress = 0x040;
 /// User exception program counter.
 pub const UEPC: CsrAddress = 0x041;
 /// User trap cause.
 pub const UCAUSE: CsrAddress = 0x042;
 /// User bad address or instruction.
 pub const UTVAL: CsrAddress = 0x043;
+/// User interrupt pending.
+pub const UIP: CsrAddress = 0x044;
 
 // User floating-point CSRs.
 /// Flating-point accrued exceptions.
@@ -35,8 +42,16 @@ pub const FRB: CsrAddress = 0x002;
 pub const FCSR: CsrAddress = 0x003;
 
 // User Counter/Timers.
+/// Cycle counter for RDCYCLE instruction.
+pub const CYCLE: CsrAddress = 0xc00;
 /// Timer for RDTIME instruction.
 pub const TIME: CsrAddress = 0xc01;
+/// Instructions-retired counter for RDINSTRET instruction.
+pub const INSTRET: CsrAddress = 0xc02;
+/// The first performance-monitoring counter, hpmcounter3.
+pub const HPMCOUNTER3: CsrAddress = 0xc03;
+/// The last performance-monitoring counter, hpmcounter31.
+pub const HPMCOUNTER31: CsrAddress = 0xc1f;
 
 /////////////////////////////////////
 // Supervisor-level CSR addresses //
@@ -53,6 +68,10 @@ pub const SIE: CsrAddress = 0x104;
 /// Supervisor trap handler base address.
 pub const STVEC: CsrAddress = 0x105;
 
+// Supervisor configuration.
+/// Supervisor environment configuration register.
+pub const SENVCFG: CsrAddress = 0x10a;
+
 // Supervisor trap handling.
 /// Scratch register for supervisor trap handlers.
 pub const SSCRATCH: CsrAddress = 0x140;
@@ -64,6 +83,8 @@ pub const SCAUSE: CsrAddress = 0x142;
 pub const STVAL: CsrAddress = 0x143;
 /// Supervisor interrupt pending.
 pub const SIP: CsrAddress = 0x144;
+/// Supervisor timer compare register of the Sstc extension.
+pub const STIMECMP: CsrAddress = 0x14d;
 
 // Supervisor protection and translation.
 /// Supervisor address translation and protection.
@@ -81,6 +102,7 @@ pub const SSTATUS_XS: u64 = 0x00018000;
 pub const SSTATUS_SUM: u64 = 0x00040000;
 pub const SSTATUS_MXR: u64 = 0x00080000;
 pub const SSTATUS_UXL: u64 = 0x0000000300000000;
+pub const SSTATUS_SD: u64 = 0x8000000000000000;
 
 /////////////////////////////////
 // Machine-level CSR addresses //
@@ -111,6 +133,50 @@ pub const MTVEC: CsrAddress = 0x305;
 /// Machine counter enable.
 pub const MCOUNTEREN: CsrAddress = 0x306;
 
+// Machine configuration.
+/// Machine environment configuration register.
+pub const MENVCFG: CsrAddress = 0x30a;
+
+// MENVCFG and SENVCFG fields.
+/// Fence of I/O implies Memory. Every access is already ordered, so it has no effect.
+pub const ENVCFG_FIOM: u64 = 1 << 0;
+/// Cache Block Clean and Flush instruction Enable. The less privileged modes can execute
+/// `cbo.clean` and `cbo.flush` if it's set.
+pub const ENVCFG_CBCFE: u64 = 1 << 6;
+/// Cache Block Zero instruction Enable. The less privileged modes can execute `cbo.zero` if it's
+/// set.
+pub const ENVCFG_CBZE: u64 = 1 << 7;
+/// STimecmp Enable. `stimecmp` drives the STIP bit and S-mode can access it if it's set. It only
+/// exists in `menvcfg`.
+pub const MENVCFG_STCE: u64 = 1 << 63;
+
+// MISA fields.
+/// The compressed extension. Instructions only need to be aligned to 2 bytes if it's set.
+pub const MISA_C: u64 = 1 << 2;
+/// Supervisor mode. S-mode is implemented if it's set.
+pub const MISA_S: u64 = 1 << 18;
+
+// MSTATUS fields.
+/// Timeout wait. `wfi` in a mode less privileged than M-mode raises an illegal-instruction
+/// exception if it's set.
+pub const MSTATUS_TW: u64 = 1 << 21;
+/// The previous privilege mode of M-mode. It only holds a supported mode.
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+/// Modify privilege. Loads and stores in M-mode are translated and protected as in the mode of
+/// MPP if it's set.
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+/// Trap SRET. `sret` in S-mode raises an illegal-instruction exception if it's set.
+pub const MSTATUS_TSR: u64 = 1 << 22;
+/// The state of the vector unit. It's dirty if all the bits are set.
+pub const MSTATUS_VS: u64 = 0b11 << 9;
+/// The state of the floating-point unit. It's dirty if all the bits are set.
+pub const MSTATUS_FS: u64 = 0b11 << 13;
+/// The state of additional user-mode extensions. It's dirty if all the bits are set.
+pub const MSTATUS_XS: u64 = 0b11 << 15;
+/// State dirty. A read-only bit which is set if any of FS, VS, and XS is dirty, so that a kernel
+/// can tell whether it needs to save extension state on a context switch.
+pub const MSTATUS_SD: u64 = 1 << 63;
+
 // Machine trap handling.
 /// Scratch register for machine trap handlers.
 pub const MSCRATCH: CsrAddress = 0x340;
@@ -123,12 +189,80 @@ pub const MTVAL: CsrAddress = 0x343;
 /// Machine interrupt pending.
 pub const MIP: CsrAddress = 0x344;
 
+// Resumable non-maskable interrupt handling of the Smrnmi extension.
+/// Scratch register for resumable NMI handlers.
+pub const MNSCRATCH: CsrAddress = 0x740;
+/// Resumable NMI program counter.
+pub const MNEPC: CsrAddress = 0x741;
+/// Resumable NMI cause.
+pub const MNCAUSE: CsrAddress = 0x742;
+/// Resumable NMI status.
+pub const MNSTATUS: CsrAddress = 0x744;
+
+// MNSTATUS fields.
+/// NMI enable. NMIs and other traps are masked while it's clear, i.e., in an NMI handler.
+/// Software can set it but not clear it.
+pub const MNSTATUS_NMIE: u64 = 1 << 3;
+/// The privilege mode before an NMI. `mnret` returns to it.
+pub const MNSTATUS_MNPP: u64 = 0b11 << 11;
+
+// Machine Counter/Timers.
+/// Machine cycle counter.
+pub const MCYCLE: CsrAddress = 0xb00;
+/// Machine instructions-retired counter.
+pub const MINSTRET: CsrAddress = 0xb02;
+
+// Machine performance monitoring. The counters and the event selectors 3 to 31 are contiguous.
+/// The first machine performance-monitoring counter, mhpmcounter3.
+pub const MHPMCOUNTER3: CsrAddress = 0xb03;
+/// The last machine performance-monitoring counter, mhpmcounter31.
+pub const MHPMCOUNTER31: CsrAddress = 0xb1f;
+/// The first machine performance-monitoring event selector, mhpmevent3.
+pub const MHPMEVENT3: CsrAddress = 0x323;
+/// The last machine performance-monitoring event selector, mhpmevent31.
+pub const MHPMEVENT31: CsrAddress = 0x33f;
+
+// The events selected by mhpmevent. Their encoding is platform-specific and 0 counts nothing.
+/// Retired load instructions, including floating-point loads.
+pub const HPM_EVENT_LOAD: u64 = 1;
+/// Retired conditional branch instructions.
+pub const HPM_EVENT_BRANCH: u64 = 2;
+
 // Machine memory protection.
 /// Physical memory protection configuration.
 pub const PMPCFG0: CsrAddress = 0x3a0;
 /// Physical memory protection address register.
 pub const PMPADDR0: CsrAddress = 0x3b0;
 
+//////////////////////////////
+// Debug-mode CSR addresses //
+//////////////////////////////
+/// Debug control and status register.
+pub const DCSR: CsrAddress = 0x7b0;
+/// Debug program counter.
+pub const DPC: CsrAddress = 0x7b1;
+/// Debug scratch register 0.
+pub const DSCRATCH0: CsrAddress = 0x7b2;
+/// Debug scratch register 1.
+pub const DSCRATCH1: CsrAddress = 0x7b3;
+
+// DCSR fields.
+/// ebreak in M-mode enters debug mode.
+pub const DCSR_EBREAKM: u64 = 1 << 15;
+/// ebreak in S-mode enters debug mode.
+pub const DCSR_EBREAKS: u64 = 1 << 13;
+/// ebreak in U-mode enters debug mode.
+pub const DCSR_EBREAKU: u64 = 1 << 12;
+/// The cause field in bits 8:6 which tells why debug mode was entered.
+pub const DCSR_CAUSE_SHIFT: u64 = 6;
+/// The cause of entering debug mode by ebreak.
+pub const DCSR_CAUSE_EBREAK: u64 = 1;
+/// The privilege mode the hart was in when debug mode was entered, in bits 1:0.
+pub const DCSR_PRV: u64 = 0b11;
+/// The reset value of dcsr: xdebugver (bits 31:28) is 4 for external debug support, and prv is
+/// M-mode.
+const DCSR_RESET: u64 = (4 << 28) | 0b11;
+
 // MIP fields.
 /// Supervisor software interrupt.
 pub const SSIP_BIT: u64 = 1 << 1;
@@ -143,9 +277,24 @@ pub const SEIP_BIT: u64 = 1 << 9;
 /// Machine external interrupt.
 pub const MEIP_BIT: u64 = 1 << 11;
 
+/// A CSR implemented outside the core, such as a vendor-specific CSR with side effects. A
+/// registered device takes precedence over the CSR of the same address in `State`.
+pub trait CsrDevice {
+    /// Read the value of the CSR.
+    fn read(&mut self) -> u64;
+    /// Write `value` to the CSR.
+    fn write(&mut self, value: u64);
+}
+
 /// The state to contains all the CSRs.
 pub struct State {
     csrs: [u64; CSR_SIZE],
+    /// The bit `i` is set if mhpmevent`i` selects an event, so that instructions are only
+    /// matched against the event selectors while a counter is configured.
+    hpm_enabled: u32,
+    /// True while the PLIC asserts the supervisor external interrupt. `mip.SEIP` reads as the OR
+    /// of it and the bit software writes, so the PLIC never overwrites what software has set.
+    seip_line: bool,
 }
 
 impl fmt::Display for State {
@@ -198,13 +347,94 @@ impl State {
             (1 << 2) | // Extensions[2] (Compressed extension)
             1; // Extensions[0] (Atomic extension)
         csrs[MISA as usize] = misa;
-
-        Self { csrs }
+        csrs[DCSR as usize] = DCSR_RESET;
+        // NMIE is reset to 0 so that firmware enables NMIs once it can handle them, but no
+        // firmware which knows Smrnmi runs before a guest of the emulator.
+        csrs[MNSTATUS as usize] = MNSTATUS_NMIE;
+
+        Self {
+            csrs,
+            hpm_enabled: 0,
+            seip_line: false,
+        }
     }
 
-    /// Increment the value in the TIME register.
+    /// Increment the value in the TIME register. It advances in step with `mtime` of the CLINT.
     pub fn increment_time(&mut self) {
-        self.csrs[TIME as usize] = self.csrs[TIME as usize].wrapping_add(1);
+        self.advance_time(1);
+    }
+
+    /// Advance the value in the TIME register by `cycles` at once.
+    pub fn advance_time(&mut self, cycles: u64) {
+        self.csrs[TIME as usize] = self.csrs[TIME as usize].wrapping_add(cycles);
+        self.update_stip();
+    }
+
+    /// Return the number of increments until TIME reaches `stimecmp`, or `None` if the Sstc
+    /// extension is disabled or the supervisor timer interrupt is already pending.
+    pub fn cycles_until_stimecmp(&self) -> Option<u64> {
+        if !self.is_sstc_enabled() {
+            return None;
+        }
+        self.csrs[STIMECMP as usize]
+            .checked_sub(self.csrs[TIME as usize])
+            .filter(|&cycles| cycles > 0)
+    }
+
+    /// Return true if the Sstc extension is enabled by `menvcfg.STCE`.
+    pub fn is_sstc_enabled(&self) -> bool {
+        self.csrs[MENVCFG as usize] & MENVCFG_STCE != 0
+    }
+
+    /// Drive the STIP bit from `stimecmp` if the Sstc extension is enabled.
+    ///
+    /// Sstc: "A supervisor timer interrupt becomes pending, as reflected in the STIP bit in the
+    /// mip and sip registers, whenever time contains a value greater than or equal to stimecmp,
+    /// treating the values as unsigned integers."
+    fn update_stip(&mut self) {
+        if !self.is_sstc_enabled() {
+            return;
+        }
+        let is_pending = self.csrs[TIME as usize] >= self.csrs[STIMECMP as usize];
+        let mip = &mut self.csrs[MIP as usize];
+        if is_pending {
+            *mip |= STIP_BIT;
+        } else {
+            *mip &= !STIP_BIT;
+        }
+    }
+
+    /// Set the supervisor external interrupt line from the PLIC.
+    ///
+    /// 3.1.9 Machine Interrupt Registers (mip and mie): "SEIP is writable in mip, and may be
+    /// written by M-mode software to indicate to S-mode that an external interrupt is pending.
+    /// Additionally, the platform-level interrupt controller may generate supervisor-level
+    /// external interrupts. Supervisor-level external interrupts are made pending based on the
+    /// logical-OR of the software-writable SEIP bit and the signal from the external interrupt
+    /// controller."
+    pub fn set_seip_line(&mut self, asserted: bool) {
+        self.seip_line = asserted;
+    }
+
+    /// Return `mip` with only the SEIP bit written by software, which a read-modify-write of
+    /// `mip` starts from so that the signal from the PLIC isn't latched. The same applies to a
+    /// CSRRS or CSRRC instruction: "only the software-writable SEIP bit participates in the
+    /// read-modify-write sequence".
+    pub fn read_mip_for_update(&self) -> u64 {
+        self.csrs[MIP as usize]
+    }
+
+    /// Increment the performance-monitoring counters whose event selector is `event`.
+    pub fn count_event(&mut self, event: u64) {
+        if self.hpm_enabled == 0 {
+            return;
+        }
+        for i in 3..32 {
+            if self.hpm_enabled & (1 << i) != 0 && self.csrs[MHPMEVENT3 as usize + i - 3] == event {
+                let counter = MHPMCOUNTER3 as usize + i - 3;
+                self.csrs[counter] = self.csrs[counter].wrapping_add(1);
+            }
+        }
     }
 
     /// Read the val from the CSR.
@@ -225,11 +455,33 @@ impl State {
                     | SSTATUS_XS
                     | SSTATUS_SUM
                     | SSTATUS_MXR
-                    | SSTATUS_UXL;
-                self.csrs[MSTATUS as usize] & mask
+                    | SSTATUS_UXL
+                    | SSTATUS_SD;
+                self.read(MSTATUS) & mask
+            }
+            MSTATUS => {
+                let mstatus = self.csrs[MSTATUS as usize];
+                let is_dirty = |field: u64| mstatus & field == field;
+                if is_dirty(MSTATUS_FS) || is_dirty(MSTATUS_VS) || is_dirty(MSTATUS_XS) {
+                    mstatus | MSTATUS_SD
+                } else {
+                    mstatus
+                }
             }
             SIE => self.csrs[MIE as usize] & self.csrs[MIDELEG as usize],
-            SIP => self.csrs[MIP as usize] & self.csrs[MIDELEG as usize],
+            SIP => self.read(MIP) & self.csrs[MIDELEG as usize],
+            MIP if self.seip_line => self.csrs[MIP as usize] | SEIP_BIT,
+            // The user-level CYCLE counter is a read-only shadow of the MCYCLE register.
+            CYCLE => self.csrs[MCYCLE as usize],
+            // The user-level INSTRET counter is a read-only shadow of the MINSTRET register.
+            INSTRET => self.csrs[MINSTRET as usize],
+            // hpmcounter3 to hpmcounter31 are read-only shadows of the machine counters.
+            HPMCOUNTER3..=HPMCOUNTER31 => self.csrs[(MHPMCOUNTER3 + (addr - HPMCOUNTER3)) as usize],
+            // "If IALIGN=32, mepc[1] is masked on reads so that it appears to be 0."
+            MEPC | SEPC | MNEPC => match self.csrs[MISA as usize] & MISA_C {
+                0 => self.csrs[addr as usize] & !0b11,
+                _ => self.csrs[addr as usize],
+            },
             _ => self.csrs[addr as usize],
         }
     }
@@ -248,6 +500,18 @@ impl State {
             MARCHID => {}
             MIMPID => {}
             MHARTID => {}
+            CYCLE => {}
+            INSTRET => {}
+            HPMCOUNTER3..=HPMCOUNTER31 => {}
+            MHPMEVENT3..=MHPMEVENT31 => {
+                let bit = 1 << (addr - MHPMEVENT3 + 3);
+                if val == 0 {
+                    self.hpm_enabled &= !bit;
+                } else {
+                    self.hpm_enabled |= bit;
+                }
+                self.csrs[addr as usize] = val;
+            }
             SSTATUS => {
                 let mask = SSTATUS_SIE
                     | SSTATUS_SPIE
@@ -258,6 +522,8 @@ impl State {
                     | SSTATUS_MXR;
                 self.csrs[MSTATUS as usize] = (self.csrs[MSTATUS as usize] & !mask) | (val & mask);
             }
+            // SD is computed from the other fields when it's read.
+            MSTATUS => self.csrs[MSTATUS as usize] = self.legalize_mpp(val) & !MSTATUS_SD,
             SIE => {
                 self.csrs[MIE as usize] = (self.csrs[MIE as usize] & !self.csrs[MIDELEG as usize])
                     | (val & self.csrs[MIDELEG as usize]);
@@ -266,10 +532,59 @@ impl State {
                 let mask = SSIP_BIT & self.csrs[MIDELEG as usize];
                 self.csrs[MIP as usize] = (self.csrs[MIP as usize] & !mask) | (val & mask);
             }
+            // 3.1.8 Machine Trap Delegation Registers (medeleg and mideleg)
+            // "mideleg holds trap delegation bits for individual interrupts" and only the
+            // supervisor-level interrupts can be delegated, so sie and sip never expose
+            // machine-level bits.
+            MIDELEG => self.csrs[MIDELEG as usize] = val & (SSIP_BIT | STIP_BIT | SEIP_BIT),
+            // "If the STCE bit is 1, then STIP is read-only in mip", because `stimecmp` drives it.
+            MIP if self.is_sstc_enabled() => {
+                self.csrs[MIP as usize] = (self.csrs[MIP as usize] & STIP_BIT) | (val & !STIP_BIT);
+            }
+            // CBIE and PBMTE aren't implemented, so they're read-only zero.
+            MENVCFG => {
+                self.csrs[MENVCFG as usize] =
+                    val & (MENVCFG_STCE | ENVCFG_CBZE | ENVCFG_CBCFE | ENVCFG_FIOM);
+                self.update_stip();
+            }
+            SENVCFG => {
+                self.csrs[SENVCFG as usize] = val & (ENVCFG_CBZE | ENVCFG_CBCFE | ENVCFG_FIOM);
+            }
+            STIMECMP => {
+                self.csrs[STIMECMP as usize] = val;
+                self.update_stip();
+            }
+            // "The low bit of mepc (mepc[0]) is always zero." sepc and mnepc are the same.
+            MEPC | SEPC | MNEPC => self.csrs[addr as usize] = val & !1,
+            // "Software can set NMIE to 1, but attempting to clear NMIE has no effect."
+            MNSTATUS => {
+                let nmie = self.csrs[MNSTATUS as usize] & MNSTATUS_NMIE;
+                self.csrs[MNSTATUS as usize] =
+                    self.legalize_mpp(val & (MNSTATUS_NMIE | MNSTATUS_MNPP)) | nmie;
+            }
             _ => self.csrs[addr as usize] = val,
         }
     }
 
+    /// Return `mstatus` whose MPP is replaced with a supported mode. 3.1.6.1 Privilege and Global
+    /// Interrupt-Enable Stack in mstatus register: "MPP is a WARL field that can hold only
+    /// privilege mode M and any implemented privilege mode." The reserved mode 2 and S-mode
+    /// without the S extension become U-mode, as Spike does, so that `mret` never returns to
+    /// an invalid mode. MNPP of `mnstatus` is at the same bits and legalized the same way.
+    fn legalize_mpp(&self, mstatus: u64) -> u64 {
+        let mpp = match (mstatus & MSTATUS_MPP) >> 11 {
+            0b01 if self.csrs[MISA as usize] & MISA_S != 0 => 0b01,
+            0b11 => 0b11,
+            _ => 0b00,
+        };
+        (mstatus & !MSTATUS_MPP) | (mpp << 11)
+    }
+
+    /// Clear `mnstatus.NMIE` as the hart does when it takes an NMI, which software can't do.
+    pub fn clear_nmie(&mut self) {
+        self.csrs[MNSTATUS as usize] &= !MNSTATUS_NMIE;
+    }
+
     /// Read a bit from the CSR.
     pub fn read_bit(&self, addr: CsrAddress, bit: usize) -> u64 {
         if bit >= MXLEN {
@@ -333,6 +648,8 @@ impl State {
     /// Reset all the CSRs.
     pub fn reset(&mut self) {
         self.csrs = [0; CSR_SIZE];
+        self.hpm_enabled = 0;
+        self.seip_line = false;
 
         let misa: u64 = (2 << 62) | // MXL[1:0]=2 (XLEN is 64)
             (1 << 18) | // Extensions[18] (Supervisor mode implemented)
@@ -343,6 +660,8 @@ impl State {
             (1 << 2) | // Extensions[2] (Compressed extension)
             1; // Extensions[0] (Atomic extension)
         self.csrs[MISA as usize] = misa;
+        self.csrs[DCSR as usize] = DCSR_RESET;
+        self.csrs[MNSTATUS as usize] = MNSTATUS_NMIE;
     }
 }
 
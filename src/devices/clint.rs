@@ -21,19 +21,19 @@ use crate::exception::Exception;
 /// register, used to assert a software interrupt for a CPU.
 const CLINT_MSIP: u64 = CLINT_BASE;
 /// The address that a msip register ends. `msip` is a 4-byte register.
-const CLINT_MSIP_END: u64 = CLINT_MSIP + 4;
+const CLINT_MSIP_END: u64 = CLINT_MSIP + 3;
 
 /// The address that a mtimecmp register starts. A mtimecmp is a memory mapped machine mode timer
 /// compare register, used to trigger an interrupt when mtimecmp is greater than or equal to mtime.
 const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
 /// The address that a mtimecmp register ends. `mtimecmp` is a 8-byte register.
-const CLINT_MTIMECMP_END: u64 = CLINT_MTIMECMP + 8;
+const CLINT_MTIMECMP_END: u64 = CLINT_MTIMECMP + 7;
 
 /// The address that a timer register starts. A mtime is a machine mode timer register which runs
 /// at a constant frequency.
 const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
 /// The address that a timer register ends. `mtime` is a 8-byte register.
-const CLINT_MTIME_END: u64 = CLINT_MTIME + 8;
+const CLINT_MTIME_END: u64 = CLINT_MTIME + 7;
 
 /// The core-local interruptor (CLINT).
 /// 0x0000 msip for hart 0 (4 bytes)
@@ -69,7 +69,7 @@ impl Clint {
 
         if (self.msip & 1) != 0 {
             // Enable the MSIP bit (MIP, 3).
-            state.write(MIP, state.read(MIP) | MSIP_BIT);
+            state.write(MIP, state.read_mip_for_update() | MSIP_BIT);
         }
 
         // 3.1.10 Machine Timer Registers (mtime and mtimecmp)
@@ -77,7 +77,7 @@ impl Clint {
         // result of writing mtimecmp)."
         if self.mtimecmp > self.mtime {
             // Clear the MTIP bit (MIP, 7).
-            state.write(MIP, state.read(MIP) & !MTIP_BIT);
+            state.write(MIP, state.read_mip_for_update() & !MTIP_BIT);
         }
 
         // 3.1.10 Machine Timer Registers (mtime and mtimecmp)
@@ -85,10 +85,28 @@ impl Clint {
         // to mtimecmp, treating the values as unsigned integers."
         if self.mtime >= self.mtimecmp {
             // Enable the MTIP bit (MIP, 7).
-            state.write(MIP, state.read(MIP) | MTIP_BIT);
+            state.write(MIP, state.read_mip_for_update() | MTIP_BIT);
         }
     }
 
+    /// Return the number of increments until `mtime` reaches `mtimecmp`, or `None` if the timer
+    /// interrupt is already posted.
+    pub fn cycles_until_interrupt(&self) -> Option<u64> {
+        self.mtimecmp
+            .checked_sub(self.mtime)
+            .filter(|&cycles| cycles > 0)
+    }
+
+    /// Return the current value of `mtime`.
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// Advance `mtime` by `cycles` at once. The MTIP bit is updated by the next increment.
+    pub fn advance(&mut self, cycles: u64) {
+        self.mtime = self.mtime.wrapping_add(cycles);
+    }
+
     /// Load `size`-bit data from a register located at `addr` in CLINT.
     pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
         // `reg` is the value of a target register in CLINT and `offset` is the byte of the start
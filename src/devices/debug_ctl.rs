@@ -0,0 +1,102 @@
+//! The debug_ctl module contains a device which lets a guest control the debugging facilities of
+//! the emulator, so that only a region of interest of a long run, such as a phase of a boot, is
+//! traced.
+//!
+//! It has two 8-byte registers:
+//! - `TRACE` (0x00): bit 0 enables the trace hook of the emulator. It's cleared at reset, so
+//!   nothing is traced until the guest sets it.
+//! - `MARKER` (0x08): writing a value logs it as a marker to find the region in the output. A load
+//!   returns the last marker.
+
+use core::any::Any;
+
+use log::info;
+
+use crate::cpu::DOUBLEWORD;
+use crate::devices::mmio::{MmioDevice, WidthRule};
+use crate::exception::Exception;
+
+/// The size of the register region.
+pub const DEBUG_CTL_SIZE: u64 = 0x10;
+
+/// The offset of the register which enables the trace.
+const DEBUG_CTL_TRACE: u64 = 0x00;
+/// The offset of the register which logs a marker.
+const DEBUG_CTL_MARKER: u64 = 0x08;
+
+/// The bit of `TRACE` which enables the trace hook.
+const DEBUG_CTL_TRACE_ENABLE: u64 = 1;
+
+/// The access widths of the registers by their offsets. All of them are 64 bits wide.
+const DEBUG_CTL_ACCESS_WIDTHS: &[WidthRule] = &[WidthRule {
+    start: 0,
+    end: DEBUG_CTL_SIZE - 1,
+    sizes: &[DOUBLEWORD],
+}];
+
+/// The debug control device. The emulator asks it whether to call the trace hook before each
+/// instruction.
+#[derive(Default)]
+pub struct DebugCtl {
+    trace: u64,
+    marker: u64,
+}
+
+impl DebugCtl {
+    /// Create a new device. The trace is disabled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Disable the trace and clear the marker.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Return true if the guest has enabled the trace.
+    pub fn is_tracing(&self) -> bool {
+        self.trace & DEBUG_CTL_TRACE_ENABLE != 0
+    }
+}
+
+impl MmioDevice for DebugCtl {
+    /// Load the register at `offset`.
+    fn read(&mut self, offset: u64, _size: u8) -> Result<u64, Exception> {
+        match offset {
+            DEBUG_CTL_TRACE => Ok(self.trace),
+            DEBUG_CTL_MARKER => Ok(self.marker),
+            _ => Err(Exception::LoadAccessFault),
+        }
+    }
+
+    /// Store the register at `offset`.
+    fn write(&mut self, offset: u64, value: u64, _size: u8) -> Result<(), Exception> {
+        match offset {
+            DEBUG_CTL_TRACE => {
+                self.trace = value & DEBUG_CTL_TRACE_ENABLE;
+                info!(
+                    "debug_ctl: trace {}",
+                    if self.is_tracing() {
+                        "enabled"
+                    } else {
+                        "disabled"
+                    }
+                );
+            }
+            DEBUG_CTL_MARKER => {
+                self.marker = value;
+                info!("debug_ctl: marker {:#x}", value);
+            }
+            _ => return Err(Exception::StoreAMOAccessFault),
+        }
+        Ok(())
+    }
+
+    fn access_widths(&self) -> &[WidthRule] {
+        DEBUG_CTL_ACCESS_WIDTHS
+    }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+}
@@ -0,0 +1,71 @@
+//! The delay module contains a device to test timing-sensitive guest code against the `mcycle`
+//! timebase. It has a single 8-byte register: a load returns the current `mcycle`, and a store of a
+//! deadline stalls the hart until `mcycle` reaches the deadline.
+
+use crate::cpu::DOUBLEWORD;
+use crate::exception::Exception;
+
+/// The size of the register region.
+pub const DELAY_SIZE: u64 = 8;
+
+/// The delay device. The hart keeps its view of `mcycle` up to date and consumes the deadline
+/// after each instruction.
+pub struct Delay {
+    base: u64,
+    mcycle: u64,
+    deadline: Option<u64>,
+}
+
+impl Delay {
+    /// Create a new delay device whose register is at `base`.
+    pub fn new(base: u64) -> Self {
+        Self {
+            base,
+            mcycle: 0,
+            deadline: None,
+        }
+    }
+
+    /// Clear the pending deadline. The address is kept.
+    pub fn reset(&mut self) {
+        self.mcycle = 0;
+        self.deadline = None;
+    }
+
+    /// Return true if `addr` belongs to the register.
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.base..self.base + DELAY_SIZE).contains(&addr)
+    }
+
+    /// Return the address of the register.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Set the value of `mcycle` which the register returns.
+    pub fn set_mcycle(&mut self, mcycle: u64) {
+        self.mcycle = mcycle;
+    }
+
+    /// Return the deadline written since the last call, if any.
+    pub fn take_deadline(&mut self) -> Option<u64> {
+        self.deadline.take()
+    }
+
+    /// Read the current `mcycle`.
+    pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
+        if addr != self.base || size != DOUBLEWORD {
+            return Err(Exception::LoadAccessFault);
+        }
+        Ok(self.mcycle)
+    }
+
+    /// Write a deadline in `mcycle`.
+    pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
+        if addr != self.base || size != DOUBLEWORD {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+        self.deadline = Some(value);
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+//! The entropy module contains the sources of random bytes handed to a guest. All randomness of
+//! the emulator comes from the source on the bus, so a seeded source makes a run reproducible.
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use log::warn;
+
+/// The host side of a random number generator.
+pub trait EntropySource {
+    /// Fill `buf` with random bytes.
+    fn fill(&mut self, buf: &mut [u8]);
+}
+
+/// The source which generates a deterministic sequence from a seed with SplitMix64. It isn't
+/// cryptographically secure, and it's meant for tests and reproducible runs.
+#[derive(Debug, Clone)]
+pub struct SeededEntropy {
+    state: u64,
+}
+
+impl SeededEntropy {
+    /// Create a new source whose sequence is determined by `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Return the next 64 bits of the sequence.
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl EntropySource for SeededEntropy {
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// The source which asks the operating system of the host for random bytes.
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+#[derive(Debug, Default)]
+pub struct OsEntropy;
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl EntropySource for OsEntropy {
+    fn fill(&mut self, buf: &mut [u8]) {
+        if let Err(e) = getrandom::getrandom(buf) {
+            // The guest still gets bytes which differ between runs, though not secure ones.
+            warn!("failed to get random bytes from the host: {}", e);
+            let seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64);
+            SeededEntropy::new(seed).fill(buf);
+        }
+    }
+}
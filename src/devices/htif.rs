@@ -0,0 +1,182 @@
+//! The htif module contains the host-target interface (HTIF), the magic-memory protocol used by
+//! riscv-tests and the Berkeley boot loader to talk to the host via the `tohost` and `fromhost`
+//! words.
+//! See more information in https://github.com/riscv-software-src/riscv-isa-sim/blob/master/fesvr/htif.cc.
+
+use crate::bus::DRAM_BASE;
+use crate::cpu::{BYTE, DOUBLEWORD};
+use crate::devices::serial::SerialBackend;
+use crate::dram::{Dram, DRAM_SIZE};
+use crate::exception::Exception;
+
+/// The offset of `fromhost` from `tohost`. Both words are aligned to 64 bytes in riscv-tests.
+pub const FROMHOST_OFFSET: u64 = 0x40;
+
+/// The device which proxies system calls to the host.
+const DEVICE_SYSCALL: u64 = 0;
+/// The device which accesses the console of the host.
+const DEVICE_CONSOLE: u64 = 1;
+
+/// The command of the console device to write a character.
+const CONSOLE_PUTCHAR: u64 = 1;
+
+/// The system call number to write data to a file descriptor.
+pub const SYS_WRITE: u64 = 64;
+/// The system call number to terminate the program.
+pub const SYS_EXIT: u64 = 93;
+
+/// The error number returned for a bad file descriptor.
+const EBADF: i64 = 9;
+/// The error number returned for an unsupported system call.
+const ENOSYS: i64 = 38;
+
+/// The HTIF device. It watches the `tohost` word and services each command as soon as it is
+/// written.
+pub struct Htif {
+    tohost_addr: u64,
+    fromhost_addr: u64,
+    fromhost: u64,
+    exit_code: Option<u64>,
+}
+
+impl Htif {
+    /// Create a new HTIF device whose `tohost` word is at `tohost_addr`.
+    pub fn new(tohost_addr: u64) -> Self {
+        Self {
+            tohost_addr,
+            fromhost_addr: tohost_addr + FROMHOST_OFFSET,
+            fromhost: 0,
+            exit_code: None,
+        }
+    }
+
+    /// Clear `fromhost` and the exit code. The addresses are kept.
+    pub fn reset(&mut self) {
+        self.fromhost = 0;
+        self.exit_code = None;
+    }
+
+    /// Return true if `addr` belongs to the `tohost` or `fromhost` word.
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.tohost_addr..self.tohost_addr + 8).contains(&addr)
+            || (self.fromhost_addr..self.fromhost_addr + 8).contains(&addr)
+    }
+
+    /// Return the address of the `tohost` word. `fromhost` follows it at `FROMHOST_OFFSET`.
+    pub fn tohost_addr(&self) -> u64 {
+        self.tohost_addr
+    }
+
+    /// Return the exit code once the guest has requested to terminate.
+    pub fn exit_code(&self) -> Option<u64> {
+        self.exit_code
+    }
+
+    /// Read the `tohost` or `fromhost` word. `tohost` always reads as 0 because commands are
+    /// consumed as soon as they are written.
+    pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
+        if size != DOUBLEWORD {
+            return Err(Exception::LoadAccessFault);
+        }
+        if addr == self.fromhost_addr {
+            Ok(self.fromhost)
+        } else if addr == self.tohost_addr {
+            Ok(0)
+        } else {
+            Err(Exception::LoadAccessFault)
+        }
+    }
+
+    /// Write the `tohost` or `fromhost` word. A write to `tohost` is decoded as a command whose
+    /// device is in bits 63:56, whose command is in bits 55:48, and whose payload is in bits 47:0.
+    pub fn write(
+        &mut self,
+        addr: u64,
+        value: u64,
+        size: u8,
+        dram: &mut Dram,
+        console: &mut dyn SerialBackend,
+    ) -> Result<(), Exception> {
+        if size != DOUBLEWORD {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+        if addr == self.fromhost_addr {
+            // The guest acknowledges a response by clearing `fromhost`.
+            self.fromhost = value;
+            return Ok(());
+        }
+        if addr != self.tohost_addr {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+
+        let device = value >> 56;
+        let command = (value >> 48) & 0xff;
+        let payload = value & 0xffff_ffff_ffff;
+        match (device, command) {
+            (DEVICE_SYSCALL, 0) => {
+                if payload & 1 == 1 {
+                    // riscv-tests report the result by writing `(code << 1) | 1`.
+                    self.exit_code = Some(payload >> 1);
+                } else {
+                    self.syscall(payload, dram, console)?;
+                    self.fromhost = 1;
+                }
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                console.write(payload as u8);
+                self.fromhost = (DEVICE_CONSOLE << 56) | (CONSOLE_PUTCHAR << 48);
+            }
+            // Ignore other devices and commands, including reading a character from the
+            // console, since no response is ever produced for them.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Service the system call described by the 8 doublewords at `magic_mem`. The first word is
+    /// the system call number followed by its arguments, and it's overwritten by the return value.
+    fn syscall(
+        &mut self,
+        magic_mem: u64,
+        dram: &mut Dram,
+        console: &mut dyn SerialBackend,
+    ) -> Result<(), Exception> {
+        let which = load(dram, magic_mem, DOUBLEWORD)?;
+        let arg0 = load(dram, magic_mem + 8, DOUBLEWORD)?;
+        let arg1 = load(dram, magic_mem + 16, DOUBLEWORD)?;
+        let arg2 = load(dram, magic_mem + 24, DOUBLEWORD)?;
+
+        let ret = match which {
+            SYS_WRITE => match arg0 {
+                // Both stdout and stderr go to the console.
+                1 | 2 => {
+                    for i in 0..arg2 {
+                        console.write(load(dram, arg1.wrapping_add(i), BYTE)? as u8);
+                    }
+                    arg2 as i64
+                }
+                _ => -EBADF,
+            },
+            SYS_EXIT => {
+                self.exit_code = Some(arg0);
+                0
+            }
+            _ => -ENOSYS,
+        };
+        dram.write(magic_mem, ret as u64, DOUBLEWORD)
+    }
+}
+
+/// Return an error unless a `size`-bit access at `addr` is entirely in DRAM.
+fn check_dram(addr: u64, size: u8) -> Result<(), Exception> {
+    match addr.checked_add(size as u64 / 8) {
+        Some(end) if addr >= DRAM_BASE && end <= DRAM_BASE + DRAM_SIZE => Ok(()),
+        _ => Err(Exception::StoreAMOAccessFault),
+    }
+}
+
+/// Load `size`-bit data from DRAM on behalf of the host.
+fn load(dram: &Dram, addr: u64, size: u8) -> Result<u64, Exception> {
+    check_dram(addr, size)?;
+    dram.read(addr, size)
+}
@@ -0,0 +1,107 @@
+//! The mailbox module contains a memory-mapped mailbox for test harnesses. The guest writes a
+//! command and its arguments to the registers and rings the doorbell, and a handler registered by
+//! the host services the command synchronously and writes back the result.
+//!
+//! All registers are 8 bytes wide:
+//! - 0x00: command
+//! - 0x08..0x48: arguments 0 to 7
+//! - 0x48: doorbell. Writing `n` calls the handler with the command followed by `n` arguments.
+//! - 0x50: result of the last command (read-only)
+
+use alloc::boxed::Box;
+
+use crate::cpu::{DOUBLEWORD, WORD};
+use crate::devices::mmio::{MmioDevice, WidthRule};
+use crate::exception::Exception;
+
+/// The offset of the command register.
+pub const MAILBOX_COMMAND: u64 = 0x00;
+/// The offset of the first argument register.
+pub const MAILBOX_ARG0: u64 = 0x08;
+/// The number of argument registers.
+pub const MAILBOX_ARGS: usize = 8;
+/// The offset of the doorbell register.
+pub const MAILBOX_DOORBELL: u64 = 0x48;
+/// The offset of the result register.
+pub const MAILBOX_RESULT: u64 = 0x50;
+/// The size of the register region.
+pub const MAILBOX_SIZE: u64 = 0x58;
+
+/// The access widths of the registers by their offsets. All of them allow 32-bit and 64-bit
+/// accesses.
+const MAILBOX_ACCESS_WIDTHS: &[WidthRule] = &[WidthRule {
+    start: 0,
+    end: MAILBOX_SIZE - 1,
+    sizes: &[WORD, DOUBLEWORD],
+}];
+
+/// The host function which services a command. It takes the command followed by its arguments
+/// and returns the result.
+pub type MailboxHandler = Box<dyn FnMut(&[u64]) -> u64>;
+
+/// The mailbox device.
+pub struct Mailbox {
+    /// The command followed by the arguments.
+    regs: [u64; 1 + MAILBOX_ARGS],
+    result: u64,
+    handler: MailboxHandler,
+}
+
+impl Mailbox {
+    /// Create a new mailbox whose commands are serviced by `handler`.
+    pub fn new<F: FnMut(&[u64]) -> u64 + 'static>(handler: F) -> Self {
+        Self {
+            regs: [0; 1 + MAILBOX_ARGS],
+            result: 0,
+            handler: Box::new(handler),
+        }
+    }
+}
+
+/// Return an error unless `offset` is at the beginning of a register. The bus has checked the
+/// width of the access.
+fn check_offset(offset: u64, error: Exception) -> Result<(), Exception> {
+    if offset & 0x7 == 0 && offset < MAILBOX_SIZE {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
+
+impl MmioDevice for Mailbox {
+    /// Load the register at `offset`. A 32-bit load reads the lower half.
+    fn read(&mut self, offset: u64, size: u8) -> Result<u64, Exception> {
+        check_offset(offset, Exception::LoadAccessFault)?;
+        let value = match offset {
+            MAILBOX_DOORBELL => 0,
+            MAILBOX_RESULT => self.result,
+            _ => self.regs[(offset / 8) as usize],
+        };
+        match size {
+            WORD => Ok(value as u32 as u64),
+            _ => Ok(value),
+        }
+    }
+
+    /// Store the register at `offset`. A 32-bit store zero-extends the value.
+    fn write(&mut self, offset: u64, value: u64, size: u8) -> Result<(), Exception> {
+        check_offset(offset, Exception::StoreAMOAccessFault)?;
+        let value = match size {
+            WORD => value as u32 as u64,
+            _ => value,
+        };
+        match offset {
+            MAILBOX_DOORBELL => {
+                let argc = (value as usize).min(MAILBOX_ARGS);
+                self.result = (self.handler)(&self.regs[..1 + argc]);
+            }
+            MAILBOX_RESULT => return Err(Exception::StoreAMOAccessFault),
+            _ => self.regs[(offset / 8) as usize] = value,
+        }
+        Ok(())
+    }
+
+    fn access_widths(&self) -> &[WidthRule] {
+        MAILBOX_ACCESS_WIDTHS
+    }
+}
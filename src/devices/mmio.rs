@@ -0,0 +1,116 @@
+//! The mmio module contains the interface of memory-mapped devices attached to the system bus
+//! from outside the core, such as models of board-specific peripherals.
+
+use alloc::boxed::Box;
+use core::any::Any;
+
+use crate::cpu::{BYTE, DOUBLEWORD, HALFWORD, WORD};
+use crate::exception::Exception;
+
+/// The byte order of the registers of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+/// The access sizes which the registers from `start` to `end` inclusive allow. A device declares
+/// a list of rules, and the bus faults on an access of another size before the device sees it.
+#[derive(Debug, Clone, Copy)]
+pub struct WidthRule {
+    pub start: u64,
+    pub end: u64,
+    /// The allowed sizes in bits, such as `BYTE`.
+    pub sizes: &'static [u8],
+}
+
+/// Return true if `rules` allow a `size`-bit access at `addr`. The first rule which contains
+/// `addr` decides, and an address which no rule contains allows any size.
+pub fn is_width_allowed(rules: &[WidthRule], addr: u64, size: u8) -> bool {
+    match rules
+        .iter()
+        .find(|rule| (rule.start..=rule.end).contains(&addr))
+    {
+        Some(rule) => rule.sizes.contains(&size),
+        None => true,
+    }
+}
+
+/// A memory-mapped device. The bus passes the offset of an access from the base address of the
+/// device.
+pub trait MmioDevice {
+    /// Load `size`-bit data from the register at `offset`.
+    fn read(&mut self, offset: u64, size: u8) -> Result<u64, Exception>;
+    /// Store `size`-bit data to the register at `offset`.
+    fn write(&mut self, offset: u64, value: u64, size: u8) -> Result<(), Exception>;
+    /// Return the byte order of the registers. The bus swaps the bytes of multi-byte accesses to
+    /// a big-endian device, so that the device sees values in its own byte order while RISC-V
+    /// software sees them in little endian.
+    fn endianness(&self) -> Endianness {
+        Endianness::Little
+    }
+    /// Return the access sizes which the registers allow, by their offsets. Any size is allowed
+    /// by default.
+    fn access_widths(&self) -> &[WidthRule] {
+        &[]
+    }
+    /// Tell the device the current `mtime` of the CLINT before the bus dispatches an access to it,
+    /// for a device whose registers count time. It's ignored by default.
+    fn set_mtime(&mut self, _mtime: u64) {}
+    /// Return the device as `Any`, so that its owner can reach the concrete device on the bus
+    /// through `Bus::device_mut`. It's `None` by default.
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        None
+    }
+}
+
+/// A device attached to the bus at `base..base + size`.
+pub struct MmioRegion {
+    /// The name of the region in the memory map, such as "watchdog".
+    pub name: &'static str,
+    pub base: u64,
+    pub size: u64,
+    pub device: Box<dyn MmioDevice>,
+}
+
+impl MmioRegion {
+    /// Return true if `addr` is in the region.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr - self.base < self.size
+    }
+
+    /// Load `size`-bit data from `addr` in the region, converting it from the byte order of the
+    /// device.
+    pub fn read(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
+        if !is_width_allowed(self.device.access_widths(), addr - self.base, size) {
+            return Err(Exception::LoadAccessFault);
+        }
+        let value = self.device.read(addr - self.base, size)?;
+        Ok(self.to_device_order(value, size))
+    }
+
+    /// Store `size`-bit data to `addr` in the region, converting it to the byte order of the
+    /// device.
+    pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
+        if !is_width_allowed(self.device.access_widths(), addr - self.base, size) {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+        let value = self.to_device_order(value, size);
+        self.device.write(addr - self.base, value, size)
+    }
+
+    /// Swap the bytes of `size`-bit `value` if the device is big-endian. Swapping is its own
+    /// inverse, so it converts values in both directions.
+    fn to_device_order(&self, value: u64, size: u8) -> u64 {
+        if self.device.endianness() == Endianness::Little {
+            return value;
+        }
+        match size {
+            BYTE => value,
+            HALFWORD => (value as u16).swap_bytes() as u64,
+            WORD => (value as u32).swap_bytes() as u64,
+            DOUBLEWORD => value.swap_bytes(),
+            _ => value,
+        }
+    }
+}
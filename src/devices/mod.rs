@@ -1,16 +1,28 @@
 //! The devices module contains peripheral devices.
 
 pub mod clint;
+pub mod debug_ctl;
+pub mod delay;
+pub mod entropy;
+pub mod htif;
+pub mod mailbox;
+pub mod mmio;
+pub mod perfcounters;
 pub mod plic;
+pub mod serial;
 pub mod virtio_blk;
+pub mod virtio_console;
+mod virtqueue;
+pub mod watchdog;
 
-#[cfg(not(target_arch = "wasm32"))]
+// The UART for WebAssembly talks to the browser via `wasm-bindgen`, which needs `std`.
+#[cfg(any(not(target_arch = "wasm32"), not(feature = "std")))]
 pub mod uart_cli;
-#[cfg(target_arch = "wasm32")]
+#[cfg(all(target_arch = "wasm32", feature = "std"))]
 pub mod uart_wasm;
 
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(any(not(target_arch = "wasm32"), not(feature = "std")))]
 pub use uart_cli as uart;
 
-#[cfg(target_arch = "wasm32")]
+#[cfg(all(target_arch = "wasm32", feature = "std"))]
 pub use uart_wasm as uart;
@@ -0,0 +1,86 @@
+//! The perfcounters module contains a device which exposes the counters of the hart as read-only
+//! registers, so that a guest without access to the counter CSRs, such as user mode without
+//! `mcounteren`, can still read them for benchmarks.
+//!
+//! It has three 8-byte registers: `mcycle` (0x00), `minstret` (0x08), and `mtime` of the CLINT
+//! (0x10).
+
+use core::any::Any;
+
+use crate::cpu::DOUBLEWORD;
+use crate::devices::mmio::{MmioDevice, WidthRule};
+use crate::exception::Exception;
+
+/// The size of the register region.
+pub const PERF_COUNTERS_SIZE: u64 = 0x18;
+
+/// The offset of the register which reads as `mcycle`.
+const PERF_COUNTERS_MCYCLE: u64 = 0x00;
+/// The offset of the register which reads as `minstret`.
+const PERF_COUNTERS_MINSTRET: u64 = 0x08;
+/// The offset of the register which reads as `mtime`.
+const PERF_COUNTERS_MTIME: u64 = 0x10;
+
+/// The access widths of the registers by their offsets. All of them are 64 bits wide.
+const PERF_COUNTERS_ACCESS_WIDTHS: &[WidthRule] = &[WidthRule {
+    start: 0,
+    end: PERF_COUNTERS_SIZE - 1,
+    sizes: &[DOUBLEWORD],
+}];
+
+/// The performance-counter readout device. The hart keeps its view of the counters up to date
+/// before each instruction, so a load sees them as a CSR read by the same instruction would.
+#[derive(Default)]
+pub struct PerfCounters {
+    mcycle: u64,
+    minstret: u64,
+    /// The `mtime` of the CLINT at the last access.
+    mtime: u64,
+}
+
+impl PerfCounters {
+    /// Create a new device whose counters are 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Clear the counters.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Set the values of `mcycle` and `minstret` which the registers return.
+    pub fn set_counters(&mut self, mcycle: u64, minstret: u64) {
+        self.mcycle = mcycle;
+        self.minstret = minstret;
+    }
+}
+
+impl MmioDevice for PerfCounters {
+    /// Read the counter at `offset`.
+    fn read(&mut self, offset: u64, _size: u8) -> Result<u64, Exception> {
+        match offset {
+            PERF_COUNTERS_MCYCLE => Ok(self.mcycle),
+            PERF_COUNTERS_MINSTRET => Ok(self.minstret),
+            PERF_COUNTERS_MTIME => Ok(self.mtime),
+            _ => Err(Exception::LoadAccessFault),
+        }
+    }
+
+    /// Stores always fault since the registers are read-only.
+    fn write(&mut self, _offset: u64, _value: u64, _size: u8) -> Result<(), Exception> {
+        Err(Exception::StoreAMOAccessFault)
+    }
+
+    fn access_widths(&self) -> &[WidthRule] {
+        PERF_COUNTERS_ACCESS_WIDTHS
+    }
+
+    fn set_mtime(&mut self, mtime: u64) {
+        self.mtime = mtime;
+    }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+}
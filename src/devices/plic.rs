@@ -14,6 +14,8 @@
 
 use crate::bus::PLIC_BASE;
 use crate::cpu::WORD;
+use crate::devices::mmio::WidthRule;
+use crate::error::ConfigError;
 use crate::exception::Exception;
 
 /// The address that interrupt source priority starts.
@@ -53,9 +55,40 @@ const PLIC_THRESHOLD_AND_CLAIM: u64 = PLIC_BASE + 0x200000;
 /// base + 0x201004: Claim/complete for context 1
 const PLIC_THRESHOLD_AND_CLAIM_END: u64 = PLIC_THRESHOLD_AND_CLAIM + 0x1007;
 
+/// The access widths of the registers. All of them are 32 bits wide.
+// TODO: should support byte-base access.
+pub const PLIC_ACCESS_WIDTHS: &[WidthRule] = &[WidthRule {
+    start: PLIC_BASE,
+    end: PLIC_THRESHOLD_AND_CLAIM_END,
+    sizes: &[WORD],
+}];
+
 /// The address of the claim/complete registers for S-mode (context 1).
 pub const PLIC_SCLAIM: u64 = PLIC_BASE + 0x201004;
 
+/// The context for M-mode of hart 0.
+pub const PLIC_MCONTEXT: usize = 0;
+/// The context for S-mode of hart 0.
+pub const PLIC_SCONTEXT: usize = 1;
+
+/// Return an error unless `id` is an interrupt source which a device can raise, which is 1 to
+/// 1023 as 0 is reserved.
+pub fn check_source(id: u32) -> Result<(), ConfigError> {
+    match id {
+        1..=1023 => Ok(()),
+        _ => Err(ConfigError::InvalidIrq(id)),
+    }
+}
+
+/// The interrupt sources connected to the PLIC. Each value is the interrupt ID of the source,
+/// which matches the `interrupts` property of the device in the device tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqSource {
+    Virtio = 1,
+    VirtioConsole = 2,
+    Uart = 10,
+}
+
 /// The platform-level-interrupt controller (PLIC).
 pub struct Plic {
     /// The interrupt priority for each interrupt source. A priority value of 0 is reserved to mean
@@ -70,9 +103,9 @@ pub struct Plic {
     /// The settings of a interrupt priority threshold of each context. The PLIC will mask all PLIC
     /// interrupts of a priority less than or equal to `threshold`.
     threshold: [u32; 2],
-    /// The ID of the highest priority pending interrupt or zero if there is no pending interrupt
-    /// for each context.
-    claim: [u32; 2],
+    /// Whether each context has an interrupt to claim. It's updated whenever the registers
+    /// change, since it's checked every cycle.
+    interrupting: [bool; 2],
 }
 
 impl Plic {
@@ -83,17 +116,72 @@ impl Plic {
             pending: [0; 128],
             enable: [0; 256],
             threshold: [0; 2],
-            claim: [0; 2],
+            interrupting: [false; 2],
         }
     }
 
-    /// Load `size`-bit data from a register located at `addr` in PLIC.
-    pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
-        // TODO: should support byte-base access.
-        if size != WORD {
-            return Err(Exception::LoadAccessFault);
+    /// Set the pending bit of `source`.
+    pub fn set_pending(&mut self, source: IrqSource) {
+        self.set_pending_id(source as u32);
+    }
+
+    /// Set the pending bit of the interrupt source `id`, such as a device outside the core. An
+    /// `id` which isn't an interrupt source is ignored.
+    pub fn set_pending_id(&mut self, id: u32) {
+        if check_source(id).is_err() {
+            return;
         }
+        let id = id as usize;
+        self.pending[id / 32] |= 1 << (id % 32);
+        self.update();
+    }
 
+    /// Return true if `context` has an interrupt to claim.
+    pub fn is_interrupting(&self, context: usize) -> bool {
+        self.interrupting[context]
+    }
+
+    /// Recompute whether each context has an interrupt to claim.
+    fn update(&mut self) {
+        for context in 0..self.interrupting.len() {
+            self.interrupting[context] = self.highest_pending(context) != 0;
+        }
+    }
+
+    /// Return the ID of the highest priority pending interrupt which is enabled for `context` and
+    /// whose priority is above its threshold, or zero if there is none. "Ties between global
+    /// interrupts of the same priority are broken by the Interrupt ID; interrupts with the lowest
+    /// ID have the highest effective priority."
+    fn highest_pending(&self, context: usize) -> u32 {
+        let mut id = 0;
+        let mut priority = self.threshold[context];
+        // 1024 sources are enabled by 32 words per context.
+        for word in 0..32 {
+            let bits = self.pending[word] & self.enable[context * 32 + word];
+            if bits == 0 {
+                continue;
+            }
+            for bit in 0..32 {
+                let source = word * 32 + bit;
+                if (bits >> bit) & 1 == 1 && self.priority[source] > priority {
+                    id = source as u32;
+                    priority = self.priority[source];
+                }
+            }
+        }
+        id
+    }
+
+    /// Claim the highest priority pending interrupt for `context`, clearing its pending bit.
+    fn claim(&mut self, context: usize) -> u32 {
+        let id = self.highest_pending(context) as usize;
+        self.pending[id / 32] &= !(1 << (id % 32));
+        self.update();
+        id as u32
+    }
+
+    /// Load `size`-bit data from a register located at `addr` in PLIC.
+    pub fn read(&mut self, addr: u64, _size: u8) -> Result<u64, Exception> {
         match addr {
             PLIC_SOURCE_PRIORITY..=PLIC_SOURCE_PRIORITY_END => {
                 let index = (addr - PLIC_SOURCE_PRIORITY).wrapping_div(0x4);
@@ -110,10 +198,11 @@ impl Plic {
             PLIC_THRESHOLD_AND_CLAIM..=PLIC_THRESHOLD_AND_CLAIM_END => {
                 let context = (addr - PLIC_THRESHOLD_AND_CLAIM).wrapping_div(0x1000);
                 let offset = addr - (PLIC_THRESHOLD_AND_CLAIM + 0x1000 * context);
-                if offset % 4 == 0 {
-                    Ok(self.threshold[context as usize] as u64)
-                } else {
-                    Ok(self.claim[context as usize] as u64)
+                match offset {
+                    0 => Ok(self.threshold[context as usize] as u64),
+                    4 => Ok(self.claim(context as usize) as u64),
+                    // Reserved.
+                    _ => Ok(0),
                 }
             }
             _ => return Err(Exception::LoadAccessFault),
@@ -121,12 +210,7 @@ impl Plic {
     }
 
     /// Store `size`-bit data to a register located at `addr` in PLIC.
-    pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
-        // TODO: should support byte-base access.
-        if size != WORD {
-            return Err(Exception::StoreAMOAccessFault);
-        }
-
+    pub fn write(&mut self, addr: u64, value: u64, _size: u8) -> Result<(), Exception> {
         match addr {
             PLIC_SOURCE_PRIORITY..=PLIC_SOURCE_PRIORITY_END => {
                 let index = (addr - PLIC_SOURCE_PRIORITY).wrapping_div(0x4);
@@ -143,15 +227,18 @@ impl Plic {
             PLIC_THRESHOLD_AND_CLAIM..=PLIC_THRESHOLD_AND_CLAIM_END => {
                 let context = (addr - PLIC_THRESHOLD_AND_CLAIM).wrapping_div(0x1000);
                 let offset = addr - (PLIC_THRESHOLD_AND_CLAIM + 0x1000 * context);
-                if offset % 4 == 0 {
+                // Writing the claimed ID to offset 4 completes the interrupt. Nothing is left to do
+                // because the gateways don't hold further requests while an interrupt is in
+                // flight.
+                if offset == 0 {
                     self.threshold[context as usize] = value as u32;
-                } else {
-                    self.claim[context as usize] = value as u32;
                 }
             }
             _ => return Err(Exception::StoreAMOAccessFault),
         }
 
+        // Priorities, pending bits, enable bits, or thresholds may have changed.
+        self.update();
         Ok(())
     }
 }
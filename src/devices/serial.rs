@@ -0,0 +1,192 @@
+//! The serial module contains the backends which receive the bytes a guest writes to its console.
+
+#[cfg(not(feature = "std"))]
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(not(feature = "std"))]
+use core::cell::RefCell;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{self, prelude::*};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::path::Path;
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, PoisonError};
+
+#[cfg(feature = "std")]
+use log::warn;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The host side of a serial console. Devices hand every byte transmitted by the guest to their
+/// backend.
+pub trait SerialBackend {
+    /// Consume a byte transmitted by the guest.
+    fn write(&mut self, byte: u8);
+}
+
+/// The backend which outputs bytes to the standard output of the host.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct StdoutBackend;
+
+#[cfg(feature = "std")]
+impl SerialBackend for StdoutBackend {
+    fn write(&mut self, byte: u8) {
+        print!("{}", byte as char);
+        // The guest can't do anything about the host's stdout, e.g., a closed pipe.
+        if let Err(e) = io::stdout().flush() {
+            warn!("failed to flush stdout: {}", e);
+        }
+    }
+}
+
+/// The backend which stores bytes in memory. Clones share the same buffer, so a clone kept by the
+/// host can inspect the output after the original is handed to a device.
+#[derive(Debug, Default, Clone)]
+pub struct BufferBackend {
+    #[cfg(feature = "std")]
+    buffer: Arc<Mutex<Vec<u8>>>,
+    #[cfg(not(feature = "std"))]
+    buffer: Rc<RefCell<Vec<u8>>>,
+}
+
+impl BufferBackend {
+    /// Create a new backend with an empty buffer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a copy of all bytes written so far.
+    pub fn bytes(&self) -> Vec<u8> {
+        #[cfg(feature = "std")]
+        let buffer = self.buffer.lock().unwrap_or_else(PoisonError::into_inner);
+        #[cfg(not(feature = "std"))]
+        let buffer = self.buffer.borrow();
+        buffer.clone()
+    }
+
+    /// Return all bytes written so far as a string. Invalid UTF-8 sequences are replaced.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.bytes()).into_owned()
+    }
+}
+
+impl SerialBackend for BufferBackend {
+    fn write(&mut self, byte: u8) {
+        #[cfg(feature = "std")]
+        let mut buffer = self.buffer.lock().unwrap_or_else(PoisonError::into_inner);
+        #[cfg(not(feature = "std"))]
+        let mut buffer = self.buffer.borrow_mut();
+        buffer.push(byte);
+    }
+}
+
+/// The backend which writes lines to a file, each prefixed with the time of the host when its
+/// first byte arrived in ISO 8601 format in UTC, e.g., `2024-01-02T03:04:05.678901Z hello`. It's
+/// meant to correlate the console of a long run with events outside the guest. A line is written
+/// once its newline arrives, and an unterminated line is written when the backend is dropped.
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+#[derive(Debug)]
+pub struct TimestampedFileBackend {
+    file: File,
+    /// The bytes of the current line.
+    line: Vec<u8>,
+    /// The time the first byte of the current line arrived.
+    started: Option<SystemTime>,
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl TimestampedFileBackend {
+    /// Create a new log file at `path`, truncating it if it exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            file: File::create(path)?,
+            line: Vec::new(),
+            started: None,
+        })
+    }
+
+    /// Write the current line with its timestamp.
+    fn write_line(&mut self) -> io::Result<()> {
+        let started = match self.started.take() {
+            Some(started) => started,
+            None => return Ok(()),
+        };
+        write!(self.file, "{} ", timestamp(started))?;
+        self.file.write_all(&self.line)?;
+        self.line.clear();
+        self.file.flush()
+    }
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl SerialBackend for TimestampedFileBackend {
+    fn write(&mut self, byte: u8) {
+        self.started.get_or_insert_with(SystemTime::now);
+        self.line.push(byte);
+        if byte == b'\n' {
+            // The guest keeps running when the host fails to write, e.g., the disk is full, and
+            // the line is dropped.
+            if let Err(e) = self.write_line() {
+                warn!("failed to write to a serial log: {}", e);
+                self.line.clear();
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl Drop for TimestampedFileBackend {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.line.push(b'\n');
+        }
+        // Panicking in a destructor may abort the host, so an error is dropped.
+        let _ = self.write_line();
+    }
+}
+
+/// Format `time` in ISO 8601 format in UTC with microseconds.
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days(secs / 86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+/// Convert the number of days since 1970-01-01 to a date in the proleptic Gregorian calendar.
+///
+/// Reference: "chrono-Compatible Low-Level Date Algorithms"
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Count from 0000-03-01 so that a leap day is the last day of a year.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
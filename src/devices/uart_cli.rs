@@ -1,28 +1,41 @@
 //! The uart module contains the implementation of a universal asynchronous receiver-transmitter
 //! (UART) for the CLI tool. The device is 16550A UART, which is used in the QEMU virt machine.
 //! See more information in http://byterunner.com/16550.html.
+//!
+//! Without `std`, there is no thread reading the standard input, so the host supplies input with
+//! `push_input`, and the output is kept by a `BufferBackend` until the host sets a backend.
 
-use std::io;
-use std::io::prelude::*;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Condvar, Mutex,
-};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::io::{self, prelude::*};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+#[cfg(feature = "std")]
 use std::thread;
 
 use crate::bus::{UART_BASE, UART_SIZE};
 use crate::cpu::BYTE;
+use crate::devices::mmio::WidthRule;
+#[cfg(not(feature = "std"))]
+use crate::devices::serial::BufferBackend;
+use crate::devices::serial::SerialBackend;
+#[cfg(feature = "std")]
+use crate::devices::serial::StdoutBackend;
 use crate::exception::Exception;
 
-/// The interrupt request of UART.
-pub const UART_IRQ: u64 = 10;
-
 /// Receive holding register (for input bytes).
 pub const UART_RHR: u64 = UART_BASE + 0;
 /// Transmit holding register (for output bytes).
 pub const UART_THR: u64 = UART_BASE + 0;
 /// Interrupt enable register.
 pub const UART_IER: u64 = UART_BASE + 1;
+/// Divisor latch, least significant byte. It's accessible instead of RHR and THR while the DLAB
+/// bit of LCR is set.
+pub const UART_DLL: u64 = UART_BASE;
+/// Divisor latch, most significant byte. It's accessible instead of IER while the DLAB bit of LCR
+/// is set.
+pub const UART_DLM: u64 = UART_BASE + 1;
 /// FIFO control register.
 pub const UART_FCR: u64 = UART_BASE + 2;
 /// Interrupt status register.
@@ -32,6 +45,10 @@ pub const UART_FCR: u64 = UART_BASE + 2;
 ///     1 = no interrupt is pending.
 pub const UART_ISR: u64 = UART_BASE + 2;
 /// Line control register.
+/// LCR BIT 7:
+///     0 = RHR, THR, and IER are accessible.
+///     1 = the divisor latch (DLL and DLM) is accessible. It's called the divisor latch access bit
+/// (DLAB).
 pub const UART_LCR: u64 = UART_BASE + 3;
 /// Line status register.
 /// LSR BIT 0:
@@ -42,6 +59,18 @@ pub const UART_LCR: u64 = UART_BASE + 3;
 ///     1 = transmitter hold register (or FIFO) is empty. CPU can load the next character.
 pub const UART_LSR: u64 = UART_BASE + 5;
 
+/// The divisor latch access bit (DLAB) of LCR.
+pub const UART_LCR_DLAB: u8 = 1 << 7;
+/// The frequency of the clock which the baud rate is divided from. It's advertised in the DTB.
+pub const UART_CLOCK_FREQUENCY: u32 = 0x384000;
+
+/// The access widths of the registers. All of them are 8 bits wide.
+pub const UART_ACCESS_WIDTHS: &[WidthRule] = &[WidthRule {
+    start: UART_BASE,
+    end: UART_BASE + UART_SIZE - 1,
+    sizes: &[BYTE],
+}];
+
 /// The receiver (RX).
 pub const UART_LSR_RX: u8 = 1;
 /// The transmitter (TX).
@@ -49,78 +78,167 @@ pub const UART_LSR_TX: u8 = 1 << 5;
 
 /// The UART, the size of which is 0x100 (2**8).
 pub struct Uart {
-    uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
-    interrupting: Arc<AtomicBool>,
+    uart: [u8; UART_SIZE as usize],
+    interrupting: bool,
+    /// Bytes which arrived from the host and haven't been delivered to the guest yet.
+    #[cfg(feature = "std")]
+    input: Arc<Mutex<VecDeque<u8>>>,
+    #[cfg(not(feature = "std"))]
+    input: VecDeque<u8>,
+    backend: Box<dyn SerialBackend>,
+    /// The divisor latch. The baud rate is `UART_CLOCK_FREQUENCY / (16 * divisor)`, but the
+    /// emulator doesn't limit the rate.
+    divisor: u16,
 }
 
 impl Uart {
-    /// Create a new UART object.
+    /// Create a new UART object which reads the standard input of the host.
     pub fn new() -> Self {
-        let uart = Arc::new((Mutex::new([0; UART_SIZE as usize]), Condvar::new()));
-        let interrupting = Arc::new(AtomicBool::new(false));
-        {
-            let (uart, _cvar) = &*uart;
-            let mut uart = uart.lock().expect("failed to get an UART object");
-            // Transmitter hold register is empty. It allows input anytime.
-            uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_TX;
-        }
+        let uart = Self::detached();
 
-        // Create a new thread for waiting for input.
-        let mut byte = [0; 1];
-        let cloned_uart = uart.clone();
-        let cloned_interrupting = interrupting.clone();
-        let _uart_thread_for_read = thread::spawn(move || loop {
-            match io::stdin().read(&mut byte) {
-                Ok(_) => {
-                    let (uart, cvar) = &*cloned_uart;
-                    let mut uart = uart.lock().expect("failed to get an UART object");
-                    // Wait for the thread to start up.
-                    while (uart[(UART_LSR - UART_BASE) as usize] & UART_LSR_RX) == 1 {
-                        uart = cvar.wait(uart).expect("the mutex is poisoned");
+        // Create a new thread for waiting for input. Bytes are queued here and delivered to the
+        // guest one by one when the receive holding register is empty.
+        #[cfg(feature = "std")]
+        {
+            let cloned_input = uart.input.clone();
+            let _uart_thread_for_read = thread::spawn(move || {
+                let mut byte = [0; 1];
+                loop {
+                    match io::stdin().read(&mut byte) {
+                        // The end of input.
+                        Ok(0) => return,
+                        Ok(_) => {
+                            cloned_input
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .push_back(byte[0]);
+                        }
+                        Err(e) => {
+                            println!("input via UART is error: {}", e);
+                        }
                     }
-                    uart[0] = byte[0];
-                    cloned_interrupting.store(true, Ordering::Release);
-                    // Data has been receive.
-                    uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_RX;
                 }
-                Err(e) => {
-                    println!("input via UART is error: {}", e);
-                }
-            }
-        });
+            });
+        }
+        uart
+    }
+
+    /// Create a new UART object which doesn't read the standard input of the host, such as a
+    /// port other than the console. Bytes only arrive by `push_input`.
+    pub fn detached() -> Self {
+        let mut uart = [0; UART_SIZE as usize];
+        // Transmitter hold register is empty. It allows input anytime.
+        uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_TX;
 
-        Self { uart, interrupting }
+        Self {
+            uart,
+            interrupting: false,
+            #[cfg(feature = "std")]
+            input: Arc::new(Mutex::new(VecDeque::new())),
+            #[cfg(not(feature = "std"))]
+            input: VecDeque::new(),
+            #[cfg(feature = "std")]
+            backend: Box::new(StdoutBackend),
+            #[cfg(not(feature = "std"))]
+            backend: Box::new(BufferBackend::new()),
+            divisor: 0,
+        }
     }
 
-    /// Return true if an interrupt is pending. Clear the interrupting flag by swapping a value.
-    pub fn is_interrupting(&self) -> bool {
-        self.interrupting.swap(false, Ordering::Acquire)
+    /// Reset the registers and drop the bytes from the host which haven't been delivered yet. The
+    /// backend is kept.
+    pub fn reset(&mut self) {
+        self.uart = [0; UART_SIZE as usize];
+        self.uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_TX;
+        self.interrupting = false;
+        self.divisor = 0;
+        self.input().clear();
     }
 
-    /// Read a byte from the receive holding register.
-    pub fn read(&mut self, index: u64, size: u8) -> Result<u64, Exception> {
-        if size != BYTE {
-            return Err(Exception::LoadAccessFault);
+    /// Return true if an interrupt is pending. Clear the interrupting flag.
+    pub fn is_interrupting(&mut self) -> bool {
+        core::mem::replace(&mut self.interrupting, false)
+    }
+
+    /// Queue bytes as if they were typed on the host.
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input().extend(bytes);
+    }
+
+    /// Take the next byte typed on the host, if any.
+    pub fn host_input(&mut self) -> Option<u8> {
+        self.input().pop_front()
+    }
+
+    /// Return the queue of bytes from the host, which is shared with the thread reading the
+    /// standard input.
+    #[cfg(feature = "std")]
+    fn input(&mut self) -> MutexGuard<'_, VecDeque<u8>> {
+        self.input.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Return the queue of bytes from the host.
+    #[cfg(not(feature = "std"))]
+    fn input(&mut self) -> &mut VecDeque<u8> {
+        &mut self.input
+    }
+
+    /// Return true if the receive holding register is empty and can take a new byte.
+    pub fn is_ready_to_receive(&self) -> bool {
+        (self.uart[(UART_LSR - UART_BASE) as usize] & UART_LSR_RX) == 0
+    }
+
+    /// Put a byte in the receive holding register and raise an interrupt.
+    pub fn receive(&mut self, byte: u8) {
+        self.uart[(UART_RHR - UART_BASE) as usize] = byte;
+        // Data has been receive.
+        self.uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_RX;
+        self.interrupting = true;
+    }
+
+    /// Replace the backend which receives the bytes written to the transmit holding register.
+    pub fn set_backend(&mut self, backend: Box<dyn SerialBackend>) {
+        self.backend = backend;
+    }
+
+    /// Return the backend which receives the bytes written to the transmit holding register.
+    pub fn backend(&mut self) -> &mut dyn SerialBackend {
+        self.backend.as_mut()
+    }
+
+    /// Return the divisor latch written by the guest.
+    pub fn divisor(&self) -> u16 {
+        self.divisor
+    }
+
+    /// Return the baud rate the guest has set, or `None` if the divisor latch is 0.
+    pub fn baud_rate(&self) -> Option<u32> {
+        match self.divisor {
+            0 => None,
+            divisor => Some(UART_CLOCK_FREQUENCY / (16 * divisor as u32)),
         }
+    }
+
+    /// Return true if the divisor latch is accessible instead of RHR, THR, and IER.
+    fn is_dlab(&self) -> bool {
+        self.uart[(UART_LCR - UART_BASE) as usize] & UART_LCR_DLAB != 0
+    }
 
-        let (uart, cvar) = &*self.uart;
-        let mut uart = uart.lock().expect("failed to get an UART object");
+    /// Read a byte from the receive holding register.
+    pub fn read(&mut self, index: u64, _size: u8) -> Result<u64, Exception> {
         match index {
+            UART_DLL if self.is_dlab() => Ok(self.divisor as u64 & 0xff),
+            UART_DLM if self.is_dlab() => Ok(self.divisor as u64 >> 8),
             UART_RHR => {
-                cvar.notify_one();
-                uart[(UART_LSR - UART_BASE) as usize] &= !UART_LSR_RX;
-                Ok(uart[(UART_RHR - UART_BASE) as usize] as u64)
+                self.uart[(UART_LSR - UART_BASE) as usize] &= !UART_LSR_RX;
+                Ok(self.uart[(UART_RHR - UART_BASE) as usize] as u64)
             }
-            _ => Ok(uart[(index - UART_BASE) as usize] as u64),
+            _ => Ok(self.uart[(index - UART_BASE) as usize] as u64),
         }
     }
 
     /// Write a byte to the transmit holding register.
-    pub fn write(&mut self, index: u64, value: u8, size: u8) -> Result<(), Exception> {
-        if size != BYTE {
-            return Err(Exception::StoreAMOAccessFault);
-        }
-
+    pub fn write(&mut self, index: u64, value: u8, _size: u8) -> Result<(), Exception> {
         // An OS allows to write a byte to a UART when UART_LSR_TX is 1.
         // e.g. (xv6):
         //   // wait for Transmit Holding Empty to be set in LSR.
@@ -131,15 +249,18 @@ impl Uart {
         // e.g. (riscv-pk):
         //   while ((uart16550[UART_REG_LSR << uart16550_reg_shift] & UART_REG_STATUS_TX) == 0);
         //   uart16550[UART_REG_QUEUE << uart16550_reg_shift] = ch;
-        let (uart, _cvar) = &*self.uart;
-        let mut uart = uart.lock().expect("failed to get an UART object");
         match index {
+            UART_DLL if self.is_dlab() => {
+                self.divisor = (self.divisor & 0xff00) | value as u16;
+            }
+            UART_DLM if self.is_dlab() => {
+                self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8;
+            }
             UART_THR => {
-                print!("{}", value as char);
-                io::stdout().flush().expect("failed to flush stdout");
+                self.backend.write(value);
             }
             _ => {
-                uart[(index - UART_BASE) as usize] = value;
+                self.uart[(index - UART_BASE) as usize] = value;
             }
         }
         Ok(())
@@ -2,12 +2,16 @@
 //! (UART) for WebAssembly. The device is 16550a UART, which is used in the QEMU virt machine. See more information
 //! in http://byterunner.com/16550.html.
 
+use std::collections::VecDeque;
+
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsValue;
 use web_sys::Window;
 
 use crate::bus::{UART_BASE, UART_SIZE};
 use crate::cpu::BYTE;
+use crate::devices::mmio::WidthRule;
+use crate::devices::serial::{BufferBackend, SerialBackend};
 use crate::exception::Exception;
 
 #[wasm_bindgen]
@@ -16,15 +20,18 @@ extern "C" {
     fn log(s: &str);
 }
 
-/// The interrupt request of UART.
-pub const UART_IRQ: u64 = 10;
-
 /// Receive holding register (for input bytes).
 pub const UART_RHR: u64 = UART_BASE + 0;
 /// Transmit holding register (for output bytes).
 pub const UART_THR: u64 = UART_BASE + 0;
 /// Interrupt enable register.
 pub const UART_IER: u64 = UART_BASE + 1;
+/// Divisor latch, least significant byte. It's accessible instead of RHR and THR while the DLAB
+/// bit of LCR is set.
+pub const UART_DLL: u64 = UART_BASE;
+/// Divisor latch, most significant byte. It's accessible instead of IER while the DLAB bit of LCR
+/// is set.
+pub const UART_DLM: u64 = UART_BASE + 1;
 /// FIFO control register.
 pub const UART_FCR: u64 = UART_BASE + 2;
 /// Interrupt status register.
@@ -34,6 +41,10 @@ pub const UART_FCR: u64 = UART_BASE + 2;
 ///     1 = no interrupt is pending.
 pub const UART_ISR: u64 = UART_BASE + 2;
 /// Line control register.
+/// LCR BIT 7:
+///     0 = RHR, THR, and IER are accessible.
+///     1 = the divisor latch (DLL and DLM) is accessible. It's called the divisor latch access bit
+/// (DLAB).
 pub const UART_LCR: u64 = UART_BASE + 3;
 /// Line status register.
 /// LSR BIT 0:
@@ -44,6 +55,18 @@ pub const UART_LCR: u64 = UART_BASE + 3;
 ///     1 = transmit holding register is empty. In FIFO mode this bit is set to one whenever the the transmitter FIFO and transmit shift register are empty.
 pub const UART_LSR: u64 = UART_BASE + 5;
 
+/// The divisor latch access bit (DLAB) of LCR.
+pub const UART_LCR_DLAB: u8 = 1 << 7;
+/// The frequency of the clock which the baud rate is divided from. It's advertised in the DTB.
+pub const UART_CLOCK_FREQUENCY: u32 = 0x384000;
+
+/// The access widths of the registers. All of them are 8 bits wide.
+pub const UART_ACCESS_WIDTHS: &[WidthRule] = &[WidthRule {
+    start: UART_BASE,
+    end: UART_BASE + UART_SIZE - 1,
+    sizes: &[BYTE],
+}];
+
 fn get_input(window: &Window) -> u8 {
     let document = window.document().expect("failed to get a document object");
     let buffer = document
@@ -64,17 +87,51 @@ fn get_input(window: &Window) -> u8 {
     }
 }
 
+/// The backend which posts each byte to the browser window as a message.
+struct WindowBackend {
+    window: web_sys::Window,
+}
+
+impl SerialBackend for WindowBackend {
+    fn write(&mut self, byte: u8) {
+        self.window
+            .post_message(&JsValue::from(byte), "*")
+            .expect("failed to post message");
+    }
+}
+
 /// The UART, the size of which is 0x100 (2**8).
 pub struct Uart {
     uart: [u8; UART_SIZE as usize],
     clock: u64,
     not_null: bool,
-    window: web_sys::Window,
+    /// The window whose input field is polled for typed bytes, or `None` for a detached port.
+    window: Option<Window>,
+    /// True if a byte has been put by `receive` and the interrupt isn't reported yet.
+    interrupting: bool,
+    /// Bytes queued by `push_input` which haven't been delivered to the guest yet.
+    input: VecDeque<u8>,
+    backend: Box<dyn SerialBackend>,
+    /// The divisor latch. The baud rate is `UART_CLOCK_FREQUENCY / (16 * divisor)`, but the
+    /// emulator doesn't limit the rate.
+    divisor: u16,
 }
 
 impl Uart {
     /// Create a new UART object.
     pub fn new() -> Self {
+        let window = web_sys::window().expect("failed to get a global window object");
+        Self {
+            window: Some(window.clone()),
+            backend: Box::new(WindowBackend { window }),
+            ..Self::detached()
+        }
+    }
+
+    /// Create a new UART object which doesn't poll the browser for input, such as a port other
+    /// than the console. Bytes only arrive by `push_input`, and the output is kept in a buffer
+    /// until the backend is replaced.
+    pub fn detached() -> Self {
         let mut uart = [0; UART_SIZE as usize];
         uart[(UART_ISR - UART_BASE) as usize] |= 1;
         uart[(UART_LSR - UART_BASE) as usize] |= 1 << 5;
@@ -83,17 +140,74 @@ impl Uart {
             uart,
             clock: 0,
             not_null: false,
-            window: web_sys::window().expect("failed to get a global window object"),
+            window: None,
+            interrupting: false,
+            input: VecDeque::new(),
+            backend: Box::new(BufferBackend::new()),
+            divisor: 0,
         }
     }
 
+    /// Reset the registers and drop the bytes from the host which haven't been delivered yet. The
+    /// backend is kept.
+    pub fn reset(&mut self) {
+        self.uart = [0; UART_SIZE as usize];
+        self.uart[(UART_ISR - UART_BASE) as usize] |= 1;
+        self.uart[(UART_LSR - UART_BASE) as usize] |= 1 << 5;
+        self.clock = 0;
+        self.not_null = false;
+        self.interrupting = false;
+        self.input.clear();
+        self.divisor = 0;
+    }
+
+    /// Replace the backend which receives the bytes written to the transmit holding register.
+    pub fn set_backend(&mut self, backend: Box<dyn SerialBackend>) {
+        self.backend = backend;
+    }
+
+    /// Return the backend which receives the bytes written to the transmit holding register.
+    pub fn backend(&mut self) -> &mut dyn SerialBackend {
+        self.backend.as_mut()
+    }
+
+    /// Queue bytes as if they were typed on the host.
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    /// Take the next byte queued by `push_input`, if any. Bytes typed in the browser are
+    /// delivered by polling in `is_interrupting` instead.
+    pub fn host_input(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    /// Return true if the receive holding register is empty and can take a new byte.
+    pub fn is_ready_to_receive(&self) -> bool {
+        (self.uart[(UART_LSR - UART_BASE) as usize] & 1) == 0
+    }
+
+    /// Put a byte in the receive holding register and raise an interrupt.
+    pub fn receive(&mut self, byte: u8) {
+        self.uart[(UART_RHR - UART_BASE) as usize] = byte;
+        self.uart[(UART_LSR - UART_BASE) as usize] |= 1;
+        self.interrupting = true;
+    }
+
     /// Return true if the byte buffer in UART is full.
     pub fn is_interrupting(&mut self) -> bool {
+        if std::mem::replace(&mut self.interrupting, false) {
+            return true;
+        }
+
         self.clock += 1;
         // Avoid too many interrupting, bus read a byte again if a byte is found in the previous step.
         if self.clock > 500000 || self.not_null {
             self.clock = 0;
-            let b = get_input(&self.window);
+            let b = match &self.window {
+                Some(window) => get_input(window),
+                None => 0,
+            };
             if b == 0 {
                 self.not_null = false;
                 return false;
@@ -107,13 +221,29 @@ impl Uart {
         false
     }
 
-    /// Read a byte from the receive holding register.
-    pub fn read(&mut self, index: u64, size: u8) -> Result<u64, Exception> {
-        if size != BYTE {
-            return Err(Exception::LoadAccessFault);
+    /// Return the divisor latch written by the guest.
+    pub fn divisor(&self) -> u16 {
+        self.divisor
+    }
+
+    /// Return the baud rate the guest has set, or `None` if the divisor latch is 0.
+    pub fn baud_rate(&self) -> Option<u32> {
+        match self.divisor {
+            0 => None,
+            divisor => Some(UART_CLOCK_FREQUENCY / (16 * divisor as u32)),
         }
+    }
+
+    /// Return true if the divisor latch is accessible instead of RHR, THR, and IER.
+    fn is_dlab(&self) -> bool {
+        self.uart[(UART_LCR - UART_BASE) as usize] & UART_LCR_DLAB != 0
+    }
 
+    /// Read a byte from the receive holding register.
+    pub fn read(&mut self, index: u64, _size: u8) -> Result<u64, Exception> {
         match index {
+            UART_DLL if self.is_dlab() => Ok(self.divisor as u64 & 0xff),
+            UART_DLM if self.is_dlab() => Ok(self.divisor as u64 >> 8),
             UART_RHR => {
                 self.uart[(UART_LSR - UART_BASE) as usize] &= !1;
                 Ok(self.uart[(index - UART_BASE) as usize] as u64)
@@ -123,16 +253,16 @@ impl Uart {
     }
 
     /// Write a byte to the transmit holding register.
-    pub fn write(&mut self, index: u64, value: u8, size: u8) -> Result<(), Exception> {
-        if size != BYTE {
-            return Err(Exception::StoreAMOAccessFault);
-        }
-
+    pub fn write(&mut self, index: u64, value: u8, _size: u8) -> Result<(), Exception> {
         match index {
+            UART_DLL if self.is_dlab() => {
+                self.divisor = (self.divisor & 0xff00) | value as u16;
+            }
+            UART_DLM if self.is_dlab() => {
+                self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8;
+            }
             UART_THR => {
-                self.window
-                    .post_message(&JsValue::from(value), "*")
-                    .expect("failed to post message");
+                self.backend.write(value);
             }
             _ => {
                 self.uart[(index - UART_BASE) as usize] = value;
@@ -5,20 +5,66 @@
 //! 5.2 Block Device:
 //! https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2390002
 
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::fs::File;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::io::{self, prelude::*, SeekFrom};
+
+use log::warn;
+
 use crate::bus::VIRTIO_BASE;
 use crate::cpu::{Cpu, BYTE, DOUBLEWORD, HALFWORD, WORD};
+use crate::devices::mmio::WidthRule;
+use crate::devices::virtqueue::{self, VirtqDesc};
 use crate::exception::Exception;
 
-/// The interrupt request of virtio.
-pub const VIRTIO_IRQ: u64 = 1;
-
-/// The size of `VRingDesc` struct.
-const VRING_DESC_SIZE: u64 = 16;
 /// The number of virtio descriptors. It must be a power of two.
 const QUEUE_SIZE: u64 = 8;
 /// The size of a sector.
 const SECTOR_SIZE: u64 = 512;
 
+// 5.2.3 Feature bits
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2420003
+/// Device is read-only.
+const VIRTIO_BLK_F_RO: u32 = 5;
+/// Device can support discard command.
+const VIRTIO_BLK_F_DISCARD: u32 = 13;
+/// Device can support write zeroes command.
+const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
+
+// 5.2.6 Device Operation
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2500006
+/// The request type to read sectors.
+const VIRTIO_BLK_T_IN: u64 = 0;
+/// The request type to write sectors.
+const VIRTIO_BLK_T_OUT: u64 = 1;
+/// The request type to get the device ID string.
+const VIRTIO_BLK_T_GET_ID: u64 = 8;
+/// The request type to discard sectors.
+const VIRTIO_BLK_T_DISCARD: u64 = 11;
+/// The request type to write zeroes to sectors.
+const VIRTIO_BLK_T_WRITE_ZEROES: u64 = 13;
+/// The status of a successful request.
+const VIRTIO_BLK_S_OK: u64 = 0;
+/// The status of a request which failed due to a device or driver error.
+const VIRTIO_BLK_S_IOERR: u64 = 1;
+/// The status of a request which the device doesn't support.
+const VIRTIO_BLK_S_UNSUPP: u64 = 2;
+/// The device ID string. `VIRTIO_BLK_T_GET_ID` returns it padded with zeroes to 20 bytes.
+const VIRTIO_BLK_ID: &[u8] = b"rvemu";
+/// The size of the device ID string.
+const VIRTIO_BLK_ID_BYTES: u64 = 20;
+/// The size of `virtio_blk_discard_write_zeroes` struct.
+const DISCARD_WRITE_ZEROES_SIZE: u64 = 16;
+
+// 2.1 Device Status Field
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-100001
+/// The driver is set up and ready to drive the device.
+const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+
 // 4.2.2 MMIO Device Register Layout
 // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1460002
 /// Magic value. Always return 0x74726976 (a Little Endian equivalent of the “virt” string).
@@ -84,138 +130,139 @@ const VIRTIO_MMIO_INTERRUPT_ACK: u64 = VIRTIO_BASE + 0x064;
 /// non-zero values to this register sets the status flags, indicating the driver progress. Writing
 /// zero (0x0) to this register triggers a device reset.
 const VIRTIO_STATUS: u64 = VIRTIO_BASE + 0x070;
-/// Configuration space.
+/// Configuration atomicity value. The driver reads it before and after accessing the
+/// configuration space, and it changes whenever the configuration space has changed. Read-only.
+const VIRTIO_CONFIG_GENERATION: u64 = VIRTIO_BASE + 0x0fc;
+/// Configuration space. The block device has its capacity in 512-byte sectors there.
 const VIRTIO_CONFIG: u64 = VIRTIO_BASE + 0x100;
-const VIRTIO_CONFIG_END: u64 = VIRTIO_CONFIG + 0x8;
-
-/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-250001
-///
-/// ```c
-/// struct virtq {
-///   struct virtq_desc desc[ Queue Size ];
-///   struct virtq_avail avail;
-///   u8 pad[ Padding ]; // Padding to the next Queue Align boundary.
-///   struct virtq_used used;
-/// };
-/// ```
-struct _Virtq {
-    /// The actual descriptors (16 bytes each)
-    /// The number of descriptors in the table is defined by the queue size for this virtqueue.
-    desc: Vec<VirtqDesc>,
-    /// A ring of available descriptor heads with free-running index.
-    avail: _VirtqAvail,
-    /// A ring of used descriptor heads with free-running index.
-    used: _VirtqUsed,
+const VIRTIO_CONFIG_END: u64 = VIRTIO_CONFIG + 0x7;
+
+/// The access widths of the registers. The configuration space only allows byte accesses, and
+/// the other registers allow any access up to 32 bits.
+pub const VIRTIO_ACCESS_WIDTHS: &[WidthRule] = &[
+    WidthRule {
+        start: VIRTIO_CONFIG,
+        end: VIRTIO_CONFIG_END,
+        sizes: &[BYTE],
+    },
+    WidthRule {
+        start: VIRTIO_BASE,
+        end: VIRTIO_BASE + 0xfff,
+        sizes: &[BYTE, HALFWORD, WORD],
+    },
+];
+
+/// The image backing the virtio disk.
+enum DiskImage {
+    /// An image owned by the device, which is writable.
+    Private(Vec<u8>),
+    /// An image shared among devices without copying it, which is read-only.
+    Shared(Arc<[u8]>),
+    /// A writable image file of the host, which other devices or processes may open as well.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    File(DiskFile),
 }
 
-/// "The descriptor table refers to the buffers the driver is using for the device. addr is a
-/// physical address, and the buffers can be chained via next. Each descriptor describes a buffer
-/// which is read-only for the device (“device-readable”) or write-only for the device
-/// (“device-writable”), but a chain of descriptors can contain both device-readable and
-/// device-writable buffers."
-///
-/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-320005
-///
-/// ```c
-/// /* This marks a buffer as continuing via the next field. */
-/// #define VIRTQ_DESC_F_NEXT 1
-/// /* This marks a buffer as device write-only (otherwise device read-only). */
-/// #define VIRTQ_DESC_F_WRITE 2
-/// /* This means the buffer contains a list of buffer descriptors. */
-/// #define VIRTQ_DESC_F_INDIRECT 4
-///
-/// struct virtq_desc {
-///   le64 addr;
-///   le32 len;
-///   le16 flags;
-///   le16 next;
-/// };
-/// ```
-struct VirtqDesc {
-    /// Address (guest-physical).
-    addr: u64,
-    /// Length.
+/// An image file of the host which backs the disk.
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+struct DiskFile {
+    file: File,
+    /// The size of the file when it was opened. The capacity of the disk doesn't change even if
+    /// another device extends the file.
     len: u64,
-    /// The flags as indicated VIRTQ_DESC_F_NEXT/VIRTQ_DESC_F_WRITE/VIRTQ_DESC_F_INDIRECT.
-    flags: u64,
-    /// Next field if flags & NEXT.
-    next: u64,
+    /// True if an exclusive advisory lock is held on the file during each request which writes to
+    /// the disk, so that the requests of devices sharing the file don't interleave.
+    locking: bool,
 }
 
-impl VirtqDesc {
-    /// Create a new virtqueue descriptor based on the address that stores the content of the
-    /// descriptor.
-    fn new(cpu: &mut Cpu, addr: u64) -> Result<Self, Exception> {
-        Ok(Self {
-            addr: cpu.bus.read(addr, DOUBLEWORD)?,
-            len: cpu.bus.read(addr.wrapping_add(8), WORD)?,
-            flags: cpu.bus.read(addr.wrapping_add(12), HALFWORD)?,
-            next: cpu.bus.read(addr.wrapping_add(14), HALFWORD)?,
-        })
+impl Default for DiskImage {
+    fn default() -> Self {
+        DiskImage::Private(Vec::new())
     }
 }
 
-/// "The driver uses the available ring to offer buffers to the device: each ring entry refers to
-/// the head of a descriptor chain. It is only written by the driver and read by the device."
-///
-/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-380006
-///
-/// ```c
-/// #define VIRTQ_AVAIL_F_NO_INTERRUPT 1
-/// struct virtq_avail {
-///   le16 flags;
-///   le16 idx;
-///   le16 ring[ /* Queue Size */ ];
-///   le16 used_event; /* Only if VIRTIO_F_EVENT_IDX */
-/// };
-/// ```
-struct _VirtqAvail {
-    flags: u16,
-    /// Indicates where the driver would put the next descriptor entry in the ring (modulo the
-    /// queue size). Starts at 0 and increases.
-    idx: u16,
-    ring: Vec<u16>,
-    used_event: u16,
-}
+impl DiskImage {
+    /// Return the size of the image in bytes.
+    fn len(&self) -> u64 {
+        match self {
+            DiskImage::Private(image) => image.len() as u64,
+            DiskImage::Shared(image) => image.len() as u64,
+            #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+            DiskImage::File(disk) => disk.len,
+        }
+    }
 
-/// "The used ring is where the device returns buffers once it is done with them: it is only
-/// written to by the device, and read by the driver."
-///
-/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-430008
-///
-/// ```c
-/// #define VIRTQ_USED_F_NO_NOTIFY 1
-/// struct virtq_used {
-///   le16 flags;
-///   le16 idx;
-///   struct virtq_used_elem ring[ /* Queue Size */];
-///   le16 avail_event; /* Only if VIRTIO_F_EVENT_IDX */
-/// };
-/// ```
-struct _VirtqUsed {
-    flags: u16,
-    /// Indicates where the device would put the next descriptor entry in the ring (modulo the
-    /// queue size). Starts at 0 and increases.
-    idx: u16,
-    ring: Vec<_VirtqUsedElem>,
-    avail_event: u16,
-}
+    /// Copy the bytes at `offset` of the image to `buf`. The range must be in the image. Return
+    /// false if the host fails to read them.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        let range = offset as usize..offset as usize + buf.len();
+        match self {
+            DiskImage::Private(image) => buf.copy_from_slice(&image[range]),
+            DiskImage::Shared(image) => buf.copy_from_slice(&image[range]),
+            #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+            DiskImage::File(disk) => {
+                let result = disk
+                    .file
+                    .seek(SeekFrom::Start(offset))
+                    .and_then(|_| disk.file.read_exact(buf));
+                if let Err(e) = result {
+                    warn!("virtio: failed to read the disk file: {}", e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
 
-/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-430008
-///
-/// ```c
-/// struct virtq_used_elem {
-///   le32 id;
-///   le32 len;
-/// };
-/// ```
-struct _VirtqUsedElem {
-    /// Index of start of used descriptor chain. Indicates the head entry of the descriptor chain
-    /// describing the buffer (this matches an entry placed in the available ring by the guest
-    /// earlier).
-    id: u32,
-    /// Total length of the descriptor chain which was used (written to).
-    len: u32,
+    /// Copy `data` to `offset` of the image. The range must be in the image. Return false if the
+    /// image is read-only or the host fails to write it.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> bool {
+        let range = offset as usize..offset as usize + data.len();
+        match self {
+            DiskImage::Private(image) => image[range].copy_from_slice(data),
+            DiskImage::Shared(_) => {
+                warn!("virtio: the disk is read-only");
+                return false;
+            }
+            #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+            DiskImage::File(disk) => {
+                let result = disk
+                    .file
+                    .seek(SeekFrom::Start(offset))
+                    .and_then(|_| disk.file.write_all(data));
+                if let Err(e) = result {
+                    warn!("virtio: failed to write to the disk file: {}", e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Take the advisory lock of an image file shared with other devices, if locking is enabled,
+    /// before a request writes to the disk. It blocks while another device holds the lock.
+    fn lock(&mut self) {
+        #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+        if let DiskImage::File(disk) = self {
+            if disk.locking {
+                if let Err(e) = disk.file.lock() {
+                    warn!("virtio: failed to lock the disk file: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Release the lock taken by `lock`.
+    fn unlock(&mut self) {
+        #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+        if let DiskImage::File(disk) = self {
+            if disk.locking {
+                if let Err(e) = disk.file.unlock() {
+                    warn!("virtio: failed to unlock the disk file: {}", e);
+                }
+            }
+        }
+    }
 }
 
 /// Paravirtualized drivers for IO virtualization.
@@ -231,6 +278,10 @@ pub struct Virtio {
     queue_align: u32,
     queue_pfn: u32,
     queue_notify: u32,
+    /// The free-running index of the next entry of the available ring to consume.
+    last_avail_idx: u16,
+    /// The free-running index of the next entry of the used ring to fill.
+    used_idx: u16,
     interrupt_status: u32,
     /// "The device status field provides a simple low-level indication of the completed steps of
     /// this sequence.
@@ -238,7 +289,15 @@ pub struct Virtio {
     /// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-100001
     status: u32,
     config: [u8; 8],
-    disk: Vec<u8>,
+    /// Incremented every time the configuration space changes.
+    config_generation: u32,
+    disk: DiskImage,
+    /// The maximum number of descriptors followed in a chain. A longer chain, such as a cyclic
+    /// one built by a buggy or malicious driver, is rejected.
+    max_chain_len: u64,
+    /// The index of the request which fails with `VIRTIO_BLK_S_IOERR` without a transfer, to test
+    /// the error paths of a driver.
+    io_error_at: Option<u64>,
 }
 
 impl Virtio {
@@ -246,7 +305,10 @@ impl Virtio {
     pub fn new() -> Self {
         Self {
             id: 0,
-            device_features: [0; 2],
+            device_features: [
+                (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES),
+                0,
+            ],
             device_features_sel: 0,
             driver_features: [0; 2],
             driver_features_sel: 0,
@@ -256,50 +318,149 @@ impl Virtio {
             queue_align: 0,
             queue_pfn: 0,
             queue_notify: 9999, // TODO: what is the correct initial value?
+            last_avail_idx: 0,
+            used_idx: 0,
             interrupt_status: 0,
             // "The device MUST initialize device status to 0 upon reset."
             // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-120002
             status: 0,
             config: [0; 8],
-            disk: Vec::new(),
+            config_generation: 0,
+            disk: DiskImage::default(),
+            max_chain_len: QUEUE_SIZE,
+            io_error_at: None,
+        }
+    }
+
+    /// Reset the registers as a driver does by writing 0 to the status. The disk and the settings
+    /// for testing, such as `max_chain_len`, are kept.
+    pub fn reset(&mut self) {
+        let disk = core::mem::take(&mut self.disk);
+        *self = Self {
+            disk,
+            max_chain_len: self.max_chain_len,
+            io_error_at: self.io_error_at,
+            ..Self::new()
+        };
+        if let DiskImage::Shared(_) = self.disk {
+            self.device_features[0] |= 1 << VIRTIO_BLK_F_RO;
         }
+        self.update_capacity();
     }
 
-    /// Return true if an interrupt is pending.
+    /// Set the maximum number of descriptors followed in a chain. It defaults to the queue size.
+    pub fn set_max_chain_len(&mut self, len: u64) {
+        self.max_chain_len = len;
+    }
+
+    /// Fail the request at `request_index`, counting from 0, with `VIRTIO_BLK_S_IOERR` instead of
+    /// accessing the disk.
+    pub fn inject_io_error_at(&mut self, request_index: u64) {
+        self.io_error_at = Some(request_index);
+    }
+
+    /// Return true if an interrupt is pending. A notification is dropped until the driver is
+    /// ready.
     pub fn is_interrupting(&mut self) -> bool {
         if self.queue_notify != 9999 {
             self.queue_notify = 9999;
-            return true;
+            return self.is_driver_ok();
         }
         false
     }
 
+    /// Return true if the driver has set up the device. "The device MUST NOT consume buffers or
+    /// send any used buffer notifications to the driver before DRIVER_OK." FEATURES_OK isn't
+    /// checked because the legacy interface doesn't have it.
+    fn is_driver_ok(&self) -> bool {
+        self.status & VIRTIO_STATUS_DRIVER_OK != 0
+    }
+
     /// Set the binary in the virtio disk.
     pub fn initialize(&mut self, binary: Vec<u8>) {
-        self.disk.extend(binary.iter().cloned());
+        match &mut self.disk {
+            DiskImage::Private(disk) => disk.extend(binary.iter().cloned()),
+            _ => self.disk = DiskImage::Private(binary),
+        }
+        self.device_features[0] &= !(1 << VIRTIO_BLK_F_RO);
+        self.update_capacity();
     }
 
-    /// Load `size`-bit data from a register located at `addr` in the virtio block device.
-    pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
-        if size == DOUBLEWORD {
-            return Err(Exception::LoadAccessFault);
+    /// Set the image shared with other devices in the virtio disk without copying it. The disk is
+    /// read-only, so write requests fail.
+    pub fn initialize_shared(&mut self, image: Arc<[u8]>) {
+        self.disk = DiskImage::Shared(image);
+        self.device_features[0] |= 1 << VIRTIO_BLK_F_RO;
+        self.update_capacity();
+    }
+
+    /// Set the image file `file` in the virtio disk. Write requests go to the file, so devices
+    /// which open the same file share the disk. With `locking`, each request which writes to the
+    /// disk holds an exclusive advisory lock on the file, so that the requests of devices which
+    /// lock the file as well don't interleave.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn initialize_file(&mut self, file: File, locking: bool) -> io::Result<()> {
+        let len = file.metadata()?.len();
+        self.disk = DiskImage::File(DiskFile { file, len, locking });
+        self.device_features[0] &= !(1 << VIRTIO_BLK_F_RO);
+        self.update_capacity();
+        Ok(())
+    }
+
+    /// Resize the disk to `len` bytes, truncating it or filling it with zeroes. A shared image
+    /// is copied and stays read-only.
+    pub fn resize(&mut self, len: usize) {
+        match &mut self.disk {
+            DiskImage::Private(disk) => disk.resize(len, 0),
+            DiskImage::Shared(image) => {
+                let mut disk = image.to_vec();
+                disk.resize(len, 0);
+                *image = disk.into();
+            }
+            #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+            DiskImage::File(disk) => match disk.file.set_len(len as u64) {
+                Ok(()) => disk.len = len as u64,
+                Err(e) => warn!("virtio: failed to resize the disk file: {}", e),
+            },
         }
+        self.update_capacity();
+        // "Configuration Change Notification - bit 1 - the interrupt was asserted because the
+        // configuration of the device has changed."
+        self.interrupt_status |= 0x2;
+    }
+
+    /// Return the configuration generation which the driver reads to detect a change of the
+    /// configuration space.
+    pub fn config_generation(&self) -> u32 {
+        self.config_generation
+    }
+
+    /// Set the capacity in the configuration space to the size of the disk.
+    fn update_capacity(&mut self) {
+        let sectors = self.disk.len() / SECTOR_SIZE;
+        self.config = sectors.to_le_bytes();
+        self.config_generation = self.config_generation.wrapping_add(1);
+    }
 
+    /// Load `size`-bit data from a register located at `addr` in the virtio block device.
+    pub fn read(&self, addr: u64, _size: u8) -> Result<u64, Exception> {
         let value = match addr {
             VIRTIO_MAGIC => 0x74726976, // A Little Endian equivalent of the “virt” string.
             VIRTIO_VERSION => 0x1,      // Legacy devices (see 4.2.4 Legacy interface) used 0x1.
             VIRTIO_DEVICE_ID => 0x2,    // Block device.
             // See https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c#L86
             VIRTIO_VENDOR_ID => 0x554d4551,
-            VIRTIO_DEVICE_FEATURES => self.device_features[self.device_features_sel as usize],
+            // Features beyond the implemented words are all 0.
+            VIRTIO_DEVICE_FEATURES => *self
+                .device_features
+                .get(self.device_features_sel as usize)
+                .unwrap_or(&0),
             VIRTIO_QUEUE_NUM_MAX => 8,
             VIRTIO_QUEUE_PFN => self.queue_pfn,
             VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status,
             VIRTIO_STATUS => self.status,
+            VIRTIO_CONFIG_GENERATION => self.config_generation,
             VIRTIO_CONFIG..=VIRTIO_CONFIG_END => {
-                if size != BYTE {
-                    return Err(Exception::LoadAccessFault);
-                }
                 let index = addr - VIRTIO_CONFIG;
                 self.config[index as usize] as u32
             }
@@ -309,40 +470,38 @@ impl Virtio {
     }
 
     /// Store `size`-bit data to a register located at `addr` in the virtio block device.
-    pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
-        if size == DOUBLEWORD {
-            return Err(Exception::StoreAMOAccessFault);
-        }
-
+    pub fn write(&mut self, addr: u64, value: u64, _size: u8) -> Result<(), Exception> {
         match addr {
             VIRTIO_DEVICE_FEATURES_SEL => self.device_features_sel = value as u32,
             VIRTIO_DRIVER_FEATURES => {
-                self.driver_features[self.driver_features_sel as usize] = value as u32
+                // Features beyond the implemented words are ignored.
+                if let Some(features) = self
+                    .driver_features
+                    .get_mut(self.driver_features_sel as usize)
+                {
+                    *features = value as u32;
+                }
             }
             VIRTIO_DRIVER_FEATURES_SEL => self.driver_features_sel = value as u32,
             VIRTIO_GUEST_PAGE_SIZE => self.guest_page_size = value as u32,
             VIRTIO_QUEUE_SEL => self.queue_sel = value as u32,
             VIRTIO_QUEUE_NUM => self.queue_num = value as u32,
             VIRTIO_QUEUE_ALIGN => self.queue_align = value as u32,
-            VIRTIO_QUEUE_PFN => self.queue_pfn = value as u32,
-            VIRTIO_QUEUE_NOTIFY => self.queue_notify = value as u32,
-            VIRTIO_MMIO_INTERRUPT_ACK => {
-                if (value & 0x1) == 1 {
-                    self.interrupt_status &= !0x1;
-                } else {
-                    panic!(
-                        "unexpected value for VIRTIO_MMIO_INTERRUPT_ACK: {:#x}",
-                        value
-                    );
-                }
+            VIRTIO_QUEUE_PFN => {
+                // The driver starts a queue from the beginning of its rings.
+                self.queue_pfn = value as u32;
+                self.last_avail_idx = 0;
+                self.used_idx = 0;
             }
+            VIRTIO_QUEUE_NOTIFY => self.queue_notify = value as u32,
+            // Clear the events acknowledged by the driver.
+            VIRTIO_MMIO_INTERRUPT_ACK => self.interrupt_status &= !(value as u32),
+            // "Writing zero (0x0) to this register triggers a device reset."
+            VIRTIO_STATUS if value == 0 => self.reset(),
             VIRTIO_STATUS => self.status = value as u32,
             VIRTIO_CONFIG..=VIRTIO_CONFIG_END => {
-                if size != BYTE {
-                    return Err(Exception::StoreAMOAccessFault);
-                }
                 let index = addr - VIRTIO_CONFIG;
-                self.config[index as usize] = (value >> (index * 8)) as u8;
+                self.config[index as usize] = value as u8;
             }
             _ => return Err(Exception::StoreAMOAccessFault),
         }
@@ -358,22 +517,251 @@ impl Virtio {
         self.queue_pfn as u64 * self.guest_page_size as u64
     }
 
-    fn read_disk(&self, addr: u64) -> u64 {
-        self.disk[addr as usize] as u64
+    /// Return the address of the used ring.
+    fn used_addr(&self) -> u64 {
+        virtqueue::used_addr(
+            self.desc_addr(),
+            self.queue_num as u64,
+            self.queue_align as u64,
+        )
     }
 
-    fn write_disk(&mut self, addr: u64, value: u64) {
-        self.disk[addr as usize] = value as u8
+    /// Follow the descriptor chain whose head is at `head` in the descriptor table and return the
+    /// descriptors in order. Fail if the chain is longer than `max_chain_len`.
+    fn read_chain(cpu: &mut Cpu, head: u64) -> Result<Vec<VirtqDesc>, Exception> {
+        let desc_addr = cpu.bus.virtio.desc_addr();
+        let max_chain_len = cpu.bus.virtio.max_chain_len;
+        virtqueue::read_chain(&mut cpu.bus, desc_addr, head, max_chain_len)
+    }
+
+    /// Transfer the data buffers of a read or write request between the memory and the disk from
+    /// `sector` directly (DMA), and return the status of the request. A buffer may cover several
+    /// sectors or a part of one, and the next buffer continues where it ends.
+    fn transfer(cpu: &mut Cpu, sector: u64, data: &[VirtqDesc]) -> Result<u64, Exception> {
+        // 5.2.6.1 Driver Requirements: Device Operation
+        // "The length of data MUST be a multiple of 512 bytes for VIRTIO_BLK_T_IN and
+        // VIRTIO_BLK_T_OUT requests."
+        // Otherwise, the last sector would only be partly transferred.
+        let len = data.iter().map(|desc| desc.len).sum::<u64>();
+        if len % SECTOR_SIZE != 0 {
+            warn!(
+                "virtio: {} bytes of data are not a multiple of the sector size",
+                len
+            );
+            return Ok(VIRTIO_BLK_S_IOERR);
+        }
+
+        // The disk is moved out of the device during the transfer so that it can be accessed
+        // along with the memory.
+        let mut start = sector.checked_mul(SECTOR_SIZE);
+        let mut disk = core::mem::take(&mut cpu.bus.virtio.disk);
+        let disk_len = disk.len();
+        let mut result = Ok(VIRTIO_BLK_S_OK);
+        for desc in data {
+            // A guest may request sectors beyond the end of the disk, or even overflow the offset.
+            let (offset, end) =
+                match start.and_then(|start| Some((start, start.checked_add(desc.len)?))) {
+                    Some((start, end)) if end <= disk_len => (start, end),
+                    _ => {
+                        warn!("virtio: sector {} is out of the disk", sector);
+                        result = Ok(VIRTIO_BLK_S_IOERR);
+                        break;
+                    }
+                };
+            // Write to a device if the second bit of `flags` is set.
+            result = match !desc.is_device_writable() {
+                true => {
+                    // Read memory data and write it to a disk directly (DMA).
+                    match cpu.bus.dma_slice(desc.addr, desc.len) {
+                        Some(memory) => match disk.write_at(offset, memory) {
+                            true => Ok(VIRTIO_BLK_S_OK),
+                            false => Ok(VIRTIO_BLK_S_IOERR),
+                        },
+                        None => Err(Exception::LoadAccessFault),
+                    }
+                }
+                false => {
+                    // Read disk data and write it to memory directly (DMA).
+                    match cpu.bus.dma_slice(desc.addr, desc.len) {
+                        Some(memory) => match disk.read_at(offset, memory) {
+                            true => Ok(VIRTIO_BLK_S_OK),
+                            false => Ok(VIRTIO_BLK_S_IOERR),
+                        },
+                        None => Err(Exception::StoreAMOAccessFault),
+                    }
+                }
+            };
+            if !matches!(result, Ok(VIRTIO_BLK_S_OK)) {
+                break;
+            }
+            start = Some(end);
+        }
+        cpu.bus.virtio.disk = disk;
+        result
+    }
+
+    /// Write the device ID string to the data buffers, and return the status of the request.
+    fn write_id(cpu: &mut Cpu, data: &[VirtqDesc]) -> Result<u64, Exception> {
+        let len = data.iter().map(|desc| desc.len).sum::<u64>();
+        if len < VIRTIO_BLK_ID_BYTES || data.iter().any(|desc| !desc.is_device_writable()) {
+            warn!("virtio: the buffers for the device ID are too small or read-only");
+            return Ok(VIRTIO_BLK_S_IOERR);
+        }
+        // A shorter ID is padded with NUL bytes.
+        let mut id = [0; VIRTIO_BLK_ID_BYTES as usize];
+        id[..VIRTIO_BLK_ID.len()].copy_from_slice(VIRTIO_BLK_ID);
+        let mut rest = &id[..];
+        for desc in data {
+            let (chunk, next) = rest.split_at(rest.len().min(desc.len as usize));
+            for (i, &byte) in chunk.iter().enumerate() {
+                cpu.bus
+                    .write(desc.addr.wrapping_add(i as u64), byte as u64, BYTE)?;
+            }
+            rest = next;
+        }
+        Ok(VIRTIO_BLK_S_OK)
+    }
+
+    /// Zero the sectors described by the `virtio_blk_discard_write_zeroes` segments in the data
+    /// buffers, and return the status of the request. Discarded sectors are zeroed as well.
+    ///
+    /// 5.2.6 Device Operation
+    /// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2500006
+    ///
+    /// ```c
+    /// struct virtio_blk_discard_write_zeroes {
+    ///   le64 sector;
+    ///   le32 num_sectors;
+    ///   struct {
+    ///     le32 unmap:1;
+    ///     le32 reserved:31;
+    ///   } flags;
+    /// };
+    /// ```
+    fn zero_sectors(cpu: &mut Cpu, data: &[VirtqDesc]) -> Result<u64, Exception> {
+        for desc in data {
+            for i in 0..desc.len / DISCARD_WRITE_ZEROES_SIZE {
+                let segment = desc.addr.wrapping_add(i * DISCARD_WRITE_ZEROES_SIZE);
+                let sector = cpu.bus.read(segment, DOUBLEWORD)?;
+                let num_sectors = cpu.bus.read(segment.wrapping_add(8), WORD)?;
+
+                let disk = &mut cpu.bus.virtio.disk;
+                let start = sector.checked_mul(SECTOR_SIZE);
+                let end = sector
+                    .checked_add(num_sectors)
+                    .and_then(|end| end.checked_mul(SECTOR_SIZE));
+                match start.zip(end).filter(|&(_, end)| end <= disk.len()) {
+                    Some((start, end)) => {
+                        // Zero a sector at a time, so that a large range doesn't need a large
+                        // buffer.
+                        let zeroes = vec![0; SECTOR_SIZE as usize];
+                        let mut offset = start;
+                        while offset < end {
+                            if !disk.write_at(offset, &zeroes) {
+                                return Ok(VIRTIO_BLK_S_IOERR);
+                            }
+                            offset += SECTOR_SIZE;
+                        }
+                    }
+                    None => {
+                        warn!(
+                            "virtio: {} sectors from sector {} are out of the disk",
+                            num_sectors, sector
+                        );
+                        return Ok(VIRTIO_BLK_S_IOERR);
+                    }
+                }
+            }
+        }
+        Ok(VIRTIO_BLK_S_OK)
+    }
+
+    /// Serve the request whose descriptor chain starts at `head`, write its status to the last
+    /// descriptor, and return the number of bytes written to the chain.
+    fn serve_request(cpu: &mut Cpu, head: u64) -> Result<u64, Exception> {
+        // xv6 chains 3 descriptors: the request header, the data, and the status. The data may
+        // span several descriptors.
+        let chain = Virtio::read_chain(cpu, head)?;
+        if chain.len() < 3 {
+            warn!(
+                "virtio: a request needs at least 3 descriptors: {}",
+                chain.len()
+            );
+            return Err(Exception::LoadAccessFault);
+        }
+        let header = &chain[0];
+        let status = &chain[chain.len() - 1];
+
+        // 5.2.6 Device Operation
+        // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2500006
+        // struct virtio_blk_req {
+        //   le32 type;
+        //   le32 reserved;
+        //   le64 sector;
+        //   u8 data[][512];
+        //   u8 status;
+        // };
+        let req_type = cpu.bus.read(header.addr, WORD)?;
+        let sector = cpu.bus.read(header.addr.wrapping_add(8), DOUBLEWORD)?;
+
+        let data = &chain[1..chain.len() - 1];
+        // `id` is the number of the requests completed so far.
+        let result = if cpu.bus.virtio.io_error_at == Some(cpu.bus.virtio.id) {
+            warn!(
+                "virtio: inject an I/O error into request {}",
+                cpu.bus.virtio.id
+            );
+            VIRTIO_BLK_S_IOERR
+        } else if cpu.bus.virtio.disk.len() == 0 {
+            // Every request fails rather than only those which reach beyond the end, since a
+            // missing image is more likely a mistake of the host than of the driver.
+            warn!("virtio: no disk image is attached");
+            VIRTIO_BLK_S_IOERR
+        } else {
+            // A request which writes to a shared image file holds its lock, so that the request
+            // isn't interleaved with those of other devices.
+            let writes = matches!(
+                req_type,
+                VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES
+            );
+            if writes {
+                cpu.bus.virtio.disk.lock();
+            }
+            let result = match req_type {
+                VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => Virtio::transfer(cpu, sector, data),
+                VIRTIO_BLK_T_GET_ID => Virtio::write_id(cpu, data),
+                VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => Virtio::zero_sectors(cpu, data),
+                _ => {
+                    warn!("virtio: request type {} isn't supported", req_type);
+                    Ok(VIRTIO_BLK_S_UNSUPP)
+                }
+            };
+            if writes {
+                cpu.bus.virtio.disk.unlock();
+            }
+            result?
+        };
+        cpu.bus.write(status.addr, result, BYTE)?;
+
+        // The device-writable data is only filled by a successful request.
+        let written = match result {
+            VIRTIO_BLK_S_OK => data
+                .iter()
+                .filter(|desc| desc.is_device_writable())
+                .map(|desc| desc.len)
+                .sum::<u64>(),
+            _ => 0,
+        };
+        Ok(written + 1)
     }
 
     /// Access the disk via virtio. This is an associated function which takes a `cpu` object to
     /// read and write with a memory directly (DMA).
     pub fn disk_access(cpu: &mut Cpu) -> Result<(), Exception> {
-        // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1460002
-        // "Used Buffer Notification
-        //     - bit 0 - the interrupt was asserted because the device has used a buffer in at
-        //     least one of the active virtual queues."
-        cpu.bus.virtio.interrupt_status |= 0x1;
+        if !cpu.bus.virtio.is_driver_ok() {
+            warn!("virtio: the queue is notified before the driver is ready");
+            return Ok(());
+        }
 
         // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-230005
         // "Each virtqueue can consist of up to 3 parts:
@@ -381,17 +769,24 @@ impl Virtio {
         //     Driver Area - extra data supplied by driver to the device
         //     Device Area - extra data supplied by device to driver"
         //
-        // https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c#L101-L103
-        //     desc = pages -- num * VirtqDesc
-        //     avail = pages + 0x40 -- 2 * uint16, then num * uint16
-        //     used = pages + 4096 -- 2 * uint16, then num * vRingUsedElem
+        // 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
+        // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-260002
+        // The available ring follows the descriptor table of `num` 16-byte entries, and the used
+        // ring starts at the next `QueueAlign` boundary as `used_addr` computes. A driver which
+        // hasn't written QueueNum gets the default size.
         //
-        // The actual descriptors (16 bytes each).
-        let desc_addr = cpu.bus.virtio.desc_addr();
+        // The actual descriptors (16 bytes each) are followed by `read_chain`.
+        //
+        // All the addresses and the offsets come from the guest, so the arithmetic on them wraps
+        // or is checked rather than overflows.
+        let num = match cpu.bus.virtio.queue_num {
+            0 => QUEUE_SIZE,
+            num => num as u64,
+        };
         // A ring of available descriptor heads with free-running index.
-        let avail_addr = cpu.bus.virtio.desc_addr() + 0x40;
+        let avail_addr = virtqueue::avail_addr(cpu.bus.virtio.desc_addr(), num);
         // A ring of used descriptor heads with free-running index.
-        let used_addr = cpu.bus.virtio.desc_addr() + 4096;
+        let used_addr = cpu.bus.virtio.used_addr();
 
         // 2.6.6 The Virtqueue Available Ring
         // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-380006
@@ -403,59 +798,54 @@ impl Virtio {
         //   le16 used_event; /* Only if VIRTIO_F_EVENT_IDX */
         // };
         //
-        // https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c#L230-L234
-        // "avail[0] is flags
-        //  avail[1] tells the device how far to look in avail[2...].
-        //  avail[2...] are desc[] indices the device should process.
-        //  we only tell device the first index in our chain of descriptors."
-        let offset = cpu.bus.read(avail_addr.wrapping_add(1), HALFWORD)?;
-        let index = cpu.bus.read(
-            avail_addr.wrapping_add(offset % QUEUE_SIZE).wrapping_add(2),
-            HALFWORD,
-        )?;
-
-        // First descriptor.
-        let desc0 = VirtqDesc::new(cpu, desc_addr + VRING_DESC_SIZE * index)?;
-
-        // Second descriptor.
-        let desc1 = VirtqDesc::new(cpu, desc_addr + VRING_DESC_SIZE * desc0.next)?;
-
-        // Third descriptor address.
-        let desc2_addr = cpu
-            .bus
-            .read(desc_addr + VRING_DESC_SIZE * desc1.next, DOUBLEWORD)?;
-        // Tell success.
-        cpu.bus.write(desc2_addr, 0, BYTE)?;
+        // The fields are at the byte offsets 0, 2, and 4. The driver has made the entries from
+        // `last_avail_idx` up to `idx` available since the device consumed the last one, and
+        // there is nothing to do if they're equal.
+        let avail_idx = virtqueue::read_avail_idx(&mut cpu.bus, avail_addr)?;
+        if avail_idx == cpu.bus.virtio.last_avail_idx {
+            return Ok(());
+        }
 
-        // 5.2.6 Device Operation
-        // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2500006
-        // struct virtio_blk_req {
-        //   le32 type;
-        //   le32 reserved;
-        //   le64 sector;
-        //   u8 data[][512];
-        //   u8 status;
-        // };
-        let sector = cpu.bus.read(desc0.addr.wrapping_add(8), DOUBLEWORD)?;
-
-        // Write to a device if the second bit of `flags` is set.
-        match (desc1.flags & 2) == 0 {
-            true => {
-                // Read memory data and write it to a disk directly (DMA).
-                for i in 0..desc1.len {
-                    let data = cpu.bus.read(desc1.addr + i, BYTE)?;
-                    cpu.bus.virtio.write_disk(sector * SECTOR_SIZE + i, data);
-                }
-            }
-            false => {
-                // Read disk data and write it to memory directly (DMA).
-                for i in 0..desc1.len {
-                    let data = cpu.bus.virtio.read_disk(sector * SECTOR_SIZE + i);
-                    cpu.bus.write(desc1.addr + i, data, BYTE)?;
+        // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1460002
+        // "Used Buffer Notification
+        //     - bit 0 - the interrupt was asserted because the device has used a buffer in at
+        //     least one of the active virtual queues."
+        cpu.bus.virtio.interrupt_status |= 0x1;
+
+        while cpu.bus.virtio.last_avail_idx != avail_idx {
+            let last_avail_idx = cpu.bus.virtio.last_avail_idx;
+            let head = virtqueue::read_avail_head(&mut cpu.bus, avail_addr, num, last_avail_idx)?;
+            cpu.bus.virtio.last_avail_idx = last_avail_idx.wrapping_add(1);
+            let written = match Virtio::serve_request(cpu, head) {
+                Ok(written) => written,
+                Err(exception) => {
+                    // The entry has been consumed, so the chain is returned to the driver as a
+                    // failed request rather than lost. The status, which is the last of at least
+                    // 3 descriptors, reports the error if it can be read and it's device-writable.
+                    if let Ok(chain) = Virtio::read_chain(cpu, head) {
+                        let status = chain.last().filter(|desc| desc.is_device_writable());
+                        if let Some(status) = status.filter(|_| chain.len() >= 3) {
+                            let _ = cpu.bus.write(status.addr, VIRTIO_BLK_S_IOERR, BYTE);
+                        }
+                    }
+                    Virtio::push_used(cpu, used_addr, num, head, 0)?;
+                    return Err(exception);
                 }
-            }
-        };
+            };
+            Virtio::push_used(cpu, used_addr, num, head, written)?;
+        }
+        Ok(())
+    }
 
+    /// Return the chain from `head` to the driver through the used ring with `written` bytes,
+    /// which completes a request.
+    fn push_used(
+        cpu: &mut Cpu,
+        used_addr: u64,
+        num: u64,
+        head: u64,
+        written: u64,
+    ) -> Result<(), Exception> {
         // 2.6.8 The Virtqueue Used Ring
         // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-430008
         // struct virtq_used {
@@ -465,9 +855,13 @@ impl Virtio {
         //   struct virtq_used_elem ring[ /* Queue Size */];
         //   le16 avail_event; /* Only if VIRTIO_F_EVENT_IDX */
         // };
-        let new_id = cpu.bus.virtio.get_new_id();
-        cpu.bus
-            .write(used_addr.wrapping_add(2), new_id % QUEUE_SIZE, HALFWORD)?;
+        // "idx field indicates where the device would put the next descriptor entry in the ring
+        // (modulo the queue size)." It's a free-running counter which wraps at 65536, and the
+        // driver takes it modulo the queue size by itself.
+        cpu.bus.virtio.get_new_id();
+        let used_idx = cpu.bus.virtio.used_idx;
+        virtqueue::push_used(&mut cpu.bus, used_addr, num, used_idx, head, written)?;
+        cpu.bus.virtio.used_idx = used_idx.wrapping_add(1);
         Ok(())
     }
 }
@@ -0,0 +1,408 @@
+//! The virtio_console module implements a virtio console device. It has a single port with the
+//! receive queue 0 and the transmit queue 1, and no optional features. The bytes a guest
+//! transmits go to a serial backend, and the bytes pushed by the host fill the buffers of the
+//! receive queue.
+//!
+//! 5.3 Console Device:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2550003
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use log::warn;
+
+use crate::bus::{Bus, VIRTIO_CONSOLE_BASE};
+use crate::cpu::{BYTE, HALFWORD, WORD};
+use crate::devices::mmio::{is_width_allowed, WidthRule};
+#[cfg(not(feature = "std"))]
+use crate::devices::serial::BufferBackend;
+use crate::devices::serial::SerialBackend;
+#[cfg(feature = "std")]
+use crate::devices::serial::StdoutBackend;
+use crate::devices::virtqueue::{self, VirtqDesc};
+use crate::exception::Exception;
+
+/// The size of the register region.
+pub const VIRTIO_CONSOLE_SIZE: u64 = 0x1000;
+
+/// The maximum number of entries of each queue.
+const QUEUE_NUM_MAX: u32 = 8;
+
+/// The queue which carries bytes from the host to the guest.
+const RECEIVEQ: usize = 0;
+/// The queue which carries bytes from the guest to the host.
+const TRANSMITQ: usize = 1;
+
+// 2.1 Device Status Field
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-100001
+/// The driver is set up and ready to drive the device.
+const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+
+// 4.2.2 MMIO Device Register Layout
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1460002
+// The registers are the same as the ones of the block device. See `virtio_blk` for the details.
+const VIRTIO_MAGIC: u64 = VIRTIO_CONSOLE_BASE;
+const VIRTIO_VERSION: u64 = VIRTIO_CONSOLE_BASE + 0x004;
+const VIRTIO_DEVICE_ID: u64 = VIRTIO_CONSOLE_BASE + 0x008;
+const VIRTIO_VENDOR_ID: u64 = VIRTIO_CONSOLE_BASE + 0x00c;
+const VIRTIO_DEVICE_FEATURES: u64 = VIRTIO_CONSOLE_BASE + 0x010;
+const VIRTIO_DEVICE_FEATURES_SEL: u64 = VIRTIO_CONSOLE_BASE + 0x014;
+const VIRTIO_DRIVER_FEATURES: u64 = VIRTIO_CONSOLE_BASE + 0x020;
+const VIRTIO_DRIVER_FEATURES_SEL: u64 = VIRTIO_CONSOLE_BASE + 0x024;
+const VIRTIO_GUEST_PAGE_SIZE: u64 = VIRTIO_CONSOLE_BASE + 0x028;
+const VIRTIO_QUEUE_SEL: u64 = VIRTIO_CONSOLE_BASE + 0x030;
+const VIRTIO_QUEUE_NUM_MAX: u64 = VIRTIO_CONSOLE_BASE + 0x034;
+const VIRTIO_QUEUE_NUM: u64 = VIRTIO_CONSOLE_BASE + 0x038;
+const VIRTIO_QUEUE_ALIGN: u64 = VIRTIO_CONSOLE_BASE + 0x03c;
+const VIRTIO_QUEUE_PFN: u64 = VIRTIO_CONSOLE_BASE + 0x040;
+const VIRTIO_QUEUE_NOTIFY: u64 = VIRTIO_CONSOLE_BASE + 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = VIRTIO_CONSOLE_BASE + 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: u64 = VIRTIO_CONSOLE_BASE + 0x064;
+const VIRTIO_STATUS: u64 = VIRTIO_CONSOLE_BASE + 0x070;
+const VIRTIO_CONFIG_GENERATION: u64 = VIRTIO_CONSOLE_BASE + 0x0fc;
+/// Configuration space. `cols`, `rows`, `max_nr_ports`, and `emerg_wr` of the console are only
+/// valid with features which this device doesn't offer, so they read as 0.
+const VIRTIO_CONFIG: u64 = VIRTIO_CONSOLE_BASE + 0x100;
+const VIRTIO_CONFIG_END: u64 = VIRTIO_CONFIG + 0xb;
+
+/// The access widths of the registers. The configuration space only allows byte accesses, and
+/// the other registers allow any access up to 32 bits. The device checks them by itself since
+/// it's optional.
+const VIRTIO_CONSOLE_ACCESS_WIDTHS: &[WidthRule] = &[
+    WidthRule {
+        start: VIRTIO_CONFIG,
+        end: VIRTIO_CONFIG_END,
+        sizes: &[BYTE],
+    },
+    WidthRule {
+        start: VIRTIO_CONSOLE_BASE,
+        end: VIRTIO_CONSOLE_BASE + VIRTIO_CONSOLE_SIZE - 1,
+        sizes: &[BYTE, HALFWORD, WORD],
+    },
+];
+
+/// The registers of a virtqueue and the position of the device in its rings.
+#[derive(Default)]
+struct Queue {
+    num: u32,
+    align: u32,
+    pfn: u32,
+    /// The free-running index of the next entry of the available ring to consume.
+    last_avail_idx: u16,
+    /// The free-running index of the next entry of the used ring to fill.
+    used_idx: u16,
+}
+
+/// The virtio console device.
+pub struct VirtioConsole {
+    guest_page_size: u32,
+    queue_sel: u32,
+    queues: [Queue; 2],
+    /// The queues which have buffers to process, a bit for each. A queue is marked when the
+    /// driver notifies it, and the receive queue also when the host pushes input.
+    pending: u32,
+    interrupt_status: u32,
+    status: u32,
+    /// The bytes from the host which haven't been delivered to the guest yet.
+    input: VecDeque<u8>,
+    backend: Box<dyn SerialBackend>,
+}
+
+impl VirtioConsole {
+    /// Create a new virtio console object. It writes to the standard output until its backend is
+    /// replaced.
+    pub fn new() -> Self {
+        Self {
+            guest_page_size: 0,
+            queue_sel: 0,
+            queues: Default::default(),
+            pending: 0,
+            interrupt_status: 0,
+            status: 0,
+            input: VecDeque::new(),
+            #[cfg(feature = "std")]
+            backend: Box::new(StdoutBackend),
+            #[cfg(not(feature = "std"))]
+            backend: Box::new(BufferBackend::new()),
+        }
+    }
+
+    /// Reset the registers and drop the bytes from the host which haven't been delivered yet. The
+    /// backend is kept.
+    pub fn reset(&mut self) {
+        let mut console = Self::new();
+        core::mem::swap(&mut console.backend, &mut self.backend);
+        *self = console;
+    }
+
+    /// Return true if `addr` is in the registers of the device.
+    pub fn contains(addr: u64) -> bool {
+        (VIRTIO_CONSOLE_BASE..VIRTIO_CONSOLE_BASE + VIRTIO_CONSOLE_SIZE).contains(&addr)
+    }
+
+    /// Replace the backend which receives the bytes transmitted by the guest.
+    pub fn set_backend(&mut self, backend: Box<dyn SerialBackend>) {
+        self.backend = backend;
+    }
+
+    /// Queue bytes to deliver to the guest through the receive queue.
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+        self.pending |= 1 << RECEIVEQ;
+    }
+
+    /// Return true if the driver is ready and a queue has buffers to process.
+    pub fn has_pending_queue(&self) -> bool {
+        self.pending != 0 && self.status & VIRTIO_STATUS_DRIVER_OK != 0
+    }
+
+    /// Process the buffers of the pending queues, and return true if the device used any of
+    /// them, after which it raises an interrupt. This is an associated function which takes a
+    /// `bus` to read and write the memory directly (DMA), so the device is moved out of the bus
+    /// while processing.
+    pub fn process_queues(bus: &mut Bus) -> Result<bool, Exception> {
+        let mut console = match bus.virtio_console.take() {
+            Some(console) => console,
+            None => return Ok(false),
+        };
+        let result = console.process(bus);
+        bus.virtio_console = Some(console);
+        result
+    }
+
+    /// Process the buffers of the pending queues, and return true if the device used any of them.
+    fn process(&mut self, bus: &mut Bus) -> Result<bool, Exception> {
+        let pending = core::mem::take(&mut self.pending);
+        let mut used = false;
+        if pending & (1 << TRANSMITQ) != 0 {
+            used |= self.transmit(bus)?;
+        }
+        // Buffers which run out before the input does are retried once the driver adds more and
+        // notifies the queue.
+        if pending & (1 << RECEIVEQ) != 0 && !self.input.is_empty() {
+            used |= self.receive(bus)?;
+        }
+        if used {
+            // "Used Buffer Notification - bit 0 - the interrupt was asserted because the device
+            // has used a buffer in at least one of the active virtual queues."
+            self.interrupt_status |= 0x1;
+        }
+        Ok(used)
+    }
+
+    /// Write the device-readable buffers of every available chain in the transmit queue to the
+    /// backend.
+    fn transmit(&mut self, bus: &mut Bus) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some((head, chain)) = self.pop_chain(bus, TRANSMITQ)? {
+            let mut result = Ok(());
+            for desc in chain.iter().filter(|desc| !desc.is_device_writable()) {
+                let bytes = match bus.dma_slice(desc.addr, desc.len) {
+                    Some(bytes) => bytes,
+                    None => {
+                        result = Err(Exception::LoadAccessFault);
+                        break;
+                    }
+                };
+                for &byte in bytes.iter() {
+                    self.backend.write(byte);
+                }
+            }
+            // The chain is returned to the driver even if a buffer is out of the memory, since
+            // its entry has been consumed.
+            self.push_used(bus, TRANSMITQ, head, 0)?;
+            result?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Fill the device-writable buffers of the available chains in the receive queue with the
+    /// input until it runs out.
+    fn receive(&mut self, bus: &mut Bus) -> Result<bool, Exception> {
+        let mut used = false;
+        while !self.input.is_empty() {
+            let (head, chain) = match self.pop_chain(bus, RECEIVEQ)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let mut written = 0;
+            let mut result = Ok(());
+            for desc in chain.iter().filter(|desc| desc.is_device_writable()) {
+                let buffer = match bus.dma_slice(desc.addr, desc.len) {
+                    Some(buffer) => buffer,
+                    None => {
+                        result = Err(Exception::StoreAMOAccessFault);
+                        break;
+                    }
+                };
+                let len = buffer.len().min(self.input.len());
+                for (slot, byte) in buffer.iter_mut().zip(self.input.drain(..len)) {
+                    *slot = byte;
+                }
+                written += len as u64;
+            }
+            // The chain is returned with the bytes written so far even if a buffer is out of the
+            // memory, since its entry has been consumed.
+            self.push_used(bus, RECEIVEQ, head, written)?;
+            result?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Return the descriptor table address of `queue`.
+    fn desc_addr(&self, queue: usize) -> u64 {
+        self.queues[queue].pfn as u64 * self.guest_page_size as u64
+    }
+
+    /// Take the next chain which the driver made available in `queue`, and return its head and
+    /// its descriptors.
+    fn pop_chain(
+        &mut self,
+        bus: &mut Bus,
+        queue: usize,
+    ) -> Result<Option<(u64, Vec<VirtqDesc>)>, Exception> {
+        let desc_addr = self.desc_addr(queue);
+        let q = &mut self.queues[queue];
+        if q.pfn == 0 || q.num == 0 {
+            return Ok(None);
+        }
+        let num = q.num as u64;
+        let avail_addr = virtqueue::avail_addr(desc_addr, num);
+        if virtqueue::read_avail_idx(bus, avail_addr)? == q.last_avail_idx {
+            return Ok(None);
+        }
+        let head = virtqueue::read_avail_head(bus, avail_addr, num, q.last_avail_idx)?;
+        q.last_avail_idx = q.last_avail_idx.wrapping_add(1);
+        // A head out of the table names no chain, so there's nothing to return to the driver.
+        if head >= num {
+            warn!(
+                "virtio: the descriptor head {} is out of the table of {} entries",
+                head, num
+            );
+            return Err(Exception::LoadAccessFault);
+        }
+        // A chain can't have more descriptors than the table without a cycle. A chain which can't
+        // be read is returned to the driver unused rather than lost, since its entry has been
+        // consumed.
+        match virtqueue::read_chain(bus, desc_addr, head, num) {
+            Ok(chain) => Ok(Some((head, chain))),
+            Err(exception) => {
+                self.push_used(bus, queue, head, 0)?;
+                Err(exception)
+            }
+        }
+    }
+
+    /// Return the chain from `head` to the driver through the used ring of `queue`.
+    fn push_used(
+        &mut self,
+        bus: &mut Bus,
+        queue: usize,
+        head: u64,
+        len: u64,
+    ) -> Result<(), Exception> {
+        let desc_addr = self.desc_addr(queue);
+        let q = &mut self.queues[queue];
+        let num = q.num as u64;
+        let used_addr = virtqueue::used_addr(desc_addr, num, q.align as u64);
+        virtqueue::push_used(bus, used_addr, num, q.used_idx, head, len)?;
+        q.used_idx = q.used_idx.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Return the queue selected by `QueueSel`, if it exists.
+    fn selected_queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Load `size`-bit data from a register located at `addr` in the virtio console device.
+    pub fn read(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
+        if !is_width_allowed(VIRTIO_CONSOLE_ACCESS_WIDTHS, addr, size) {
+            return Err(Exception::LoadAccessFault);
+        }
+        let value = match addr {
+            VIRTIO_MAGIC => 0x74726976, // A Little Endian equivalent of the “virt” string.
+            VIRTIO_VERSION => 0x1,      // Legacy devices (see 4.2.4 Legacy interface) used 0x1.
+            VIRTIO_DEVICE_ID => 0x3,    // Console.
+            VIRTIO_VENDOR_ID => 0x554d4551,
+            // No features are offered.
+            VIRTIO_DEVICE_FEATURES => 0,
+            // A queue which doesn't exist reads as unavailable.
+            VIRTIO_QUEUE_NUM_MAX => match self.selected_queue() {
+                Some(_) => QUEUE_NUM_MAX,
+                None => 0,
+            },
+            VIRTIO_QUEUE_PFN => self.selected_queue().map_or(0, |queue| queue.pfn),
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status,
+            VIRTIO_STATUS => self.status,
+            VIRTIO_CONFIG_GENERATION => 0,
+            VIRTIO_CONFIG..=VIRTIO_CONFIG_END => 0,
+            _ => return Err(Exception::LoadAccessFault),
+        };
+        Ok(value as u64)
+    }
+
+    /// Store `size`-bit data to a register located at `addr` in the virtio console device.
+    pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
+        if !is_width_allowed(VIRTIO_CONSOLE_ACCESS_WIDTHS, addr, size) {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+        let value = value as u32;
+        match addr {
+            // No features are offered, so the driver can't activate any.
+            VIRTIO_DEVICE_FEATURES_SEL | VIRTIO_DRIVER_FEATURES | VIRTIO_DRIVER_FEATURES_SEL => {}
+            VIRTIO_GUEST_PAGE_SIZE => self.guest_page_size = value,
+            VIRTIO_QUEUE_SEL => self.queue_sel = value,
+            // The registers of a queue which doesn't exist are ignored.
+            VIRTIO_QUEUE_NUM => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.num = value.min(QUEUE_NUM_MAX);
+                }
+            }
+            VIRTIO_QUEUE_ALIGN => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.align = value;
+                }
+            }
+            VIRTIO_QUEUE_PFN => {
+                if let Some(queue) = self.selected_queue() {
+                    // The driver starts a queue from the beginning of its rings.
+                    queue.pfn = value;
+                    queue.last_avail_idx = 0;
+                    queue.used_idx = 0;
+                }
+            }
+            VIRTIO_QUEUE_NOTIFY => {
+                if (value as usize) < self.queues.len() {
+                    self.pending |= 1 << value;
+                }
+            }
+            // Clear the events acknowledged by the driver.
+            VIRTIO_MMIO_INTERRUPT_ACK => self.interrupt_status &= !value,
+            // "Writing zero (0x0) to this register triggers a device reset."
+            // The bytes from the host are kept for the driver which sets up the device again.
+            VIRTIO_STATUS if value == 0 => {
+                let input = core::mem::take(&mut self.input);
+                self.reset();
+                self.input = input;
+                if !self.input.is_empty() {
+                    self.pending |= 1 << RECEIVEQ;
+                }
+            }
+            VIRTIO_STATUS => self.status = value,
+            // The configuration space is read-only without `VIRTIO_CONSOLE_F_EMERG_WRITE`.
+            VIRTIO_CONFIG..=VIRTIO_CONFIG_END => {}
+            _ => return Err(Exception::StoreAMOAccessFault),
+        }
+        Ok(())
+    }
+}
+
+impl Default for VirtioConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,253 @@
+//! The virtqueue module contains the parts of the split virtqueue shared by the virtio devices:
+//! the layout of a queue in the legacy interface and the descriptor chains in it.
+//!
+//! 2.6 Split Virtqueues
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-240006
+
+use alloc::vec::Vec;
+
+use log::warn;
+
+use crate::bus::Bus;
+use crate::cpu::{DOUBLEWORD, HALFWORD, WORD};
+use crate::exception::Exception;
+
+/// The size of `VRingDesc` struct.
+pub const VRING_DESC_SIZE: u64 = 16;
+/// The alignment of the used ring if the driver doesn't write `QueueAlign`, which is the page
+/// size of legacy drivers.
+pub const DEFAULT_QUEUE_ALIGN: u64 = 4096;
+
+/// The descriptor continues via the `next` field.
+pub const VIRTQ_DESC_F_NEXT: u64 = 1;
+/// The buffer is device write-only (otherwise device read-only).
+pub const VIRTQ_DESC_F_WRITE: u64 = 2;
+
+/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-250001
+///
+/// ```c
+/// struct virtq {
+///   struct virtq_desc desc[ Queue Size ];
+///   struct virtq_avail avail;
+///   u8 pad[ Padding ]; // Padding to the next Queue Align boundary.
+///   struct virtq_used used;
+/// };
+/// ```
+struct _Virtq {
+    /// The actual descriptors (16 bytes each)
+    /// The number of descriptors in the table is defined by the queue size for this virtqueue.
+    desc: Vec<VirtqDesc>,
+    /// A ring of available descriptor heads with free-running index.
+    avail: _VirtqAvail,
+    /// A ring of used descriptor heads with free-running index.
+    used: _VirtqUsed,
+}
+
+/// "The descriptor table refers to the buffers the driver is using for the device. addr is a
+/// physical address, and the buffers can be chained via next. Each descriptor describes a buffer
+/// which is read-only for the device (“device-readable”) or write-only for the device
+/// (“device-writable”), but a chain of descriptors can contain both device-readable and
+/// device-writable buffers."
+///
+/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-320005
+///
+/// ```c
+/// /* This marks a buffer as continuing via the next field. */
+/// #define VIRTQ_DESC_F_NEXT 1
+/// /* This marks a buffer as device write-only (otherwise device read-only). */
+/// #define VIRTQ_DESC_F_WRITE 2
+/// /* This means the buffer contains a list of buffer descriptors. */
+/// #define VIRTQ_DESC_F_INDIRECT 4
+///
+/// struct virtq_desc {
+///   le64 addr;
+///   le32 len;
+///   le16 flags;
+///   le16 next;
+/// };
+/// ```
+pub struct VirtqDesc {
+    /// Address (guest-physical).
+    pub addr: u64,
+    /// Length.
+    pub len: u64,
+    /// The flags as indicated VIRTQ_DESC_F_NEXT/VIRTQ_DESC_F_WRITE/VIRTQ_DESC_F_INDIRECT.
+    pub flags: u64,
+    /// Next field if flags & NEXT.
+    pub next: u64,
+}
+
+impl VirtqDesc {
+    /// Create a new virtqueue descriptor based on the address that stores the content of the
+    /// descriptor.
+    pub fn new(bus: &mut Bus, addr: u64) -> Result<Self, Exception> {
+        Ok(Self {
+            addr: bus.read(addr, DOUBLEWORD)?,
+            len: bus.read(addr.wrapping_add(8), WORD)?,
+            flags: bus.read(addr.wrapping_add(12), HALFWORD)?,
+            next: bus.read(addr.wrapping_add(14), HALFWORD)?,
+        })
+    }
+
+    /// Return true if the buffer is written by the device, otherwise it's read by the device.
+    pub fn is_device_writable(&self) -> bool {
+        (self.flags & VIRTQ_DESC_F_WRITE) != 0
+    }
+}
+
+/// "The driver uses the available ring to offer buffers to the device: each ring entry refers to
+/// the head of a descriptor chain. It is only written by the driver and read by the device."
+///
+/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-380006
+///
+/// ```c
+/// #define VIRTQ_AVAIL_F_NO_INTERRUPT 1
+/// struct virtq_avail {
+///   le16 flags;
+///   le16 idx;
+///   le16 ring[ /* Queue Size */ ];
+///   le16 used_event; /* Only if VIRTIO_F_EVENT_IDX */
+/// };
+/// ```
+struct _VirtqAvail {
+    flags: u16,
+    /// Indicates where the driver would put the next descriptor entry in the ring (modulo the
+    /// queue size). Starts at 0 and increases.
+    idx: u16,
+    ring: Vec<u16>,
+    used_event: u16,
+}
+
+/// "The used ring is where the device returns buffers once it is done with them: it is only
+/// written to by the device, and read by the driver."
+///
+/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-430008
+///
+/// ```c
+/// #define VIRTQ_USED_F_NO_NOTIFY 1
+/// struct virtq_used {
+///   le16 flags;
+///   le16 idx;
+///   struct virtq_used_elem ring[ /* Queue Size */];
+///   le16 avail_event; /* Only if VIRTIO_F_EVENT_IDX */
+/// };
+/// ```
+struct _VirtqUsed {
+    flags: u16,
+    /// Indicates where the device would put the next descriptor entry in the ring (modulo the
+    /// queue size). Starts at 0 and increases.
+    idx: u16,
+    ring: Vec<_VirtqUsedElem>,
+    avail_event: u16,
+}
+
+/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-430008
+///
+/// ```c
+/// struct virtq_used_elem {
+///   le32 id;
+///   le32 len;
+/// };
+/// ```
+struct _VirtqUsedElem {
+    /// Index of start of used descriptor chain. Indicates the head entry of the descriptor chain
+    /// describing the buffer (this matches an entry placed in the available ring by the guest
+    /// earlier).
+    id: u32,
+    /// Total length of the descriptor chain which was used (written to).
+    len: u32,
+}
+
+/// Return the address of the available ring of the queue whose descriptor table of `num` entries
+/// is at `desc_addr`. It immediately follows the descriptor table.
+pub fn avail_addr(desc_addr: u64, num: u64) -> u64 {
+    desc_addr.wrapping_add(VRING_DESC_SIZE.wrapping_mul(num))
+}
+
+/// Return the address of the used ring of the queue whose descriptor table of `num` entries is at
+/// `desc_addr`. In the legacy layout, it starts at the first `align` boundary after the available
+/// ring, whose size depends on `num`. An `align` of 0 means the driver hasn't written it.
+///
+/// 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
+/// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-260002
+pub fn used_addr(desc_addr: u64, num: u64, align: u64) -> u64 {
+    let align = match align {
+        0 => DEFAULT_QUEUE_ALIGN,
+        align => align,
+    };
+    // `flags`, `idx`, `ring[num]`, and `used_event` of the available ring.
+    let avail_end = avail_addr(desc_addr, num).wrapping_add(2 * (3 + num));
+    avail_end.wrapping_add(align - 1) / align * align
+}
+
+/// Follow the descriptor chain whose head is at `head` in the descriptor table at `desc_addr` and
+/// return the descriptors in order. Fail if the chain is longer than `max_chain_len`, such as a
+/// cyclic one built by a buggy or malicious driver.
+pub fn read_chain(
+    bus: &mut Bus,
+    desc_addr: u64,
+    head: u64,
+    max_chain_len: u64,
+) -> Result<Vec<VirtqDesc>, Exception> {
+    let mut chain = Vec::new();
+    let mut index = head;
+    loop {
+        if chain.len() as u64 >= max_chain_len {
+            warn!(
+                "virtio: a descriptor chain from {} exceeds {} descriptors",
+                head, max_chain_len
+            );
+            return Err(Exception::LoadAccessFault);
+        }
+        let desc = VirtqDesc::new(
+            bus,
+            desc_addr.wrapping_add(VRING_DESC_SIZE.wrapping_mul(index)),
+        )?;
+        let next = desc.next;
+        let has_next = (desc.flags & VIRTQ_DESC_F_NEXT) != 0;
+        chain.push(desc);
+        if !has_next {
+            return Ok(chain);
+        }
+        index = next;
+    }
+}
+
+/// Return the `idx` field of the available ring at `avail_addr`, which is where the driver would
+/// put the next descriptor head in the ring.
+pub fn read_avail_idx(bus: &mut Bus, avail_addr: u64) -> Result<u16, Exception> {
+    Ok(bus.read(avail_addr.wrapping_add(2), HALFWORD)? as u16)
+}
+
+/// Return the descriptor head which the driver put at the free-running index `index` of the
+/// available ring at `avail_addr` of a queue with `num` entries.
+pub fn read_avail_head(
+    bus: &mut Bus,
+    avail_addr: u64,
+    num: u64,
+    index: u16,
+) -> Result<u64, Exception> {
+    let slot = index as u64 % num;
+    bus.read(avail_addr.wrapping_add(4 + 2 * slot), HALFWORD)
+}
+
+/// Put the chain from `head`, of which the device wrote `len` bytes, at the free-running index
+/// `index` of the used ring at `used_addr` of a queue with `num` entries, and publish it to the
+/// driver by advancing `idx`.
+pub fn push_used(
+    bus: &mut Bus,
+    used_addr: u64,
+    num: u64,
+    index: u16,
+    head: u64,
+    len: u64,
+) -> Result<(), Exception> {
+    let elem = used_addr.wrapping_add(4 + 8 * (index as u64 % num));
+    bus.write(elem, head, WORD)?;
+    bus.write(elem.wrapping_add(4), len, WORD)?;
+    bus.write(
+        used_addr.wrapping_add(2),
+        index.wrapping_add(1) as u64,
+        HALFWORD,
+    )
+}
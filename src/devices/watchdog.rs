@@ -0,0 +1,153 @@
+//! The watchdog module contains a device to test guests which pet a watchdog. The guest sets a
+//! timeout in ticks of the CLINT `mtime` and writes a keepalive periodically. If no keepalive
+//! arrives within the timeout, the watchdog lapses and raises an interrupt or resets the machine.
+//!
+//! It has three 8-byte registers:
+//! - `TIMEOUT` (0x00): the timeout. Writing it restarts the countdown, and 0 disables the
+//!   watchdog.
+//! - `KEEPALIVE` (0x08): writing any value restarts the countdown. A load returns the ticks left
+//!   before the watchdog lapses, or 0 if it's disabled.
+//! - `STATUS` (0x10): bit 0 is set when the watchdog lapses. Writing 1 to it clears it.
+
+use core::any::Any;
+
+use crate::cpu::DOUBLEWORD;
+use crate::devices::mmio::{MmioDevice, WidthRule};
+use crate::exception::Exception;
+
+/// The size of the register region.
+pub const WATCHDOG_SIZE: u64 = 0x18;
+
+/// The offset of the register which holds the timeout.
+const WATCHDOG_TIMEOUT: u64 = 0x00;
+/// The offset of the register which restarts the countdown.
+const WATCHDOG_KEEPALIVE: u64 = 0x08;
+/// The offset of the register which reports a lapse.
+const WATCHDOG_STATUS: u64 = 0x10;
+
+/// The bit of `STATUS` which is set when the watchdog lapses.
+const WATCHDOG_STATUS_LAPSED: u64 = 1;
+
+/// The access widths of the registers by their offsets. All of them are 64 bits wide.
+const WATCHDOG_ACCESS_WIDTHS: &[WidthRule] = &[WidthRule {
+    start: 0,
+    end: WATCHDOG_SIZE - 1,
+    sizes: &[DOUBLEWORD],
+}];
+
+/// What the watchdog does when it lapses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    /// Raise the PLIC interrupt source.
+    Interrupt(u32),
+    /// Reset the harts, the memory, and the devices as `Emulator::reset` does.
+    Reset,
+}
+
+/// The watchdog device. The hart polls it with the current `mtime` along with the other devices
+/// which can interrupt.
+pub struct Watchdog {
+    action: WatchdogAction,
+    timeout: u64,
+    /// The `mtime` at which the watchdog lapses, or `None` if it's disabled or has lapsed.
+    deadline: Option<u64>,
+    status: u64,
+    /// True if the watchdog has lapsed with `WatchdogAction::Reset` and the machine hasn't been
+    /// reset yet.
+    reset_requested: bool,
+    /// The `mtime` of the CLINT at the last access or poll.
+    mtime: u64,
+}
+
+impl Watchdog {
+    /// Create a new disabled watchdog which takes `action` when it lapses.
+    pub fn new(action: WatchdogAction) -> Self {
+        Self {
+            action,
+            timeout: 0,
+            deadline: None,
+            status: 0,
+            reset_requested: false,
+            mtime: 0,
+        }
+    }
+
+    /// Disable the watchdog and clear its status. The action is kept.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.action);
+    }
+
+    /// Return what the watchdog does when it lapses.
+    pub fn action(&self) -> WatchdogAction {
+        self.action
+    }
+
+    /// Check the deadline against `mtime`, and return true if the watchdog has just lapsed. It
+    /// stays disabled until the guest writes a keepalive or a timeout again.
+    pub fn poll(&mut self, mtime: u64) -> bool {
+        self.mtime = mtime;
+        match self.deadline {
+            Some(deadline) if mtime >= deadline => {
+                self.deadline = None;
+                self.status |= WATCHDOG_STATUS_LAPSED;
+                self.reset_requested = self.action == WatchdogAction::Reset;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Return true once after the watchdog has lapsed with `WatchdogAction::Reset`.
+    pub fn take_reset_request(&mut self) -> bool {
+        core::mem::take(&mut self.reset_requested)
+    }
+
+    /// Restart the countdown from the current `mtime`, or disable the watchdog if the timeout is
+    /// 0.
+    fn restart(&mut self) {
+        self.deadline = match self.timeout {
+            0 => None,
+            timeout => Some(self.mtime.saturating_add(timeout)),
+        };
+    }
+}
+
+impl MmioDevice for Watchdog {
+    /// Load the register at `offset`.
+    fn read(&mut self, offset: u64, _size: u8) -> Result<u64, Exception> {
+        match offset {
+            WATCHDOG_TIMEOUT => Ok(self.timeout),
+            WATCHDOG_KEEPALIVE => Ok(self
+                .deadline
+                .map_or(0, |deadline| deadline.saturating_sub(self.mtime))),
+            WATCHDOG_STATUS => Ok(self.status),
+            _ => Err(Exception::LoadAccessFault),
+        }
+    }
+
+    /// Store the register at `offset`.
+    fn write(&mut self, offset: u64, value: u64, _size: u8) -> Result<(), Exception> {
+        match offset {
+            WATCHDOG_TIMEOUT => {
+                self.timeout = value;
+                self.restart();
+            }
+            WATCHDOG_KEEPALIVE => self.restart(),
+            WATCHDOG_STATUS => self.status &= !(value & WATCHDOG_STATUS_LAPSED),
+            _ => return Err(Exception::StoreAMOAccessFault),
+        }
+        Ok(())
+    }
+
+    fn access_widths(&self) -> &[WidthRule] {
+        WATCHDOG_ACCESS_WIDTHS
+    }
+
+    fn set_mtime(&mut self, mtime: u64) {
+        self.mtime = mtime;
+    }
+
+    fn as_any_mut(&mut self) -> Option<&mut dyn Any> {
+        Some(self)
+    }
+}
@@ -1,5 +1,8 @@
 //! The memory module contains the memory structure and implementation to read/write the memory.
 
+use alloc::vec;
+use alloc::vec::Vec;
+
 use crate::bus::DRAM_BASE;
 use crate::cpu::{BYTE, DOUBLEWORD, HALFWORD, WORD};
 use crate::exception::Exception;
@@ -12,6 +15,10 @@ pub const DRAM_SIZE: u64 = 1024 * 1024 * 1024;
 pub struct Dram {
     pub dram: Vec<u8>,
     code_size: u64,
+    /// The binary set by `initialize`, which is loaded again on reset.
+    image: Vec<u8>,
+    /// The pattern set by `fill`, which the memory is filled with again on reset.
+    pattern: u32,
 }
 
 impl Dram {
@@ -20,6 +27,22 @@ impl Dram {
         Self {
             dram: vec![0; DRAM_SIZE as usize],
             code_size: 0,
+            image: Vec::new(),
+            pattern: 0,
+        }
+    }
+
+    /// Fill the whole memory with `pattern` repeated in little endian instead of zeros, so that
+    /// a guest reading memory it never initialized sees a recognizable value.
+    pub fn fill(&mut self, pattern: u32) {
+        self.pattern = pattern;
+        self.dram[..4].copy_from_slice(&pattern.to_le_bytes());
+        // Double the filled part until it covers the memory.
+        let mut filled = 4;
+        while filled < self.dram.len() {
+            let len = filled.min(self.dram.len() - filled);
+            self.dram.copy_within(..len, filled);
+            filled += len;
         }
     }
 
@@ -27,6 +50,24 @@ impl Dram {
     pub fn initialize(&mut self, binary: Vec<u8>) {
         self.code_size = binary.len() as u64;
         self.dram.splice(..binary.len(), binary.iter().cloned());
+        self.image = binary;
+    }
+
+    /// Restore the memory to the state after `fill` and `initialize` without reallocating it.
+    /// Only the pages which differ from the pattern are written, so that the pages the guest
+    /// never touched stay unmapped on the host.
+    pub fn reset(&mut self) {
+        let mut page = [0; 4096];
+        for chunk in page.chunks_mut(4) {
+            chunk.copy_from_slice(&self.pattern.to_le_bytes());
+        }
+        for chunk in self.dram.chunks_mut(page.len()) {
+            let pattern = &page[..chunk.len()];
+            if chunk != pattern {
+                chunk.copy_from_slice(pattern);
+            }
+        }
+        self.dram[..self.image.len()].copy_from_slice(&self.image);
     }
 
     /// Load `size`-bit data from the memory.
@@ -52,7 +93,8 @@ impl Dram {
         Ok(())
     }
 
-    /// Write a byte to the memory.
+    /// Write a byte to the memory. Stores write exactly the low bytes of the value, so each
+    /// writer truncates it to its width before splitting it into bytes.
     fn write8(&mut self, addr: u64, val: u64) {
         let index = (addr - DRAM_BASE) as usize;
         self.dram[index] = val as u8
@@ -61,30 +103,19 @@ impl Dram {
     /// Write 2 bytes to the memory with little endian.
     fn write16(&mut self, addr: u64, val: u64) {
         let index = (addr - DRAM_BASE) as usize;
-        self.dram[index] = (val & 0xff) as u8;
-        self.dram[index + 1] = ((val >> 8) & 0xff) as u8;
+        self.dram[index..index + 2].copy_from_slice(&(val as u16).to_le_bytes());
     }
 
     /// Write 4 bytes to the memory with little endian.
     fn write32(&mut self, addr: u64, val: u64) {
         let index = (addr - DRAM_BASE) as usize;
-        self.dram[index] = (val & 0xff) as u8;
-        self.dram[index + 1] = ((val >> 8) & 0xff) as u8;
-        self.dram[index + 2] = ((val >> 16) & 0xff) as u8;
-        self.dram[index + 3] = ((val >> 24) & 0xff) as u8;
+        self.dram[index..index + 4].copy_from_slice(&(val as u32).to_le_bytes());
     }
 
     /// Write 8 bytes to the memory with little endian.
     fn write64(&mut self, addr: u64, val: u64) {
         let index = (addr - DRAM_BASE) as usize;
-        self.dram[index] = (val & 0xff) as u8;
-        self.dram[index + 1] = ((val >> 8) & 0xff) as u8;
-        self.dram[index + 2] = ((val >> 16) & 0xff) as u8;
-        self.dram[index + 3] = ((val >> 24) & 0xff) as u8;
-        self.dram[index + 4] = ((val >> 32) & 0xff) as u8;
-        self.dram[index + 5] = ((val >> 40) & 0xff) as u8;
-        self.dram[index + 6] = ((val >> 48) & 0xff) as u8;
-        self.dram[index + 7] = ((val >> 56) & 0xff) as u8;
+        self.dram[index..index + 8].copy_from_slice(&val.to_le_bytes());
     }
 
     /// Read a byte from the memory.
@@ -1,16 +1,211 @@
 //! The emulator module represents an entire computer.
 
-use crate::cpu::Cpu;
-use crate::exception::Trap;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::bus::{DRAM_BASE, VIRTIO_CONSOLE_BASE};
+use crate::cpu::{Cpu, HartContext, Mode, BYTE, HALFWORD, WORD};
+use crate::csr::*;
+use crate::devices::{
+    debug_ctl::{DebugCtl, DEBUG_CTL_SIZE},
+    delay::Delay,
+    htif::Htif,
+    perfcounters::{PerfCounters, PERF_COUNTERS_SIZE},
+    plic::{check_source, IrqSource},
+    serial::BufferBackend,
+    virtio_console::{VirtioConsole, VIRTIO_CONSOLE_SIZE},
+    watchdog::{Watchdog, WatchdogAction, WATCHDOG_SIZE},
+};
+use crate::dram::DRAM_SIZE;
+use crate::error::ConfigError;
+use crate::exception::{Exception, Trap};
+use crate::golden::{GoldenChecker, GoldenWriter};
+use crate::repl;
+use crate::replay::{Recorder, Replayer};
+use crate::sbi::{self, SbiResult};
+use crate::spike::SpikeChecker;
+
+/// The default address where `Emulator::load_initrd` places an initramfs. It's high in the memory
+/// declared in the DTB so that the kernel doesn't overwrite it.
+pub const INITRD_BASE: u64 = DRAM_BASE + 0x0600_0000;
+
+/// The default number of instructions a hart executes before the next hart runs.
+pub const DEFAULT_QUANTUM: u64 = 1000;
+
+/// The number of instructions `Emulator::run_for_duration` executes between checks of the host
+/// clock. Reading the clock on every instruction would slow the emulator down.
+const CLOCK_CHECK_INTERVAL: u64 = 1024;
+
+/// The reason why the emulator stopped executing instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Halt {
+    /// The maximum number of instructions has been executed.
+    InstructionLimit,
+    /// A trap which the execution environment can't handle occurred.
+    FatalTrap,
+    /// The guest requested to shut down with the exit code via HTIF or SBI.
+    Shutdown(u64),
+    /// The hart entered debug mode. It stays halted until `Cpu::leave_debug_mode` is called.
+    Debug,
+    /// The hart hit the software breakpoint or the breakpoint added by `add_breakpoint` at the
+    /// address. The program counter points at it.
+    Breakpoint(u64),
+    /// The budget of host time has been used up.
+    TimeSliceExpired,
+    /// The host received SIGINT while `SigintBehavior::Halt` was set.
+    Interrupted,
+}
+
+/// The number of bytes of memory before and after the trap value which a trap checkpoint keeps.
+pub const TRAP_CHECKPOINT_WINDOW: u64 = 64;
+
+/// The CSRs which a trap checkpoint keeps.
+const TRAP_CHECKPOINT_CSRS: [CsrAddress; 12] = [
+    MSTATUS, MEDELEG, MTVEC, MEPC, MCAUSE, MTVAL, STVEC, SEPC, SCAUSE, STVAL, SATP, MCYCLE,
+];
+
+/// The state of a hart right after it took an exception, for post-mortem debugging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrapCheckpoint {
+    /// The hartid of the hart which took the exception.
+    pub hartid: u64,
+    /// The privilege mode the exception was raised in.
+    pub mode: Mode,
+    /// The exception code written to `mcause` or `scause`.
+    pub cause: u64,
+    /// The address of the instruction written to `mepc` or `sepc`.
+    pub epc: u64,
+    /// The value written to `mtval` or `stval`, e.g., the faulting address.
+    pub tval: u64,
+    /// The integer registers.
+    pub xregs: [u64; 32],
+    /// The CSRs related to traps and their values after the trap was taken.
+    pub csrs: Vec<(CsrAddress, u64)>,
+    /// The physical address where `memory` starts.
+    pub memory_base: u64,
+    /// The contents of DRAM within `TRAP_CHECKPOINT_WINDOW` bytes of `tval`, taken as a physical
+    /// address. It's empty if the window is outside DRAM.
+    pub memory: Vec<u8>,
+}
+
+/// What the emulator does when the host receives SIGINT, e.g., by Ctrl-C, instead of being
+/// killed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigintBehavior {
+    /// Deliver the byte to the UART as if it were typed, e.g., 0x03, which a terminal sends for
+    /// Ctrl-C.
+    InjectByte(u8),
+    /// Raise the PLIC interrupt source.
+    RaiseInterrupt(u32),
+    /// Stop with `Halt::Interrupted`.
+    Halt,
+}
+
+/// An instruction retired by the running hart, which is passed to the trace hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// The address of the instruction.
+    pub pc: u64,
+    /// The instruction. A compressed instruction isn't expanded.
+    pub inst: u64,
+    /// The integer register whose value the instruction changed and its new value, or `None` if
+    /// no integer register changed, e.g., for a store or a write to `x0`.
+    pub rd: Option<(u64, u64)>,
+}
+
+impl fmt::Display for TraceEntry {
+    /// Format the entry as a line of a golden file: `<pc> <inst> <rd> <value>`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:x} {:08x} ", self.pc, self.inst)?;
+        match self.rd {
+            Some((rd, value)) => write!(f, "x{} {:x}", rd, value),
+            None => write!(f, "- -"),
+        }
+    }
+}
+
+/// `ebreak`, which replaces a 32-bit instruction at a software breakpoint.
+const EBREAK: [u8; 4] = 0x0010_0073u32.to_le_bytes();
+/// `c.ebreak`, which replaces a compressed instruction at a software breakpoint.
+const C_EBREAK: [u8; 2] = 0x9002u16.to_le_bytes();
+
+/// The function called when the hart enters debug mode.
+type DebugEntryHook = Box<dyn FnMut(&mut Cpu)>;
+
+/// The function called every time the guest takes an exception.
+type TrapCheckpointHook = Box<dyn FnMut(&TrapCheckpoint)>;
+
+/// The function called every time an instruction retires.
+type TraceHook = Box<dyn FnMut(&TraceEntry)>;
 
 /// The emulator to hold a CPU.
 pub struct Emulator {
-    /// The CPU which is the core implementation of this emulator.
+    /// The CPU which is the core implementation of this emulator. It holds the state of the
+    /// running hart if there are several harts.
     pub cpu: Cpu,
     /// The debug flag. Output messages if it's true, otherwise output nothing.
     pub is_debug: bool,
     /// The test flag for riscv/riscv-tests.
     pub is_test: bool,
+    /// The SBI flag. The emulator services `ecall`s from S-mode if it's true.
+    pub is_sbi: bool,
+    /// The debug-call flag. An `ecall` from any mode with `EID_DEBUG_DUMP` in a7 dumps the
+    /// registers to the console instead of trapping if it's true.
+    pub is_debug_call: bool,
+    /// The address where `load_initrd` places an initramfs.
+    pub initrd_base: u64,
+    /// The program counter set by `initialize_pc`, which all harts start at again on reset.
+    initial_pc: u64,
+    /// The number of cycles executed so far. Recorded inputs are stamped with it.
+    ticks: u64,
+    /// The log of inputs being recorded.
+    recorder: Option<Recorder>,
+    /// The log of inputs being replayed instead of the inputs from the host.
+    replayer: Option<Replayer>,
+    /// The function called when the hart enters debug mode.
+    debug_entry_hook: Option<DebugEntryHook>,
+    /// The function called every time an instruction retires.
+    trace_hook: Option<TraceHook>,
+    /// The function called every time the guest takes an exception.
+    trap_checkpoint_hook: Option<TrapCheckpointHook>,
+    /// The checkpoint of the last exception which stopped the emulator.
+    fatal_checkpoint: Option<TrapCheckpoint>,
+    /// What to do on SIGINT, or `None` if SIGINT isn't handled.
+    sigint_behavior: Option<SigintBehavior>,
+    /// The flag which the signal handler of the host sets on SIGINT.
+    sigint: Arc<AtomicBool>,
+    /// The original bytes of the instructions replaced by software breakpoints, keyed by their
+    /// physical addresses.
+    breakpoints: HashMap<u64, Vec<u8>>,
+    /// The numbers of hits of the breakpoints compared with the program counter, keyed by their
+    /// virtual addresses.
+    pc_breakpoints: HashMap<u64, u64>,
+    /// The flag to execute the next instruction even if a breakpoint is at it.
+    skip_breakpoint: bool,
+    /// The contexts of all harts indexed by hartid, or empty if there is only one hart. The
+    /// context of the running hart is stale because its state is in `cpu`.
+    harts: Vec<HartContext>,
+    /// The hartid of the running hart.
+    current_hart: usize,
+    /// The number of instructions a hart executes before the next hart runs.
+    quantum: u64,
+    /// The number of instructions the running hart has executed in its quantum.
+    executed_in_quantum: u64,
+    /// The flag to switch to the next hart after every memory barrier.
+    switch_on_barrier: bool,
+    /// The flag to advance the timers of a hart waiting in `wfi` to the next timer interrupt.
+    fast_forward_wfi: bool,
 }
 
 impl Emulator {
@@ -20,12 +215,120 @@ impl Emulator {
             cpu: Cpu::new(),
             is_debug: false,
             is_test: false,
+            is_sbi: false,
+            is_debug_call: false,
+            initrd_base: INITRD_BASE,
+            initial_pc: 0,
+            ticks: 0,
+            recorder: None,
+            replayer: None,
+            debug_entry_hook: None,
+            trace_hook: None,
+            trap_checkpoint_hook: None,
+            fatal_checkpoint: None,
+            sigint_behavior: None,
+            sigint: Arc::new(AtomicBool::new(false)),
+            breakpoints: HashMap::new(),
+            pc_breakpoints: HashMap::new(),
+            skip_breakpoint: false,
+            harts: Vec::new(),
+            current_hart: 0,
+            quantum: DEFAULT_QUANTUM,
+            executed_in_quantum: 0,
+            switch_on_barrier: false,
+            fast_forward_wfi: false,
         }
     }
 
-    /// Reset CPU state.
+    /// Reset the harts, the memory, and the devices to their state after initialization without
+    /// reallocating them, e.g., to run many tests in one process. DRAM is filled again and the
+    /// binary set by `initialize_dram` is loaded again, and all harts start at the address set
+    /// by `initialize_pc`. Software breakpoints stay set.
     pub fn reset(&mut self) {
-        self.cpu.reset()
+        self.switch_to_hart(0);
+        self.cpu.bus.reset();
+        self.cpu.reset();
+        self.cpu.pc = self.initial_pc;
+        for (hartid, context) in self.harts.iter_mut().enumerate() {
+            *context = HartContext::new(hartid as u64, self.initial_pc);
+        }
+        self.executed_in_quantum = 0;
+
+        // The instructions replaced by the breakpoints have been loaded again.
+        let breakpoints: Vec<(u64, usize)> = self
+            .breakpoints
+            .iter()
+            .map(|(&addr, original)| (addr, original.len()))
+            .collect();
+        for (addr, len) in breakpoints {
+            let ebreak: &[u8] = if len == 4 { &EBREAK } else { &C_EBREAK };
+            if let Err(exception) = self.write_physical(addr, ebreak) {
+                warn!(
+                    "failed to restore the breakpoint at {:#x}: {:?}",
+                    addr, exception
+                );
+            }
+        }
+    }
+
+    /// Set the number of harts. New harts start at the program counter of the running hart in
+    /// machine mode, and they share the bus with the others.
+    pub fn set_num_harts(&mut self, num_harts: usize) {
+        assert!(num_harts > 0, "at least one hart is required");
+        self.switch_to_hart(0);
+        if num_harts == 1 {
+            self.harts.clear();
+            return;
+        }
+        let pc = self.cpu.pc;
+        self.harts.truncate(num_harts);
+        for hartid in self.harts.len()..num_harts {
+            self.harts.push(HartContext::new(hartid as u64, pc));
+        }
+    }
+
+    /// Return the number of harts.
+    pub fn num_harts(&self) -> usize {
+        self.harts.len().max(1)
+    }
+
+    /// Return the hartid of the hart whose state is in `cpu`.
+    pub fn current_hart(&self) -> usize {
+        self.current_hart
+    }
+
+    /// Swap the hart `hartid` into `cpu`, e.g., to inspect its registers. It runs next, and it
+    /// starts a new quantum.
+    pub fn switch_to_hart(&mut self, hartid: usize) {
+        assert!(hartid < self.num_harts(), "no such hart: {}", hartid);
+        self.executed_in_quantum = 0;
+        if hartid == self.current_hart {
+            return;
+        }
+        // Save the running hart into its stale slot, and load the other.
+        self.cpu.swap_context(&mut self.harts[self.current_hart]);
+        self.cpu.swap_context(&mut self.harts[hartid]);
+        self.current_hart = hartid;
+    }
+
+    /// Set the number of instructions each hart executes before the next hart runs. Harts run
+    /// round-robin in the order of their hartids, so the interleaving is deterministic.
+    pub fn set_scheduling_quantum(&mut self, quantum: u64) {
+        assert!(quantum > 0, "the quantum must be at least one instruction");
+        self.quantum = quantum;
+    }
+
+    /// Switch to the next hart after every memory barrier as well as at the end of a quantum.
+    /// It's useful to stress lock code.
+    pub fn set_switch_on_barrier(&mut self, switch_on_barrier: bool) {
+        self.switch_on_barrier = switch_on_barrier;
+    }
+
+    /// Advance `mtime` and TIME of a hart waiting in `wfi` to the nearest `mtimecmp` or
+    /// `stimecmp` at once instead of cycle by cycle, so idle loops end quickly. The timers only
+    /// count cycles, so runs stay deterministic. It only applies while there is a single hart.
+    pub fn set_fast_forward_wfi(&mut self, fast_forward_wfi: bool) {
+        self.fast_forward_wfi = fast_forward_wfi;
     }
 
     /// Set binary data to the beginning of the DRAM from the emulator console.
@@ -33,14 +336,479 @@ impl Emulator {
         self.cpu.bus.initialize_dram(data);
     }
 
+    /// Fill DRAM with `pattern`, e.g., 0xdeadbeef, instead of zeros to catch guests which rely on
+    /// memory they never initialized. Call it before loading anything to DRAM because it
+    /// overwrites the whole memory.
+    pub fn dram_fill(&mut self, pattern: u32) {
+        self.cpu.bus.fill_dram(pattern);
+    }
+
     /// Set binary data to the virtio disk from the emulator console.
     pub fn initialize_disk(&mut self, data: Vec<u8>) {
         self.cpu.bus.initialize_disk(data);
     }
 
-    /// Set the program counter to the CPU field.
+    /// Set a read-only image to the virtio disk. The image is shared with other emulators instead
+    /// of being copied.
+    pub fn initialize_shared_disk(&mut self, image: Arc<[u8]>) {
+        self.cpu.bus.initialize_shared_disk(image);
+    }
+
+    /// Open the image file at `path` for reading and writing as the virtio disk. Write requests go
+    /// to the file, so emulators which open the same file share the disk. With `locking`, each
+    /// request which writes to the disk holds an exclusive advisory lock on the file, so that the
+    /// requests of emulators which lock it as well don't interleave.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open_disk<P: AsRef<Path>>(&mut self, path: P, locking: bool) -> io::Result<()> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        self.cpu.bus.initialize_file_disk(file, locking)
+    }
+
+    /// Replace the device tree blob in ROM, e.g., with one compiled beforehand.
+    pub fn initialize_dtb(&mut self, dtb: Vec<u8>) {
+        self.cpu.bus.rom.set_dtb(dtb);
+    }
+
+    /// Place an initramfs, such as a cpio archive, at `initrd_base` in DRAM and tell the kernel
+    /// its range by `linux,initrd-start` and `linux,initrd-end` in the `/chosen` node of the DTB.
+    /// Fail if the initramfs doesn't fit in DRAM.
+    pub fn load_initrd(&mut self, data: Vec<u8>) -> Result<(), ConfigError> {
+        let start = self.initrd_base;
+        let size = data.len() as u64;
+        let out_of_dram = ConfigError::OutOfDram { base: start, size };
+        let end = match start.checked_add(size) {
+            Some(end) if start >= DRAM_BASE && end <= DRAM_BASE + DRAM_SIZE => end,
+            _ => return Err(out_of_dram),
+        };
+        self.write_physical(start, &data).map_err(|_| out_of_dram)?;
+
+        let rom = &mut self.cpu.bus.rom;
+        if !(rom.set_chosen_property("linux,initrd-start", &start.to_be_bytes())
+            && rom.set_chosen_property("linux,initrd-end", &end.to_be_bytes()))
+        {
+            warn!("failed to add the initrd to the device tree blob");
+        }
+        Ok(())
+    }
+
+    /// Copy `data` to the physical address `addr` through the system bus, e.g., to place a page
+    /// table or a trampoline before starting. The address isn't translated and the copy doesn't
+    /// count as a store by the guest.
+    pub fn write_physical(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        // The predecoded blocks may be overwritten.
+        #[cfg(feature = "threaded")]
+        self.cpu.block_cache.flush();
+
+        if let Some(dram) = self.cpu.bus.dma_slice(addr, data.len() as u64) {
+            dram.copy_from_slice(data);
+            return Ok(());
+        }
+        for (i, &byte) in data.iter().enumerate() {
+            self.cpu
+                .bus
+                .write(addr.wrapping_add(i as u64), byte as u64, BYTE)?;
+        }
+        Ok(())
+    }
+
+    /// Return the physical addresses of all occurrences of `pattern` in DRAM in ascending order,
+    /// e.g., to locate a string or a structure of the guest. Occurrences may overlap.
+    pub fn search_memory(&self, pattern: &[u8]) -> Vec<u64> {
+        const PAGE_SIZE: usize = 4096;
+        let zero_page = [0; PAGE_SIZE];
+        let dram = self.cpu.bus.dram();
+        let mut found = Vec::new();
+        if pattern.is_empty() || pattern.len() > dram.len() {
+            return found;
+        }
+
+        // Most of DRAM is usually never written. An occurrence of a pattern which isn't all
+        // zeros overlaps a page which isn't all zeros, so the other pages are skipped.
+        let skip_zero_pages = pattern.iter().any(|&byte| byte != 0);
+        // The first offset which hasn't been checked yet.
+        let mut next = 0;
+        for (i, page) in dram.chunks(PAGE_SIZE).enumerate() {
+            if skip_zero_pages && page == &zero_page[..page.len()] {
+                continue;
+            }
+            let page_start = i * PAGE_SIZE;
+            let start = page_start.saturating_sub(pattern.len() - 1).max(next);
+            let end = (page_start + page.len() + pattern.len() - 1).min(dram.len());
+            for (offset, window) in dram[start..end].windows(pattern.len()).enumerate() {
+                if window == pattern {
+                    found.push(DRAM_BASE + (start + offset) as u64);
+                }
+            }
+            next = page_start + page.len();
+        }
+        found
+    }
+
+    /// Set the program counter to the CPU field. All harts start at the same address.
     pub fn initialize_pc(&mut self, pc: u64) {
+        self.initial_pc = pc;
         self.cpu.pc = pc;
+        for context in self.harts.iter_mut() {
+            context.pc = pc;
+        }
+    }
+
+    /// Enable the HTIF device whose `tohost` word is at `tohost`. `fromhost` follows it at
+    /// `tohost + 0x40`.
+    pub fn enable_htif(&mut self, tohost: u64) {
+        self.cpu.bus.htif = Some(Htif::new(tohost));
+    }
+
+    /// Add a UART at `base` besides the console, e.g., as a debug port. It raises the PLIC
+    /// interrupt source `irq`, and its backend is configured through `Bus::uart_at`. Fail if `irq`
+    /// isn't a valid interrupt source or the registers overlap another device.
+    pub fn add_uart(&mut self, base: u64, irq: u32) -> Result<(), ConfigError> {
+        self.cpu.bus.add_uart(base, irq)
+    }
+
+    /// Enable the delay device whose register is at `base`. It reads as `mcycle`, and writing a
+    /// deadline to it stalls the hart until `mcycle` reaches the deadline.
+    pub fn enable_delay(&mut self, base: u64) {
+        self.cpu.bus.delay = Some(Delay::new(base));
+    }
+
+    /// Enable the virtio console at `VIRTIO_CONSOLE_BASE`. It raises the PLIC interrupt source
+    /// 2, and its backend and input are configured through `Bus::virtio_console`. The device is
+    /// added to the DTB so that a kernel finds it.
+    pub fn enable_virtio_console(&mut self) {
+        self.cpu.bus.virtio_console = Some(VirtioConsole::new());
+
+        let mut reg = Vec::new();
+        for cell in [
+            VIRTIO_CONSOLE_BASE >> 32,
+            VIRTIO_CONSOLE_BASE,
+            VIRTIO_CONSOLE_SIZE >> 32,
+            VIRTIO_CONSOLE_SIZE,
+        ]
+        .iter()
+        {
+            reg.extend_from_slice(&(*cell as u32).to_be_bytes());
+        }
+        let props: [(&str, &[u8]); 4] = [
+            (
+                "interrupts",
+                &(IrqSource::VirtioConsole as u32).to_be_bytes(),
+            ),
+            // The phandle of the PLIC.
+            ("interrupt-parent", &3u32.to_be_bytes()),
+            ("reg", &reg),
+            ("compatible", b"virtio,mmio\0"),
+        ];
+        let name = format!("virtio_mmio@{:x}", VIRTIO_CONSOLE_BASE);
+        if !self.cpu.bus.rom.add_node(&name, &props) {
+            warn!("failed to add the virtio console to the device tree blob");
+        }
+    }
+
+    /// Enable the watchdog whose registers are at `base`. It lapses unless the guest writes a
+    /// keepalive within the timeout it sets in ticks of `mtime`, and then takes `action`. Fail if
+    /// `action` raises an invalid interrupt source or the registers overlap another device.
+    pub fn enable_watchdog(
+        &mut self,
+        base: u64,
+        action: WatchdogAction,
+    ) -> Result<(), ConfigError> {
+        if let WatchdogAction::Interrupt(irq) = action {
+            check_source(irq)?;
+        }
+        self.cpu.bus.attach_named(
+            "watchdog",
+            base,
+            WATCHDOG_SIZE,
+            Box::new(Watchdog::new(action)),
+        )
+    }
+
+    /// Enable the device whose registers at `base` read as `mcycle`, `minstret`, and `mtime`. Fail
+    /// if the registers overlap another device.
+    pub fn enable_perf_counters(&mut self, base: u64) -> Result<(), ConfigError> {
+        self.cpu.bus.attach_named(
+            "perf_counters",
+            base,
+            PERF_COUNTERS_SIZE,
+            Box::new(PerfCounters::new()),
+        )
+    }
+
+    /// Raise a resumable NMI with `cause` on the running hart, e.g., to model a hardware error. It
+    /// vectors to the NMI handler address regardless of `mstatus.MIE`, and records the state in
+    /// `mnepc`, `mncause`, and `mnstatus` of the Smrnmi extension so that `mnret` resumes.
+    pub fn raise_nmi(&mut self, cause: u64) {
+        self.cpu.raise_nmi(cause);
+    }
+
+    /// Set the address of the NMI handler of every hart.
+    pub fn set_nmi_vector(&mut self, addr: u64) {
+        self.cpu.nmi_vector = addr;
+    }
+
+    /// Enable the debug control device whose registers are at `base`. Once it's enabled, the
+    /// trace hook is only called while the guest has turned the trace on through it, and the
+    /// guest can log markers. Fail if the registers overlap another device.
+    pub fn enable_debug_ctl(&mut self, base: u64) -> Result<(), ConfigError> {
+        self.cpu
+            .bus
+            .attach_named("debug_ctl", base, DEBUG_CTL_SIZE, Box::new(DebugCtl::new()))
+    }
+
+    /// Record every input from the host, such as bytes typed to the UART, to the file at `path`.
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.recorder = Some(Recorder::create(path)?);
+        Ok(())
+    }
+
+    /// Feed the inputs recorded in the file at `path` back at the same cycles as they were
+    /// recorded. Inputs from the host are ignored while replaying.
+    pub fn replay<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.replayer = Some(Replayer::open(path)?);
+        Ok(())
+    }
+
+    /// Call `hook` every time the hart enters debug mode, before the emulator returns
+    /// `Halt::Debug`.
+    pub fn set_debug_entry_hook<F: FnMut(&mut Cpu) + 'static>(&mut self, hook: F) {
+        self.debug_entry_hook = Some(Box::new(hook));
+    }
+
+    /// Handle SIGINT of the host with `behavior`. The emulator doesn't install a signal handler
+    /// itself; the handler of the host sets the flag returned by `sigint_flag`, and the emulator
+    /// acts on it before the next instruction. Fail if `behavior` raises an interrupt which isn't
+    /// a valid interrupt source.
+    pub fn set_sigint_behavior(&mut self, behavior: SigintBehavior) -> Result<(), ConfigError> {
+        if let SigintBehavior::RaiseInterrupt(irq) = behavior {
+            check_source(irq)?;
+        }
+        self.sigint_behavior = Some(behavior);
+        Ok(())
+    }
+
+    /// Return the flag to set when the host receives SIGINT. Setting it is async-signal-safe.
+    pub fn sigint_flag(&self) -> Arc<AtomicBool> {
+        self.sigint.clone()
+    }
+
+    /// Call `hook` with a checkpoint every time the guest takes an exception, and keep running.
+    /// An exception which stops the emulator with `Halt::FatalTrap` isn't passed to it; its
+    /// checkpoint is taken by `take_fatal_checkpoint` instead.
+    pub fn set_trap_checkpoint_hook<F: FnMut(&TrapCheckpoint) + 'static>(&mut self, hook: F) {
+        self.trap_checkpoint_hook = Some(Box::new(hook));
+    }
+
+    /// Take the checkpoint of the exception which stopped the emulator with `Halt::FatalTrap`.
+    pub fn take_fatal_checkpoint(&mut self) -> Option<TrapCheckpoint> {
+        self.fatal_checkpoint.take()
+    }
+
+    /// Call `hook` with every instruction which retires. An instruction which raises an exception
+    /// doesn't retire. With the `threaded` feature, basic blocks aren't used while it's set.
+    /// If the debug control device is enabled, it's only called while the guest has turned the
+    /// trace on, from the instruction after the store which does it.
+    pub fn set_trace_hook<F: FnMut(&TraceEntry) + 'static>(&mut self, hook: F) {
+        self.trace_hook = Some(Box::new(hook));
+    }
+
+    /// Stop calling the trace hook.
+    pub fn clear_trace_hook(&mut self) {
+        self.trace_hook = None;
+    }
+
+    /// Execute at most `max_instructions` instructions like `run`, and save the trace of the
+    /// retired instructions to a golden file at `path`. See the `golden` module for the format.
+    pub fn write_golden<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        max_instructions: u64,
+    ) -> io::Result<Halt> {
+        let writer = Rc::new(RefCell::new(GoldenWriter::create(path)?));
+        let error = Rc::new(RefCell::new(None));
+        let (hook_writer, hook_error) = (writer.clone(), error.clone());
+        let previous = self.trace_hook.replace(Box::new(move |entry: &TraceEntry| {
+            if let Err(e) = hook_writer.borrow_mut().write(entry) {
+                hook_error.borrow_mut().get_or_insert(e);
+            }
+        }));
+        let halt = self.run(max_instructions);
+        self.trace_hook = previous;
+        let error = error.borrow_mut().take();
+        match error {
+            Some(e) => Err(e),
+            None => Ok(halt),
+        }
+    }
+
+    /// Execute instructions until the trace covers the golden file at `path`, and fail at the
+    /// first instruction which differs from it with both lines in the error. It also fails if
+    /// the emulator stops before the end of the golden file. Otherwise, return why the emulator
+    /// stopped, or `Halt::InstructionLimit` if it can continue.
+    pub fn run_with_golden<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Halt> {
+        let checker = Rc::new(RefCell::new(GoldenChecker::open(path)?));
+        let hook_checker = checker.clone();
+        let previous = self.trace_hook.replace(Box::new(move |entry: &TraceEntry| {
+            hook_checker.borrow_mut().check(entry)
+        }));
+        let result = loop {
+            let halt = if checker.borrow().is_finished() {
+                Some(Halt::InstructionLimit)
+            } else {
+                self.tick()
+            };
+            let mut checker = checker.borrow_mut();
+            if let Some(divergence) = checker.take_divergence() {
+                break Err(divergence);
+            }
+            match halt {
+                Some(halt) if checker.is_finished() => break Ok(halt),
+                Some(halt) => {
+                    let (matched, total) = checker.progress();
+                    break Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "the emulator stopped ({:?}) after {} of {} instructions in the \
+                             golden file",
+                            halt, matched, total
+                        ),
+                    ));
+                }
+                None => {}
+            }
+        };
+        self.trace_hook = previous;
+        result
+    }
+
+    /// Execute instructions in lock step with a Spike commit log at `path`, which is written by
+    /// `spike --log-commits`, and fail at the first instruction whose address, encoding, or
+    /// integer register writebacks differ from the log, with the lines before it as context.
+    /// The records before the first one at the current pc, such as those of the boot ROM of
+    /// Spike, are skipped. It also fails if the emulator stops before the end of the log.
+    /// Otherwise, return why the emulator stopped, or `Halt::InstructionLimit` if it can
+    /// continue. See the `spike` module for the format.
+    pub fn check_against_spike_log<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Halt> {
+        let mut checker = SpikeChecker::open(path)?;
+        checker.skip_to(self.cpu.pc);
+        let retired = Rc::new(RefCell::new(None));
+        let hook_retired = retired.clone();
+        let previous = self.trace_hook.replace(Box::new(move |entry: &TraceEntry| {
+            *hook_retired.borrow_mut() = Some(*entry);
+        }));
+        let result = loop {
+            let halt = if checker.is_finished() {
+                Some(Halt::InstructionLimit)
+            } else {
+                self.tick()
+            };
+            // The registers are compared after the instruction, so the hook only keeps the entry.
+            if let Some(entry) = retired.borrow_mut().take() {
+                checker.check(&entry, &self.cpu.xregs);
+            }
+            if let Some(divergence) = checker.take_divergence() {
+                break Err(divergence);
+            }
+            match halt {
+                Some(halt) if checker.is_finished() => break Ok(halt),
+                Some(halt) => {
+                    let (matched, total) = checker.progress();
+                    break Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "the emulator stopped ({:?}) after {} of {} instructions in the \
+                             Spike log",
+                            halt, matched, total
+                        ),
+                    ));
+                }
+                None => {}
+            }
+        };
+        self.trace_hook = previous;
+        result
+    }
+
+    /// Set a software breakpoint at the physical address `addr`, as the `Z0` packet of a GDB stub
+    /// does. The instruction there is saved and replaced by `ebreak`, or by `c.ebreak` if it's a
+    /// compressed instruction.
+    pub fn insert_breakpoint(&mut self, addr: u64) -> Result<(), Exception> {
+        if self.breakpoints.contains_key(&addr) {
+            return Ok(());
+        }
+        let halfword = self.cpu.bus.read(addr, HALFWORD)?;
+        let original = if halfword & 0b11 == 0b11 {
+            (self.cpu.bus.read(addr, WORD)? as u32)
+                .to_le_bytes()
+                .to_vec()
+        } else {
+            (halfword as u16).to_le_bytes().to_vec()
+        };
+        let ebreak: &[u8] = if original.len() == 4 {
+            &EBREAK
+        } else {
+            &C_EBREAK
+        };
+        self.write_physical(addr, ebreak)?;
+        self.breakpoints.insert(addr, original);
+        Ok(())
+    }
+
+    /// Add a breakpoint at the virtual address `addr`. Unlike a software breakpoint, the memory
+    /// isn't modified; the program counter is compared with it before every instruction is
+    /// fetched, and the emulator stops with `Halt::Breakpoint` before executing it.
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        self.pc_breakpoints.entry(addr).or_insert(0);
+    }
+
+    /// Remove the breakpoint added by `add_breakpoint` and the software breakpoint at `addr`, and
+    /// restore the original instruction of the software breakpoint, as the `z0` packet of a GDB
+    /// stub does. Return false if there is no breakpoint at `addr`.
+    pub fn remove_breakpoint(&mut self, addr: u64) -> Result<bool, Exception> {
+        let removed = self.pc_breakpoints.remove(&addr).is_some();
+        Ok(self.remove_software_breakpoint(addr)? || removed)
+    }
+
+    /// Return the addresses of the breakpoints added by `add_breakpoint` with the number of times
+    /// each one has stopped the emulator, sorted by address.
+    pub fn list_breakpoints(&self) -> Vec<(u64, u64)> {
+        let mut breakpoints: Vec<(u64, u64)> = self
+            .pc_breakpoints
+            .iter()
+            .map(|(&addr, &hits)| (addr, hits))
+            .collect();
+        breakpoints.sort_unstable();
+        breakpoints
+    }
+
+    /// Remove the software breakpoint at the physical address `addr` and restore the original
+    /// instruction. Return false if there is no software breakpoint at `addr`.
+    fn remove_software_breakpoint(&mut self, addr: u64) -> Result<bool, Exception> {
+        match self.breakpoints.remove(&addr) {
+            Some(original) => {
+                self.write_physical(addr, &original)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Debug the guest interactively with the commands read from `input`, writing the results to
+    /// `output`. See the `repl` module for the commands.
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, output: &mut W) -> io::Result<()> {
+        repl::run(self, input, output)
+    }
+
+    /// Return the address of the software breakpoint which the `ebreak` just executed replaces.
+    /// The program counter has already advanced 4 bytes past it, as for every trap, even if it's
+    /// `c.ebreak`.
+    fn hit_breakpoint(&self) -> Option<u64> {
+        let addr = self.cpu.pc.wrapping_sub(4);
+        if self.breakpoints.contains_key(&addr) {
+            Some(addr)
+        } else {
+            None
+        }
     }
 
     /// Start executing the emulator.
@@ -56,40 +824,374 @@ impl Emulator {
                 return;
             }
 
-            // Run a cycle on peripheral devices.
-            self.cpu.devices_increment();
-
-            // Take an interrupt.
-            match self.cpu.check_pending_interrupt() {
-                Some(interrupt) => interrupt.take_trap(&mut self.cpu),
+            match self.tick() {
+                Some(Halt::FatalTrap) => {
+                    println!("pc: {:#x}, trap {:#?}", self.cpu.pc, Trap::Fatal);
+                    return;
+                }
+                Some(_) => return,
                 None => {}
             }
+        }
+    }
+
+    /// Execute at most `max_instructions` instructions and return why the emulator stopped. With
+    /// the `threaded` feature, a basic block of a single hart counts as one instruction.
+    pub fn run(&mut self, max_instructions: u64) -> Halt {
+        for _ in 0..max_instructions {
+            if let Some(halt) = self.tick() {
+                return halt;
+            }
+        }
+        Halt::InstructionLimit
+    }
 
-            // Execute a fetched instruction.
-            let trap = match self.cpu.execute() {
-                Ok(inst) => {
-                    if self.is_debug {
-                        dbg!(format!(
-                            "pc: {:#x} , inst: {:#x}, is_inst 16? {}",
-                            self.cpu.pc,
-                            inst,
-                            // Check if an instruction is one of the compressed instructions.
-                            (inst & 0xffff_0000) == 0,
-                        ));
-                    }
-                    // Return a dummy trap.
-                    Trap::Requested
+    /// Execute instructions until `budget` of host time has elapsed, e.g., to keep a UI responsive
+    /// between frames. The clock is checked every `CLOCK_CHECK_INTERVAL` instructions, so the
+    /// call may overrun the budget slightly.
+    pub fn run_for_duration(&mut self, budget: Duration) -> Halt {
+        let start = Instant::now();
+        loop {
+            match self.run(CLOCK_CHECK_INTERVAL) {
+                Halt::InstructionLimit if start.elapsed() >= budget => {
+                    return Halt::TimeSliceExpired
                 }
-                Err(exception) => exception.take_trap(&mut self.cpu),
-            };
+                Halt::InstructionLimit => {}
+                halt => return halt,
+            }
+        }
+    }
 
-            match trap {
-                Trap::Fatal => {
-                    println!("pc: {:#x}, trap {:#?}", self.cpu.pc, trap);
-                    return;
+    /// Execute at most `max_instructions` instructions like `run`. The instruction at the program
+    /// counter is executed first even if a breakpoint is at it, as a debugger resuming from the
+    /// breakpoint does.
+    pub fn resume(&mut self, max_instructions: u64) -> Halt {
+        if max_instructions == 0 {
+            return Halt::InstructionLimit;
+        }
+        self.skip_breakpoint = true;
+        let addr = self.cpu.pc;
+        if let Ok(true) = self.remove_software_breakpoint(addr) {
+            let halt = self.tick();
+            // It can be written again because it was written when it was inserted.
+            let _ = self.insert_breakpoint(addr);
+            if let Some(halt) = halt {
+                return halt;
+            }
+            return self.run(max_instructions - 1);
+        }
+        self.run(max_instructions)
+    }
+
+    /// Execute at most `max_instructions` instructions and return why the emulator stopped along
+    /// with everything the guest wrote to the UART. The UART keeps writing to the capturing
+    /// backend afterwards.
+    pub fn run_and_capture_output(&mut self, max_instructions: u64) -> (Halt, String) {
+        let backend = BufferBackend::new();
+        self.cpu.bus.uart.set_backend(Box::new(backend.clone()));
+        let halt = self.run(max_instructions);
+        (halt, backend.contents())
+    }
+
+    /// Run a cycle on peripheral devices, take a pending interrupt, and execute an instruction.
+    /// Return the reason to stop if the emulator can't continue.
+    fn tick(&mut self) -> Option<Halt> {
+        // The hart doesn't run in debug mode until the embedder resumes it.
+        if self.cpu.mode == Mode::Debug {
+            return Some(Halt::Debug);
+        }
+
+        self.ticks += 1;
+
+        if self.sigint_behavior.is_some() && self.sigint.swap(false, Ordering::Relaxed) {
+            if let Some(halt) = self.deliver_sigint() {
+                return Some(halt);
+            }
+        }
+
+        // Skip the cycles an idle hart would wait for its next timer interrupt. Other harts would
+        // observe the jump, so it only applies to a single hart.
+        if self.fast_forward_wfi && self.harts.is_empty() {
+            self.cpu.fast_forward_timers();
+        }
+
+        // Run a cycle on peripheral devices.
+        self.devices_increment();
+        self.deliver_input();
+
+        // Take an NMI, which masks the interrupts until `mnret`, or an interrupt. The devices are
+        // polled either way.
+        self.cpu.take_pending_nmi();
+        if let Some(interrupt) = self.cpu.check_pending_interrupt() {
+            interrupt.take_trap(&mut self.cpu);
+        }
+
+        // A lapsed watchdog resets the machine instead of interrupting it.
+        if let Some(watchdog) = self.cpu.bus.device_mut::<Watchdog>() {
+            if watchdog.take_reset_request() {
+                warn!("the watchdog lapsed and reset the machine");
+                self.reset();
+                return None;
+            }
+        }
+
+        // Stop before fetching an instruction at a breakpoint. An idle hart fetches nothing.
+        let skip_breakpoint = core::mem::take(&mut self.skip_breakpoint);
+        if !self.pc_breakpoints.is_empty() && !skip_breakpoint && !self.cpu.idle {
+            if let Some(hits) = self.pc_breakpoints.get_mut(&self.cpu.pc) {
+                *hits += 1;
+                return Some(Halt::Breakpoint(self.cpu.pc));
+            }
+        }
+
+        // Keep the state before the instruction to trace it. An idle hart executes nothing, and
+        // the guest may have turned the trace off.
+        let is_tracing = match self.cpu.bus.device_mut::<DebugCtl>() {
+            Some(debug_ctl) => debug_ctl.is_tracing(),
+            None => true,
+        };
+        let traced = match &self.trace_hook {
+            Some(_) if !self.cpu.idle && is_tracing => Some((self.cpu.pc, self.cpu.xregs.clone())),
+            _ => None,
+        };
+
+        // Execute a fetched instruction, or a basic block on the threaded-code interpreter. Harts
+        // are switched per instruction, and instructions which are traced, counted, or compared
+        // with breakpoints are executed one by one, so only a single hart without them runs
+        // blocks. A trace hook disables blocks even while the guest has turned the trace off,
+        // since a block may turn it on.
+        #[cfg(not(feature = "threaded"))]
+        let result = self.cpu.execute();
+        #[cfg(feature = "threaded")]
+        let result = if self.harts.is_empty()
+            && self.trace_hook.is_none()
+            && self.pc_breakpoints.is_empty()
+            && !self.cpu.is_count
+        {
+            self.cpu.execute_block()
+        } else {
+            self.cpu.execute()
+        };
+        let executed = *result.as_ref().unwrap_or(&0);
+        if let (Some((pc, xregs)), Ok(inst), Some(hook)) = (traced, &result, &mut self.trace_hook) {
+            let after = &self.cpu.xregs;
+            let rd = (1..32)
+                .map(|i| (i, after.read(i)))
+                .find(|&(i, value)| xregs.read(i) != value);
+            hook(&TraceEntry {
+                pc,
+                inst: *inst,
+                rd,
+            });
+        }
+        let trap = match result {
+            Ok(inst) => {
+                if self.is_debug {
+                    dbg!(format!(
+                        "pc: {:#x} , inst: {:#x}, is_inst 16? {}",
+                        self.cpu.pc,
+                        inst,
+                        // Check if an instruction is one of the compressed instructions.
+                        (inst & 0xffff_0000) == 0,
+                    ));
+                }
+                // Return a dummy trap.
+                Trap::Requested
+            }
+            // Report a software breakpoint to the debugger instead of the guest.
+            Err(Exception::Breakpoint) => match self.hit_breakpoint() {
+                Some(addr) => {
+                    self.cpu.pc = addr;
+                    return Some(Halt::Breakpoint(addr));
                 }
+                None => self.take_exception(Exception::Breakpoint),
+            },
+            Err(
+                Exception::EnvironmentCallFromUMode
+                | Exception::EnvironmentCallFromSMode
+                | Exception::EnvironmentCallFromMMode,
+            ) if self.is_debug_call && self.cpu.xregs.read(17) == sbi::EID_DEBUG_DUMP => {
+                sbi::dump_registers(&mut self.cpu);
+                Trap::Requested
+            }
+            Err(Exception::EnvironmentCallFromSMode) if self.is_sbi => {
+                match sbi::handle_call(&mut self.cpu) {
+                    SbiResult::Shutdown(code) => return Some(Halt::Shutdown(code)),
+                    SbiResult::Return => Trap::Requested,
+                }
+            }
+            Err(exception) => self.take_exception(exception),
+        };
+
+        if let Trap::Fatal = trap {
+            return Some(Halt::FatalTrap);
+        }
+
+        // Return control to the embedder instead of running a trap handler.
+        if self.cpu.mode == Mode::Debug {
+            if let Some(hook) = &mut self.debug_entry_hook {
+                hook(&mut self.cpu);
+            }
+            return Some(Halt::Debug);
+        }
+
+        // Stop when the guest requests to exit via HTIF.
+        if let Some(htif) = &self.cpu.bus.htif {
+            if let Some(code) = htif.exit_code() {
+                return Some(Halt::Shutdown(code));
+            }
+        }
+
+        self.schedule(executed);
+        None
+    }
+
+    /// Run a cycle on peripheral devices. The CLINT only serves hart 0, and the time of every
+    /// hart advances.
+    fn devices_increment(&mut self) {
+        if self.harts.is_empty() {
+            self.cpu.devices_increment();
+            return;
+        }
+        for (hartid, context) in self.harts.iter_mut().enumerate() {
+            let state = if hartid == self.current_hart {
+                &mut self.cpu.state
+            } else {
+                &mut context.state
+            };
+            if hartid == 0 {
+                self.cpu.bus.clint.increment(state);
+            }
+            state.increment_time();
+        }
+    }
+
+    /// Switch to the next hart if the running hart has used up its quantum, or if `inst`, which
+    /// it has just executed, is a memory barrier and `switch_on_barrier` is set.
+    fn schedule(&mut self, inst: u64) {
+        if self.harts.is_empty() {
+            return;
+        }
+        self.executed_in_quantum += 1;
+        if self.executed_in_quantum >= self.quantum || (self.switch_on_barrier && is_barrier(inst))
+        {
+            let next = (self.current_hart + 1) % self.harts.len();
+            self.switch_to_hart(next);
+        }
+    }
+
+    /// Let the guest take `exception`, and take a checkpoint of it for the hook, or for the
+    /// embedder if it's fatal.
+    fn take_exception(&mut self, exception: Exception) -> Trap {
+        let mode = self.cpu.mode;
+        let trap = exception.take_trap(&mut self.cpu);
+        let is_fatal = matches!(trap, Trap::Fatal);
+        if is_fatal || self.trap_checkpoint_hook.is_some() {
+            let checkpoint = self.checkpoint_trap(mode);
+            match &mut self.trap_checkpoint_hook {
+                Some(hook) if !is_fatal => hook(&checkpoint),
                 _ => {}
             }
+            if is_fatal {
+                self.fatal_checkpoint = Some(checkpoint);
+            }
+        }
+        trap
+    }
+
+    /// Return the checkpoint of the exception which the running hart has just taken from `mode`.
+    fn checkpoint_trap(&self, mode: Mode) -> TrapCheckpoint {
+        let cpu = &self.cpu;
+        let (cause, epc, tval) = match cpu.mode {
+            Mode::Supervisor => (SCAUSE, SEPC, STVAL),
+            _ => (MCAUSE, MEPC, MTVAL),
+        };
+        let tval = cpu.state.read(tval);
+        let mut xregs = [0; 32];
+        for (i, value) in xregs.iter_mut().enumerate() {
+            *value = cpu.xregs.read(i as u64);
+        }
+
+        let dram = cpu.bus.dram();
+        let dram_end = DRAM_BASE + dram.len() as u64;
+        let start = tval
+            .saturating_sub(TRAP_CHECKPOINT_WINDOW)
+            .clamp(DRAM_BASE, dram_end);
+        let end = tval
+            .saturating_add(TRAP_CHECKPOINT_WINDOW)
+            .clamp(DRAM_BASE, dram_end);
+        let memory = dram[(start - DRAM_BASE) as usize..(end - DRAM_BASE) as usize].to_vec();
+
+        TrapCheckpoint {
+            hartid: cpu.hartid,
+            mode,
+            cause: cpu.state.read(cause),
+            epc: cpu.state.read(epc),
+            tval,
+            xregs,
+            csrs: TRAP_CHECKPOINT_CSRS
+                .iter()
+                .map(|&csr| (csr, cpu.state.read(csr)))
+                .collect(),
+            memory_base: start,
+            memory,
+        }
+    }
+
+    /// Act on SIGINT of the host as `sigint_behavior` says. Return the reason to stop if the
+    /// emulator halts.
+    fn deliver_sigint(&mut self) -> Option<Halt> {
+        match self.sigint_behavior? {
+            // The byte is queued like typed input, so it's recorded if recording.
+            SigintBehavior::InjectByte(byte) => self.cpu.bus.uart.push_input(&[byte]),
+            SigintBehavior::RaiseInterrupt(irq) => self.cpu.bus.plic.set_pending_id(irq),
+            SigintBehavior::Halt => return Some(Halt::Interrupted),
+        }
+        None
+    }
+
+    /// Deliver a byte to the UART if it can take one, either from the host or from the replayed
+    /// log. The other UARTs only take the bytes pushed by the host, which aren't recorded.
+    fn deliver_input(&mut self) {
+        for port in &mut self.cpu.bus.uarts {
+            if port.uart.is_ready_to_receive() {
+                if let Some(byte) = port.uart.host_input() {
+                    port.uart.receive(byte);
+                }
+            }
+        }
+
+        let uart = &mut self.cpu.bus.uart;
+        if !uart.is_ready_to_receive() {
+            return;
         }
+
+        let byte = match &mut self.replayer {
+            Some(replayer) => replayer.next_uart_input(self.ticks),
+            None => uart.host_input(),
+        };
+        if let Some(byte) = byte {
+            if let Some(recorder) = &mut self.recorder {
+                if let Err(e) = recorder.record_uart_input(self.ticks, byte) {
+                    // A recording with a missing input can't be replayed, so stop recording.
+                    warn!("failed to record an input, so the recording stops: {}", e);
+                    self.recorder = None;
+                }
+            }
+            uart.receive(byte);
+        }
+    }
+}
+
+/// Return true if `inst` orders memory accesses with other harts: `fence` or `fence.tso` whose
+/// predecessor and successor sets aren't empty, or an atomic instruction with the aq or rl bit.
+fn is_barrier(inst: u64) -> bool {
+    let opcode = inst & 0x7f;
+    let funct3 = (inst >> 12) & 0x7;
+    match opcode {
+        // A fence with an empty set orders nothing, and it's used as a hint.
+        0x0f => funct3 == 0x0 && (inst >> 24) & 0xf != 0 && (inst >> 20) & 0xf != 0,
+        0x2f => (inst >> 25) & 0b11 != 0,
+        _ => false,
     }
 }
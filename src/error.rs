@@ -0,0 +1,45 @@
+//! The error module contains the errors of the configuration by the host, such as adding a device,
+//! as opposed to the exceptions which the guest raises.
+
+use alloc::string::String;
+use core::fmt;
+
+/// An error in the configuration of the emulator by the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The interrupt source isn't one of the PLIC sources 1 to 1023.
+    InvalidIrq(u32),
+    /// The region of `size` bytes at `base` overlaps the region `name` which is already on the
+    /// bus.
+    Overlap { base: u64, size: u64, name: String },
+    /// The region of `size` bytes at `base` wraps around the end of the address space.
+    Wraparound { base: u64, size: u64 },
+    /// The region of `size` bytes at `base` which must be in DRAM doesn't fit in it.
+    OutOfDram { base: u64, size: u64 },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::InvalidIrq(irq) => write!(f, "invalid interrupt source: {}", irq),
+            ConfigError::Overlap { base, size, name } => write!(
+                f,
+                "the region of {:#x} bytes at {:#x} overlaps {}",
+                size, base, name
+            ),
+            ConfigError::Wraparound { base, size } => write!(
+                f,
+                "the region of {:#x} bytes at {:#x} wraps around the address space",
+                size, base
+            ),
+            ConfigError::OutOfDram { base, size } => write!(
+                f,
+                "the region of {:#x} bytes at {:#x} doesn't fit in DRAM",
+                size, base
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ConfigError {}
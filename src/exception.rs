@@ -1,5 +1,7 @@
 //! The exception module contains all the exception kinds and the function to handle exceptions.
 
+use log::{debug, warn};
+
 use crate::{
     cpu::{Cpu, Mode},
     csr::*,
@@ -9,21 +11,28 @@ use crate::{
 #[derive(Debug)]
 pub enum Exception {
     /// With the addition of the C extension, no instructions can raise
-    /// instruction-address-misaligned exceptions.
-    InstructionAddressMisaligned,
+    /// instruction-address-misaligned exceptions. The payload is the misaligned target address.
+    InstructionAddressMisaligned(u64),
     InstructionAccessFault,
-    IllegalInstruction,
+    /// The payload is the faulting instruction at its actual width, so a compressed instruction
+    /// is zero-extended from 16 bits.
+    IllegalInstruction(u64),
     Breakpoint,
-    LoadAddressMisaligned,
+    /// The payload is the misaligned virtual address.
+    LoadAddressMisaligned(u64),
     LoadAccessFault,
-    StoreAMOAddressMisaligned,
+    /// The payload is the misaligned virtual address.
+    StoreAMOAddressMisaligned(u64),
     StoreAMOAccessFault,
     EnvironmentCallFromUMode,
     EnvironmentCallFromSMode,
     EnvironmentCallFromMMode,
-    InstructionPageFault,
-    LoadPageFault,
-    StoreAMOPageFault,
+    /// The payload is the faulting virtual address.
+    InstructionPageFault(u64),
+    /// The payload is the faulting virtual address.
+    LoadPageFault(u64),
+    /// The payload is the faulting virtual address.
+    StoreAMOPageFault(u64),
 }
 
 /// All the trap kinds.
@@ -46,20 +55,34 @@ pub enum Trap {
 impl Exception {
     fn exception_code(&self) -> u64 {
         match self {
-            Exception::InstructionAddressMisaligned => 0,
+            Exception::InstructionAddressMisaligned(_) => 0,
             Exception::InstructionAccessFault => 1,
-            Exception::IllegalInstruction => 2,
+            Exception::IllegalInstruction(_) => 2,
             Exception::Breakpoint => 3,
-            Exception::LoadAddressMisaligned => 4,
+            Exception::LoadAddressMisaligned(_) => 4,
             Exception::LoadAccessFault => 5,
-            Exception::StoreAMOAddressMisaligned => 6,
+            Exception::StoreAMOAddressMisaligned(_) => 6,
             Exception::StoreAMOAccessFault => 7,
             Exception::EnvironmentCallFromUMode => 8,
             Exception::EnvironmentCallFromSMode => 9,
             Exception::EnvironmentCallFromMMode => 11,
-            Exception::InstructionPageFault => 12,
-            Exception::LoadPageFault => 13,
-            Exception::StoreAMOPageFault => 15,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StoreAMOPageFault(_) => 15,
+        }
+    }
+
+    /// Return the exception-specific value written to stval or mtval when the trap is taken.
+    fn trap_value(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(addr)
+            | Exception::LoadAddressMisaligned(addr)
+            | Exception::StoreAMOAddressMisaligned(addr)
+            | Exception::InstructionPageFault(addr)
+            | Exception::LoadPageFault(addr)
+            | Exception::StoreAMOPageFault(addr) => *addr,
+            Exception::IllegalInstruction(inst) => *inst,
+            _ => 0,
         }
     }
 
@@ -73,6 +96,7 @@ impl Exception {
         cpu.prev_mode = cpu.mode;
 
         let cause = self.exception_code();
+        let tval = self.trap_value();
 
         // 3.1.8 Machine Trap Delegation Registers (medeleg and mideleg)
         // "By default, all traps at any privilege level are handled in machine mode"
@@ -88,9 +112,14 @@ impl Exception {
         {
             // Handle the trap in S-mode.
             cpu.mode = Mode::Supervisor;
+            debug!(
+                "exception {:?} at {:#x} delegated from {:?} to {:?} mode",
+                self, exception_pc, cpu.prev_mode, cpu.mode
+            );
 
             // Set the program counter to the supervisor trap-handler base address (stvec).
-            cpu.pc = (cpu.state.read(STVEC) & !1) as u64;
+            // Exceptions always go to the base address, even in vectored mode.
+            cpu.pc = cpu.state.read(STVEC) & !0b11;
 
             // 4.1.9 Supervisor Exception Program Counter (sepc)
             // "The low bit of sepc (sepc[0]) is always zero."
@@ -115,7 +144,7 @@ impl Exception {
             // written with the faulting virtual address. On an illegal instruction trap,
             // stval may be written with the first XLEN or ILEN bits of the faulting
             // instruction as described below. For other exceptions, stval is set to zero."
-            cpu.state.write(STVAL, 0);
+            cpu.state.write(STVAL, tval);
 
             // Set a privious interrupt-enable bit for supervisor mode (SPIE, 5) to the value
             // of a global interrupt-enable bit for supervisor mode (SIE, 1).
@@ -133,9 +162,14 @@ impl Exception {
         } else {
             // Handle the trap in M-mode.
             cpu.mode = Mode::Machine;
+            debug!(
+                "exception {:?} at {:#x} taken from {:?} to {:?} mode",
+                self, exception_pc, cpu.prev_mode, cpu.mode
+            );
 
             // Set the program counter to the machine trap-handler base address (mtvec).
-            cpu.pc = (cpu.state.read(MTVEC) & !1) as u64;
+            // Exceptions always go to the base address, even in vectored mode.
+            cpu.pc = cpu.state.read(MTVEC) & !0b11;
 
             // 3.1.15 Machine Exception Program Counter (mepc)
             // "The low bit of mepc (mepc[0]) is always zero."
@@ -161,7 +195,7 @@ impl Exception {
             // written with the faulting virtual address. On an illegal instruction trap,
             // mtval may be written with the first XLEN or ILEN bits of the faulting
             // instruction as described below. For other traps, mtval is set to zero."
-            cpu.state.write(MTVAL, 0);
+            cpu.state.write(MTVAL, tval);
 
             // Set a privious interrupt-enable bit for supervisor mode (MPIE, 7) to the value
             // of a global interrupt-enable bit for supervisor mode (MIE, 3).
@@ -175,26 +209,31 @@ impl Exception {
                 Mode::User => cpu.state.write_bits(MSTATUS, 11..13, 0b00),
                 Mode::Supervisor => cpu.state.write_bits(MSTATUS, 11..13, 0b01),
                 Mode::Machine => cpu.state.write_bits(MSTATUS, 11..13, 0b11),
-                _ => panic!("previous privilege mode is invalid"),
+                // Debug mode has no encoding in MPP, so return to M-mode, which is the most
+                // privileged mode in the field.
+                Mode::Debug => {
+                    warn!("a trap is taken from debug mode, so MPP is set to M-mode");
+                    cpu.state.write_bits(MSTATUS, 11..13, 0b11)
+                }
             }
         }
 
         match self {
-            Exception::InstructionAddressMisaligned | Exception::InstructionAccessFault => {
+            Exception::InstructionAddressMisaligned(_) | Exception::InstructionAccessFault => {
                 Trap::Fatal
             }
-            Exception::IllegalInstruction => Trap::Invisible,
+            Exception::IllegalInstruction(_) => Trap::Invisible,
             Exception::Breakpoint => Trap::Requested,
-            Exception::LoadAddressMisaligned
+            Exception::LoadAddressMisaligned(_)
             | Exception::LoadAccessFault
-            | Exception::StoreAMOAddressMisaligned
+            | Exception::StoreAMOAddressMisaligned(_)
             | Exception::StoreAMOAccessFault => Trap::Fatal,
             Exception::EnvironmentCallFromUMode
             | Exception::EnvironmentCallFromSMode
             | Exception::EnvironmentCallFromMMode => Trap::Requested,
-            Exception::InstructionPageFault
-            | Exception::LoadPageFault
-            | Exception::StoreAMOPageFault => Trap::Invisible,
+            Exception::InstructionPageFault(_)
+            | Exception::LoadPageFault(_)
+            | Exception::StoreAMOPageFault(_) => Trap::Invisible,
         }
     }
 }
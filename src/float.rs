@@ -0,0 +1,75 @@
+//! The float module provides the floating-point functions which `core` lacks, backed by `libm`.
+//! `std` has them as inherent methods, which take precedence, so it's only needed without `std`.
+
+/// The floating-point functions used by the interpreter.
+pub trait Float {
+    fn sqrt(self) -> Self;
+    fn mul_add(self, a: Self, b: Self) -> Self;
+    fn trunc(self) -> Self;
+    fn floor(self) -> Self;
+    fn ceil(self) -> Self;
+    fn round(self) -> Self;
+    fn round_ties_even(self) -> Self;
+}
+
+impl Float for f32 {
+    fn sqrt(self) -> Self {
+        libm::sqrtf(self)
+    }
+
+    fn mul_add(self, a: Self, b: Self) -> Self {
+        libm::fmaf(self, a, b)
+    }
+
+    fn trunc(self) -> Self {
+        libm::truncf(self)
+    }
+
+    fn floor(self) -> Self {
+        libm::floorf(self)
+    }
+
+    fn ceil(self) -> Self {
+        libm::ceilf(self)
+    }
+
+    fn round(self) -> Self {
+        libm::roundf(self)
+    }
+
+    fn round_ties_even(self) -> Self {
+        // `rint` rounds in the default rounding mode, which is to nearest, ties to even.
+        libm::rintf(self)
+    }
+}
+
+impl Float for f64 {
+    fn sqrt(self) -> Self {
+        libm::sqrt(self)
+    }
+
+    fn mul_add(self, a: Self, b: Self) -> Self {
+        libm::fma(self, a, b)
+    }
+
+    fn trunc(self) -> Self {
+        libm::trunc(self)
+    }
+
+    fn floor(self) -> Self {
+        libm::floor(self)
+    }
+
+    fn ceil(self) -> Self {
+        libm::ceil(self)
+    }
+
+    fn round(self) -> Self {
+        libm::round(self)
+    }
+
+    fn round_ties_even(self) -> Self {
+        // `rint` rounds in the default rounding mode, which is to nearest, ties to even.
+        libm::rint(self)
+    }
+}
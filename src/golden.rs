@@ -0,0 +1,94 @@
+//! The golden module compares the instructions a program executes with a trace checked in
+//! beforehand, in order to catch changes of the semantics of instructions.
+//!
+//! Each line of a golden file is the `Display` form of a `TraceEntry`: `<pc> <inst> <rd> <value>`
+//! in hexadecimal, where `rd` and `value` are `-` if the instruction didn't change any integer
+//! register, e.g., `80000000 02a00f93 x31 2a`.
+
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::Path;
+
+use crate::emulator::TraceEntry;
+
+/// The writer which saves a trace as a golden file.
+pub struct GoldenWriter {
+    file: File,
+}
+
+impl GoldenWriter {
+    /// Create a new golden file at `path`, truncating it if it exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            file: File::create(path)?,
+        })
+    }
+
+    /// Append `entry` to the file.
+    pub fn write(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        writeln!(self.file, "{}", entry)
+    }
+}
+
+/// The checker which compares a trace with a golden file entry by entry.
+pub struct GoldenChecker {
+    lines: Vec<String>,
+    /// The number of entries which have matched so far.
+    matched: usize,
+    /// The first divergence, which is kept until it's taken.
+    divergence: Option<io::Error>,
+}
+
+impl GoldenChecker {
+    /// Load a golden file at `path`. Empty lines are ignored.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let lines = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect();
+        Ok(Self {
+            lines,
+            matched: 0,
+            divergence: None,
+        })
+    }
+
+    /// Compare `entry` with the next line. Entries after the first divergence are ignored.
+    pub fn check(&mut self, entry: &TraceEntry) {
+        if self.divergence.is_some() || self.is_finished() {
+            return;
+        }
+        let actual = entry.to_string();
+        let expected = &self.lines[self.matched];
+        if actual == *expected {
+            self.matched += 1;
+            return;
+        }
+        self.divergence = Some(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the trace diverged from the golden file at instruction {}:\n- {}\n+ {}",
+                self.matched + 1,
+                expected,
+                actual
+            ),
+        ));
+    }
+
+    /// Return true if every line of the golden file has matched.
+    pub fn is_finished(&self) -> bool {
+        self.matched == self.lines.len()
+    }
+
+    /// Return the number of lines which have matched and the number of all lines.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.matched, self.lines.len())
+    }
+
+    /// Take the first divergence if the trace has diverged.
+    pub fn take_divergence(&mut self) -> Option<io::Error> {
+        self.divergence.take()
+    }
+}
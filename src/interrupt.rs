@@ -1,12 +1,14 @@
 //! The interrupt module contains all the interrupt kinds and the function to handle interrupts.
 
+use log::{debug, warn};
+
 use crate::{
     cpu::{Cpu, Mode},
     csr::*,
 };
 
 /// All the interrupt kinds.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Interrupt {
     UserSoftwareInterrupt,
     SupervisorSoftwareInterrupt,
@@ -63,14 +65,18 @@ impl Interrupt {
         {
             // Handle the trap in S-mode.
             cpu.mode = Mode::Supervisor;
+            debug!(
+                "interrupt {:?} at {:#x} delegated from {:?} to {:?} mode",
+                self, exception_pc, cpu.prev_mode, cpu.mode
+            );
 
             // Set the program counter to the supervisor trap-handler base address (stvec)
-            // depending on the mode.
+            // depending on the mode. The two MODE bits aren't a part of the address.
             let vector = match cpu.state.read_bit(STVEC, 0) {
                 1 => 4 * cause, // vectored mode
                 _ => 0,         // direct mode
             };
-            cpu.pc = ((cpu.state.read(STVEC) & !1) + vector) as u64;
+            cpu.pc = (cpu.state.read(STVEC) & !0b11) + vector;
 
             // 4.1.9 Supervisor Exception Program Counter (sepc)
             // "The low bit of sepc (sepc[0]) is always zero."
@@ -113,14 +119,18 @@ impl Interrupt {
         } else {
             // Handle the trap in M-mode.
             cpu.mode = Mode::Machine;
+            debug!(
+                "interrupt {:?} at {:#x} taken from {:?} to {:?} mode",
+                self, exception_pc, cpu.prev_mode, cpu.mode
+            );
 
             // Set the program counter to the machine trap-handler base address (mtvec)
-            // depending on the mode.
+            // depending on the mode. The two MODE bits aren't a part of the address.
             let vector = match cpu.state.read_bit(MTVEC, 0) {
                 1 => 4 * cause, // vectored mode
                 _ => 0,         // direct mode
             };
-            cpu.pc = ((cpu.state.read(MTVEC) & !1) + vector) as u64;
+            cpu.pc = (cpu.state.read(MTVEC) & !0b11) + vector;
 
             // 3.1.15 Machine Exception Program Counter (mepc)
             // "The low bit of mepc (mepc[0]) is always zero."
@@ -160,7 +170,12 @@ impl Interrupt {
                 Mode::User => cpu.state.write_bits(MSTATUS, 11..13, 0b00),
                 Mode::Supervisor => cpu.state.write_bits(MSTATUS, 11..13, 0b01),
                 Mode::Machine => cpu.state.write_bits(MSTATUS, 11..13, 0b11),
-                _ => panic!("previous privilege mode is invalid"),
+                // Debug mode has no encoding in MPP, so return to M-mode, which is the most
+                // privileged mode in the field.
+                Mode::Debug => {
+                    warn!("a trap is taken from debug mode, so MPP is set to M-mode");
+                    cpu.state.write_bits(MSTATUS, 11..13, 0b11)
+                }
             }
         }
     }
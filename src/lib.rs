@@ -30,13 +30,40 @@
 //!
 //! See the example usage in
 //! [rvemu/lib/rvemu-cli/src/main.rs](https://github.com/d0iasm/rvemu/blob/master/lib/rvemu-cli/src/main.rs).
+//!
+//! # Robustness
+//! A guest bug never crashes the host. An access past the end of ROM or DRAM always raises an
+//! access fault, and the errors of the host which the guest can't act on are only logged.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
 pub mod bus;
 pub mod cpu;
 pub mod csr;
 pub mod devices;
 pub mod dram;
+#[cfg(feature = "std")]
 pub mod emulator;
+pub mod error;
 pub mod exception;
+#[cfg(not(feature = "std"))]
+mod float;
+#[cfg(feature = "std")]
+pub mod golden;
 pub mod interrupt;
+#[cfg(feature = "std")]
+pub mod repl;
+#[cfg(feature = "std")]
+pub mod replay;
+pub mod reservation;
 pub mod rom;
+pub mod sbi;
+#[cfg(feature = "std")]
+pub mod spike;
+#[cfg(feature = "threaded")]
+pub mod threaded;
+pub mod tlb;
+#[cfg(feature = "std")]
+pub mod wasm;
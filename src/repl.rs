@@ -0,0 +1,142 @@
+//! The repl module contains a command loop to debug a guest interactively without GDB. Each line
+//! is one of the commands below. Addresses are physical, and only DRAM is shown since reading
+//! the registers of a device may change its state.
+//!
+//! - `step [n]`: execute `n` instructions, 1 by default.
+//! - `continue`: execute instructions until the emulator stops, e.g., at a breakpoint.
+//! - `regs`: show the integer registers and the program counter.
+//! - `mem <addr> <len>`: show `len` bytes of memory from `addr`.
+//! - `break <addr>` and `delete <addr>`: set and remove a software breakpoint.
+//! - `disas [n]` or `hex [n]`: show the encodings of `n` instructions from the program counter,
+//!   5 by default. The program counter is a virtual address, which is translated by the current
+//!   page tables.
+//! - `quit`: leave the loop.
+
+use std::io::{self, BufRead, Write};
+
+use crate::bus::DRAM_BASE;
+use crate::emulator::{Emulator, Halt};
+
+/// The number of instructions `disas` shows by default.
+const DISAS_COUNT: u64 = 5;
+
+/// Read commands from `input` until `quit` or the end of the input, and write their results to
+/// `output`.
+pub fn run<R: BufRead, W: Write>(emu: &mut Emulator, input: R, output: &mut W) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            [] => {}
+            ["quit"] => break,
+            ["step"] => resume(emu, 1, output)?,
+            ["step", n] => match parse_number(n) {
+                Some(n) => resume(emu, n, output)?,
+                None => writeln!(output, "invalid count: {}", n)?,
+            },
+            ["continue"] => resume(emu, u64::MAX, output)?,
+            ["regs"] => {
+                writeln!(output, "{}", emu.cpu.xregs)?;
+                writeln!(output, "pc: {:#x}", emu.cpu.pc)?;
+            }
+            ["mem", addr, len] => match (parse_number(addr), parse_number(len)) {
+                (Some(addr), Some(len)) => dump_memory(emu, addr, len, output)?,
+                _ => writeln!(output, "usage: mem <addr> <len>")?,
+            },
+            ["break", addr] => match parse_number(addr).map(|a| (a, emu.insert_breakpoint(a))) {
+                Some((addr, Ok(()))) => writeln!(output, "breakpoint at {:#x}", addr)?,
+                Some((addr, Err(_))) => writeln!(output, "can't set a breakpoint at {:#x}", addr)?,
+                None => writeln!(output, "invalid address: {}", addr)?,
+            },
+            ["delete", addr] => match parse_number(addr).map(|a| (a, emu.remove_breakpoint(a))) {
+                Some((addr, Ok(true))) => writeln!(output, "deleted breakpoint at {:#x}", addr)?,
+                Some((addr, _)) => writeln!(output, "no breakpoint at {:#x}", addr)?,
+                None => writeln!(output, "invalid address: {}", addr)?,
+            },
+            ["disas"] | ["hex"] => show_encodings(emu, DISAS_COUNT, output)?,
+            ["disas", n] | ["hex", n] => match parse_number(n) {
+                Some(n) => show_encodings(emu, n, output)?,
+                None => writeln!(output, "invalid count: {}", n)?,
+            },
+            _ => writeln!(output, "unknown command: {}", line.trim())?,
+        }
+    }
+    Ok(())
+}
+
+/// Parse a hexadecimal number prefixed with `0x` or a decimal number.
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Execute at most `max_instructions` instructions and report why the emulator stopped.
+fn resume<W: Write>(emu: &mut Emulator, max_instructions: u64, output: &mut W) -> io::Result<()> {
+    match emu.resume(max_instructions) {
+        Halt::InstructionLimit => writeln!(output, "pc: {:#x}", emu.cpu.pc),
+        Halt::Breakpoint(addr) => writeln!(output, "breakpoint at {:#x}", addr),
+        halt => writeln!(output, "stopped at {:#x}: {:?}", emu.cpu.pc, halt),
+    }
+}
+
+/// Return the byte of DRAM at the physical address `addr`, or `None` outside DRAM.
+fn peek(emu: &Emulator, addr: u64) -> Option<u8> {
+    let offset = addr.checked_sub(DRAM_BASE)?;
+    emu.cpu.bus.dram().get(offset as usize).copied()
+}
+
+/// Return the halfword of an instruction at the virtual address `vaddr`, or `None` if it isn't
+/// mapped to DRAM. A halfword-aligned halfword doesn't cross a page.
+fn peek_inst(emu: &mut Emulator, vaddr: u64) -> Option<u64> {
+    let addr = emu.cpu.translate_inst(vaddr).ok()?;
+    let low = peek(emu, addr)?;
+    let high = peek(emu, addr.wrapping_add(1))?;
+    Some(u16::from_le_bytes([low, high]) as u64)
+}
+
+/// Show `len` bytes of DRAM from `addr`, 16 bytes per line. A byte outside DRAM is shown as `??`.
+fn dump_memory<W: Write>(
+    emu: &mut Emulator,
+    addr: u64,
+    len: u64,
+    output: &mut W,
+) -> io::Result<()> {
+    for line in (0..len).step_by(16) {
+        let line_addr = addr.wrapping_add(line);
+        write!(output, "{:#x}:", line_addr)?;
+        for i in 0..16.min(len - line) {
+            match peek(emu, line_addr.wrapping_add(i)) {
+                Some(byte) => write!(output, " {:02x}", byte)?,
+                None => write!(output, " ??")?,
+            }
+        }
+        writeln!(output)?;
+    }
+    Ok(())
+}
+
+/// Show the encodings of `count` instructions from the program counter. A compressed instruction
+/// is shown with 4 hex digits. The halves of a 32-bit instruction are translated separately, since
+/// it may cross a page.
+fn show_encodings<W: Write>(emu: &mut Emulator, count: u64, output: &mut W) -> io::Result<()> {
+    let mut addr = emu.cpu.pc;
+    for _ in 0..count {
+        let low = match peek_inst(emu, addr) {
+            Some(low) => low,
+            None => return writeln!(output, "{:#x}: ????", addr),
+        };
+        if low & 0b11 != 0b11 {
+            writeln!(output, "{:#x}: {:04x}", addr, low)?;
+            addr = addr.wrapping_add(2);
+            continue;
+        }
+        match peek_inst(emu, addr.wrapping_add(2)) {
+            Some(high) => writeln!(output, "{:#x}: {:08x}", addr, (high << 16) | low)?,
+            None => return writeln!(output, "{:#x}: ????????", addr),
+        }
+        addr = addr.wrapping_add(4);
+    }
+    Ok(())
+}
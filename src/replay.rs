@@ -0,0 +1,73 @@
+//! The replay module records the non-deterministic inputs of a run to a file and feeds them back
+//! in a later run so that the guest behaves identically.
+//!
+//! Each line of the file is `uart <tick> <byte>`, which means `byte` was delivered to the UART at
+//! the `tick`-th cycle of the emulator. The timer only counts cycles, so it's deterministic and
+//! isn't recorded.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::Path;
+
+/// The writer which appends each input to a log file.
+pub struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    /// Create a new log file at `path`, truncating it if it exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            file: File::create(path)?,
+        })
+    }
+
+    /// Record that `byte` was delivered to the UART at `tick`.
+    pub fn record_uart_input(&mut self, tick: u64, byte: u8) -> io::Result<()> {
+        writeln!(self.file, "uart {} {}", tick, byte)
+    }
+}
+
+/// The reader which feeds the inputs of a log file back in order.
+pub struct Replayer {
+    uart_inputs: VecDeque<(u64, u8)>,
+}
+
+impl Replayer {
+    /// Load a log file at `path` which was written by `Recorder`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut uart_inputs = VecDeque::new();
+        for line in fs::read_to_string(path)?.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["uart", tick, byte] => {
+                    let tick = tick.parse().map_err(invalid_data)?;
+                    let byte = byte.parse().map_err(invalid_data)?;
+                    uart_inputs.push_back((tick, byte));
+                }
+                [] => {}
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown record: {}", line),
+                    ))
+                }
+            }
+        }
+        Ok(Self { uart_inputs })
+    }
+
+    /// Return the next UART input if it was recorded at or before `tick`.
+    pub fn next_uart_input(&mut self, tick: u64) -> Option<u8> {
+        match self.uart_inputs.front() {
+            Some(&(t, _)) if t <= tick => self.uart_inputs.pop_front().map(|(_, byte)| byte),
+            _ => None,
+        }
+    }
+}
+
+/// Convert a parse error to an I/O error.
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,53 @@
+//! The reservation module contains the monitor of the reservations made by load-reserved (LR)
+//! instructions. It's shared by all harts on the bus so that a store from any hart or device
+//! breaks the reservations of the others.
+
+use alloc::collections::BTreeMap;
+
+/// The size of a reservation set. A store to any byte of a reserved granule breaks the
+/// reservation.
+pub const RESERVATION_GRANULE: u64 = 8;
+
+/// The reservation monitor which holds at most one reservation per hart.
+#[derive(Default)]
+pub struct ReservationMonitor {
+    /// The reserved granule of each hart, keyed by its hart ID.
+    reservations: BTreeMap<u64, u64>,
+}
+
+impl ReservationMonitor {
+    /// Create a new monitor without reservations.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserve the granule which contains the physical address `addr` for `hart`. The previous
+    /// reservation of the hart is released.
+    pub fn reserve(&mut self, hart: u64, addr: u64) {
+        self.reservations.insert(hart, granule(addr));
+    }
+
+    /// Release the reservation of `hart`, and return true if it was held on the granule which
+    /// contains `addr`.
+    pub fn release(&mut self, hart: u64, addr: u64) -> bool {
+        // "Regardless of success or failure, executing an SC instruction invalidates any
+        // reservation held by this hart."
+        self.reservations.remove(&hart) == Some(granule(addr))
+    }
+
+    /// Break the reservations of all harts on the granules which overlap `addr..addr + len`.
+    pub fn invalidate(&mut self, addr: u64, len: u64) {
+        if self.reservations.is_empty() || len == 0 {
+            return;
+        }
+        let first = granule(addr);
+        let last = granule(addr.saturating_add(len - 1));
+        self.reservations
+            .retain(|_, &mut reserved| reserved < first || reserved > last);
+    }
+}
+
+/// Return the address of the granule which contains `addr`.
+fn granule(addr: u64) -> u64 {
+    addr & !(RESERVATION_GRANULE - 1)
+}
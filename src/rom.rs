@@ -1,17 +1,22 @@
 //! The rom module contains the read-only memory structure and implementation to read the memory. ROM includes a device tree blob (DTB) compiled from a device tree source (DTS).
 
+use alloc::vec;
+use alloc::vec::Vec;
+
 use crate::bus::MROM_BASE;
 use crate::cpu::{BYTE, DOUBLEWORD, HALFWORD, WORD};
 use crate::exception::Exception;
 
-use std::fs::File;
-use std::io::prelude::*;
-use std::process::Command;
+#[cfg(feature = "std")]
+use std::{fs::File, io::prelude::*, process::Command};
 
+#[cfg(feature = "std")]
 const DTS_FILE_NAME: &str = "rvemu.dts";
+#[cfg(feature = "std")]
 const DTB_FILE_NAME: &str = "rvemu.dtb";
 
 /// Create a new dts file. If the file already existed, the old content is destroyed. Otherwise, a new file is created.
+#[cfg(feature = "std")]
 fn create_dts() -> std::io::Result<()> {
     // TODO: Make this content more flexible depending on the number of cpus.
     // Reference code is https://github.com/riscv/riscv-isa-sim/blob/66b44bfbedda562a32e4a2cd0716afbf731b69cd/riscv/dts.cc#L38-L54
@@ -62,7 +67,9 @@ fn create_dts() -> std::io::Result<()> {
             reg = <0x00>;
             status = "okay";
             compatible = "riscv";
-            riscv,isa = "rv64imafdcsu";
+            riscv,isa = "rv64imafdcsu_zicbom_zicboz";
+            riscv,cbom-block-size = <0x40>;
+            riscv,cboz-block-size = <0x40>;
             mmu-type = "riscv,sv48";
 
             interrupt-controller {
@@ -110,6 +117,7 @@ fn create_dts() -> std::io::Result<()> {
 }
 
 /// Compile a dts file to a dtb file.
+#[cfg(feature = "std")]
 fn compile_dts() -> std::io::Result<()> {
     // dtc -I dts -O dtb -o <FILE_NAME>.dtb <FILE_NAME>.dts
     Command::new("dtc")
@@ -119,6 +127,7 @@ fn compile_dts() -> std::io::Result<()> {
 }
 
 /// Read a dtb file. First, create a dts file. Second, compile it to a dtb file. Finally, read the dtb file and return the binary content.
+#[cfg(feature = "std")]
 fn dtb() -> std::io::Result<Vec<u8>> {
     create_dts()?;
     compile_dts()?;
@@ -129,15 +138,20 @@ fn dtb() -> std::io::Result<Vec<u8>> {
     Ok(dtb)
 }
 
+/// The offset of the DTB in the ROM. 0x20 is the size of a reset vector.
+pub const DTB_OFFSET: u64 = 0x20;
+
 /// The read-only memory (ROM).
 pub struct Rom {
     data: Vec<u8>,
+    dtb: Vec<u8>,
 }
 
 impl Rom {
     /// Create a new `rom` object.
     pub fn new() -> Self {
-        let mut dtb = match dtb() {
+        #[cfg(feature = "std")]
+        let dtb = match dtb() {
             Ok(dtb) => dtb,
             Err(e) => {
                 // TODO: should fail?
@@ -148,19 +162,66 @@ impl Rom {
                 Vec::new()
             }
         };
+        // `dtc` isn't available without `std`. The DTB can be set by `set_dtb` instead.
+        #[cfg(not(feature = "std"))]
+        let dtb = Vec::new();
+
+        let mut rom = Self {
+            data: Vec::new(),
+            dtb: Vec::new(),
+        };
+        rom.set_dtb(dtb);
+        rom
+    }
 
+    /// Return the device tree blob in the ROM.
+    pub fn dtb(&self) -> &[u8] {
+        &self.dtb
+    }
+
+    /// Replace the device tree blob in the ROM.
+    pub fn set_dtb(&mut self, dtb: Vec<u8>) {
         // TODO: set a reset vector correctly.
-        // 0x20 is the size of a reset vector.
-        let mut rom = vec![0; 32];
-        rom.append(&mut dtb);
+        let mut rom = vec![0; DTB_OFFSET as usize];
+        rom.extend_from_slice(&dtb);
         let align = 0x1000;
         rom.resize((rom.len() + align - 1) / align * align, 0);
 
-        Self { data: rom }
+        self.data = rom;
+        self.dtb = dtb;
+    }
+
+    /// Set the property `name` of the `/chosen` node in the DTB to `value`. The node is created if
+    /// it doesn't exist. Return false if the ROM doesn't have a valid DTB.
+    pub fn set_chosen_property(&mut self, name: &str, value: &[u8]) -> bool {
+        match set_chosen_property(&self.dtb, name, value) {
+            Some(dtb) => {
+                self.set_dtb(dtb);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add the node `name` with the properties `props` to the root node of the DTB, as a device
+    /// enabled after the DTB has been compiled. The node is kept if the root already has it.
+    /// Return false if the ROM doesn't have a valid DTB.
+    pub fn add_node(&mut self, name: &str, props: &[(&str, &[u8])]) -> bool {
+        match add_root_node(&self.dtb, name, props) {
+            Some(dtb) => {
+                self.set_dtb(dtb);
+                true
+            }
+            None => false,
+        }
     }
 
     /// Load `size`-bit data from the memory.
     pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
+        // The ROM region is larger than its contents.
+        if addr - MROM_BASE + size as u64 / 8 > self.data.len() as u64 {
+            return Err(Exception::LoadAccessFault);
+        }
         match size {
             BYTE => Ok(self.read8(addr)),
             HALFWORD => Ok(self.read16(addr)),
@@ -209,3 +270,281 @@ impl Rom {
             | ((self.data[index + 7] as u64) << 56);
     }
 }
+
+/// The magic number at the beginning of a DTB.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// The tokens of the structure block.
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// The offsets of the header fields used here.
+const FDT_TOTALSIZE: usize = 0x4;
+const FDT_OFF_DT_STRUCT: usize = 0x8;
+const FDT_OFF_DT_STRINGS: usize = 0xc;
+const FDT_SIZE_DT_STRINGS: usize = 0x20;
+const FDT_SIZE_DT_STRUCT: usize = 0x24;
+const FDT_HEADER_SIZE: usize = 0x28;
+
+/// Read a big-endian 32-bit value at `offset`.
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Round `len` up to the 4-byte alignment of the structure block.
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Return the offsets and the sizes of the structure block and the strings block of `dtb`.
+fn blocks(dtb: &[u8]) -> Option<(usize, usize, usize, usize)> {
+    if be32(dtb, 0)? != FDT_MAGIC {
+        return None;
+    }
+    let off_struct = be32(dtb, FDT_OFF_DT_STRUCT)? as usize;
+    let size_struct = be32(dtb, FDT_SIZE_DT_STRUCT)? as usize;
+    let off_strings = be32(dtb, FDT_OFF_DT_STRINGS)? as usize;
+    let size_strings = be32(dtb, FDT_SIZE_DT_STRINGS)? as usize;
+    Some((off_struct, size_struct, off_strings, size_strings))
+}
+
+/// Return the nul-terminated string at `offset`.
+fn c_str(data: &[u8], offset: usize) -> Option<&[u8]> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|&b| b == 0)?;
+    Some(&bytes[..len])
+}
+
+/// The positions in the structure block found by `find_chosen`.
+struct ChosenNode {
+    /// The offset where a property of `/chosen` can be inserted, or where `/chosen` itself can be
+    /// inserted if `exists` is false.
+    insert_at: usize,
+    exists: bool,
+    /// The range of the property being looked for, if any.
+    prop: Option<(usize, usize)>,
+}
+
+/// Walk the structure block `dt_struct` and find the `/chosen` node and its property `name`.
+fn find_chosen(dt_struct: &[u8], strings: &[u8], name: &str) -> Option<ChosenNode> {
+    let mut pos = 0;
+    let mut depth = 0;
+    let mut in_chosen = false;
+    let mut chosen: Option<ChosenNode> = None;
+    loop {
+        let token = be32(dt_struct, pos)?;
+        let start = pos;
+        pos += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let node_name = c_str(dt_struct, pos)?;
+                pos += align4(node_name.len() + 1);
+                depth += 1;
+                // Unit addresses aren't expected on `/chosen`.
+                if depth == 2 && node_name == b"chosen" {
+                    in_chosen = true;
+                    chosen = Some(ChosenNode {
+                        insert_at: pos,
+                        exists: true,
+                        prop: None,
+                    });
+                }
+            }
+            FDT_END_NODE => {
+                if depth == 2 {
+                    in_chosen = false;
+                }
+                if depth == 1 && chosen.is_none() {
+                    // Add `/chosen` as the last child of the root node.
+                    chosen = Some(ChosenNode {
+                        insert_at: start,
+                        exists: false,
+                        prop: None,
+                    });
+                }
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = be32(dt_struct, pos)? as usize;
+                let nameoff = be32(dt_struct, pos + 4)? as usize;
+                pos += 8 + align4(len);
+                if in_chosen && c_str(strings, nameoff)? == name.as_bytes() {
+                    if let Some(chosen) = &mut chosen {
+                        chosen.prop = Some((start, pos));
+                    }
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => return chosen,
+            _ => return None,
+        }
+    }
+}
+
+/// Return the offset of `name` in the strings block, appending it if it's missing.
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> usize {
+    let mut offset = 0;
+    for s in strings.split(|&b| b == 0) {
+        if s == name.as_bytes() && offset + s.len() < strings.len() {
+            return offset;
+        }
+        offset += s.len() + 1;
+    }
+    let offset = strings.len();
+    strings.extend_from_slice(name.as_bytes());
+    strings.push(0);
+    offset
+}
+
+/// Return a copy of `dtb` whose `/chosen` node has the property `name` set to `value`. Return
+/// `None` if `dtb` isn't a valid DTB laid out by dtc, i.e., the strings block follows the
+/// structure block.
+fn set_chosen_property(dtb: &[u8], name: &str, value: &[u8]) -> Option<Vec<u8>> {
+    let (off_struct, size_struct, off_strings, size_strings) = blocks(dtb)?;
+    if off_struct < FDT_HEADER_SIZE || off_strings < off_struct + size_struct {
+        return None;
+    }
+    let mut dt_struct = dtb.get(off_struct..off_struct + size_struct)?.to_vec();
+    let mut strings = dtb.get(off_strings..off_strings + size_strings)?.to_vec();
+
+    let chosen = find_chosen(&dt_struct, &strings, name)?;
+    let mut prop = encode_prop(&mut strings, name, value);
+
+    if !chosen.exists {
+        let mut node = Vec::new();
+        node.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+        node.extend_from_slice(b"chosen\0\0");
+        node.append(&mut prop);
+        node.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+        prop = node;
+    }
+    // The old property is always after the insertion point.
+    if let Some((start, end)) = chosen.prop {
+        dt_struct.drain(start..end);
+    }
+    dt_struct.splice(chosen.insert_at..chosen.insert_at, prop);
+    Some(relayout(dtb, off_struct, &dt_struct, &strings))
+}
+
+/// Return the structure block token of the property `name` whose value is `value`, adding `name`
+/// to `strings` if it's missing.
+fn encode_prop(strings: &mut Vec<u8>, name: &str, value: &[u8]) -> Vec<u8> {
+    let nameoff = string_offset(strings, name);
+    let mut prop = Vec::new();
+    prop.extend_from_slice(&FDT_PROP.to_be_bytes());
+    prop.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    prop.extend_from_slice(&(nameoff as u32).to_be_bytes());
+    prop.extend_from_slice(value);
+    prop.resize(align4(prop.len()), 0);
+    prop
+}
+
+/// Return a copy of `dtb` whose structure block and strings block are replaced with `dt_struct`
+/// and `strings`. The structure block of `dtb` starts at `off_struct`.
+fn relayout(dtb: &[u8], off_struct: usize, dt_struct: &[u8], strings: &[u8]) -> Vec<u8> {
+    // Keep the header and the memory reservation block, and lay out the blocks again.
+    let mut new_dtb = dtb[..off_struct].to_vec();
+    new_dtb.extend_from_slice(dt_struct);
+    let new_off_strings = new_dtb.len();
+    new_dtb.extend_from_slice(strings);
+    let fields = [
+        (FDT_TOTALSIZE, new_dtb.len()),
+        (FDT_OFF_DT_STRINGS, new_off_strings),
+        (FDT_SIZE_DT_STRINGS, strings.len()),
+        (FDT_SIZE_DT_STRUCT, dt_struct.len()),
+    ];
+    for &(offset, value) in fields.iter() {
+        new_dtb[offset..offset + 4].copy_from_slice(&(value as u32).to_be_bytes());
+    }
+    new_dtb
+}
+
+/// Walk the structure block `dt_struct` and return the offset of the end of the root node, where
+/// a child can be added, and whether the root node has the child `name`.
+fn find_root_end(dt_struct: &[u8], name: &str) -> Option<(usize, bool)> {
+    let mut pos = 0;
+    let mut depth = 0;
+    let mut exists = false;
+    loop {
+        let token = be32(dt_struct, pos)?;
+        let start = pos;
+        pos += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let node_name = c_str(dt_struct, pos)?;
+                pos += align4(node_name.len() + 1);
+                depth += 1;
+                if depth == 2 && node_name == name.as_bytes() {
+                    exists = true;
+                }
+            }
+            FDT_END_NODE => {
+                if depth == 1 {
+                    return Some((start, exists));
+                }
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = be32(dt_struct, pos)? as usize;
+                pos += 8 + align4(len);
+            }
+            FDT_NOP => {}
+            _ => return None,
+        }
+    }
+}
+
+/// Return a copy of `dtb` whose root node has the child `name` with the properties `props`, or
+/// `dtb` as is if the root node already has it. Return `None` if `dtb` isn't a valid DTB laid out
+/// by dtc.
+fn add_root_node(dtb: &[u8], name: &str, props: &[(&str, &[u8])]) -> Option<Vec<u8>> {
+    let (off_struct, size_struct, off_strings, size_strings) = blocks(dtb)?;
+    if off_struct < FDT_HEADER_SIZE || off_strings < off_struct + size_struct {
+        return None;
+    }
+    let mut dt_struct = dtb.get(off_struct..off_struct + size_struct)?.to_vec();
+    let mut strings = dtb.get(off_strings..off_strings + size_strings)?.to_vec();
+
+    let (insert_at, exists) = find_root_end(&dt_struct, name)?;
+    if exists {
+        return Some(dtb.to_vec());
+    }
+    let mut node = Vec::new();
+    node.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+    node.extend_from_slice(name.as_bytes());
+    node.resize(node.len() + align4(name.len() + 1) - name.len(), 0);
+    for &(prop_name, value) in props.iter() {
+        node.append(&mut encode_prop(&mut strings, prop_name, value));
+    }
+    node.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+    dt_struct.splice(insert_at..insert_at, node);
+    Some(relayout(dtb, off_struct, &dt_struct, &strings))
+}
+
+/// Return true if the root node of `dtb` has the child `name`.
+pub fn has_root_node(dtb: &[u8], name: &str) -> bool {
+    let (off_struct, size_struct, _, _) = match blocks(dtb) {
+        Some(blocks) => blocks,
+        None => return false,
+    };
+    let root = dtb
+        .get(off_struct..off_struct + size_struct)
+        .and_then(|dt_struct| find_root_end(dt_struct, name));
+    matches!(root, Some((_, true)))
+}
+
+/// Return the value of the property `name` of the `/chosen` node in `dtb`.
+pub fn chosen_property(dtb: &[u8], name: &str) -> Option<Vec<u8>> {
+    let (off_struct, size_struct, off_strings, size_strings) = blocks(dtb)?;
+    let dt_struct = dtb.get(off_struct..off_struct + size_struct)?;
+    let strings = dtb.get(off_strings..off_strings + size_strings)?;
+
+    let (start, _) = find_chosen(dt_struct, strings, name)?.prop?;
+    let len = be32(dt_struct, start + 4)? as usize;
+    dt_struct
+        .get(start + 12..start + 12 + len)
+        .map(|v| v.to_vec())
+}
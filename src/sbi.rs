@@ -0,0 +1,70 @@
+//! The sbi module contains a minimal supervisor binary interface (SBI) implementation. When it's
+//! enabled, the emulator plays the role of the SBI firmware and services `ecall`s from S-mode
+//! instead of trapping to M-mode.
+//! See more information in https://github.com/riscv-non-isa/riscv-sbi-doc.
+
+use alloc::format;
+
+use crate::cpu::Cpu;
+
+/// The extension ID of the legacy shutdown call.
+pub const EID_LEGACY_SHUTDOWN: u64 = 0x08;
+/// The extension ID of the system reset extension, "SRST".
+pub const EID_SRST: u64 = 0x5352_5354;
+/// The extension ID of the debug call which dumps the registers to the console. It's in the
+/// experimental extension space, and it's serviced only if the emulator enables it.
+pub const EID_DEBUG_DUMP: u64 = 0x0A00_0000;
+
+/// The function ID of `sbi_system_reset` in the system reset extension.
+const FID_SYSTEM_RESET: u64 = 0;
+/// The reset type of `sbi_system_reset` to shut down the system.
+pub const RESET_TYPE_SHUTDOWN: u64 = 0;
+
+/// The error code returned for an unsupported call.
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+
+/// The registers of the SBI calling convention.
+const A0: u64 = 10;
+const A1: u64 = 11;
+const A6: u64 = 16;
+const A7: u64 = 17;
+
+/// The result of an SBI call.
+pub enum SbiResult {
+    /// The call returned to the caller.
+    Return,
+    /// The caller requested to shut down the system with the exit code.
+    Shutdown(u64),
+}
+
+/// Service the SBI call whose extension ID is in a7 and function ID is in a6. The program counter
+/// must already point to the instruction after the `ecall`.
+pub fn handle_call(cpu: &mut Cpu) -> SbiResult {
+    let eid = cpu.xregs.read(A7);
+    let fid = cpu.xregs.read(A6);
+    match (eid, fid) {
+        // The legacy shutdown doesn't take a status, so it's reported as a success.
+        (EID_LEGACY_SHUTDOWN, _) => SbiResult::Shutdown(0),
+        // "sbi_system_reset(uint32_t reset_type, uint32_t reset_reason)". The reason is reported
+        // as the exit code.
+        (EID_SRST, FID_SYSTEM_RESET) if cpu.xregs.read(A0) as u32 as u64 == RESET_TYPE_SHUTDOWN => {
+            SbiResult::Shutdown(cpu.xregs.read(A1) as u32 as u64)
+        }
+        _ => {
+            cpu.xregs.write(A0, SBI_ERR_NOT_SUPPORTED as u64);
+            cpu.xregs.write(A1, 0);
+            SbiResult::Return
+        }
+    }
+}
+
+/// Write the program counter and the integer registers to the console. It works before the guest
+/// configures the UART, and no register is modified, so that it can be called anywhere like
+/// `printf`.
+pub fn dump_registers(cpu: &mut Cpu) {
+    let dump = format!("pc={:#x}{}\n", cpu.pc, cpu.xregs);
+    let console = cpu.bus.uart.backend();
+    for &byte in dump.as_bytes() {
+        console.write(byte);
+    }
+}
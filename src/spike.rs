@@ -0,0 +1,180 @@
+//! The spike module compares the instructions a program executes with a commit log of Spike, the
+//! reference simulator of RISC-V, in order to validate the semantics of instructions against it.
+//!
+//! A commit log is written by `spike --log-commits`. Each retired instruction is a line of the
+//! hart, the privilege mode, the program counter, the instruction, and the registers and memory
+//! it wrote, e.g., `core   0: 3 0x0000000080000000 (0x02a00f93) x31 0x000000000000002a`. Only the
+//! writebacks to the integer registers of hart 0 are compared. Other lines, such as those of
+//! traps, are ignored.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::cpu::XRegisters;
+use crate::emulator::TraceEntry;
+
+/// The number of matched lines shown before a divergence.
+const CONTEXT_LINES: usize = 3;
+
+/// An instruction retired by Spike.
+#[derive(Debug, Clone)]
+struct SpikeRecord {
+    /// The line number in the log, starting at 1.
+    line_number: usize,
+    /// The line as it is in the log.
+    line: String,
+    pc: u64,
+    inst: u64,
+    /// The integer registers the instruction wrote and their new values.
+    writes: Vec<(u64, u64)>,
+}
+
+impl SpikeRecord {
+    /// Parse a line of a commit log, or return `None` if it isn't a retired instruction of hart
+    /// 0.
+    fn parse(line_number: usize, line: &str) -> Option<Self> {
+        let rest = line.trim().strip_prefix("core")?;
+        let (hart, rest) = rest.split_once(':')?;
+        if hart.trim() != "0" {
+            return None;
+        }
+        let mut fields = rest.split_whitespace();
+        // The privilege mode only appears in a commit log, which tells it from an instruction
+        // log.
+        fields.next()?.parse::<u8>().ok()?;
+        let pc = parse_hex(fields.next()?)?;
+        let inst = parse_hex(
+            fields
+                .next()?
+                .strip_prefix('(')
+                .and_then(|inst| inst.strip_suffix(')'))?,
+        )?;
+
+        // The rest is pairs of a destination and a value, such as `x5 0x10`, `f1 0x3f80`,
+        // `c768_mstatus 0x8`, or `mem 0x80001000`. A store has an extra value for the data.
+        let mut writes = Vec::new();
+        let mut fields = fields.peekable();
+        while let Some(field) = fields.next() {
+            let value = fields.next().and_then(parse_hex);
+            if let (Some(index), Some(value)) = (field.strip_prefix('x'), value) {
+                match index.parse::<u64>() {
+                    Ok(index) if index < 32 => writes.push((index, value)),
+                    _ => return None,
+                }
+            }
+            // Skip the data of a store after its address.
+            if field == "mem" {
+                if let Some(data) = fields.peek() {
+                    if data.starts_with("0x") {
+                        fields.next();
+                    }
+                }
+            }
+        }
+        Some(Self {
+            line_number,
+            line: line.trim().to_string(),
+            pc,
+            inst,
+            writes,
+        })
+    }
+}
+
+/// Parse a hexadecimal number with the `0x` prefix.
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+/// The checker which compares the instructions retired by the emulator with a Spike commit log
+/// one by one.
+pub struct SpikeChecker {
+    records: Vec<SpikeRecord>,
+    /// The number of records which have matched so far.
+    matched: usize,
+    /// The first divergence, which is kept until it's taken.
+    divergence: Option<io::Error>,
+}
+
+impl SpikeChecker {
+    /// Load a Spike commit log at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let records = fs::read_to_string(path)?
+            .lines()
+            .enumerate()
+            .filter_map(|(i, line)| SpikeRecord::parse(i + 1, line))
+            .collect();
+        Ok(Self {
+            records,
+            matched: 0,
+            divergence: None,
+        })
+    }
+
+    /// Skip the records before the first one at `pc`, such as those of the boot ROM of Spike, so
+    /// that the comparison starts where the emulator starts. Nothing is skipped if no record is
+    /// at `pc`.
+    pub fn skip_to(&mut self, pc: u64) {
+        if let Some(start) = self.records.iter().position(|record| record.pc == pc) {
+            self.records.drain(..start);
+        }
+    }
+
+    /// Compare the instruction `entry` with the next record. `xregs` are the integer registers
+    /// after the instruction. A write which doesn't change the value of a register is only
+    /// visible in the registers, so every register Spike wrote must have its value in `xregs`,
+    /// and the emulator must not have changed any other register. Instructions after the first
+    /// divergence are ignored.
+    pub fn check(&mut self, entry: &TraceEntry, xregs: &XRegisters) {
+        if self.divergence.is_some() || self.is_finished() {
+            return;
+        }
+        let record = &self.records[self.matched];
+        let writes_match = record
+            .writes
+            .iter()
+            .all(|&(index, value)| index == 0 || xregs.read(index) == value);
+        let changed_expected = match entry.rd {
+            Some((rd, _)) => record.writes.iter().any(|&(index, _)| index == rd),
+            None => true,
+        };
+        if record.pc == entry.pc && record.inst == entry.inst && writes_match && changed_expected {
+            self.matched += 1;
+            return;
+        }
+
+        let actual_writes = match entry.rd {
+            Some((rd, value)) => format!(" x{} {:#018x}", rd, value),
+            None => String::new(),
+        };
+        let mut message = format!(
+            "the emulator diverged from the Spike log at instruction {} (line {}):\n",
+            self.matched + 1,
+            record.line_number
+        );
+        for context in &self.records[self.matched.saturating_sub(CONTEXT_LINES)..self.matched] {
+            message.push_str(&format!("  {}\n", context.line));
+        }
+        message.push_str(&format!(
+            "- {}\n+ {:#018x} ({:#010x}){}",
+            record.line, entry.pc, entry.inst, actual_writes
+        ));
+        self.divergence = Some(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+
+    /// Return true if every record of the log has matched.
+    pub fn is_finished(&self) -> bool {
+        self.matched == self.records.len()
+    }
+
+    /// Return the number of records which have matched and the number of all records.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.matched, self.records.len())
+    }
+
+    /// Take the first divergence if the emulator has diverged.
+    pub fn take_divergence(&mut self) -> Option<io::Error> {
+        self.divergence.take()
+    }
+}
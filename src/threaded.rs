@@ -0,0 +1,320 @@
+//! The threaded module contains an experimental threaded-code interpreter. A basic block is
+//! predecoded once into a list of handlers, and the list runs on every later visit to the block
+//! without fetching and decoding each instruction again. It's enabled by the `threaded` feature.
+//!
+//! Only simple integer instructions have their own handlers. The others fall back to the classic
+//! interpreter, and a control transfer, a system instruction, or a compressed instruction ends a
+//! block.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+
+use crate::bus::Bus;
+use crate::cpu::{Cpu, HALFWORD, WORD};
+use crate::exception::Exception;
+
+/// The maximum number of instructions in a block.
+const MAX_BLOCK_LEN: usize = 64;
+
+/// A handler which executes a predecoded instruction `inst` and advances the program counter.
+pub type Handler = fn(&mut Cpu, u64) -> Result<(), Exception>;
+
+/// A predecoded instruction.
+#[derive(Clone, Copy)]
+pub struct Op {
+    pub handler: Handler,
+    pub inst: u64,
+}
+
+/// The cache of predecoded blocks keyed by the physical address of their first instruction.
+#[derive(Default)]
+pub struct BlockCache {
+    blocks: BTreeMap<u64, Rc<[Op]>>,
+    /// Physical page numbers which contain cached blocks.
+    pages: BTreeSet<u64>,
+    /// Incremented every time the cache is flushed.
+    generation: u64,
+}
+
+impl BlockCache {
+    /// Create an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the block which starts at `paddr`, predecoding it on a miss.
+    pub fn get_or_predecode(&mut self, bus: &mut Bus, paddr: u64) -> Rc<[Op]> {
+        if let Some(block) = self.blocks.get(&paddr) {
+            return block.clone();
+        }
+        let block: Rc<[Op]> = predecode(bus, paddr).into();
+        self.pages.insert(paddr >> 12);
+        self.blocks.insert(paddr, block.clone());
+        block
+    }
+
+    /// Drop the cached blocks if a write to `paddr` may modify one of them.
+    pub fn invalidate(&mut self, paddr: u64) {
+        if self.pages.contains(&(paddr >> 12)) {
+            self.flush();
+        }
+    }
+
+    /// Drop all cached blocks.
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.pages.clear();
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    /// Return the number of times the cache has been flushed. A running block stops if it changes.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Return the number of cached blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Return true if no block is cached.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// Predecode the block which starts at `paddr`. A block never crosses a page boundary, so that
+/// all of its instructions are in the page translated at the beginning of the block.
+fn predecode(bus: &mut Bus, paddr: u64) -> Vec<Op> {
+    let mut ops = Vec::new();
+    let mut addr = paddr;
+    while ops.len() < MAX_BLOCK_LEN {
+        // An instruction which can't be fetched, a compressed instruction, and an instruction
+        // crossing a page boundary are left to the classic interpreter, which raises the
+        // exception on the fetch if any.
+        let inst = match bus.read(addr, HALFWORD) {
+            Ok(inst) if inst & 0b11 == 0b11 && (addr & 0xfff) <= 0xffc => bus.read(addr, WORD).ok(),
+            _ => None,
+        };
+        let inst = match inst {
+            Some(inst) => inst,
+            None => {
+                ops.push(Op {
+                    handler: interpret,
+                    inst: 0,
+                });
+                break;
+            }
+        };
+
+        match decode(inst) {
+            Some(handler) => ops.push(Op { handler, inst }),
+            None => {
+                ops.push(Op {
+                    handler: interpret,
+                    inst,
+                });
+                if ends_block(inst) {
+                    break;
+                }
+            }
+        }
+        addr += 4;
+        if addr & 0xfff == 0 {
+            break;
+        }
+    }
+    ops
+}
+
+/// Return true if `inst` may change the program counter, the privilege mode, the address
+/// translation, or the cached instructions.
+fn ends_block(inst: u64) -> bool {
+    match inst & 0x7f {
+        // Branches, jal, and jalr.
+        0x63 | 0x67 | 0x6f => true,
+        // fence.i.
+        0x0f => true,
+        // ecall, ebreak, xret, wfi, sfence.vma, and CSR instructions.
+        0x73 => true,
+        _ => false,
+    }
+}
+
+/// Return the handler of a 32-bit instruction `inst` if it has a dedicated one.
+fn decode(inst: u64) -> Option<Handler> {
+    let funct3 = (inst >> 12) & 0x7;
+    let funct7 = inst >> 25;
+    let handler: Handler = match (inst & 0x7f, funct3) {
+        (0x13, 0x0) => addi,
+        (0x13, 0x1) if funct7 >> 1 == 0x00 => slli,
+        (0x13, 0x2) => slti,
+        (0x13, 0x3) => sltiu,
+        (0x13, 0x4) => xori,
+        (0x13, 0x5) if funct7 >> 1 == 0x00 => srli,
+        (0x13, 0x5) if funct7 >> 1 == 0x10 => srai,
+        (0x13, 0x6) => ori,
+        (0x13, 0x7) => andi,
+        (0x17, _) => auipc,
+        (0x1b, 0x0) => addiw,
+        (0x33, _) => match (funct3, funct7) {
+            (0x0, 0x00) => add,
+            (0x0, 0x20) => sub,
+            (0x1, 0x00) => sll,
+            (0x2, 0x00) => slt,
+            (0x3, 0x00) => sltu,
+            (0x4, 0x00) => xor,
+            (0x5, 0x00) => srl,
+            (0x5, 0x20) => sra,
+            (0x6, 0x00) => or,
+            (0x7, 0x00) => and,
+            _ => return None,
+        },
+        (0x37, _) => lui,
+        (0x3b, 0x0) if funct7 == 0x00 => addw,
+        (0x3b, 0x0) if funct7 == 0x20 => subw,
+        _ => return None,
+    };
+    Some(handler)
+}
+
+/// Execute an instruction without a dedicated handler on the classic interpreter.
+fn interpret(cpu: &mut Cpu, _inst: u64) -> Result<(), Exception> {
+    cpu.execute().map(|_| ())
+}
+
+fn rd(inst: u64) -> u64 {
+    (inst >> 7) & 0x1f
+}
+
+fn rs1(inst: u64) -> u64 {
+    (inst >> 15) & 0x1f
+}
+
+fn rs2(inst: u64) -> u64 {
+    (inst >> 20) & 0x1f
+}
+
+/// imm[11:0] = inst[31:20]
+fn imm_i(inst: u64) -> u64 {
+    ((inst as i32 as i64) >> 20) as u64
+}
+
+/// Execute an I-type instruction `inst` which writes `f(rs1, imm)` to `rd`.
+fn op_imm(cpu: &mut Cpu, inst: u64, f: impl FnOnce(u64, u64) -> u64) -> Result<(), Exception> {
+    let value = f(cpu.xregs.read(rs1(inst)), imm_i(inst));
+    cpu.xregs.write(rd(inst), value);
+    cpu.retire(inst);
+    Ok(())
+}
+
+/// Execute an R-type instruction `inst` which writes `f(rs1, rs2)` to `rd`.
+fn op(cpu: &mut Cpu, inst: u64, f: impl FnOnce(u64, u64) -> u64) -> Result<(), Exception> {
+    let value = f(cpu.xregs.read(rs1(inst)), cpu.xregs.read(rs2(inst)));
+    cpu.xregs.write(rd(inst), value);
+    cpu.retire(inst);
+    Ok(())
+}
+
+fn addi(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op_imm(cpu, inst, |a, imm| a.wrapping_add(imm))
+}
+
+fn slli(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op_imm(cpu, inst, |a, imm| a << (imm & 0x3f))
+}
+
+fn slti(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op_imm(cpu, inst, |a, imm| ((a as i64) < (imm as i64)) as u64)
+}
+
+fn sltiu(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op_imm(cpu, inst, |a, imm| (a < imm) as u64)
+}
+
+fn xori(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op_imm(cpu, inst, |a, imm| a ^ imm)
+}
+
+fn srli(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op_imm(cpu, inst, |a, imm| a >> (imm & 0x3f))
+}
+
+fn srai(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op_imm(cpu, inst, |a, imm| ((a as i64) >> (imm & 0x3f)) as u64)
+}
+
+fn ori(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op_imm(cpu, inst, |a, imm| a | imm)
+}
+
+fn andi(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op_imm(cpu, inst, |a, imm| a & imm)
+}
+
+fn addiw(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op_imm(cpu, inst, |a, imm| a.wrapping_add(imm) as i32 as i64 as u64)
+}
+
+fn auipc(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    let imm = (inst & 0xfffff000) as i32 as i64 as u64;
+    cpu.xregs.write(rd(inst), cpu.pc.wrapping_add(imm));
+    cpu.retire(inst);
+    Ok(())
+}
+
+fn lui(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    cpu.xregs
+        .write(rd(inst), (inst & 0xfffff000) as i32 as i64 as u64);
+    cpu.retire(inst);
+    Ok(())
+}
+
+fn add(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| a.wrapping_add(b))
+}
+
+fn sub(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| a.wrapping_sub(b))
+}
+
+fn sll(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| a << (b & 0x3f))
+}
+
+fn slt(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| ((a as i64) < (b as i64)) as u64)
+}
+
+fn sltu(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| (a < b) as u64)
+}
+
+fn xor(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| a ^ b)
+}
+
+fn srl(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| a >> (b & 0x3f))
+}
+
+fn sra(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| ((a as i64) >> (b & 0x3f)) as u64)
+}
+
+fn or(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| a | b)
+}
+
+fn and(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| a & b)
+}
+
+fn addw(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| a.wrapping_add(b) as i32 as i64 as u64)
+}
+
+fn subw(cpu: &mut Cpu, inst: u64) -> Result<(), Exception> {
+    op(cpu, inst, |a, b| a.wrapping_sub(b) as i32 as i64 as u64)
+}
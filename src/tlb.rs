@@ -0,0 +1,133 @@
+//! The tlb module contains the translation lookaside buffer (TLB) which caches the results of
+//! page-table walks.
+
+use alloc::collections::BTreeMap;
+
+/// The number of bits of the page offset in a virtual or physical address.
+const PAGE_SHIFT: u64 = 12;
+
+/// The G bit of a PTE. A global mapping exists in all address spaces.
+const PTE_G: u64 = 1 << 5;
+
+/// The tag of the entries for global mappings. It's outside the 16-bit ASID space.
+const GLOBAL: u64 = u64::MAX;
+
+/// The number of bits of the virtual page number which each level of the page table translates.
+const LEVEL_BITS: u64 = 9;
+
+/// A cached translation of a 4 KiB page.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    ppn: u64,
+    /// The leaf PTE which grants the permission.
+    pte: u64,
+    /// The level of the page table where the leaf PTE is, which is 0 for a 4 KiB page, 1 for a
+    /// 2 MiB megapage, and 2 for a 1 GiB gigapage.
+    level: u64,
+}
+
+/// The translation lookaside buffer. It maps a virtual page number to a physical page number and
+/// the leaf PTE at 4 KiB granularity, so superpages occupy one entry per 4 KiB page that has been
+/// touched. Entries are tagged with the address-space identifier (ASID) so that switching to
+/// another address space doesn't discard them.
+#[derive(Debug, Default)]
+pub struct Tlb {
+    entries: BTreeMap<(u64, u64), Entry>,
+    /// The ASID of the current address space.
+    asid: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Tlb {
+    /// Create a new empty TLB.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Look up the physical address and the leaf PTE for the virtual address `vaddr`. Return
+    /// `None` and count a miss if the page isn't cached.
+    pub fn lookup(&mut self, vaddr: u64) -> Option<(u64, u64)> {
+        let vpn = vaddr >> PAGE_SHIFT;
+        let entry = self
+            .entries
+            .get(&(self.asid, vpn))
+            .or_else(|| self.entries.get(&(GLOBAL, vpn)));
+        match entry {
+            Some(&Entry { ppn, pte, .. }) => {
+                self.hits += 1;
+                Some(((ppn << PAGE_SHIFT) | (vaddr & ((1 << PAGE_SHIFT) - 1)), pte))
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache the translation from the page containing `vaddr` to the page containing `paddr`
+    /// along with the leaf PTE which grants its permission and the `level` of the page table
+    /// where the PTE is.
+    pub fn insert(&mut self, vaddr: u64, paddr: u64, pte: u64, level: u64) {
+        let tag = if pte & PTE_G != 0 { GLOBAL } else { self.asid };
+        let entry = Entry {
+            ppn: paddr >> PAGE_SHIFT,
+            pte,
+            level,
+        };
+        self.entries.insert((tag, vaddr >> PAGE_SHIFT), entry);
+    }
+
+    /// Switch to the address space `asid`. The cached translations of the other address spaces
+    /// are kept.
+    pub fn set_asid(&mut self, asid: u64) {
+        self.asid = asid;
+    }
+
+    /// Return the ASID of the current address space.
+    pub fn asid(&self) -> u64 {
+        self.asid
+    }
+
+    /// Return the number of cached translations.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Return true if no translation is cached.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Invalidate all cached translations.
+    pub fn flush(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Invalidate the cached translations selected as SFENCE.VMA does: those of the page
+    /// containing `vaddr` if it's given, otherwise of all pages, and those of the address space
+    /// `asid` except for global mappings if it's given, otherwise of all address spaces. The page
+    /// containing `vaddr` may be a superpage, whose 4 KiB pages are all invalidated.
+    pub fn invalidate(&mut self, vaddr: Option<u64>, asid: Option<u64>) {
+        let vpn = vaddr.map(|vaddr| vaddr >> PAGE_SHIFT);
+        self.entries.retain(|&(tag, entry_vpn), entry| {
+            let shift = LEVEL_BITS * entry.level;
+            let page_matches = match vpn {
+                Some(vpn) => vpn >> shift == entry_vpn >> shift,
+                None => true,
+            };
+            let asid_matches = asid.is_none() || asid == Some(tag);
+            !(page_matches && asid_matches)
+        });
+    }
+
+    /// The number of lookups which found a cached translation.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// The number of lookups which required a page-table walk.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
@@ -0,0 +1,64 @@
+//! The wasm module contains a wrapper of the emulator for web pages. With the `wasm` feature, it's
+//! exported to JavaScript by `wasm-bindgen`, so that a page can run a kernel a slice at a time and
+//! exchange bytes with the console of the guest in between.
+
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::bus::DRAM_BASE;
+use crate::devices::serial::BufferBackend;
+use crate::emulator::{Emulator, Halt};
+
+/// The emulator which runs a kernel from the beginning of DRAM. The bytes the guest writes to the
+/// UART are buffered until they're taken by `get_output`.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct WasmEmulator {
+    emu: Emulator,
+    output: BufferBackend,
+    /// The number of bytes in `output` which have already been taken.
+    taken: usize,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl WasmEmulator {
+    /// Create a new emulator which executes `kernel` placed at `DRAM_BASE`.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new(kernel: Vec<u8>) -> Self {
+        let mut emu = Emulator::new();
+        emu.initialize_dram(kernel);
+        emu.initialize_pc(DRAM_BASE);
+        let output = BufferBackend::new();
+        emu.cpu.bus.uart.set_backend(Box::new(output.clone()));
+        Self {
+            emu,
+            output,
+            taken: 0,
+        }
+    }
+
+    /// Execute at most `n` instructions. Return false if the emulator stopped before that and
+    /// can't continue.
+    pub fn step(&mut self, n: u32) -> bool {
+        self.emu.run(n as u64) == Halt::InstructionLimit
+    }
+
+    /// Return the output of the guest since the last call. Invalid UTF-8 sequences are replaced.
+    pub fn get_output(&mut self) -> String {
+        let bytes = self.output.bytes();
+        let output = String::from_utf8_lossy(&bytes[self.taken..]).into_owned();
+        self.taken = bytes.len();
+        output
+    }
+
+    /// Queue `input` as if it were typed on the console.
+    pub fn send_input(&mut self, input: &str) {
+        self.emu.cpu.bus.uart.push_input(input.as_bytes());
+    }
+
+    /// Return the integer registers from x0 to x31 followed by the program counter.
+    pub fn get_registers(&self) -> Vec<u64> {
+        let mut registers: Vec<u64> = (0..32).map(|i| self.emu.cpu.xregs.read(i)).collect();
+        registers.push(self.emu.cpu.pc);
+        registers
+    }
+}
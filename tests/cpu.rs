@@ -0,0 +1,1864 @@
+extern crate rvemu;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use rvemu::{
+    bus::{DRAM_BASE, PLIC_BASE},
+    cpu::{CostModel, Mode, BYTE, DOUBLEWORD, WORD},
+    csr::{
+        CsrDevice, ENVCFG_CBCFE, ENVCFG_CBZE, ENVCFG_FIOM, FCSR, MCAUSE, MCYCLE, MEDELEG, MEIP_BIT,
+        MENVCFG, MENVCFG_STCE, MEPC, MHPMCOUNTER3, MIDELEG, MIE, MIP, MISA, MISA_C, MISA_S,
+        MNCAUSE, MNSTATUS, MNSTATUS_MNPP, MNSTATUS_NMIE, MSCRATCH, MSTATUS, MSTATUS_FS,
+        MSTATUS_MPP, MSTATUS_MPRV, MSTATUS_SD, MSTATUS_TSR, MSTATUS_TW, MTIP_BIT, MTVAL, MTVEC,
+        SCAUSE, SEIP_BIT, SENVCFG, SEPC, SIE, SIP, SSTATUS, SSTATUS_SPP, STIMECMP, STIP_BIT, STVEC,
+    },
+    devices::plic::IrqSource,
+    emulator::{Emulator, Halt},
+    exception::Exception,
+    interrupt::Interrupt,
+};
+
+/// Create an emulator which has `data` at the beginning of DRAM.
+fn setup(data: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data);
+    emu.initialize_pc(DRAM_BASE);
+    emu
+}
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+/// Map the 4 KiB page at `va` to `pa` with `flags` in the SV39 page table whose root is at `root`.
+/// Intermediate tables are allocated from `next_table`, which is bumped by 4 KiB per table.
+fn map_page(emu: &mut Emulator, root: u64, next_table: &mut u64, va: u64, pa: u64, flags: u64) {
+    let mut table = root;
+    for level in (1..3).rev() {
+        let pte_addr = table + ((va >> (12 + 9 * level)) & 0x1ff) * 8;
+        let pte = emu.cpu.bus.read(pte_addr, DOUBLEWORD).unwrap();
+        table = if pte & PTE_V == 0 {
+            let new_table = *next_table;
+            *next_table += 0x1000;
+            emu.cpu
+                .bus
+                .write(pte_addr, ((new_table >> 12) << 10) | PTE_V, DOUBLEWORD)
+                .unwrap();
+            new_table
+        } else {
+            (pte >> 10) << 12
+        };
+    }
+    let pte_addr = table + ((va >> 12) & 0x1ff) * 8;
+    emu.cpu
+        .bus
+        .write(pte_addr, ((pa >> 12) << 10) | flags | PTE_V, DOUBLEWORD)
+        .unwrap();
+}
+
+/// Return the satp value which enables SV39 with the page table whose root is at `root`.
+fn sv39_satp(root: u64) -> u64 {
+    (8 << 60) | (root >> 12)
+}
+
+/// Execute `n` instructions. Panics if an instruction raises an exception.
+fn step(emu: &mut Emulator, n: usize) {
+    for _ in 0..n {
+        emu.cpu.execute().expect("failed to execute an instruction");
+    }
+}
+
+#[test]
+fn mcycle_reflects_cost_model() {
+    let data = vec![
+        0x93, 0x05, 0x40, 0x06, // addi a1, x0, 100
+        0x13, 0x06, 0x70, 0x00, // addi a2, x0, 7
+        0x33, 0xc5, 0xc5, 0x02, // div a0, a1, a2
+    ];
+    let mut emu = setup(data);
+    emu.cpu.set_cost_model(CostModel {
+        alu: 1,
+        div: 20,
+        ..CostModel::default()
+    });
+
+    step(&mut emu, 2);
+    assert_eq!(2, emu.cpu.state.read(MCYCLE));
+
+    step(&mut emu, 1);
+    assert_eq!(14, emu.cpu.xregs.read(10));
+    assert_eq!(22, emu.cpu.state.read(MCYCLE));
+}
+
+#[test]
+fn hpm_counters_count_selected_events() {
+    let data = vec![
+        0x93, 0x02, 0x10, 0x00, // addi t0, zero, 1
+        0x73, 0x90, 0x32, 0x32, // csrw mhpmevent3, t0
+        0x13, 0x03, 0x20, 0x00, // addi t1, zero, 2
+        0x73, 0x10, 0x43, 0x32, // csrw mhpmevent4, t1
+        0x97, 0x05, 0x00, 0x00, // auipc a1, 0
+        0x03, 0xa5, 0x05, 0x00, // lw a0, 0(a1)
+        0x03, 0x85, 0x45, 0x00, // lb a0, 4(a1)
+        0x63, 0x02, 0x00, 0x00, // beq zero, zero, 4
+        0x73, 0x26, 0x30, 0xc0, // csrr a2, hpmcounter3
+        0xf3, 0x26, 0x40, 0xc0, // csrr a3, hpmcounter4
+        0x73, 0x27, 0x30, 0xb0, // csrr a4, mhpmcounter3
+    ];
+    let mut emu = setup(data);
+    step(&mut emu, 11);
+
+    // mhpmevent3 selects loads and mhpmevent4 selects branches.
+    assert_eq!(2, emu.cpu.xregs.read(12));
+    assert_eq!(1, emu.cpu.xregs.read(13));
+    assert_eq!(2, emu.cpu.xregs.read(14));
+    // A counter without an event doesn't advance.
+    assert_eq!(0, emu.cpu.state.read(MHPMCOUNTER3 + 2));
+}
+
+#[test]
+fn fclass_classifies_each_kind_of_value() {
+    // The bits moved to f0 and the class of them as a single and as a double.
+    let singles: &[(u64, u64)] = &[
+        (0x0000_0000, 1 << 4), // +0
+        (0x8000_0000, 1 << 3), // -0
+        (0x7f80_0000, 1 << 7), // +inf
+        (0xff80_0000, 1 << 0), // -inf
+        (0x3f80_0000, 1 << 6), // 1.0
+        (0xbf80_0000, 1 << 1), // -1.0
+        (0x0000_0001, 1 << 5), // the smallest subnormal
+        (0x8000_0001, 1 << 2), // a negative subnormal
+        (0x7fc0_0000, 1 << 9), // a quiet NaN
+        (0x7f80_0001, 1 << 8), // a signaling NaN
+    ];
+    let doubles: &[(u64, u64)] = &[
+        (0x0000_0000_0000_0000, 1 << 4),
+        (0x8000_0000_0000_0000, 1 << 3),
+        (0x7ff0_0000_0000_0000, 1 << 7),
+        (0xfff0_0000_0000_0000, 1 << 0),
+        (0x0000_0000_0000_0001, 1 << 5),
+        (0x7ff8_0000_0000_0000, 1 << 9),
+        (0x7ff0_0000_0000_0001, 1 << 8),
+    ];
+    let fclass_s = vec![
+        0x53, 0x80, 0x05, 0xf0, // fmv.w.x f0, a1
+        0x53, 0x15, 0x00, 0xe0, // fclass.s a0, f0
+    ];
+    let fclass_d = vec![
+        0x53, 0x80, 0x05, 0xf2, // fmv.d.x f0, a1
+        0x53, 0x15, 0x00, 0xe2, // fclass.d a0, f0
+    ];
+
+    for (data, cases) in &[(fclass_s, singles), (fclass_d, doubles)] {
+        for &(bits, class) in cases.iter() {
+            let mut emu = setup(data.clone());
+            emu.cpu.xregs.write(11, bits);
+            step(&mut emu, 2);
+            assert_eq!(class, emu.cpu.xregs.read(10), "{:#x}", bits);
+        }
+    }
+}
+
+#[test]
+fn pending_interrupts_are_taken_by_priority() {
+    let mut emu = setup(Vec::new());
+    // The UART source is enabled for the M-mode context of the PLIC.
+    emu.cpu.bus.write(PLIC_BASE + 4 * 10, 1, WORD).unwrap();
+    emu.cpu
+        .bus
+        .write(PLIC_BASE + 0x2000, 1 << 10, WORD)
+        .unwrap();
+    emu.cpu.bus.raise_irq(IrqSource::Uart);
+    emu.cpu.state.write(MIE, MEIP_BIT | MTIP_BIT);
+    emu.cpu.state.write(MSTATUS, 1 << 3);
+
+    // The external interrupt is taken before the timer interrupt pending at the same time.
+    emu.cpu.state.write(MIP, MTIP_BIT);
+    assert_eq!(
+        Some(Interrupt::MachineExternalInterrupt),
+        emu.cpu.check_pending_interrupt()
+    );
+    emu.cpu.bus.write(PLIC_BASE + 0x2000, 0, WORD).unwrap();
+    assert_eq!(
+        Some(Interrupt::MachineTimerInterrupt),
+        emu.cpu.check_pending_interrupt()
+    );
+
+    // An interrupt delegated to S-mode isn't taken in M-mode.
+    emu.cpu.state.write(MIDELEG, STIP_BIT);
+    emu.cpu.state.write(MIE, STIP_BIT | MTIP_BIT);
+    emu.cpu.state.write(MIP, STIP_BIT);
+    assert_eq!(None, emu.cpu.check_pending_interrupt());
+
+    // In S-mode, interrupts destined for M-mode are taken first even if SIE is clear.
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.state.write(MSTATUS, 0);
+    emu.cpu.state.write(MIP, STIP_BIT | MTIP_BIT);
+    assert_eq!(
+        Some(Interrupt::MachineTimerInterrupt),
+        emu.cpu.check_pending_interrupt()
+    );
+    assert_eq!(None, emu.cpu.check_pending_interrupt());
+    emu.cpu.state.write(SSTATUS, 1 << 1);
+    assert_eq!(
+        Some(Interrupt::SupervisorTimerInterrupt),
+        emu.cpu.check_pending_interrupt()
+    );
+}
+
+#[test]
+fn software_seip_is_kept_and_taken_in_s_mode() {
+    let mut data = vec![
+        0x73, 0xa0, 0x42, 0x34, // csrrs zero, mip, t0
+        0x73, 0x00, 0x20, 0x30, // mret
+    ];
+    data.resize(0x100, 0);
+    data.extend_from_slice(&[0x6f, 0x00, 0x00, 0x00]); // jal zero, 0
+    data.resize(0x200, 0);
+    data.extend_from_slice(&[0x6f, 0x00, 0x00, 0x00]); // jal zero, 0
+    let mut emu = setup(data);
+    emu.cpu.xregs.write(5, SEIP_BIT);
+    emu.cpu.state.write(MEPC, DRAM_BASE + 0x100);
+    // MPP is S-mode, and SIE is set.
+    emu.cpu.state.write(MSTATUS, (1 << 11) | (1 << 1));
+    emu.cpu.state.write(MIDELEG, SEIP_BIT);
+    emu.cpu.state.write(MIE, SEIP_BIT);
+    emu.cpu.state.write(STVEC, DRAM_BASE + 0x200);
+
+    // No PLIC source is pending, but SEIP set by M-mode stays set and S-mode takes it.
+    emu.run(10);
+    assert_eq!(Mode::Supervisor, emu.cpu.mode);
+    assert_eq!(DRAM_BASE + 0x200, emu.cpu.pc);
+    assert_eq!((1 << 63) | 9, emu.cpu.state.read(SCAUSE));
+    assert_eq!(DRAM_BASE + 0x100, emu.cpu.state.read(SEPC));
+
+    // The PLIC line is ORed into SEIP, but a read-modify-write of mip doesn't latch it.
+    emu.cpu.state.set_seip_line(true);
+    assert_eq!(SEIP_BIT, emu.cpu.state.read(MIP) & SEIP_BIT);
+    assert_eq!(0, emu.cpu.state.read_mip_for_update() & SEIP_BIT);
+    emu.cpu.state.set_seip_line(false);
+    assert_eq!(0, emu.cpu.state.read(MIP) & SEIP_BIT);
+}
+
+#[test]
+fn misaligned_amo_traps_with_address_in_mtval() {
+    let data = vec![
+        0x93, 0x05, 0x10, 0x00, // addi a1, zero, 1
+        0x93, 0x95, 0xf5, 0x01, // slli a1, a1, 31
+        0x93, 0x85, 0x35, 0x10, // addi a1, a1, 0x103
+        0x13, 0x06, 0x50, 0x00, // addi a2, zero, 5
+        0x2f, 0xb5, 0xc5, 0x00, // amoadd.d a0, a2, (a1)
+    ];
+    let mut emu = setup(data);
+    let addr = DRAM_BASE + 0x103;
+
+    step(&mut emu, 4);
+    let exception = emu.cpu.execute().expect_err("amoadd.d should trap");
+    match exception {
+        Exception::StoreAMOAddressMisaligned(a) => assert_eq!(addr, a),
+        e => panic!("unexpected exception: {:?}", e),
+    }
+    exception.take_trap(&mut emu.cpu);
+
+    assert_eq!(6, emu.cpu.state.read(MCAUSE));
+    assert_eq!(addr, emu.cpu.state.read(MTVAL));
+    // Neither memory nor the destination register is modified.
+    assert_eq!(0, emu.cpu.xregs.read(10));
+    assert_eq!(0, emu.cpu.bus.read(DRAM_BASE + 0x100, DOUBLEWORD).unwrap());
+}
+
+#[test]
+fn trap_from_debug_mode_returns_to_machine_mode() {
+    let mut emu = setup(vec![]);
+    emu.cpu.mode = Mode::Debug;
+    Exception::Breakpoint.take_trap(&mut emu.cpu);
+    assert_eq!(Mode::Machine, emu.cpu.mode);
+    assert_eq!(MSTATUS_MPP, emu.cpu.state.read(MSTATUS) & MSTATUS_MPP);
+
+    emu.cpu.mode = Mode::Debug;
+    Interrupt::MachineTimerInterrupt.take_trap(&mut emu.cpu);
+    assert_eq!(Mode::Machine, emu.cpu.mode);
+    assert_eq!(MSTATUS_MPP, emu.cpu.state.read(MSTATUS) & MSTATUS_MPP);
+}
+
+#[test]
+fn bare_mode_accesses_bypass_tlb() {
+    let data = vec![
+        0x93, 0x02, 0x40, 0x06, // addi t0, zero, 100
+        0x13, 0x03, 0x10, 0x00, // addi t1, zero, 1
+        0x13, 0x13, 0xf3, 0x01, // slli t1, t1, 31
+        0x13, 0x03, 0x03, 0x40, // addi t1, t1, 0x400
+        0x23, 0x30, 0x53, 0x00, // sd t0, 0(t1)
+        0x83, 0x33, 0x03, 0x00, // ld t2, 0(t1)
+        0x93, 0x82, 0xf2, 0xff, // addi t0, t0, -1
+        0xe3, 0x9a, 0x02, 0xfe, // bne t0, zero, -12
+    ];
+    let mut emu = setup(data);
+    // satp is 0 (Bare) after reset, so S-mode accesses are untranslated as well.
+    emu.cpu.mode = Mode::Supervisor;
+
+    step(&mut emu, 4 + 100 * 4);
+
+    assert_eq!(0, emu.cpu.xregs.read(5));
+    assert_eq!(1, emu.cpu.xregs.read(7));
+    assert_eq!(0, emu.cpu.tlb.hits());
+    assert_eq!(0, emu.cpu.tlb.misses());
+}
+
+#[test]
+fn sip_and_sie_are_views_of_delegated_mip_and_mie() {
+    let mut emu = Emulator::new();
+    let state = &mut emu.cpu.state;
+    state.write(MIP, STIP_BIT);
+
+    // Not delegated: the supervisor timer interrupt is invisible to S-mode.
+    assert_eq!(0, state.read(SIP));
+    state.write(SIE, STIP_BIT);
+    assert_eq!(0, state.read(MIE));
+
+    // Delegated: STIP shows through sip, and STIE can be set through sie.
+    state.write(MIDELEG, STIP_BIT | MTIP_BIT);
+    assert_eq!(STIP_BIT, state.read(MIDELEG));
+    assert_eq!(STIP_BIT, state.read(SIP));
+    state.write(SIE, STIP_BIT | MTIP_BIT);
+    assert_eq!(STIP_BIT, state.read(MIE));
+    assert_eq!(STIP_BIT, state.read(SIE));
+
+    // STIP itself isn't writable from S-mode.
+    state.write(SIP, 0);
+    assert_eq!(STIP_BIT, state.read(MIP));
+}
+
+#[test]
+fn stores_write_only_the_low_bytes() {
+    let data = vec![
+        0x23, 0x04, 0xb5, 0x00, // sb a1, 8(a0)
+        0x23, 0x1c, 0xb5, 0x00, // sh a1, 24(a0)
+        0x23, 0x24, 0xb5, 0x02, // sw a1, 40(a0)
+        0x23, 0x3c, 0xb5, 0x02, // sd a1, 56(a0)
+    ];
+    let mut emu = setup(data);
+    let base = DRAM_BASE + 0x100;
+    // Fill the slots with a pattern to detect bytes written by mistake.
+    for offset in 0..0x40 {
+        emu.cpu.bus.write(base + offset, 0x5a, BYTE).unwrap();
+    }
+    emu.cpu.xregs.write(10, base);
+    emu.cpu.xregs.write(11, u64::MAX);
+
+    step(&mut emu, 4);
+    // Each store writes `len` bytes at the middle of its 16-byte slot.
+    for (slot, &len) in [1, 2, 4, 8].iter().enumerate() {
+        let start = base + 16 * slot as u64;
+        for offset in 0..16 {
+            let expected = if (8..8 + len).contains(&offset) {
+                0xff
+            } else {
+                0x5a
+            };
+            let byte = emu.cpu.bus.read(start + offset, BYTE).unwrap();
+            assert_eq!(expected, byte, "{}-byte store, offset {}", len, offset);
+        }
+    }
+}
+
+#[test]
+fn reserved_encodings_raise_illegal_instruction() {
+    let encodings = [
+        0x0000_0000, // The all-zeros halfword is defined to be illegal.
+        0xffff_ffff, // The encodings longer than 32 bits.
+        0x0000_007f, // The opcode reserved for encodings longer than 64 bits.
+        0x0000_2067, // jalr with funct3 2.
+        0x0200_101b, // slliw with imm[5] set.
+        0x3020_00f3, // mret with rd = ra.
+        0x5810_0053, // fsqrt.s with rs2 = 1.
+        0x4020_0053, // fcvt.s.d with rs2 = 2.
+        0x0600_0043, // fmadd.q without the Q extension.
+        0x0000_7003, // A load with funct3 7.
+    ];
+    for &inst in &encodings {
+        let data = (inst as u32).to_le_bytes().to_vec();
+        let mut emu = setup(data);
+
+        let exception = emu
+            .cpu
+            .execute()
+            .expect_err("a reserved encoding should trap");
+        match exception {
+            Exception::IllegalInstruction(value) => assert_eq!(inst, value),
+            e => panic!("unexpected exception for {:#x}: {:?}", inst, e),
+        }
+        exception.take_trap(&mut emu.cpu);
+        assert_eq!(2, emu.cpu.state.read(MCAUSE));
+        assert_eq!(inst, emu.cpu.state.read(MTVAL));
+        assert_eq!(DRAM_BASE, emu.cpu.state.read(MEPC));
+    }
+}
+
+#[test]
+fn writes_to_x0_are_discarded() {
+    let data = vec![
+        0x13, 0x00, 0x50, 0x00, // addi zero, zero, 5
+        0x97, 0x02, 0x00, 0x00, // auipc t0, 0
+        0x03, 0xa0, 0x02, 0x00, // lw zero, 0(t0)
+        0x13, 0x03, 0x90, 0x00, // addi t1, zero, 9
+        0x2f, 0xa0, 0x62, 0x08, // amoswap.w zero, t1, (t0)
+    ];
+    let mut emu = setup(data);
+
+    step(&mut emu, 1);
+    assert_eq!(0, emu.cpu.xregs.read(0));
+
+    // The load is performed but its nonzero result is discarded.
+    step(&mut emu, 2);
+    assert_eq!(0, emu.cpu.xregs.read(0));
+
+    // The AMO still updates memory.
+    step(&mut emu, 2);
+    assert_eq!(0, emu.cpu.xregs.read(0));
+    assert_eq!(9, emu.cpu.bus.read(DRAM_BASE + 4, WORD).unwrap());
+}
+
+#[test]
+fn read_and_write_virt_go_through_page_tables() {
+    let data = vec![
+        0x73, 0x10, 0x05, 0x18, // csrrw zero, satp, a0
+    ];
+    let mut emu = setup(data);
+    let root = DRAM_BASE + 0x10000;
+    let mut next_table = root + 0x1000;
+    let code = DRAM_BASE;
+    let va = 0x4000_0000;
+    let pa = DRAM_BASE + 0x5000;
+    map_page(
+        &mut emu,
+        root,
+        &mut next_table,
+        code,
+        code,
+        PTE_R | PTE_X | PTE_A,
+    );
+    map_page(
+        &mut emu,
+        root,
+        &mut next_table,
+        va,
+        pa,
+        PTE_R | PTE_W | PTE_A | PTE_D,
+    );
+    emu.cpu.bus.write(pa + 8, 0x1122_3344, DOUBLEWORD).unwrap();
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.xregs.write(10, sv39_satp(root));
+    step(&mut emu, 1);
+
+    assert_eq!(0x1122_3344, emu.cpu.read_virt(va + 8, DOUBLEWORD).unwrap());
+    emu.cpu.write_virt(va + 16, WORD, 0xcafe).unwrap();
+    assert_eq!(0xcafe, emu.cpu.bus.read(pa + 16, WORD).unwrap());
+
+    // The unmapped page faults with its address, and the code page isn't writable.
+    let result = emu.cpu.read_virt(va + 0x1000, DOUBLEWORD);
+    assert!(matches!(result, Err(Exception::LoadPageFault(addr)) if addr == va + 0x1000));
+    let result = emu.cpu.write_virt(code, BYTE, 0);
+    assert!(matches!(result, Err(Exception::StoreAMOPageFault(addr)) if addr == code));
+}
+
+#[test]
+fn fetch_from_non_executable_page_faults() {
+    let data = vec![
+        0x73, 0x10, 0x05, 0x18, // csrrw zero, satp, a0
+        0x83, 0xb2, 0x05, 0x00, // ld t0, 0(a1)
+        0x67, 0x80, 0x05, 0x00, // jalr zero, 0(a1)
+    ];
+    let mut emu = setup(data);
+    let root = DRAM_BASE + 0x10000;
+    let mut next_table = root + 0x1000;
+    let code = DRAM_BASE;
+    let page = DRAM_BASE + 0x5000;
+    map_page(
+        &mut emu,
+        root,
+        &mut next_table,
+        code,
+        code,
+        PTE_R | PTE_X | PTE_A,
+    );
+    map_page(
+        &mut emu,
+        root,
+        &mut next_table,
+        page,
+        page,
+        PTE_R | PTE_W | PTE_A | PTE_D,
+    );
+    emu.cpu.bus.write(page, 0x1234, DOUBLEWORD).unwrap();
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.xregs.write(10, sv39_satp(root));
+    emu.cpu.xregs.write(11, page);
+
+    // The page is readable.
+    step(&mut emu, 2);
+    assert_eq!(0x1234, emu.cpu.xregs.read(5));
+
+    // The jump succeeds but fetching from the page doesn't.
+    step(&mut emu, 1);
+    assert_eq!(page, emu.cpu.pc);
+    let exception = emu.cpu.execute().expect_err("fetch should fault");
+    match exception {
+        Exception::InstructionPageFault(addr) => assert_eq!(page, addr),
+        e => panic!("unexpected exception: {:?}", e),
+    }
+    exception.take_trap(&mut emu.cpu);
+    assert_eq!(12, emu.cpu.state.read(MCAUSE));
+    assert_eq!(page, emu.cpu.state.read(MEPC));
+    assert_eq!(page, emu.cpu.state.read(MTVAL));
+}
+
+#[test]
+fn satp_write_switches_translation() {
+    let data = vec![
+        0x73, 0x10, 0x05, 0x18, // csrrw zero, satp, a0
+        0x83, 0xb2, 0x06, 0x00, // ld t0, 0(a3)
+        0x73, 0x90, 0x05, 0x18, // csrrw zero, satp, a1
+        0x03, 0xb3, 0x06, 0x00, // ld t1, 0(a3)
+        0x73, 0x10, 0x06, 0x18, // csrrw zero, satp, a2
+        0x83, 0xb3, 0x06, 0x00, // ld t2, 0(a3)
+    ];
+    let mut emu = setup(data);
+    let va = DRAM_BASE + 0x100000;
+    let old_page = DRAM_BASE + 0x5000;
+    let new_page = DRAM_BASE + 0x6000;
+    let old_root = DRAM_BASE + 0x10000;
+    let new_root = DRAM_BASE + 0x20000;
+    for &(root, page) in &[(old_root, old_page), (new_root, new_page)] {
+        let mut next_table = root + 0x1000;
+        let flags = PTE_R | PTE_X | PTE_A;
+        map_page(&mut emu, root, &mut next_table, DRAM_BASE, DRAM_BASE, flags);
+        map_page(&mut emu, root, &mut next_table, va, page, PTE_R | PTE_A);
+    }
+    emu.cpu.bus.write(old_page, 0x1111, DOUBLEWORD).unwrap();
+    emu.cpu.bus.write(new_page, 0x2222, DOUBLEWORD).unwrap();
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.xregs.write(10, sv39_satp(old_root));
+    emu.cpu.xregs.write(11, sv39_satp(new_root));
+    // The same page table with ASID 1.
+    emu.cpu.xregs.write(12, sv39_satp(new_root) | (1 << 44));
+    emu.cpu.xregs.write(13, va);
+
+    step(&mut emu, 2);
+    assert_eq!(0x1111, emu.cpu.xregs.read(5));
+
+    // The cached translation of `va` belongs to the old page table.
+    step(&mut emu, 2);
+    assert_eq!(0x2222, emu.cpu.xregs.read(6));
+    let cached = emu.cpu.tlb.len();
+
+    // Changing only the ASID keeps the cached translations.
+    step(&mut emu, 1);
+    assert_eq!(1, emu.cpu.tlb.asid());
+    assert_eq!(cached, emu.cpu.tlb.len());
+    step(&mut emu, 1);
+    assert_eq!(0x2222, emu.cpu.xregs.read(7));
+}
+
+#[test]
+fn asids_cache_address_spaces_independently() {
+    let data = vec![
+        0x73, 0x10, 0x05, 0x18, // csrrw zero, satp, a0
+        0x83, 0xb2, 0x06, 0x00, // ld t0, 0(a3)
+        0x73, 0x90, 0x05, 0x18, // csrrw zero, satp, a1
+        0x03, 0xb3, 0x06, 0x00, // ld t1, 0(a3)
+        0x73, 0x10, 0x05, 0x18, // csrrw zero, satp, a0
+        0x83, 0xb3, 0x06, 0x00, // ld t2, 0(a3)
+        0x73, 0x00, 0xe0, 0x12, // sfence.vma zero, a4
+        0x73, 0x90, 0x05, 0x18, // csrrw zero, satp, a1
+        0x03, 0xbe, 0x06, 0x00, // ld t3, 0(a3)
+        0x73, 0x80, 0x06, 0x12, // sfence.vma a3, zero
+        0x73, 0x10, 0x05, 0x18, // csrrw zero, satp, a0
+        0x83, 0xbe, 0x06, 0x00, // ld t4, 0(a3)
+    ];
+    let mut emu = setup(data);
+    let va = DRAM_BASE + 0x100000;
+    let page = DRAM_BASE + 0x5000;
+    let root = DRAM_BASE + 0x10000;
+    let mut next_table = root + 0x1000;
+    let flags = PTE_R | PTE_X | PTE_A;
+    map_page(&mut emu, root, &mut next_table, DRAM_BASE, DRAM_BASE, flags);
+    map_page(&mut emu, root, &mut next_table, va, page, PTE_R | PTE_A);
+    emu.cpu.bus.write(page, 0x1111, DOUBLEWORD).unwrap();
+    emu.cpu.mode = Mode::Supervisor;
+    // The same page table with ASIDs 1 and 2.
+    emu.cpu.xregs.write(10, sv39_satp(root) | (1 << 44));
+    emu.cpu.xregs.write(11, sv39_satp(root) | (2 << 44));
+    emu.cpu.xregs.write(13, va);
+    emu.cpu.xregs.write(14, 2);
+
+    step(&mut emu, 4);
+    assert_eq!(0x1111, emu.cpu.xregs.read(5));
+    assert_eq!(0x1111, emu.cpu.xregs.read(6));
+
+    // Switching back to ASID 1 finds both the code and `va` in the TLB.
+    let misses = emu.cpu.tlb.misses();
+    step(&mut emu, 2);
+    assert_eq!(0x1111, emu.cpu.xregs.read(7));
+    assert_eq!(misses, emu.cpu.tlb.misses());
+
+    // Flushing ASID 2 makes it walk both pages again.
+    step(&mut emu, 3);
+    assert_eq!(0x1111, emu.cpu.xregs.read(28));
+    assert_eq!(misses + 2, emu.cpu.tlb.misses());
+
+    // Flushing `va` in all address spaces keeps the code of ASID 1.
+    step(&mut emu, 3);
+    assert_eq!(0x1111, emu.cpu.xregs.read(29));
+    assert_eq!(misses + 3, emu.cpu.tlb.misses());
+}
+
+#[test]
+fn satp_root_change_flushes_every_asid() {
+    let data = vec![
+        0x73, 0x90, 0x05, 0x18, // csrrw zero, satp, a1
+        0x83, 0xb2, 0x06, 0x00, // ld t0, 0(a3)
+        0x73, 0x10, 0x05, 0x18, // csrrw zero, satp, a0
+        0x73, 0x10, 0x06, 0x18, // csrrw zero, satp, a2
+        0x03, 0xb3, 0x06, 0x00, // ld t1, 0(a3)
+    ];
+    let mut emu = setup(data);
+    let va = DRAM_BASE + 0x100000;
+    let page1 = DRAM_BASE + 0x5000;
+    let page2 = DRAM_BASE + 0x6000;
+    let root1 = DRAM_BASE + 0x10000;
+    let root2 = DRAM_BASE + 0x20000;
+    for &(root, page) in &[(root1, page1), (root2, page2)] {
+        let mut next_table = root + 0x1000;
+        let flags = PTE_R | PTE_X | PTE_A;
+        map_page(&mut emu, root, &mut next_table, DRAM_BASE, DRAM_BASE, flags);
+        map_page(&mut emu, root, &mut next_table, va, page, PTE_R | PTE_A);
+    }
+    emu.cpu.bus.write(page1, 0x1111, DOUBLEWORD).unwrap();
+    emu.cpu.bus.write(page2, 0x2222, DOUBLEWORD).unwrap();
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.xregs.write(10, sv39_satp(root1) | (1 << 44));
+    emu.cpu.xregs.write(11, sv39_satp(root1) | (2 << 44));
+    emu.cpu.xregs.write(12, sv39_satp(root2) | (2 << 44));
+    emu.cpu.xregs.write(13, va);
+
+    // ASID 2 caches `va` with the first page table.
+    step(&mut emu, 2);
+    assert_eq!(0x1111, emu.cpu.xregs.read(5));
+
+    // Reusing ASID 2 for another page table doesn't find the stale translation.
+    step(&mut emu, 3);
+    assert_eq!(2, emu.cpu.tlb.asid());
+    assert_eq!(0x2222, emu.cpu.xregs.read(6));
+}
+
+#[test]
+fn sfence_vma_flushes_every_page_of_a_megapage() {
+    let data = vec![
+        0x73, 0x10, 0x05, 0x18, // csrrw zero, satp, a0
+        0x83, 0xb2, 0x06, 0x00, // ld t0, 0(a3)
+        0x03, 0x33, 0x07, 0x00, // ld t1, 0(a4)
+        0x73, 0x00, 0x07, 0x12, // sfence.vma a4, zero
+        0x83, 0xb3, 0x06, 0x00, // ld t2, 0(a3)
+    ];
+    let mut emu = setup(data);
+    let root = DRAM_BASE + 0x10000;
+    let mut next_table = root + 0x1000;
+    let flags = PTE_R | PTE_X | PTE_A;
+    map_page(&mut emu, root, &mut next_table, DRAM_BASE, DRAM_BASE, flags);
+    // A 2 MiB megapage at 0xc000_0000, whose leaf PTE is in the second level.
+    let va = 0xc000_0000;
+    let pa = DRAM_BASE + 0x20_0000;
+    let table = next_table;
+    let bus = &mut emu.cpu.bus;
+    bus.write(root + 3 * 8, ((table >> 12) << 10) | PTE_V, DOUBLEWORD)
+        .unwrap();
+    bus.write(
+        table,
+        ((pa >> 12) << 10) | PTE_R | PTE_A | PTE_V,
+        DOUBLEWORD,
+    )
+    .unwrap();
+    bus.write(pa, 0x1111, DOUBLEWORD).unwrap();
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.xregs.write(10, sv39_satp(root));
+    emu.cpu.xregs.write(13, va);
+    emu.cpu.xregs.write(14, va + 0x1000);
+
+    // Each 4 KiB page of the megapage is cached separately.
+    step(&mut emu, 3);
+    assert_eq!(0x1111, emu.cpu.xregs.read(5));
+    let cached = emu.cpu.tlb.len();
+    let misses = emu.cpu.tlb.misses();
+
+    // Flushing the second page flushes the first one as well, but not the code.
+    step(&mut emu, 1);
+    assert_eq!(cached - 2, emu.cpu.tlb.len());
+    step(&mut emu, 1);
+    assert_eq!(0x1111, emu.cpu.xregs.read(7));
+    assert_eq!(misses + 1, emu.cpu.tlb.misses());
+}
+
+#[test]
+fn ecall_cause_depends_on_privilege() {
+    for &(mode, cause) in &[(Mode::User, 8), (Mode::Supervisor, 9), (Mode::Machine, 11)] {
+        let data = vec![
+            0x73, 0x00, 0x00, 0x00, // ecall
+        ];
+        let mut emu = setup(data);
+        emu.cpu.mode = mode;
+
+        let exception = emu.cpu.execute().expect_err("ecall should trap");
+        exception.take_trap(&mut emu.cpu);
+        assert_eq!(cause, emu.cpu.state.read(MCAUSE));
+        assert_eq!(DRAM_BASE, emu.cpu.state.read(MEPC));
+        assert_eq!(Mode::Machine, emu.cpu.mode);
+    }
+}
+
+#[test]
+fn n_extension_instructions_and_csrs_are_illegal() {
+    let encodings = [
+        0x0020_0073, // uret
+        0x0000_2573, // csrr a0, ustatus
+        0x0440_2573, // csrr a0, uip
+        0x0405_1073, // csrw uscratch, a0
+        0x1025_1073, // csrw sedeleg, a0
+        0x1030_2573, // csrr a0, sideleg
+    ];
+    for &inst in &encodings {
+        let data = (inst as u32).to_le_bytes().to_vec();
+        let mut emu = setup(data);
+        emu.cpu.state.write(MTVEC, DRAM_BASE + 0x100);
+
+        assert_eq!(Halt::InstructionLimit, emu.run(1));
+        assert_eq!(2, emu.cpu.state.read(MCAUSE), "{:#x}", inst);
+        assert_eq!(inst, emu.cpu.state.read(MTVAL));
+        assert_eq!(DRAM_BASE, emu.cpu.state.read(MEPC));
+        assert_eq!(DRAM_BASE + 0x100, emu.cpu.pc);
+    }
+
+    // The floating-point CSRs next to them still exist.
+    let data = vec![
+        0x73, 0x25, 0x10, 0x00, // csrr a0, fflags
+    ];
+    let mut emu = setup(data);
+    step(&mut emu, 1);
+}
+
+#[test]
+fn mcause_distinguishes_interrupts_from_exceptions() {
+    let base = DRAM_BASE + 0x100;
+
+    // A timer interrupt sets the interrupt bit, and vectored mode jumps to base + 4 * cause.
+    let mut emu = setup(vec![0x00, 0x00, 0x00, 0x00]);
+    emu.cpu.state.write(MTVEC, base | 1);
+    emu.cpu.state.write(MSTATUS, 1 << 3);
+    emu.cpu.state.write(MIE, MTIP_BIT);
+    emu.cpu.state.write(MIP, MTIP_BIT);
+    let interrupt = emu.cpu.check_pending_interrupt().unwrap();
+    assert_eq!(Interrupt::MachineTimerInterrupt, interrupt);
+    interrupt.take_trap(&mut emu.cpu);
+    assert_eq!((1 << 63) | 7, emu.cpu.state.read(MCAUSE));
+    assert_eq!(DRAM_BASE, emu.cpu.state.read(MEPC));
+    assert_eq!(base + 4 * 7, emu.cpu.pc);
+
+    // An exception doesn't, and it always goes to the base address.
+    let mut emu = setup(vec![0x00, 0x00, 0x00, 0x00]);
+    emu.cpu.state.write(MTVEC, base | 1);
+    let exception = emu.cpu.execute().unwrap_err();
+    exception.take_trap(&mut emu.cpu);
+    assert_eq!(2, emu.cpu.state.read(MCAUSE));
+    assert_eq!(DRAM_BASE, emu.cpu.state.read(MEPC));
+    assert_eq!(base, emu.cpu.pc);
+
+    // scause is encoded in the same way for the traps delegated to S-mode.
+    let mut emu = setup(vec![0x00, 0x00, 0x00, 0x00]);
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.state.write(SSTATUS, 1 << 1);
+    emu.cpu.state.write(STVEC, base | 1);
+    emu.cpu.state.write(MEDELEG, 1 << 2);
+    emu.cpu.state.write(MIDELEG, STIP_BIT);
+    emu.cpu.state.write(MIE, STIP_BIT);
+    emu.cpu.state.write(MIP, STIP_BIT);
+    let interrupt = emu.cpu.check_pending_interrupt().unwrap();
+    interrupt.take_trap(&mut emu.cpu);
+    assert_eq!((1 << 63) | 5, emu.cpu.state.read(SCAUSE));
+    assert_eq!(base + 4 * 5, emu.cpu.pc);
+
+    emu.cpu.pc = DRAM_BASE;
+    let exception = emu.cpu.execute().unwrap_err();
+    exception.take_trap(&mut emu.cpu);
+    assert_eq!(2, emu.cpu.state.read(SCAUSE));
+    assert_eq!(base, emu.cpu.pc);
+}
+
+#[test]
+fn wfi_traps_in_supervisor_mode_when_tw_is_set() {
+    for &(mode, tw, traps) in &[
+        (Mode::Supervisor, true, true),
+        (Mode::Supervisor, false, false),
+        (Mode::Machine, true, false),
+    ] {
+        let data = vec![
+            0x73, 0x00, 0x50, 0x10, // wfi
+        ];
+        let mut emu = setup(data);
+        emu.cpu.mode = mode;
+        if tw {
+            emu.cpu.state.write(MSTATUS, MSTATUS_TW);
+        }
+
+        let result = emu.cpu.execute();
+        assert_eq!(
+            traps,
+            matches!(result, Err(Exception::IllegalInstruction(_)))
+        );
+        assert_eq!(!traps, emu.cpu.idle);
+    }
+}
+
+#[test]
+fn sret_is_gated_by_mode_and_tsr() {
+    for &(mode, tsr, traps) in &[
+        (Mode::User, false, true),
+        (Mode::Supervisor, true, true),
+        (Mode::Supervisor, false, false),
+        (Mode::Machine, true, false),
+    ] {
+        let data = vec![
+            0x73, 0x00, 0x20, 0x10, // sret
+        ];
+        let mut emu = setup(data);
+        emu.cpu.mode = mode;
+        emu.cpu.state.write(SEPC, DRAM_BASE + 0x100);
+        if tsr {
+            emu.cpu.state.write(MSTATUS, MSTATUS_TSR);
+        }
+
+        let result = emu.cpu.execute();
+        assert_eq!(
+            traps,
+            matches!(result, Err(Exception::IllegalInstruction(_)))
+        );
+        if traps {
+            assert_eq!(mode, emu.cpu.mode);
+        } else {
+            assert_eq!(DRAM_BASE + 0x100, emu.cpu.pc);
+        }
+    }
+}
+
+#[test]
+fn sret_returns_to_spp() {
+    for &(spp, mode) in &[(0, Mode::User), (SSTATUS_SPP, Mode::Supervisor)] {
+        let data = vec![
+            0x73, 0x00, 0x20, 0x10, // sret
+        ];
+        let mut emu = setup(data);
+        emu.cpu.mode = Mode::Machine;
+        emu.cpu.state.write(SEPC, DRAM_BASE + 0x100);
+        emu.cpu.state.write(SSTATUS, spp);
+
+        emu.cpu.execute().unwrap();
+        assert_eq!(mode, emu.cpu.mode);
+        assert_eq!(DRAM_BASE + 0x100, emu.cpu.pc);
+        // SPP is cleared to U-mode.
+        assert_eq!(0, emu.cpu.state.read(SSTATUS) & SSTATUS_SPP);
+    }
+}
+
+#[test]
+fn jalr_clears_low_bit_of_target() {
+    let data = vec![
+        0xe7, 0x80, 0x02, 0x00, // jalr ra, 0(t0)
+    ];
+    let mut emu = setup(data);
+    emu.cpu.xregs.write(5, DRAM_BASE + 9);
+
+    step(&mut emu, 1);
+    assert_eq!(DRAM_BASE + 8, emu.cpu.pc);
+    assert_eq!(DRAM_BASE + 4, emu.cpu.xregs.read(1));
+}
+
+#[test]
+fn jalr_to_halfword_target_faults_only_without_c() {
+    let data = vec![
+        0xe7, 0x80, 0x02, 0x00, // jalr ra, 0(t0)
+    ];
+    let mut emu = setup(data.clone());
+    emu.cpu.xregs.write(5, DRAM_BASE + 6);
+    step(&mut emu, 1);
+    assert_eq!(DRAM_BASE + 6, emu.cpu.pc);
+
+    let mut emu = setup(data);
+    emu.cpu.xregs.write(5, DRAM_BASE + 6);
+    emu.cpu
+        .state
+        .write(MISA, emu.cpu.state.read(MISA) & !MISA_C);
+    let exception = emu.cpu.execute().expect_err("jalr should trap");
+    assert!(matches!(
+        exception,
+        Exception::InstructionAddressMisaligned(addr) if addr == DRAM_BASE + 6
+    ));
+    // rd isn't written.
+    assert_eq!(0, emu.cpu.xregs.read(1));
+    exception.take_trap(&mut emu.cpu);
+    assert_eq!(DRAM_BASE, emu.cpu.state.read(MEPC));
+    assert_eq!(DRAM_BASE + 6, emu.cpu.state.read(MTVAL));
+}
+
+#[test]
+fn mret_returns_to_aligned_mepc() {
+    // The low bit is always masked, and bit 1 is masked on reads without the C extension.
+    for &(mepc, compressed) in &[(DRAM_BASE + 9, true), (DRAM_BASE + 10, false)] {
+        let data = vec![
+            0x73, 0x00, 0x20, 0x30, // mret
+            0x13, 0x05, 0x10, 0x00, // addi a0, zero, 1
+            0x13, 0x05, 0x20, 0x00, // addi a0, zero, 2
+        ];
+        let mut emu = setup(data);
+        if !compressed {
+            emu.cpu
+                .state
+                .write(MISA, emu.cpu.state.read(MISA) & !MISA_C);
+        }
+        emu.cpu.state.write(MEPC, mepc);
+        assert_eq!(DRAM_BASE + 8, emu.cpu.state.read(MEPC));
+
+        step(&mut emu, 2);
+        assert_eq!(2, emu.cpu.xregs.read(10));
+        assert_eq!(DRAM_BASE + 12, emu.cpu.pc);
+    }
+}
+
+#[test]
+fn mstatus_mpp_only_holds_supported_modes() {
+    let data = vec![
+        0xb7, 0x12, 0x00, 0x00, // lui t0, 1 (MPP=2)
+        0x73, 0x90, 0x02, 0x30, // csrw mstatus, t0
+        0x73, 0x25, 0x00, 0x30, // csrr a0, mstatus
+        0x73, 0x00, 0x20, 0x30, // mret
+    ];
+    let mut emu = setup(data);
+    emu.cpu.state.write(MEPC, DRAM_BASE + 0x100);
+
+    // The reserved mode reads back as U-mode, and `mret` returns to it.
+    step(&mut emu, 3);
+    assert_eq!(0, emu.cpu.xregs.read(10) & MSTATUS_MPP);
+    step(&mut emu, 1);
+    assert_eq!(Mode::User, emu.cpu.mode);
+    assert_eq!(DRAM_BASE + 0x100, emu.cpu.pc);
+
+    // S-mode is kept only while the S extension is implemented.
+    emu.cpu.state.write(MSTATUS, 1 << 11);
+    assert_eq!(1 << 11, emu.cpu.state.read(MSTATUS) & MSTATUS_MPP);
+    emu.cpu
+        .state
+        .write(MISA, emu.cpu.state.read(MISA) & !MISA_S);
+    emu.cpu.state.write(MSTATUS, 1 << 11);
+    assert_eq!(0, emu.cpu.state.read(MSTATUS) & MSTATUS_MPP);
+    emu.cpu.state.write(MSTATUS, MSTATUS_MPP);
+    assert_eq!(MSTATUS_MPP, emu.cpu.state.read(MSTATUS) & MSTATUS_MPP);
+}
+
+#[test]
+fn nmi_is_taken_with_interrupts_disabled_and_mnret_resumes() {
+    let mut data = vec![
+        0x13, 0x05, 0x10, 0x00, // addi a0, zero, 1
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    data.resize(0x100, 0);
+    // The NMI handler.
+    data.extend_from_slice(&[
+        0xf3, 0x22, 0x20, 0x74, // csrr t0, mncause
+        0x73, 0x23, 0x10, 0x74, // csrr t1, mnepc
+        0xf3, 0x23, 0x40, 0x74, // csrr t2, mnstatus
+        0x13, 0x0e, 0x1e, 0x00, // addi t3, t3, 1
+        0x73, 0x00, 0x20, 0x70, // mnret
+    ]);
+    let mut emu = setup(data);
+    emu.set_nmi_vector(DRAM_BASE + 0x100);
+    emu.cpu.state.write(MSTATUS, 0);
+    emu.run(5);
+    assert_eq!(DRAM_BASE + 4, emu.cpu.pc);
+
+    // MIE is clear, but the NMI is taken and the handler returns to the loop.
+    emu.raise_nmi(5);
+    emu.run(20);
+    assert_eq!(1, emu.cpu.xregs.read(28));
+    assert_eq!((1 << 63) | 5, emu.cpu.xregs.read(5));
+    assert_eq!(DRAM_BASE + 4, emu.cpu.xregs.read(6));
+    assert_eq!(MNSTATUS_MNPP, emu.cpu.xregs.read(7));
+    assert_eq!(DRAM_BASE + 4, emu.cpu.pc);
+    assert_eq!(Mode::Machine, emu.cpu.mode);
+    assert_eq!(MNSTATUS_NMIE, emu.cpu.state.read(MNSTATUS) & MNSTATUS_NMIE);
+
+    // Software can't clear NMIE, and an NMI waits while it's clear.
+    emu.cpu.state.write(MNSTATUS, 0);
+    assert_eq!(MNSTATUS_NMIE, emu.cpu.state.read(MNSTATUS) & MNSTATUS_NMIE);
+    emu.cpu.state.clear_nmie();
+    emu.raise_nmi(7);
+    emu.run(20);
+    assert_eq!(1, emu.cpu.xregs.read(28));
+    emu.cpu.state.write(MNSTATUS, MNSTATUS_NMIE);
+    emu.run(20);
+    assert_eq!(2, emu.cpu.xregs.read(28));
+    assert_eq!((1 << 63) | 7, emu.cpu.xregs.read(5));
+
+    // `mnret` returns to the mode which the NMI interrupted, and clears MPRV below M-mode.
+    emu.cpu.mode = Mode::User;
+    emu.cpu.state.write(MSTATUS, MSTATUS_MPRV);
+    emu.raise_nmi(9);
+    emu.run(20);
+    assert_eq!(3, emu.cpu.xregs.read(28));
+    assert_eq!(0, emu.cpu.xregs.read(7) & MNSTATUS_MNPP);
+    assert_eq!(Mode::User, emu.cpu.mode);
+    assert_eq!(DRAM_BASE + 4, emu.cpu.pc);
+    assert_eq!(0, emu.cpu.state.read(MSTATUS) & MSTATUS_MPRV);
+
+    // An NMI is masked in Debug mode and is taken after the hart leaves it.
+    emu.cpu.mode = Mode::Debug;
+    emu.raise_nmi(11);
+    assert!(!emu.cpu.take_pending_nmi());
+    assert_eq!(Mode::Debug, emu.cpu.mode);
+    emu.cpu.mode = Mode::Supervisor;
+    assert!(emu.cpu.take_pending_nmi());
+    assert_eq!(1 << 11, emu.cpu.state.read(MNSTATUS) & MNSTATUS_MNPP);
+    assert_eq!((1 << 63) | 11, emu.cpu.state.read(MNCAUSE));
+}
+
+#[test]
+fn illegal_compressed_instruction_sets_mtval_to_its_16_bits() {
+    let data = vec![
+        0x04, 0x00, // c.addi4spn s1, sp, 0 (reserved, nzuimm is 0)
+        0xff, 0xff, // the following halfword isn't part of the instruction
+    ];
+    let mut emu = setup(data);
+
+    let exception = emu.cpu.execute().expect_err("c.addi4spn should trap");
+    assert!(matches!(exception, Exception::IllegalInstruction(0x0004)));
+    exception.take_trap(&mut emu.cpu);
+    assert_eq!(2, emu.cpu.state.read(MCAUSE));
+    assert_eq!(0x0004, emu.cpu.state.read(MTVAL));
+    // mepc points at the compressed instruction, not 2 bytes before it.
+    assert_eq!(DRAM_BASE, emu.cpu.state.read(MEPC));
+}
+
+#[test]
+fn fcvt_rounds_in_mode_of_rm_field() {
+    let data = vec![
+        0x53, 0x05, 0x05, 0xc2, // fcvt.w.d a0, fa0, rne
+        0xd3, 0x15, 0x05, 0xc2, // fcvt.w.d a1, fa0, rtz
+        0x53, 0x76, 0x05, 0xc2, // fcvt.w.d a2, fa0, dyn
+        0xd3, 0x86, 0x25, 0xc2, // fcvt.l.d a3, fa1, rne
+    ];
+    let mut emu = setup(data);
+    emu.cpu.fregs.write(10, 3.5);
+    emu.cpu.fregs.write(11, -2.5);
+    // frm is round towards zero.
+    emu.cpu.state.write(FCSR, 0b001 << 5);
+
+    step(&mut emu, 4);
+    assert_eq!(4, emu.cpu.xregs.read(10));
+    assert_eq!(3, emu.cpu.xregs.read(11));
+    assert_eq!(3, emu.cpu.xregs.read(12));
+    assert_eq!(-2i64 as u64, emu.cpu.xregs.read(13));
+}
+
+#[test]
+fn fcvt_with_reserved_rm_is_illegal() {
+    let data = vec![
+        0x53, 0x55, 0x05, 0xc2, // fcvt.w.d a0, fa0 with the reserved rm 0b101
+    ];
+    let mut emu = setup(data);
+    emu.cpu.fregs.write(10, 3.5);
+
+    let result = emu.cpu.execute();
+    assert!(matches!(result, Err(Exception::IllegalInstruction(_))));
+}
+
+#[test]
+fn fp_instruction_sets_fs_dirty_until_kernel_cleans_it() {
+    let data = vec![
+        0x53, 0xf0, 0x20, 0x02, // fadd.d ft0, ft1, ft2
+        0x73, 0xb0, 0x02, 0x10, // csrrc zero, sstatus, t0
+    ];
+    let mut emu = setup(data);
+    // Clearing the low bit of FS turns Dirty into Clean.
+    emu.cpu.xregs.write(5, 0b01 << 13);
+    assert_eq!(0, emu.cpu.state.read(MSTATUS) & (MSTATUS_FS | MSTATUS_SD));
+
+    step(&mut emu, 1);
+    assert_eq!(MSTATUS_FS, emu.cpu.state.read(MSTATUS) & MSTATUS_FS);
+    assert_eq!(MSTATUS_SD, emu.cpu.state.read(MSTATUS) & MSTATUS_SD);
+    assert_eq!(MSTATUS_SD, emu.cpu.state.read(SSTATUS) & MSTATUS_SD);
+
+    step(&mut emu, 1);
+    assert_eq!(0b10 << 13, emu.cpu.state.read(MSTATUS) & MSTATUS_FS);
+    assert_eq!(0, emu.cpu.state.read(MSTATUS) & MSTATUS_SD);
+}
+
+#[test]
+fn czero_writes_zero_or_rs1_by_condition() {
+    for &(condition, eqz, nez) in &[(0, 0, 42), (7, 42, 0)] {
+        let data = vec![
+            0x33, 0xd5, 0xc5, 0x0e, // czero.eqz a0, a1, a2
+            0xb3, 0xf6, 0xc5, 0x0e, // czero.nez a3, a1, a2
+        ];
+        let mut emu = setup(data);
+        emu.cpu.xregs.write(10, 1);
+        emu.cpu.xregs.write(11, 42);
+        emu.cpu.xregs.write(12, condition);
+        emu.cpu.xregs.write(13, 1);
+
+        step(&mut emu, 2);
+        assert_eq!(eqz, emu.cpu.xregs.read(10));
+        assert_eq!(nez, emu.cpu.xregs.read(13));
+    }
+}
+
+#[test]
+fn czero_is_illegal_without_zicond() {
+    let data = vec![
+        0x33, 0xd5, 0xc5, 0x0e, // czero.eqz a0, a1, a2
+    ];
+    let mut emu = setup(data);
+    emu.cpu.is_zicond = false;
+
+    let result = emu.cpu.execute();
+    assert!(matches!(result, Err(Exception::IllegalInstruction(_))));
+}
+
+#[test]
+fn zbs_sets_clears_inverts_and_extracts_single_bits() {
+    let data = vec![
+        0x33, 0x95, 0xc5, 0x28, // bset a0, a1, a2
+        0xb3, 0x96, 0xc5, 0x48, // bclr a3, a1, a2
+        0x33, 0x97, 0xc5, 0x68, // binv a4, a1, a2
+        0xb3, 0xd7, 0xc5, 0x48, // bext a5, a1, a2
+    ];
+    // The index is taken modulo 64, so 127 is bit 63.
+    for &(index, bit) in &[(0, 1), (63, 1 << 63), (127, 1 << 63)] {
+        for &rs1 in &[0, u64::MAX] {
+            let mut emu = setup(data.clone());
+            emu.cpu.xregs.write(11, rs1);
+            emu.cpu.xregs.write(12, index);
+
+            step(&mut emu, 4);
+            assert_eq!(rs1 | bit, emu.cpu.xregs.read(10));
+            assert_eq!(rs1 & !bit, emu.cpu.xregs.read(13));
+            assert_eq!(rs1 ^ bit, emu.cpu.xregs.read(14));
+            assert_eq!(rs1 & 1, emu.cpu.xregs.read(15));
+        }
+    }
+}
+
+#[test]
+fn zbs_immediate_forms_use_shamt_as_index() {
+    let data = vec![
+        0x13, 0x95, 0xf5, 0x2b, // bseti a0, a1, 63
+        0x93, 0x96, 0x05, 0x48, // bclri a3, a1, 0
+        0x13, 0x97, 0xf5, 0x6b, // binvi a4, a1, 63
+        0x93, 0xd7, 0xf5, 0x4b, // bexti a5, a1, 63
+        0x13, 0xd8, 0x05, 0x48, // bexti a6, a1, 0
+    ];
+    let mut emu = setup(data);
+    emu.cpu.xregs.write(11, 0x8000_0000_0000_0001);
+
+    step(&mut emu, 5);
+    assert_eq!(0x8000_0000_0000_0001, emu.cpu.xregs.read(10));
+    assert_eq!(0x8000_0000_0000_0000, emu.cpu.xregs.read(13));
+    assert_eq!(0x0000_0000_0000_0001, emu.cpu.xregs.read(14));
+    assert_eq!(1, emu.cpu.xregs.read(15));
+    assert_eq!(1, emu.cpu.xregs.read(16));
+}
+
+#[test]
+fn zbs_is_illegal_without_zbs() {
+    for &inst in &[0x28c5_9533u32, 0x2bf5_9513] {
+        let mut emu = setup(inst.to_le_bytes().to_vec());
+        emu.cpu.is_zbs = false;
+
+        let result = emu.cpu.execute();
+        assert!(matches!(result, Err(Exception::IllegalInstruction(_))));
+    }
+}
+
+#[test]
+fn zbc_carry_less_products() {
+    let data = vec![
+        0x33, 0x16, 0xb5, 0x0a, // clmul a2, a0, a1
+        0xb3, 0x36, 0xb5, 0x0a, // clmulh a3, a0, a1
+        0x33, 0x27, 0xb5, 0x0a, // clmulr a4, a0, a1
+    ];
+    // (rs1, rs2, clmul, clmulh, clmulr)
+    let cases = [
+        // (x + 1)^2 = x^2 + 1 because the carry is discarded.
+        (0b11, 0b11, 0b101, 0, 0),
+        (u64::MAX, 2, 0xffff_ffff_ffff_fffe, 1, 3),
+        (
+            u64::MAX,
+            u64::MAX,
+            0x5555_5555_5555_5555,
+            0x5555_5555_5555_5555,
+            0xaaaa_aaaa_aaaa_aaaa,
+        ),
+        (
+            0x8000_0000_0000_0001,
+            0x8000_0000_0000_0001,
+            1,
+            0x4000_0000_0000_0000,
+            0x8000_0000_0000_0000,
+        ),
+    ];
+    for &(rs1, rs2, low, high, reversed) in &cases {
+        let mut emu = setup(data.clone());
+        emu.cpu.xregs.write(10, rs1);
+        emu.cpu.xregs.write(11, rs2);
+
+        step(&mut emu, 3);
+        assert_eq!(low, emu.cpu.xregs.read(12));
+        assert_eq!(high, emu.cpu.xregs.read(13));
+        assert_eq!(reversed, emu.cpu.xregs.read(14));
+    }
+}
+
+#[test]
+fn zbc_is_illegal_without_zbc() {
+    let data = vec![
+        0x33, 0x16, 0xb5, 0x0a, // clmul a2, a0, a1
+    ];
+    let mut emu = setup(data);
+    emu.cpu.is_zbc = false;
+    emu.cpu.is_zbkc = false;
+
+    let result = emu.cpu.execute();
+    assert!(matches!(result, Err(Exception::IllegalInstruction(_))));
+}
+
+#[test]
+fn zbkb_brev8_and_pack() {
+    let data = vec![
+        0x13, 0x56, 0x75, 0x68, // brev8 a2, a0
+        0xb3, 0x46, 0xb5, 0x08, // pack a3, a0, a1
+        0x33, 0x77, 0xb5, 0x08, // packh a4, a0, a1
+        0xbb, 0x47, 0xb5, 0x08, // packw a5, a0, a1
+    ];
+    let mut emu = setup(data);
+    emu.cpu.xregs.write(10, 0x0123_4567_89ab_cdef);
+    emu.cpu.xregs.write(11, 0xfedc_ba98_7654_3210);
+
+    step(&mut emu, 4);
+    assert_eq!(0x80c4_a2e6_91d5_b3f7, emu.cpu.xregs.read(12));
+    assert_eq!(0x7654_3210_89ab_cdef, emu.cpu.xregs.read(13));
+    assert_eq!(0x10ef, emu.cpu.xregs.read(14));
+    assert_eq!(0x3210_cdef, emu.cpu.xregs.read(15));
+}
+
+#[test]
+fn zbkx_xperm_selects_elements() {
+    let data = vec![
+        0x33, 0x26, 0xb5, 0x28, // xperm4 a2, a0, a1
+        0xb3, 0x46, 0xb5, 0x28, // xperm8 a3, a0, a1
+    ];
+    let mut emu = setup(data);
+    emu.cpu.xregs.write(10, 0x0123_4567_89ab_cdef);
+    // Nibble or byte indices, where 0x10 and 0xff are out of range for xperm8.
+    emu.cpu.xregs.write(11, 0x0001_0203_0410_ff07);
+
+    step(&mut emu, 2);
+    assert_eq!(0xfffe_fdfc_fbef_00f8, emu.cpu.xregs.read(12));
+    assert_eq!(0xefcd_ab89_6700_0001, emu.cpu.xregs.read(13));
+}
+
+#[test]
+fn zbkb_is_illegal_without_zbkb() {
+    let data = vec![
+        0x33, 0x46, 0xb5, 0x08, // pack a2, a0, a1
+    ];
+    let mut emu = setup(data);
+    emu.cpu.is_zbkb = false;
+
+    let result = emu.cpu.execute();
+    assert!(matches!(result, Err(Exception::IllegalInstruction(_))));
+}
+
+#[test]
+fn instruction_histogram_counts_each_mnemonic() {
+    let data = vec![
+        0x13, 0x05, 0x30, 0x00, // addi a0, zero, 3
+        0x13, 0x05, 0xf5, 0xff, // addi a0, a0, -1
+        0xe3, 0x1e, 0x05, 0xfe, // bne a0, zero, -4
+        0x6f, 0x00, 0x80, 0x00, // jal zero, 8
+        0x93, 0x05, 0x10, 0x00, // addi a1, zero, 1
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data.clone());
+    emu.run(10);
+    assert!(emu.cpu.instruction_histogram().is_empty());
+
+    let mut emu = setup(data);
+    emu.cpu.is_count = true;
+    // The loop runs 3 times, and the last jal spins twice.
+    emu.run(10);
+    let histogram = emu.cpu.instruction_histogram();
+    assert_eq!(Some(&4), histogram.get("addi"));
+    assert_eq!(Some(&3), histogram.get("bne"));
+    assert_eq!(Some(&3), histogram.get("jal"));
+    assert_eq!(10, histogram.values().sum::<u64>());
+}
+
+#[test]
+fn fences_execute_as_ordering_nops() {
+    let data = vec![
+        0x0f, 0x00, 0xf0, 0x0f, // fence
+        0x0f, 0x00, 0x30, 0x83, // fence.tso
+        0x0f, 0x00, 0x10, 0x03, // fence rw, w
+        0x0f, 0x00, 0x30, 0x93, // fence rw, rw with the reserved fm 1001
+        0x0f, 0x00, 0x00, 0x00, // fence 0, 0
+    ];
+    let mut emu = setup(data);
+    emu.cpu.is_count = true;
+
+    for i in 1..=5 {
+        emu.cpu.execute().unwrap();
+        assert_eq!(DRAM_BASE + 4 * i, emu.cpu.pc);
+    }
+    let histogram = emu.cpu.instruction_histogram();
+    assert_eq!(Some(&1), histogram.get("fence.tso"));
+    assert_eq!(Some(&4), histogram.get("fence"));
+}
+
+#[test]
+fn upper_immediates_are_sign_extended() {
+    let data = vec![
+        0x37, 0x05, 0x00, 0x80, // lui a0, 0x80000
+        0xb7, 0xf5, 0xff, 0xff, // lui a1, 0xfffff
+        0x17, 0x06, 0x00, 0x80, // auipc a2, 0x80000
+        0x97, 0xf6, 0xff, 0xff, // auipc a3, 0xfffff
+    ];
+    let mut emu = setup(data);
+
+    step(&mut emu, 4);
+    assert_eq!(0xffff_ffff_8000_0000, emu.cpu.xregs.read(10));
+    assert_eq!(0xffff_ffff_ffff_f000, emu.cpu.xregs.read(11));
+    // The offsets are negative, so they move the addresses down from the instructions.
+    assert_eq!(
+        (DRAM_BASE + 8).wrapping_sub(0x8000_0000),
+        emu.cpu.xregs.read(12)
+    );
+    assert_eq!(DRAM_BASE + 12 - 0x1000, emu.cpu.xregs.read(13));
+}
+
+#[test]
+fn shifts_mask_the_amount_by_operand_width() {
+    let data = vec![
+        0x33, 0x16, 0xb5, 0x00, // sll a2, a0, a1
+        0xb3, 0x56, 0xb5, 0x00, // srl a3, a0, a1
+        0x33, 0x57, 0xb5, 0x40, // sra a4, a0, a1
+        0xbb, 0x17, 0xb5, 0x00, // sllw a5, a0, a1
+        0x3b, 0x58, 0xb5, 0x00, // srlw a6, a0, a1
+        0xbb, 0x58, 0xb5, 0x40, // sraw a7, a0, a1
+        0x13, 0x19, 0x15, 0x02, // slli s2, a0, 33
+        0x93, 0x59, 0x15, 0x02, // srli s3, a0, 33
+        0x13, 0x5a, 0x15, 0x42, // srai s4, a0, 33
+        0x9b, 0x1a, 0x15, 0x00, // slliw s5, a0, 1
+        0x1b, 0x5b, 0x15, 0x00, // srliw s6, a0, 1
+        0x9b, 0x5b, 0x15, 0x40, // sraiw s7, a0, 1
+    ];
+    let mut emu = setup(data);
+    emu.cpu.xregs.write(10, 0xf000_0001_8000_0003);
+    // The 64-bit forms shift by 33, and the 32-bit forms only see the low 5 bits, 1.
+    emu.cpu.xregs.write(11, 33);
+
+    step(&mut emu, 12);
+    assert_eq!(0x0000_0006_0000_0000, emu.cpu.xregs.read(12));
+    assert_eq!(0x0000_0000_7800_0000, emu.cpu.xregs.read(13));
+    assert_eq!(0xffff_ffff_f800_0000, emu.cpu.xregs.read(14));
+    assert_eq!(0x0000_0000_0000_0006, emu.cpu.xregs.read(15));
+    assert_eq!(0x0000_0000_4000_0001, emu.cpu.xregs.read(16));
+    assert_eq!(0xffff_ffff_c000_0001, emu.cpu.xregs.read(17));
+    assert_eq!(emu.cpu.xregs.read(12), emu.cpu.xregs.read(18));
+    assert_eq!(emu.cpu.xregs.read(13), emu.cpu.xregs.read(19));
+    assert_eq!(emu.cpu.xregs.read(14), emu.cpu.xregs.read(20));
+    assert_eq!(emu.cpu.xregs.read(15), emu.cpu.xregs.read(21));
+    assert_eq!(emu.cpu.xregs.read(16), emu.cpu.xregs.read(22));
+    assert_eq!(emu.cpu.xregs.read(17), emu.cpu.xregs.read(23));
+}
+
+#[test]
+fn word_shifts_by_immediates_over_31_are_illegal() {
+    for inst in [0x0215_1a9bu32, 0x0215_5b1b, 0x4215_5b9b].iter() {
+        // slliw s5, srliw s6, and sraiw s7 by 33 from a0, whose imm[5] is set.
+        let mut emu = setup(inst.to_le_bytes().to_vec());
+
+        let result = emu.cpu.execute();
+        assert!(matches!(result, Err(Exception::IllegalInstruction(_))));
+    }
+}
+
+#[test]
+fn stimecmp_raises_supervisor_timer_interrupt() {
+    let data = vec![
+        0x73, 0x10, 0xd5, 0x14, // csrw stimecmp, a0
+        0xf3, 0x25, 0x10, 0xc0, // csrr a1, time
+        0x73, 0x90, 0xd5, 0x14, // csrw stimecmp, a1
+    ];
+    let mut emu = setup(data.clone());
+    emu.cpu.state.write(MENVCFG, MENVCFG_STCE);
+    emu.cpu.state.write(MIDELEG, STIP_BIT);
+    emu.cpu.state.write(MIE, STIP_BIT);
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.state.write(SSTATUS, 1 << 1);
+    emu.cpu.xregs.write(10, u64::MAX);
+
+    step(&mut emu, 1);
+    assert_eq!(0, emu.cpu.state.read(MIP) & STIP_BIT);
+    assert_eq!(None, emu.cpu.check_pending_interrupt());
+
+    // `time` is greater than or equal to `stimecmp` from now on.
+    step(&mut emu, 2);
+    assert_eq!(STIP_BIT, emu.cpu.state.read(SIP) & STIP_BIT);
+    assert_eq!(
+        Some(Interrupt::SupervisorTimerInterrupt),
+        emu.cpu.check_pending_interrupt()
+    );
+    // STIP follows `stimecmp` instead of writes to mip.
+    emu.cpu.state.write(MIP, 0);
+    assert_eq!(STIP_BIT, emu.cpu.state.read(MIP) & STIP_BIT);
+    emu.cpu
+        .state
+        .write(STIMECMP, emu.cpu.state.read(STIMECMP) + 10);
+    assert_eq!(0, emu.cpu.state.read(MIP) & STIP_BIT);
+
+    // Without STCE, S-mode can't access `stimecmp`.
+    let mut emu = setup(data);
+    emu.cpu.mode = Mode::Supervisor;
+    let result = emu.cpu.execute();
+    assert!(matches!(result, Err(Exception::IllegalInstruction(_))));
+}
+
+#[test]
+fn stimecmp_only_fires_when_stce_is_set() {
+    let data = vec![
+        0x73, 0x10, 0xd0, 0x14, // csrw stimecmp, zero
+        0x73, 0x10, 0xa5, 0x30, // csrw menvcfg, a0
+    ];
+    let mut emu = setup(data);
+    emu.cpu.xregs.write(10, MENVCFG_STCE);
+
+    // `time` is equal to `stimecmp`, but it doesn't drive STIP without STCE.
+    step(&mut emu, 1);
+    assert_eq!(0, emu.cpu.state.read(MIP) & STIP_BIT);
+
+    step(&mut emu, 1);
+    assert_eq!(STIP_BIT, emu.cpu.state.read(MIP) & STIP_BIT);
+
+    // Only the implemented fields are writable.
+    emu.cpu.state.write(MENVCFG, u64::MAX);
+    assert_eq!(
+        MENVCFG_STCE | ENVCFG_CBZE | ENVCFG_CBCFE | ENVCFG_FIOM,
+        emu.cpu.state.read(MENVCFG)
+    );
+    emu.cpu.state.write(SENVCFG, u64::MAX);
+    assert_eq!(
+        ENVCFG_CBZE | ENVCFG_CBCFE | ENVCFG_FIOM,
+        emu.cpu.state.read(SENVCFG)
+    );
+}
+
+#[test]
+fn cache_block_operations_are_enabled_by_envcfg() {
+    let data = vec![
+        0x0f, 0x20, 0x45, 0x00, // cbo.zero (a0)
+        0x0f, 0x20, 0x15, 0x00, // cbo.clean (a0)
+        0x0f, 0x20, 0x25, 0x00, // cbo.flush (a0)
+        0x0f, 0x20, 0x05, 0x00, // cbo.inval (a0)
+    ];
+    let block = DRAM_BASE + 0x100;
+    let run_at = |mode, menvcfg, senvcfg, offset| {
+        let mut emu = setup(data.clone());
+        for addr in (block..block + 128).step_by(8) {
+            emu.cpu.bus.write(addr, u64::MAX, DOUBLEWORD).unwrap();
+        }
+        emu.cpu.state.write(MENVCFG, menvcfg);
+        emu.cpu.state.write(SENVCFG, senvcfg);
+        emu.cpu.mode = mode;
+        emu.cpu.pc = DRAM_BASE + offset;
+        // The address doesn't need to be aligned to the cache block.
+        emu.cpu.xregs.write(10, block + 0x18);
+        let result = emu.cpu.execute();
+        (result, emu)
+    };
+
+    for &(mode, menvcfg, senvcfg) in &[
+        (Mode::Supervisor, 0, ENVCFG_CBZE),
+        (Mode::User, ENVCFG_CBZE, 0),
+    ] {
+        let (result, _) = run_at(mode, menvcfg, senvcfg, 0);
+        assert!(matches!(result, Err(Exception::IllegalInstruction(_))));
+    }
+    let (result, mut emu) = run_at(Mode::User, ENVCFG_CBZE, ENVCFG_CBZE, 0);
+    assert!(result.is_ok());
+    for addr in (block..block + 64).step_by(8) {
+        assert_eq!(0, emu.cpu.bus.read(addr, DOUBLEWORD).unwrap());
+    }
+    assert_eq!(u64::MAX, emu.cpu.bus.read(block + 64, DOUBLEWORD).unwrap());
+
+    for &offset in &[4, 8] {
+        let (result, _) = run_at(Mode::Supervisor, ENVCFG_CBZE, 0, offset);
+        assert!(matches!(result, Err(Exception::IllegalInstruction(_))));
+        let (result, _) = run_at(Mode::Supervisor, ENVCFG_CBCFE, 0, offset);
+        assert!(result.is_ok());
+    }
+    // cbo.inval is only allowed in M-mode.
+    let (result, _) = run_at(Mode::Supervisor, u64::MAX, u64::MAX, 12);
+    assert!(matches!(result, Err(Exception::IllegalInstruction(_))));
+    let (result, _) = run_at(Mode::Machine, 0, 0, 12);
+    assert!(result.is_ok());
+}
+
+#[test]
+fn cache_block_operations_raise_store_page_faults() {
+    let data = vec![
+        0x73, 0x10, 0x05, 0x18, // csrrw zero, satp, a0
+        0x0f, 0xa0, 0x15, 0x00, // cbo.clean (a1)
+        0x0f, 0xa0, 0x45, 0x00, // cbo.zero (a1)
+        0x0f, 0x20, 0x26, 0x00, // cbo.flush (a2)
+        0x0f, 0xa0, 0x16, 0x00, // cbo.clean (a3)
+        0x0f, 0xa0, 0x46, 0x00, // cbo.zero (a3)
+    ];
+    let mut emu = setup(data);
+    let root = DRAM_BASE + 0x10000;
+    let mut next_table = root + 0x1000;
+    let va = 0x4000_0000;
+    map_page(
+        &mut emu,
+        root,
+        &mut next_table,
+        DRAM_BASE,
+        DRAM_BASE,
+        PTE_R | PTE_X | PTE_A,
+    );
+    map_page(
+        &mut emu,
+        root,
+        &mut next_table,
+        va,
+        DRAM_BASE + 0x5000,
+        PTE_R | PTE_A,
+    );
+    emu.cpu.state.write(MENVCFG, ENVCFG_CBZE | ENVCFG_CBCFE);
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.xregs.write(10, sv39_satp(root));
+    emu.cpu.xregs.write(11, va + 0x18);
+    emu.cpu.xregs.write(12, va + 0x1008);
+
+    // A read-only page can be cleaned, but it can't be zeroed.
+    step(&mut emu, 2);
+    let result = emu.cpu.execute();
+    assert!(matches!(result, Err(Exception::StoreAMOPageFault(addr)) if addr == va + 0x18));
+
+    // An unmapped page can't be flushed.
+    emu.cpu.pc = DRAM_BASE + 12;
+    let result = emu.cpu.execute();
+    assert!(matches!(result, Err(Exception::StoreAMOPageFault(addr)) if addr == va + 0x1008));
+
+    // The page table of the page is out of the memory, which is a store access fault for both
+    // kinds of operations.
+    let bad_va = 0xc000_0000;
+    emu.cpu.bus.write(root + 8 * 3, 1, DOUBLEWORD).unwrap();
+    emu.cpu.xregs.write(13, bad_va);
+    for &offset in &[16, 20] {
+        emu.cpu.pc = DRAM_BASE + offset;
+        let result = emu.cpu.execute();
+        assert!(
+            matches!(result, Err(Exception::StoreAMOAccessFault)),
+            "{:?}",
+            result
+        );
+    }
+}
+
+#[test]
+fn cache_block_zero_faults_before_writing_the_block() {
+    let data = vec![
+        0x0f, 0x20, 0x45, 0x00, // cbo.zero (a0)
+    ];
+    let mut emu = setup(data);
+    let block = DRAM_BASE + 0x100;
+    for addr in (block..block + 64).step_by(8) {
+        emu.cpu.bus.write(addr, u64::MAX, DOUBLEWORD).unwrap();
+    }
+    emu.cpu.state.write(MENVCFG, ENVCFG_CBZE);
+    emu.cpu.xregs.write(10, block);
+
+    // An error in the middle of the block keeps the first half from being zeroed.
+    emu.cpu.bus.inject_bus_error(block + 0x20, 8);
+    let result = emu.cpu.execute();
+    assert!(matches!(result, Err(Exception::StoreAMOAccessFault)));
+    emu.cpu.bus.clear_bus_errors();
+    for addr in (block..block + 64).step_by(8) {
+        assert_eq!(u64::MAX, emu.cpu.bus.read(addr, DOUBLEWORD).unwrap());
+    }
+
+    // A block out of the memory faults too.
+    emu.cpu.pc = DRAM_BASE;
+    emu.cpu.xregs.write(10, 0);
+    let result = emu.cpu.execute();
+    assert!(matches!(result, Err(Exception::StoreAMOAccessFault)));
+}
+
+#[test]
+fn branches_compare_full_64_bit_values() {
+    // b<op> a0, a1, 8 and b<op> a0, a1, -4 differ from beq only in funct3.
+    let (forward, backward) = (0x00b5_0463u32, 0xfeb5_0ee3u32);
+    let operands = [
+        (-1i64 as u64, 1),
+        (1, -1i64 as u64),
+        (i64::MIN as u64, i64::MAX as u64),
+        (0x8000_0000, 1),
+        (5, 5),
+    ];
+    for &funct3 in &[0x0, 0x1, 0x4, 0x5, 0x6, 0x7] {
+        for &(a, b) in operands.iter() {
+            let taken = match funct3 {
+                0x0 => a == b,                   // beq
+                0x1 => a != b,                   // bne
+                0x4 => (a as i64) < (b as i64),  // blt
+                0x5 => (a as i64) >= (b as i64), // bge
+                0x6 => a < b,                    // bltu
+                _ => a >= b,                     // bgeu
+            };
+            let mut data = (forward | funct3 << 12).to_le_bytes().to_vec();
+            data.extend_from_slice(&(backward | funct3 << 12).to_le_bytes());
+            let mut emu = setup(data);
+            emu.cpu.xregs.write(10, a);
+            emu.cpu.xregs.write(11, b);
+
+            step(&mut emu, 1);
+            let expected = if taken { 8 } else { 4 };
+            assert_eq!(DRAM_BASE + expected, emu.cpu.pc, "{:#x} {:#x}", a, b);
+
+            emu.cpu.pc = DRAM_BASE + 4;
+            step(&mut emu, 1);
+            let expected = if taken { 0 } else { 8 };
+            assert_eq!(DRAM_BASE + expected, emu.cpu.pc, "{:#x} {:#x}", a, b);
+        }
+    }
+}
+
+#[test]
+fn not_taken_branches_fall_through_and_taken_ones_jump() {
+    let data = vec![
+        0x13, 0x05, 0x10, 0x00, // addi a0, zero, 1
+        0x63, 0x04, 0x05, 0x00, // beq a0, zero, 8
+        0x93, 0x05, 0x20, 0x00, // addi a1, zero, 2
+        0x63, 0x04, 0xa5, 0x00, // beq a0, a0, 8
+        0x93, 0x05, 0x30, 0x00, // addi a1, zero, 3
+        0x11, 0xc1, // c.beqz a0, 4
+        0x11, 0xe1, // c.bnez a0, 4
+        0x91, 0x45, // c.li a1, 4
+        0x13, 0x06, 0x50, 0x00, // addi a2, zero, 5
+    ];
+    let mut emu = setup(data);
+
+    // A not-taken branch advances by its width, and a taken one skips the next instruction.
+    for &pc in &[0x4, 0x8, 0xc, 0x14, 0x16, 0x1a, 0x1e] {
+        step(&mut emu, 1);
+        assert_eq!(DRAM_BASE + pc, emu.cpu.pc);
+    }
+    assert_eq!(2, emu.cpu.xregs.read(11));
+    assert_eq!(5, emu.cpu.xregs.read(12));
+}
+
+/// A custom CSR which counts how many times it's written and reads as the count.
+struct WriteCounter {
+    writes: Rc<Cell<u64>>,
+}
+
+impl CsrDevice for WriteCounter {
+    fn read(&mut self) -> u64 {
+        self.writes.get()
+    }
+
+    fn write(&mut self, _value: u64) {
+        self.writes.set(self.writes.get() + 1);
+    }
+}
+
+#[test]
+fn custom_csr_handler_counts_writes() {
+    let data = vec![
+        0x13, 0x05, 0x50, 0x00, // addi a0, zero, 5
+        0x73, 0x10, 0x05, 0x7c, // csrrw zero, 0x7c0, a0
+        0x73, 0xd0, 0x01, 0x7c, // csrrwi zero, 0x7c0, 3
+        0x73, 0x26, 0x00, 0x7c, // csrrs a2, 0x7c0, zero
+    ];
+    let mut emu = setup(data);
+    let writes = Rc::new(Cell::new(0));
+    emu.cpu.register_csr_handler(
+        0x7c0,
+        Box::new(WriteCounter {
+            writes: writes.clone(),
+        }),
+    );
+
+    step(&mut emu, 4);
+    // csrrs with rs1=x0 reads the CSR without writing it.
+    assert_eq!(2, writes.get());
+    assert_eq!(2, emu.cpu.xregs.read(12));
+    // The default CSR at the same address is untouched.
+    assert_eq!(0, emu.cpu.state.read(0x7c0));
+}
+
+#[test]
+fn csr_immediates_are_zero_extended_and_zero_doesnt_write() {
+    let data = vec![
+        0x73, 0xe5, 0x00, 0x34, // csrrsi a0, mscratch, 1
+        0xf3, 0x65, 0x08, 0x34, // csrrsi a1, mscratch, 16
+        0x73, 0x76, 0x00, 0x34, // csrrci a2, mscratch, 0
+        0xf3, 0xd6, 0x0f, 0x34, // csrrwi a3, mscratch, 31
+        0x73, 0x77, 0x00, 0x7c, // csrrci a4, 0x7c0, 0
+        0x73, 0x60, 0x00, 0x7c, // csrrsi zero, 0x7c0, 0
+        0x73, 0x50, 0x00, 0x7c, // csrrwi zero, 0x7c0, 0
+    ];
+    let mut emu = setup(data);
+    let writes = Rc::new(Cell::new(0));
+    emu.cpu.register_csr_handler(
+        0x7c0,
+        Box::new(WriteCounter {
+            writes: writes.clone(),
+        }),
+    );
+
+    step(&mut emu, 4);
+    assert_eq!(0, emu.cpu.xregs.read(10));
+    assert_eq!(1, emu.cpu.xregs.read(11));
+    // The immediate with its top bit set sets only bit 4 rather than the upper bits.
+    assert_eq!(0x11, emu.cpu.xregs.read(12));
+    assert_eq!(0x11, emu.cpu.xregs.read(13));
+    assert_eq!(31, emu.cpu.state.read(MSCRATCH));
+
+    // csrrsi and csrrci with uimm=0 only read the CSR, while csrrwi writes it.
+    step(&mut emu, 2);
+    assert_eq!(0, writes.get());
+    step(&mut emu, 1);
+    assert_eq!(1, writes.get());
+}
+
+/// Execute `lr.w` and `sc.w` on hart 0 for the word at `DRAM_BASE + 0x1000`, calling `between`
+/// after `lr.w`. Return the emulator after `sc.w`.
+fn lr_sc(between: impl FnOnce(&mut Emulator)) -> Emulator {
+    let data = vec![
+        0x2f, 0xa3, 0x02, 0x10, // lr.w t1, (t0)
+        0xaf, 0xa3, 0xc2, 0x19, // sc.w t2, t3, (t0)
+    ];
+    let mut emu = setup(data);
+    emu.cpu.xregs.write(5, DRAM_BASE + 0x1000);
+    emu.cpu.xregs.write(28, 42);
+
+    step(&mut emu, 1);
+    between(&mut emu);
+    step(&mut emu, 1);
+    emu
+}
+
+#[test]
+fn sc_succeeds_without_intervening_store() {
+    let mut emu = lr_sc(|_| {});
+    assert_eq!(0, emu.cpu.xregs.read(7));
+    assert_eq!(42, emu.cpu.bus.read(DRAM_BASE + 0x1000, WORD).unwrap());
+}
+
+#[test]
+fn sc_fails_after_store_from_another_hart() {
+    let mut emu = lr_sc(|emu| {
+        // Hart 1 stores to the other word of the reserved granule.
+        emu.cpu.bus.write(DRAM_BASE + 0x1004, 7, WORD).unwrap();
+    });
+    assert_eq!(1, emu.cpu.xregs.read(7));
+    assert_eq!(0, emu.cpu.bus.read(DRAM_BASE + 0x1000, WORD).unwrap());
+}
+
+#[test]
+fn store_breaks_reservation_of_another_hart() {
+    let mut emu = lr_sc(|emu| emu.cpu.bus.reservations.reserve(1, DRAM_BASE + 0x1000));
+    // Hart 0's successful sc.w breaks hart 1's reservation on the same word.
+    assert_eq!(0, emu.cpu.xregs.read(7));
+    assert!(!emu.cpu.bus.reservations.release(1, DRAM_BASE + 0x1000));
+}
+
+/// Run `data` until the program counter reaches `DRAM_BASE + end` both on the classic interpreter
+/// and on the threaded-code interpreter, and check that both end in the same state. The last
+/// instruction before `end` must end a block.
+#[cfg(feature = "threaded")]
+fn assert_same_state_when_threaded(data: Vec<u8>, end: u64) -> Emulator {
+    let mut classic = setup(data.clone());
+    while classic.cpu.pc != DRAM_BASE + end {
+        step(&mut classic, 1);
+    }
+    let mut threaded = setup(data);
+    while threaded.cpu.pc != DRAM_BASE + end {
+        threaded
+            .cpu
+            .execute_block()
+            .expect("failed to execute a block");
+    }
+
+    for i in 0..32 {
+        assert_eq!(
+            classic.cpu.xregs.read(i),
+            threaded.cpu.xregs.read(i),
+            "x{}",
+            i
+        );
+    }
+    assert_eq!(
+        classic.cpu.state.read(MCYCLE),
+        threaded.cpu.state.read(MCYCLE)
+    );
+    threaded
+}
+
+#[cfg(feature = "threaded")]
+#[test]
+fn threaded_block_matches_classic_interpreter() {
+    let data = vec![
+        0x13, 0x06, 0x80, 0x3e, // addi a2, zero, 1000
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x13, 0x05, 0x35, 0x00, // addi a0, a0, 3
+        0x93, 0x16, 0x25, 0x00, // slli a3, a0, 2
+        0x33, 0xc7, 0xa6, 0x00, // xor a4, a3, a0
+        0xb3, 0x87, 0xe7, 0x00, // add a5, a5, a4
+        0x33, 0x88, 0xc7, 0x40, // sub a6, a5, a2
+        0x23, 0xb0, 0x02, 0x01, // sd a6, 0(t0)
+        0x13, 0x06, 0xf6, 0xff, // addi a2, a2, -1
+        0xe3, 0x12, 0x06, 0xfe, // bne a2, zero, -28
+    ];
+    let emu = assert_same_state_when_threaded(data, 40);
+    // The entry and the loop body are cached.
+    assert_eq!(2, emu.cpu.block_cache.len());
+}
+
+#[cfg(feature = "threaded")]
+#[test]
+fn threaded_block_sees_self_modifying_code() {
+    let data = vec![
+        0x97, 0x02, 0x00, 0x00, // auipc t0, 0
+        0x03, 0xa3, 0x82, 0x01, // lw t1, 24(t0)
+        0x23, 0xa8, 0x62, 0x00, // sw t1, 16(t0)
+        0x13, 0x00, 0x00, 0x00, // addi zero, zero, 0
+        0x13, 0x05, 0x10, 0x00, // addi a0, zero, 1 (overwritten by the word at 24)
+        0x13, 0x06, 0x05, 0x00, // addi a2, a0, 0
+        0x13, 0x05, 0x20, 0x00, // addi a0, zero, 2
+        0x6f, 0x00, 0x40, 0x00, // jal zero, 4
+    ];
+    let emu = assert_same_state_when_threaded(data, 32);
+    assert_eq!(2, emu.cpu.xregs.read(12));
+}
@@ -0,0 +1,1485 @@
+extern crate rvemu;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rvemu::{
+    bus::{
+        Bus, CLINT_BASE, DRAM_BASE, MROM_BASE, PLIC_BASE, UART_BASE, VIRTIO_BASE,
+        VIRTIO_CONSOLE_BASE,
+    },
+    cpu::{BYTE, DOUBLEWORD, HALFWORD, WORD},
+    csr::{MCYCLE, MINSTRET},
+    devices::{
+        entropy::{EntropySource, SeededEntropy},
+        htif::{FROMHOST_OFFSET, SYS_WRITE},
+        mailbox::{Mailbox, MAILBOX_SIZE},
+        mmio::{Endianness, MmioDevice},
+        plic::{IrqSource, PLIC_SCLAIM, PLIC_SCONTEXT},
+        serial::{BufferBackend, SerialBackend, TimestampedFileBackend},
+        uart::{
+            UART_CLOCK_FREQUENCY, UART_DLL, UART_DLM, UART_IER, UART_LCR, UART_LCR_DLAB, UART_THR,
+        },
+        virtio_blk::Virtio,
+        virtio_console::VirtioConsole,
+        watchdog::WatchdogAction,
+    },
+    dram::DRAM_SIZE,
+    emulator::Emulator,
+    error::ConfigError,
+    exception::Exception,
+};
+
+/// Put `bytes` at `offset` from the beginning of DRAM in `data`, growing it if needed.
+fn place(data: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+    if data.len() < offset + bytes.len() {
+        data.resize(offset + bytes.len(), 0);
+    }
+    data[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// The guest-physical address of the virtqueue used by the virtio tests.
+const QUEUE_ADDR: u64 = DRAM_BASE + 0x1000;
+
+/// Place the virtqueue of the virtio block device at `QUEUE_ADDR` and make the device ready as xv6
+/// does.
+fn setup_virtqueue(emu: &mut Emulator) {
+    // ACKNOWLEDGE, DRIVER, FEATURES_OK, and DRIVER_OK in Status.
+    emu.cpu.bus.write(VIRTIO_BASE + 0x70, 0xf, WORD).unwrap();
+    // GuestPageSize and QueuePFN.
+    emu.cpu.bus.write(VIRTIO_BASE + 0x28, 4096, WORD).unwrap();
+    emu.cpu
+        .bus
+        .write(VIRTIO_BASE + 0x40, QUEUE_ADDR / 4096, WORD)
+        .unwrap();
+}
+
+/// Write a descriptor to `index` of the descriptor table.
+fn write_desc(emu: &mut Emulator, index: u64, addr: u64, len: u64, flags: u64, next: u64) {
+    let desc = QUEUE_ADDR + 16 * index;
+    let bus = &mut emu.cpu.bus;
+    bus.write(desc, addr, DOUBLEWORD).unwrap();
+    bus.write(desc + 8, len, WORD).unwrap();
+    bus.write(desc + 12, flags, HALFWORD).unwrap();
+    bus.write(desc + 14, next, HALFWORD).unwrap();
+}
+
+/// The guest-physical addresses of the header, the data, and the status of a block request. They
+/// are after the used ring of the largest queue in the tests, which the device writes.
+const HEADER_ADDR: u64 = DRAM_BASE + 0x8000;
+const STATUS_ADDR: u64 = DRAM_BASE + 0x8100;
+const DATA_ADDR: u64 = DRAM_BASE + 0x9000;
+
+/// Chain a block request of `req_type` on `sector` from descriptor 0, which is the head of the
+/// available ring. Its data is `len` bytes at `DATA_ADDR`.
+fn write_request(emu: &mut Emulator, req_type: u64, sector: u64, len: u64, device_writable: bool) {
+    emu.cpu.bus.write(HEADER_ADDR, req_type, WORD).unwrap();
+    emu.cpu
+        .bus
+        .write(HEADER_ADDR + 8, sector, DOUBLEWORD)
+        .unwrap();
+    emu.cpu.bus.write(STATUS_ADDR, 0xff, BYTE).unwrap();
+    let flags = if device_writable { 1 | 2 } else { 1 };
+    write_desc(emu, 0, HEADER_ADDR, 16, 1, 1);
+    write_desc(emu, 1, DATA_ADDR, len, flags, 2);
+    write_desc(emu, 2, STATUS_ADDR, 1, 2, 0);
+    make_available(emu, 8, 0);
+}
+
+/// Put the chain from descriptor `head` at the next slot of the available ring of a queue with
+/// `num` entries, and advance its `idx`.
+fn make_available(emu: &mut Emulator, num: u64, head: u64) {
+    let avail = QUEUE_ADDR + 16 * num;
+    let bus = &mut emu.cpu.bus;
+    let idx = bus.read(avail + 2, HALFWORD).unwrap();
+    bus.write(avail + 4 + 2 * (idx % num), head, HALFWORD)
+        .unwrap();
+    bus.write(avail + 2, (idx + 1) & 0xffff, HALFWORD).unwrap();
+}
+
+#[test]
+fn htif_sys_write_reaches_serial_backend() {
+    let mut data = vec![
+        0x93, 0x02, 0x10, 0x00, // addi t0, zero, 1
+        0x93, 0x92, 0xf2, 0x01, // slli t0, t0, 31
+        0x13, 0x83, 0x02, 0x20, // addi t1, t0, 0x200
+        0x93, 0x83, 0x02, 0x10, // addi t2, t0, 0x100
+        0x23, 0xb0, 0x63, 0x00, // sd t1, 0(t2)
+        0x13, 0x03, 0x70, 0x00, // addi t1, zero, 7
+        0x23, 0xb0, 0x63, 0x00, // sd t1, 0(t2)
+    ];
+    // The magic memory for `write(1, DRAM_BASE + 0x300, 5)`.
+    let magic_mem = [SYS_WRITE, 1, DRAM_BASE + 0x300, 5];
+    for (i, word) in magic_mem.iter().enumerate() {
+        place(&mut data, 0x200 + i * 8, &word.to_le_bytes());
+    }
+    place(&mut data, 0x300, b"hello");
+
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data);
+    emu.initialize_pc(DRAM_BASE);
+    emu.is_test = true;
+    emu.enable_htif(DRAM_BASE + 0x100);
+    let backend = BufferBackend::new();
+    emu.cpu.bus.uart.set_backend(Box::new(backend.clone()));
+
+    emu.start();
+
+    assert_eq!("hello", backend.contents());
+    // The return value overwrites the system call number and the host acknowledges it.
+    assert_eq!(5, emu.cpu.bus.read(DRAM_BASE + 0x200, DOUBLEWORD).unwrap());
+    assert_eq!(
+        1,
+        emu.cpu
+            .bus
+            .read(DRAM_BASE + 0x100 + FROMHOST_OFFSET, DOUBLEWORD)
+            .unwrap()
+    );
+    // `tohost = (3 << 1) | 1` stops the emulator with exit code 3.
+    assert_eq!(Some(3), emu.cpu.bus.htif.as_ref().unwrap().exit_code());
+    assert_eq!(DRAM_BASE + 28, emu.cpu.pc);
+}
+
+#[test]
+fn timestamped_file_backend_stamps_each_line() {
+    let path = std::env::temp_dir().join("rvemu-serial-test.log");
+    let mut backend = TimestampedFileBackend::create(&path).unwrap();
+    b"first\n".iter().for_each(|&byte| backend.write(byte));
+    thread::sleep(Duration::from_millis(10));
+    b"second\nunterminated"
+        .iter()
+        .for_each(|&byte| backend.write(byte));
+    drop(backend);
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<(&str, &str)> = log
+        .lines()
+        .map(|line| line.split_once(' ').unwrap())
+        .collect();
+    assert_eq!(
+        vec!["first", "second", "unterminated"],
+        lines.iter().map(|&(_, text)| text).collect::<Vec<_>>()
+    );
+    for &(timestamp, _) in &lines {
+        // e.g., 2024-01-02T03:04:05.678901Z
+        assert_eq!(27, timestamp.len(), "{}", timestamp);
+        assert_eq!(Some(10), timestamp.find('T'));
+        assert!(timestamp.ends_with('Z'));
+    }
+    assert!(lines[0].0 < lines[1].0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn timestamped_file_backend_drops_lines_it_fails_to_write() {
+    // Every write to /dev/full fails as if the disk were full.
+    let mut backend = TimestampedFileBackend::create("/dev/full").unwrap();
+    b"lost\nlost too\n"
+        .iter()
+        .for_each(|&byte| backend.write(byte));
+}
+
+#[test]
+fn uart_divisor_latch_is_aliased_by_dlab() {
+    let mut emu = Emulator::new();
+    let backend = BufferBackend::new();
+    emu.cpu.bus.uart.set_backend(Box::new(backend.clone()));
+    let bus = &mut emu.cpu.bus;
+
+    // Set DLAB in LCR and write the divisor 0x0001 to DLL and DLM.
+    bus.write(UART_LCR, UART_LCR_DLAB as u64 | 0x3, BYTE)
+        .unwrap();
+    bus.write(UART_DLL, 0x01, BYTE).unwrap();
+    bus.write(UART_DLM, 0x00, BYTE).unwrap();
+    assert_eq!(0x01, bus.read(UART_DLL, BYTE).unwrap());
+    assert_eq!(0x00, bus.read(UART_DLM, BYTE).unwrap());
+    assert_eq!(1, bus.uart.divisor());
+    assert_eq!(Some(UART_CLOCK_FREQUENCY / 16), bus.uart.baud_rate());
+    assert_eq!("", backend.contents());
+
+    // Clearing DLAB exposes THR and IER again without touching the divisor latch.
+    bus.write(UART_LCR, 0x3, BYTE).unwrap();
+    bus.write(UART_IER, 0x02, BYTE).unwrap();
+    bus.write(UART_THR, b'a' as u64, BYTE).unwrap();
+    assert_eq!(0x02, bus.read(UART_IER, BYTE).unwrap());
+    assert_eq!(1, bus.uart.divisor());
+    assert_eq!("a", backend.contents());
+}
+
+#[test]
+fn second_uart_has_its_own_backend_and_interrupt() {
+    let debug_base = 0x1000_3000;
+    let mut emu = Emulator::new();
+    emu.add_uart(debug_base, 11).unwrap();
+    let console = BufferBackend::new();
+    let debug = BufferBackend::new();
+    emu.cpu.bus.uart.set_backend(Box::new(console.clone()));
+    emu.cpu
+        .bus
+        .uart_at(debug_base)
+        .unwrap()
+        .set_backend(Box::new(debug.clone()));
+
+    emu.cpu.bus.write(UART_THR, b'c' as u64, BYTE).unwrap();
+    emu.cpu.bus.write(debug_base, b'd' as u64, BYTE).unwrap();
+    emu.cpu.bus.write(debug_base, b'e' as u64, BYTE).unwrap();
+    assert_eq!("c", console.contents());
+    assert_eq!("de", debug.contents());
+    assert!(emu.cpu.bus.read(debug_base, WORD).is_err());
+    assert!(emu
+        .cpu
+        .bus
+        .memory_map()
+        .contains(&("uart".to_string(), debug_base, 0x100)));
+
+    // A byte received by the debug port raises its own interrupt source.
+    emu.cpu.bus.uart_at(debug_base).unwrap().receive(b'x');
+    emu.cpu.check_pending_interrupt();
+    let pending = emu.cpu.bus.read(PLIC_BASE + 0x1000, WORD).unwrap();
+    assert_eq!(1 << 11, pending);
+    assert_eq!(b'x' as u64, emu.cpu.bus.read(debug_base, BYTE).unwrap());
+}
+
+#[test]
+fn add_uart_rejects_invalid_irq_and_overlap() {
+    let mut emu = Emulator::new();
+    assert_eq!(
+        Err(ConfigError::InvalidIrq(0)),
+        emu.add_uart(0x1000_3000, 0)
+    );
+    assert_eq!(
+        Err(ConfigError::InvalidIrq(1024)),
+        emu.add_uart(0x1000_3000, 1024)
+    );
+    assert!(matches!(
+        emu.add_uart(UART_BASE + 0x80, 11),
+        Err(ConfigError::Overlap { ref name, .. }) if name == "uart"
+    ));
+    emu.add_uart(0x1000_3000, 11).unwrap();
+    assert!(matches!(
+        emu.add_uart(0x1000_3000, 12),
+        Err(ConfigError::Overlap { .. })
+    ));
+}
+
+#[test]
+fn injected_bus_error_faults_until_cleared() {
+    let data = vec![
+        0x97, 0x05, 0x00, 0x00, // auipc a1, 0
+        0x03, 0xa5, 0x85, 0x10, // lw a0, 264(a1)
+        0x03, 0xa5, 0x05, 0x10, // lw a0, 256(a1)
+    ];
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data);
+    emu.initialize_pc(DRAM_BASE);
+    emu.cpu.bus.inject_bus_error(DRAM_BASE + 0x100, 8);
+
+    // The neighboring word is still readable.
+    emu.cpu.execute().unwrap();
+    emu.cpu.execute().unwrap();
+    assert!(matches!(emu.cpu.execute(), Err(Exception::LoadAccessFault)));
+
+    let bus = &mut emu.cpu.bus;
+    assert!(matches!(
+        bus.write(DRAM_BASE + 0x107, 0, BYTE),
+        Err(Exception::StoreAMOAccessFault)
+    ));
+    // An access which overlaps the range partially faults too.
+    assert!(bus.read(DRAM_BASE + 0xfc, DOUBLEWORD).is_err());
+    assert!(bus.dma_slice(DRAM_BASE, 0x200).is_none());
+
+    bus.clear_bus_errors();
+    assert!(bus.read(DRAM_BASE + 0x100, WORD).is_ok());
+}
+
+#[test]
+fn dma_slice_aliases_dram() {
+    let mut emu = Emulator::new();
+    let bus = &mut emu.cpu.bus;
+    bus.write(DRAM_BASE + 0x10, 0x1122_3344_5566_7788, DOUBLEWORD)
+        .unwrap();
+
+    let slice = bus.dma_slice(DRAM_BASE + 0x10, 8).unwrap();
+    assert_eq!(0x1122_3344_5566_7788u64.to_le_bytes(), *slice);
+    slice.copy_from_slice(&0xdead_beefu64.to_le_bytes());
+    assert_eq!(0xdead_beef, bus.read(DRAM_BASE + 0x10, DOUBLEWORD).unwrap());
+
+    // MMIO and partially or wholly out-of-range ranges aren't backed by DRAM.
+    assert!(bus.dma_slice(UART_BASE, 1).is_none());
+    assert!(bus.dma_slice(DRAM_BASE - 4, 8).is_none());
+    assert!(bus.dma_slice(DRAM_BASE + DRAM_SIZE - 4, 8).is_none());
+    assert!(bus.dma_slice(u64::MAX, 2).is_none());
+}
+
+#[test]
+fn memory_map_lists_regions_in_address_order() {
+    let mut emu = Emulator::new();
+    let map = emu.cpu.bus.memory_map();
+
+    let region = |name: &str| map.iter().find(|(n, _, _)| n == name).cloned();
+    assert_eq!(
+        Some(("dram".to_string(), DRAM_BASE, DRAM_SIZE)),
+        region("dram")
+    );
+    assert_eq!(
+        Some(("virtio".to_string(), VIRTIO_BASE, 0x1000)),
+        region("virtio")
+    );
+    assert_eq!(None, region("htif"));
+    assert!(map
+        .windows(2)
+        .all(|pair| pair[0].1 + pair[0].2 <= pair[1].1));
+
+    // Optional devices appear once enabled.
+    emu.enable_htif(DRAM_BASE - 0x1000);
+    let map = emu.cpu.bus.memory_map();
+    assert!(map.contains(&("htif".to_string(), DRAM_BASE - 0x1000, 0x48)));
+}
+
+#[test]
+fn cyclic_descriptor_chain_is_rejected() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0; 512]);
+    setup_virtqueue(&mut emu);
+    // The head of the available ring is descriptor 0, which chains to itself forever.
+    write_desc(&mut emu, 0, DRAM_BASE + 0x2000, 16, 1, 0);
+    make_available(&mut emu, 8, 0);
+
+    assert!(Virtio::disk_access(&mut emu.cpu).is_err());
+
+    // A longer limit still terminates.
+    emu.cpu.bus.virtio.set_max_chain_len(1000);
+    make_available(&mut emu, 8, 0);
+    assert!(Virtio::disk_access(&mut emu.cpu).is_err());
+}
+
+#[test]
+fn requests_are_taken_from_the_available_ring_in_order() {
+    let mut disk = vec![0xaa; 512];
+    disk.extend_from_slice(&[0xbb; 512]);
+    let mut emu = Emulator::new();
+    emu.initialize_disk(disk);
+    setup_virtqueue(&mut emu);
+    // QueueNum. The available ring follows the 8 descriptors at 0x80.
+    emu.cpu.bus.write(VIRTIO_BASE + 0x38, 8, WORD).unwrap();
+    let avail = QUEUE_ADDR + 0x80;
+
+    // The first request reads sector 1 with the chain from descriptor 5, and descriptor 0 is
+    // invalid so that a wrong head fails. Flags are set so that a misaligned read of `idx` would
+    // see them.
+    write_request(&mut emu, 0, 1, 512, true);
+    write_desc(&mut emu, 5, HEADER_ADDR, 16, 1, 6);
+    write_desc(&mut emu, 6, DATA_ADDR, 512, 1 | 2, 7);
+    write_desc(&mut emu, 7, STATUS_ADDR, 1, 2, 0);
+    write_desc(&mut emu, 0, 0, 0, 0, 0);
+    let bus = &mut emu.cpu.bus;
+    bus.write(avail, 1, HALFWORD).unwrap();
+    bus.write(avail + 4, 5, HALFWORD).unwrap();
+    bus.write(avail + 2, 1, HALFWORD).unwrap();
+
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    assert_eq!(0xbb, emu.cpu.bus.read(DATA_ADDR, BYTE).unwrap());
+
+    // The second request is at the next slot.
+    write_request(&mut emu, 0, 0, 512, true);
+    let bus = &mut emu.cpu.bus;
+    bus.write(avail + 6, 0, HALFWORD).unwrap();
+    bus.write(avail + 2, 2, HALFWORD).unwrap();
+
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    assert_eq!(0xaa, emu.cpu.bus.read(DATA_ADDR, BYTE).unwrap());
+
+    // A notification without a new entry does nothing, even though `flags` differs from `idx`.
+    emu.cpu.bus.write(STATUS_ADDR, 0xff, BYTE).unwrap();
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(0xff, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    assert_eq!(
+        2,
+        emu.cpu.bus.read(QUEUE_ADDR + 4096 + 2, HALFWORD).unwrap()
+    );
+
+    // The driver starts from the beginning of the rings after it writes QueuePFN again, so the
+    // chain from descriptor 5 is taken rather than the invalid descriptor 0.
+    write_desc(&mut emu, 0, 0, 0, 0, 0);
+    emu.cpu
+        .bus
+        .write(VIRTIO_BASE + 0x40, QUEUE_ADDR / 4096, WORD)
+        .unwrap();
+    emu.cpu.bus.write(avail + 2, 1, HALFWORD).unwrap();
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    assert_eq!(
+        1,
+        emu.cpu.bus.read(QUEUE_ADDR + 4096 + 2, HALFWORD).unwrap()
+    );
+}
+
+#[test]
+fn failed_request_is_returned_through_the_used_ring() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0; 512]);
+    setup_virtqueue(&mut emu);
+    emu.cpu.bus.write(VIRTIO_BASE + 0x38, 8, WORD).unwrap();
+    let used = QUEUE_ADDR + 4096;
+
+    // The data buffer of a VIRTIO_BLK_T_OUT request is out of the memory.
+    write_request(&mut emu, 1, 0, 512, false);
+    write_desc(&mut emu, 1, 0, 512, 1, 2);
+    assert!(Virtio::disk_access(&mut emu.cpu).is_err());
+
+    // The chain is used with no bytes written, and its status reports VIRTIO_BLK_S_IOERR.
+    assert_eq!(1, emu.cpu.bus.read(used + 2, HALFWORD).unwrap());
+    assert_eq!(0, emu.cpu.bus.read(used + 4, WORD).unwrap());
+    assert_eq!(0, emu.cpu.bus.read(used + 8, WORD).unwrap());
+    assert_eq!(1, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+
+    // The next request is served.
+    write_request(&mut emu, 0, 0, 512, true);
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    assert_eq!(2, emu.cpu.bus.read(used + 2, HALFWORD).unwrap());
+}
+
+#[test]
+fn locked_file_disks_do_not_interleave_write_requests() {
+    // A request writes a sector per data descriptor between the header and the status.
+    const SECTORS: u64 = 6;
+    let path = std::env::temp_dir().join("rvemu-locked-disk-test.img");
+    std::fs::write(&path, vec![0; 512 * SECTORS as usize]).unwrap();
+    let held = std::fs::File::open(&path).unwrap();
+    held.lock().unwrap();
+
+    // Two emulators write the whole disk with their own pattern many times at once.
+    let writers: Vec<_> = [0x11u8, 0x22]
+        .iter()
+        .map(|&pattern| {
+            let path = path.clone();
+            thread::spawn(move || {
+                let mut emu = Emulator::new();
+                emu.open_disk(&path, true).unwrap();
+                setup_virtqueue(&mut emu);
+                emu.cpu.bus.write(VIRTIO_BASE + 0x38, 8, WORD).unwrap();
+                for i in 0..512 * SECTORS {
+                    emu.cpu
+                        .bus
+                        .write(DATA_ADDR + i, pattern as u64, BYTE)
+                        .unwrap();
+                }
+                for _ in 0..200 {
+                    write_request(&mut emu, 1, 0, 512, false);
+                    for i in 1..=SECTORS {
+                        write_desc(&mut emu, i, DATA_ADDR + 512 * (i - 1), 512, 1, i + 1);
+                    }
+                    write_desc(&mut emu, SECTORS + 1, STATUS_ADDR, 1, 2, 0);
+                    Virtio::disk_access(&mut emu.cpu).unwrap();
+                    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+                }
+            })
+        })
+        .collect();
+
+    // The write requests wait while the lock is held by another.
+    thread::sleep(Duration::from_millis(100));
+    assert!(std::fs::read(&path).unwrap().iter().all(|&byte| byte == 0));
+    held.unlock().unwrap();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    // The disk holds the last request as a whole.
+    let disk = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(512 * SECTORS as usize, disk.len());
+    assert!(disk[0] == 0x11 || disk[0] == 0x22);
+    assert!(disk.iter().all(|&byte| byte == disk[0]));
+}
+
+#[test]
+fn used_ring_follows_the_available_ring_at_queue_align() {
+    // (QueueNum, QueueAlign, the offset of the used ring from the descriptor table)
+    let layouts = [
+        (4, 0x1000, 0x1000),
+        (4, 0x100, 0x100),
+        // 256 descriptors and the available ring take 0x1206 bytes.
+        (256, 0x1000, 0x2000),
+    ];
+    for &(num, align, offset) in layouts.iter() {
+        let mut emu = Emulator::new();
+        emu.initialize_disk(vec![0; 512]);
+        setup_virtqueue(&mut emu);
+        // QueueNum and QueueAlign.
+        emu.cpu.bus.write(VIRTIO_BASE + 0x38, num, WORD).unwrap();
+        emu.cpu.bus.write(VIRTIO_BASE + 0x3c, align, WORD).unwrap();
+        write_request(&mut emu, 0, 0, 512, true);
+        make_available(&mut emu, num, 0);
+
+        Virtio::disk_access(&mut emu.cpu).unwrap();
+        // `idx` of the used ring.
+        let used_idx = QUEUE_ADDR + offset + 2;
+        assert_eq!(
+            1,
+            emu.cpu.bus.read(used_idx, HALFWORD).unwrap(),
+            "{:#x}",
+            align
+        );
+    }
+}
+
+#[test]
+fn write_zeroes_clears_sectors() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0xff; 512 * 4]);
+    setup_virtqueue(&mut emu);
+
+    // Both commands are advertised.
+    let features = emu.cpu.bus.read(VIRTIO_BASE + 0x10, WORD).unwrap();
+    assert_eq!(0b11 << 13, features & (0b11 << 13));
+
+    // VIRTIO_BLK_T_WRITE_ZEROES on sectors 1 and 2.
+    write_request(&mut emu, 13, 0, 16, false);
+    emu.cpu.bus.write(DATA_ADDR, 1, DOUBLEWORD).unwrap();
+    emu.cpu.bus.write(DATA_ADDR + 8, 2, WORD).unwrap();
+    emu.cpu.bus.write(DATA_ADDR + 12, 0, WORD).unwrap();
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+
+    // Read the whole disk back with VIRTIO_BLK_T_IN.
+    write_request(&mut emu, 0, 0, 512 * 4, true);
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    let disk = emu.cpu.bus.dma_slice(DATA_ADDR, 512 * 4).unwrap();
+    assert!(disk[..512].iter().all(|&b| b == 0xff));
+    assert!(disk[512..512 * 3].iter().all(|&b| b == 0));
+    assert!(disk[512 * 3..].iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn write_zeroes_out_of_disk_fails_with_ioerr() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0xff; 512 * 4]);
+    setup_virtqueue(&mut emu);
+
+    // VIRTIO_BLK_T_DISCARD on sectors 3 and 4, where sector 4 doesn't exist.
+    write_request(&mut emu, 11, 0, 16, false);
+    emu.cpu.bus.write(DATA_ADDR, 3, DOUBLEWORD).unwrap();
+    emu.cpu.bus.write(DATA_ADDR + 8, 2, WORD).unwrap();
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(1, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+}
+
+#[test]
+fn hostile_offsets_fail_without_overflow() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0xff; 512 * 4]);
+    setup_virtqueue(&mut emu);
+
+    // VIRTIO_BLK_T_IN and VIRTIO_BLK_T_OUT on the last possible sector.
+    for &device_writable in &[true, false] {
+        write_request(&mut emu, 0, u64::MAX, 512, device_writable);
+        Virtio::disk_access(&mut emu.cpu).unwrap();
+        assert_eq!(1, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    }
+
+    // VIRTIO_BLK_T_WRITE_ZEROES whose end overflows.
+    write_request(&mut emu, 13, 0, 16, false);
+    emu.cpu.bus.write(DATA_ADDR, u64::MAX, DOUBLEWORD).unwrap();
+    emu.cpu.bus.write(DATA_ADDR + 8, 0xffff_ffff, WORD).unwrap();
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(1, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+
+    // A data buffer at the end of the address space.
+    write_request(&mut emu, 0, 0, 512, true);
+    write_desc(&mut emu, 1, u64::MAX - 0xff, 512, 1 | 2, 2);
+    assert!(Virtio::disk_access(&mut emu.cpu).is_err());
+
+    // A virtqueue at the end of the address space.
+    emu.cpu
+        .bus
+        .write(VIRTIO_BASE + 0x28, 0xffff_ffff, WORD)
+        .unwrap();
+    emu.cpu
+        .bus
+        .write(VIRTIO_BASE + 0x40, 0xffff_ffff, WORD)
+        .unwrap();
+    assert!(Virtio::disk_access(&mut emu.cpu).is_err());
+}
+
+#[test]
+fn config_generation_changes_with_capacity() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0; 512 * 4]);
+    let bus = &mut emu.cpu.bus;
+    let capacity = |bus: &mut Bus| {
+        (0..8).fold(0, |capacity, i| {
+            capacity | bus.read(VIRTIO_BASE + 0x100 + i, BYTE).unwrap() << (8 * i)
+        })
+    };
+
+    let generation = bus.read(VIRTIO_BASE + 0xfc, WORD).unwrap();
+    assert_eq!(4, capacity(bus));
+
+    bus.virtio.resize(512 * 6);
+    assert_eq!(generation + 1, bus.read(VIRTIO_BASE + 0xfc, WORD).unwrap());
+    assert_eq!(6, capacity(bus));
+    // The driver is notified of the configuration change.
+    assert_eq!(0x2, bus.read(VIRTIO_BASE + 0x60, WORD).unwrap() & 0x2);
+}
+
+#[test]
+fn virtio_config_space_only_allows_byte_accesses() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0; 512 * 3]);
+    let config = VIRTIO_BASE + 0x100;
+
+    // The capacity is 3 sectors.
+    assert_eq!(3, emu.cpu.bus.read(config, BYTE).unwrap());
+    assert!(matches!(
+        emu.cpu.bus.read(config, WORD),
+        Err(Exception::LoadAccessFault)
+    ));
+    assert!(matches!(
+        emu.cpu.bus.write(config, 0, HALFWORD),
+        Err(Exception::StoreAMOAccessFault)
+    ));
+    // The other registers allow 32-bit accesses.
+    assert_eq!(0x74726976, emu.cpu.bus.read(VIRTIO_BASE, WORD).unwrap());
+    assert!(matches!(
+        emu.cpu.bus.read(VIRTIO_BASE, DOUBLEWORD),
+        Err(Exception::LoadAccessFault)
+    ));
+}
+
+#[test]
+fn shared_disk_is_read_only_and_not_copied() {
+    let image: Arc<[u8]> = vec![0xab; 512].into();
+    let mut emus = [Emulator::new(), Emulator::new()];
+    for emu in emus.iter_mut() {
+        emu.initialize_shared_disk(image.clone());
+        setup_virtqueue(emu);
+    }
+    // Both devices refer to the image itself.
+    assert_eq!(3, Arc::strong_count(&image));
+
+    // VIRTIO_BLK_F_RO is advertised.
+    let emu = &mut emus[0];
+    let features = emu.cpu.bus.read(VIRTIO_BASE + 0x10, WORD).unwrap();
+    assert_eq!(1 << 5, features & (1 << 5));
+
+    // VIRTIO_BLK_T_IN succeeds.
+    write_request(emu, 0, 0, 512, true);
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    assert_eq!(0xab, emu.cpu.bus.read(DATA_ADDR, BYTE).unwrap());
+
+    // VIRTIO_BLK_T_OUT fails with VIRTIO_BLK_S_IOERR and leaves the image intact.
+    emu.cpu.bus.write(DATA_ADDR, 0, BYTE).unwrap();
+    write_request(emu, 1, 0, 512, false);
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(1, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    assert!(image.iter().all(|&b| b == 0xab));
+}
+
+/// A toy device with a single 32-bit register whose byte order is configurable.
+struct ToyDevice {
+    reg: u32,
+    endianness: Endianness,
+}
+
+impl MmioDevice for ToyDevice {
+    fn read(&mut self, _offset: u64, _size: u8) -> Result<u64, Exception> {
+        Ok(self.reg as u64)
+    }
+
+    fn write(&mut self, _offset: u64, value: u64, _size: u8) -> Result<(), Exception> {
+        self.reg = value as u32;
+        Ok(())
+    }
+
+    fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+}
+
+#[test]
+fn accesses_straddling_a_region_end_fault() {
+    let mut emu = Emulator::new();
+    let bus = &mut emu.cpu.bus;
+    bus.attach(
+        0x4000_0000,
+        0x100,
+        Box::new(ToyDevice {
+            reg: 0,
+            endianness: Endianness::Little,
+        }),
+    );
+
+    // A doubleword 4 bytes before the end of DRAM.
+    let addr = DRAM_BASE + DRAM_SIZE - 4;
+    assert!(matches!(
+        bus.read(addr, DOUBLEWORD),
+        Err(Exception::LoadAccessFault)
+    ));
+    assert!(matches!(
+        bus.write(addr, 0, DOUBLEWORD),
+        Err(Exception::StoreAMOAccessFault)
+    ));
+    // The word which fits is accessible.
+    bus.write(addr, 0x1234_5678, WORD).unwrap();
+    assert_eq!(0x1234_5678, bus.read(addr, WORD).unwrap());
+
+    // The same applies to the end of a device.
+    assert!(matches!(
+        bus.read(0x4000_00fc, DOUBLEWORD),
+        Err(Exception::LoadAccessFault)
+    ));
+    assert!(matches!(
+        bus.write(0x4000_00fc, 0, DOUBLEWORD),
+        Err(Exception::StoreAMOAccessFault)
+    ));
+    assert!(bus.read(0x4000_00fc, WORD).is_ok());
+}
+
+#[test]
+fn seeded_entropy_is_reproducible() {
+    // The first outputs of SplitMix64 seeded with 0 are 0xe220a8397b1dcdaf and
+    // 0x6e789e6aa1b965f4.
+    let expected = [
+        0xaf, 0xcd, 0x1d, 0x7b, 0x39, 0xa8, 0x20, 0xe2, 0xf4, 0x65, 0xb9, 0xa1,
+    ];
+    let mut buf = [0; 12];
+    SeededEntropy::new(0).fill(&mut buf);
+    assert_eq!(expected, buf);
+
+    // The bus hands out the bytes of the injected source.
+    let mut emu = Emulator::new();
+    emu.cpu
+        .bus
+        .set_entropy_source(Box::new(SeededEntropy::new(0)));
+    let mut buf = [0; 12];
+    emu.cpu.bus.fill_random(&mut buf);
+    assert_eq!(expected, buf);
+}
+
+#[test]
+fn big_endian_device_accesses_are_swapped() {
+    let mut emu = Emulator::new();
+    let bus = &mut emu.cpu.bus;
+    bus.attach(
+        0x4000_0000,
+        0x100,
+        Box::new(ToyDevice {
+            reg: 0x1234_5678,
+            endianness: Endianness::Big,
+        }),
+    );
+    bus.attach(
+        0x4000_1000,
+        0x100,
+        Box::new(ToyDevice {
+            reg: 0x1234_5678,
+            endianness: Endianness::Little,
+        }),
+    );
+
+    assert_eq!(0x7856_3412, bus.read(0x4000_0000, WORD).unwrap());
+    assert_eq!(0x1234_5678, bus.read(0x4000_1000, WORD).unwrap());
+
+    // A written word reads back as is because it's swapped in both directions.
+    bus.write(0x4000_0000, 0xaabb_ccdd, WORD).unwrap();
+    assert_eq!(0xaabb_ccdd, bus.read(0x4000_0000, WORD).unwrap());
+
+    // Addresses outside any region still fault.
+    assert!(bus.read(0x4000_0100, WORD).is_err());
+}
+
+#[test]
+fn mailbox_command_is_serviced_by_host() {
+    let data = vec![
+        0xb7, 0x02, 0x00, 0x40, // lui t0, 0x40000
+        0x13, 0x03, 0x50, 0x01, // addi t1, zero, 21
+        0x23, 0xb4, 0x62, 0x00, // sd t1, 8(t0)
+        0x13, 0x03, 0x10, 0x00, // addi t1, zero, 1
+        0x23, 0xb0, 0x62, 0x00, // sd t1, 0(t0)
+        0x23, 0xb4, 0x62, 0x04, // sd t1, 72(t0)
+        0x03, 0xb5, 0x02, 0x05, // ld a0, 80(t0)
+    ];
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data);
+    emu.initialize_pc(DRAM_BASE);
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let handler_calls = calls.clone();
+    emu.cpu.bus.attach(
+        0x4000_0000,
+        MAILBOX_SIZE,
+        Box::new(Mailbox::new(move |args: &[u64]| {
+            handler_calls.borrow_mut().push(args.to_vec());
+            args[1] * 2
+        })),
+    );
+
+    emu.run(7);
+
+    // The command 1 with one argument.
+    assert_eq!(vec![vec![1, 21]], *calls.borrow());
+    assert_eq!(42, emu.cpu.xregs.read(10));
+
+    // The registers allow only 32-bit and 64-bit accesses at their beginnings.
+    let bus = &mut emu.cpu.bus;
+    assert_eq!(42, bus.read(0x4000_0050, WORD).unwrap());
+    assert!(bus.read(0x4000_0050, HALFWORD).is_err());
+    assert!(bus.write(0x4000_0008, 1, BYTE).is_err());
+    assert!(bus.read(0x4000_0054, WORD).is_err());
+}
+
+#[test]
+fn plic_claims_higher_priority_source_first() {
+    for &(uart_priority, virtio_priority, order) in &[(3, 1, [10, 1]), (1, 3, [1, 10])] {
+        let mut emu = Emulator::new();
+        let bus = &mut emu.cpu.bus;
+        bus.write(PLIC_BASE + 4 * IrqSource::Uart as u64, uart_priority, WORD)
+            .unwrap();
+        bus.write(
+            PLIC_BASE + 4 * IrqSource::Virtio as u64,
+            virtio_priority,
+            WORD,
+        )
+        .unwrap();
+        // Enable both sources for S-mode of hart 0 (context 1).
+        bus.write(PLIC_BASE + 0x2080, (1 << 10) | (1 << 1), WORD)
+            .unwrap();
+
+        bus.raise_irq(IrqSource::Virtio);
+        bus.raise_irq(IrqSource::Uart);
+
+        assert!(bus.plic.is_interrupting(PLIC_SCONTEXT));
+        for &id in order.iter() {
+            assert_eq!(id, bus.read(PLIC_SCLAIM, WORD).unwrap());
+            bus.write(PLIC_SCLAIM, id, WORD).unwrap();
+        }
+        assert_eq!(0, bus.read(PLIC_SCLAIM, WORD).unwrap());
+        assert!(!bus.plic.is_interrupting(PLIC_SCONTEXT));
+    }
+}
+
+#[test]
+fn queue_notify_before_driver_ok_is_ignored() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0xab; 512]);
+    setup_virtqueue(&mut emu);
+    write_request(&mut emu, 0, 0, 512, true);
+    // Only ACKNOWLEDGE, DRIVER, and FEATURES_OK in Status.
+    emu.cpu.bus.write(VIRTIO_BASE + 0x70, 0xb, WORD).unwrap();
+
+    // QueueNotify.
+    emu.cpu.bus.write(VIRTIO_BASE + 0x50, 0, WORD).unwrap();
+    emu.cpu.check_pending_interrupt();
+
+    assert_eq!(0xff, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    assert_eq!(0, emu.cpu.bus.read(DATA_ADDR, DOUBLEWORD).unwrap());
+    // InterruptStatus.
+    assert_eq!(0, emu.cpu.bus.read(VIRTIO_BASE + 0x60, WORD).unwrap());
+
+    // The same request is served once the driver is ready.
+    emu.cpu.bus.write(VIRTIO_BASE + 0x70, 0xf, WORD).unwrap();
+    emu.cpu.bus.write(VIRTIO_BASE + 0x50, 0, WORD).unwrap();
+    emu.cpu.check_pending_interrupt();
+
+    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    assert_eq!(
+        0xabab_abab_abab_abab,
+        emu.cpu.bus.read(DATA_ADDR, DOUBLEWORD).unwrap()
+    );
+}
+
+#[test]
+fn requests_without_a_disk_fail_with_ioerr() {
+    let mut emu = Emulator::new();
+    setup_virtqueue(&mut emu);
+
+    // A read, a write, and a read of no data all fail without touching the memory.
+    for &(req_type, len, device_writable) in &[(0, 512, true), (1, 512, false), (0, 0, true)] {
+        emu.cpu.bus.write(DATA_ADDR, 0x5a, BYTE).unwrap();
+        write_request(&mut emu, req_type, 0, len, device_writable);
+        emu.cpu.bus.write(VIRTIO_BASE + 0x50, 0, WORD).unwrap();
+        emu.cpu.check_pending_interrupt();
+
+        assert_eq!(1, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+        assert_eq!(0x5a, emu.cpu.bus.read(DATA_ADDR, BYTE).unwrap());
+    }
+}
+
+#[test]
+fn data_may_span_several_sectors_but_not_a_partial_one() {
+    // The byte at `offset` of the original disk.
+    let original = |offset: u64| offset / 512 * 0x10 + offset % 7;
+    let mut emu = Emulator::new();
+    emu.initialize_disk((0..4 * 512).map(|i| original(i) as u8).collect());
+    setup_virtqueue(&mut emu);
+
+    // Read 3 sectors from sector 1 with a single descriptor.
+    write_request(&mut emu, 0, 1, 3 * 512, true);
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    for i in 0..3 * 512 {
+        assert_eq!(
+            original(512 + i),
+            emu.cpu.bus.read(DATA_ADDR + i, BYTE).unwrap(),
+            "{}",
+            i
+        );
+    }
+
+    // Write them back to sectors 0 to 2, and sector 3 is kept.
+    write_request(&mut emu, 1, 0, 3 * 512, false);
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    write_request(&mut emu, 0, 0, 4 * 512, true);
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    for i in 0..4 * 512 {
+        let expected = if i < 3 * 512 {
+            original(512 + i)
+        } else {
+            original(i)
+        };
+        assert_eq!(
+            expected,
+            emu.cpu.bus.read(DATA_ADDR + i, BYTE).unwrap(),
+            "{}",
+            i
+        );
+    }
+
+    // Data which isn't a multiple of the sector size fails without a transfer.
+    emu.cpu.bus.write(DATA_ADDR, 0x5a, BYTE).unwrap();
+    write_request(&mut emu, 0, 0, 1000, true);
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(1, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    assert_eq!(0x5a, emu.cpu.bus.read(DATA_ADDR, BYTE).unwrap());
+}
+
+#[test]
+fn get_id_returns_the_id_and_unknown_requests_are_unsupported() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0; 512]);
+    setup_virtqueue(&mut emu);
+
+    // VIRTIO_BLK_T_GET_ID fills 20 bytes, which aren't a multiple of the sector size.
+    emu.cpu
+        .bus
+        .write(DATA_ADDR + 8, u64::MAX, DOUBLEWORD)
+        .unwrap();
+    write_request(&mut emu, 8, 0, 20, true);
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(0, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+    let id = (0..20)
+        .map(|i| emu.cpu.bus.read(DATA_ADDR + i, BYTE).unwrap() as u8)
+        .collect::<Vec<_>>();
+    assert_eq!(b"rvemu\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", &id[..]);
+
+    // A buffer too small for the ID fails.
+    write_request(&mut emu, 8, 0, 16, true);
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(1, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+
+    // VIRTIO_BLK_S_UNSUPP for a request type the device doesn't know.
+    write_request(&mut emu, 99, 0, 512, true);
+    Virtio::disk_access(&mut emu.cpu).unwrap();
+    assert_eq!(2, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+}
+
+#[test]
+fn used_ring_idx_is_free_running() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0; 512]);
+    setup_virtqueue(&mut emu);
+    let used_idx = QUEUE_ADDR + 4096 + 2;
+
+    // A driver finds completions by comparing the last index it has seen with `idx`.
+    let mut last_seen = emu.cpu.bus.read(used_idx, HALFWORD).unwrap();
+    let mut completions = 0;
+    for _ in 0..10 {
+        write_request(&mut emu, 0, 0, 512, true);
+        Virtio::disk_access(&mut emu.cpu).unwrap();
+
+        let idx = emu.cpu.bus.read(used_idx, HALFWORD).unwrap();
+        if idx != last_seen {
+            completions += 1;
+        }
+        assert_eq!((last_seen + 1) & 0xffff, idx);
+        last_seen = idx;
+    }
+    assert_eq!(10, completions);
+    assert_eq!(10, last_seen);
+}
+
+#[test]
+fn used_ring_elements_report_the_head_and_written_length() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0; 512 * 2]);
+    setup_virtqueue(&mut emu);
+    let used = QUEUE_ADDR + 4096;
+    for elem in 0..3 {
+        emu.cpu
+            .bus
+            .write(used + 4 + 8 * elem, u64::MAX, DOUBLEWORD)
+            .unwrap();
+    }
+
+    // A read writes the data and the status, a write only the status, and so does a failed read.
+    for (i, &(req_type, sector, device_writable, len)) in
+        [(0, 0, true, 1024 + 1), (1, 0, false, 1), (0, 1, true, 1)]
+            .iter()
+            .enumerate()
+    {
+        write_request(&mut emu, req_type, sector, 1024, device_writable);
+        Virtio::disk_access(&mut emu.cpu).unwrap();
+
+        let elem = used + 4 + 8 * i as u64;
+        assert_eq!(0, emu.cpu.bus.read(elem, WORD).unwrap());
+        assert_eq!(len, emu.cpu.bus.read(elem + 4, WORD).unwrap(), "{}", i);
+    }
+    assert_eq!(3, emu.cpu.bus.read(used + 2, HALFWORD).unwrap());
+}
+
+#[test]
+fn injected_io_error_fails_only_that_request() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0xaa; 512]);
+    setup_virtqueue(&mut emu);
+    emu.cpu.bus.virtio.inject_io_error_at(2);
+
+    for i in 0..4 {
+        emu.cpu.bus.write(DATA_ADDR, 0, BYTE).unwrap();
+        write_request(&mut emu, 0, 0, 512, true);
+        Virtio::disk_access(&mut emu.cpu).unwrap();
+
+        // The third request reports VIRTIO_BLK_S_IOERR and doesn't read the disk.
+        let (status, data) = if i == 2 { (1, 0) } else { (0, 0xaa) };
+        assert_eq!(status, emu.cpu.bus.read(STATUS_ADDR, BYTE).unwrap());
+        assert_eq!(data, emu.cpu.bus.read(DATA_ADDR, BYTE).unwrap());
+    }
+    // The failed request is still completed.
+    assert_eq!(
+        4,
+        emu.cpu.bus.read(QUEUE_ADDR + 4096 + 2, HALFWORD).unwrap()
+    );
+}
+
+#[test]
+fn delay_device_stalls_until_deadline() {
+    let data = vec![
+        0xb7, 0x02, 0x00, 0x20, // lui t0, 0x20000
+        0x03, 0xb3, 0x02, 0x00, // ld t1, 0(t0)
+        0x93, 0x03, 0x43, 0x06, // addi t2, t1, 100
+        0x23, 0xb0, 0x72, 0x00, // sd t2, 0(t0)
+        0x03, 0xbe, 0x02, 0x00, // ld t3, 0(t0)
+    ];
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data);
+    emu.initialize_pc(DRAM_BASE);
+    emu.enable_delay(0x2000_0000);
+    emu.cpu.state.write(MCYCLE, 1000);
+
+    for _ in 0..5 {
+        emu.cpu.execute().unwrap();
+    }
+
+    let start = emu.cpu.xregs.read(6);
+    assert!(start >= 1000);
+    // The load after the deadline sees at least 100 more cycles.
+    assert!(emu.cpu.xregs.read(28) >= start + 100);
+    assert!(emu.cpu.state.read(MCYCLE) > start + 100);
+}
+
+/// Place queue `queue` of the virtio console at `addr` with 8 entries, and make the device ready.
+/// Return the addresses of the descriptor table, the available ring, and the used ring.
+fn setup_console_queue(emu: &mut Emulator, queue: u64, addr: u64) -> (u64, u64, u64) {
+    let bus = &mut emu.cpu.bus;
+    // GuestPageSize, QueueSel, QueueNum, QueueAlign, and QueuePFN.
+    bus.write(VIRTIO_CONSOLE_BASE + 0x28, 4096, WORD).unwrap();
+    bus.write(VIRTIO_CONSOLE_BASE + 0x30, queue, WORD).unwrap();
+    bus.write(VIRTIO_CONSOLE_BASE + 0x38, 8, WORD).unwrap();
+    bus.write(VIRTIO_CONSOLE_BASE + 0x3c, 4096, WORD).unwrap();
+    bus.write(VIRTIO_CONSOLE_BASE + 0x40, addr / 4096, WORD)
+        .unwrap();
+    // ACKNOWLEDGE, DRIVER, FEATURES_OK, and DRIVER_OK in Status.
+    bus.write(VIRTIO_CONSOLE_BASE + 0x70, 0xf, WORD).unwrap();
+    (addr, addr + 16 * 8, addr + 4096)
+}
+
+/// Make the chain of the single descriptor `index` for `len` bytes at `buffer` available at
+/// `slot` of the available ring.
+fn offer_console_buffer(
+    emu: &mut Emulator,
+    (desc, avail, _): (u64, u64, u64),
+    slot: u64,
+    index: u64,
+    buffer: u64,
+    len: u64,
+    device_writable: bool,
+) {
+    let bus = &mut emu.cpu.bus;
+    bus.write(desc + 16 * index, buffer, DOUBLEWORD).unwrap();
+    bus.write(desc + 16 * index + 8, len, WORD).unwrap();
+    let flags = if device_writable { 2 } else { 0 };
+    bus.write(desc + 16 * index + 12, flags, HALFWORD).unwrap();
+    bus.write(avail + 4 + 2 * slot, index, HALFWORD).unwrap();
+    bus.write(avail + 2, slot + 1, HALFWORD).unwrap();
+}
+
+#[test]
+fn virtio_console_transmits_to_backend() {
+    let mut emu = Emulator::new();
+    emu.enable_virtio_console();
+    let backend = BufferBackend::new();
+    let console = emu.cpu.bus.virtio_console.as_mut().unwrap();
+    console.set_backend(Box::new(backend.clone()));
+    assert_eq!(
+        3,
+        emu.cpu.bus.read(VIRTIO_CONSOLE_BASE + 0x8, WORD).unwrap()
+    );
+
+    let queue = setup_console_queue(&mut emu, 1, DRAM_BASE + 0x4000);
+    emu.cpu
+        .bus
+        .write(DATA_ADDR, 0x6f_6c6c_6568, DOUBLEWORD)
+        .unwrap();
+    emu.cpu
+        .bus
+        .write(DATA_ADDR + 0x100, 0x0a_216e_7572, DOUBLEWORD)
+        .unwrap();
+    offer_console_buffer(&mut emu, queue, 0, 3, DATA_ADDR, 5, false);
+    offer_console_buffer(&mut emu, queue, 1, 5, DATA_ADDR + 0x100, 5, false);
+
+    // Nothing is sent until the driver notifies the transmit queue.
+    emu.cpu.check_pending_interrupt();
+    assert_eq!("", backend.contents());
+    emu.cpu
+        .bus
+        .write(VIRTIO_CONSOLE_BASE + 0x50, 1, WORD)
+        .unwrap();
+    emu.cpu.check_pending_interrupt();
+
+    assert_eq!("hellorun!\n", backend.contents());
+    // Both chains are used in order, and the used buffer notification is raised.
+    let (_, _, used) = queue;
+    assert_eq!(2, emu.cpu.bus.read(used + 2, HALFWORD).unwrap());
+    assert_eq!(3, emu.cpu.bus.read(used + 4, WORD).unwrap());
+    assert_eq!(5, emu.cpu.bus.read(used + 12, WORD).unwrap());
+    assert_eq!(
+        1,
+        emu.cpu.bus.read(VIRTIO_CONSOLE_BASE + 0x60, WORD).unwrap()
+    );
+    let pending = emu.cpu.bus.read(PLIC_BASE + 0x1000, WORD).unwrap();
+    assert_eq!(1 << IrqSource::VirtioConsole as u32, pending);
+}
+
+#[test]
+fn virtio_console_delivers_input_to_receive_buffers() {
+    let mut emu = Emulator::new();
+    emu.enable_virtio_console();
+    emu.cpu
+        .bus
+        .virtio_console
+        .as_mut()
+        .unwrap()
+        .push_input(b"ls\n");
+
+    // The input waits until the driver offers a buffer.
+    let queue = setup_console_queue(&mut emu, 0, DRAM_BASE + 0x4000);
+    emu.cpu.check_pending_interrupt();
+    assert_eq!(
+        0,
+        emu.cpu.bus.read(VIRTIO_CONSOLE_BASE + 0x60, WORD).unwrap()
+    );
+
+    offer_console_buffer(&mut emu, queue, 0, 0, DATA_ADDR, 2, true);
+    offer_console_buffer(&mut emu, queue, 1, 1, DATA_ADDR + 0x100, 16, true);
+    emu.cpu
+        .bus
+        .write(VIRTIO_CONSOLE_BASE + 0x50, 0, WORD)
+        .unwrap();
+    emu.cpu.check_pending_interrupt();
+
+    // The input is split across the buffers, and each used element has the length written.
+    assert_eq!(0x736c, emu.cpu.bus.read(DATA_ADDR, HALFWORD).unwrap());
+    assert_eq!(0x0a, emu.cpu.bus.read(DATA_ADDR + 0x100, BYTE).unwrap());
+    let (_, _, used) = queue;
+    assert_eq!(2, emu.cpu.bus.read(used + 2, HALFWORD).unwrap());
+    assert_eq!(2, emu.cpu.bus.read(used + 8, WORD).unwrap());
+    assert_eq!(1, emu.cpu.bus.read(used + 12, WORD).unwrap());
+    assert_eq!(1, emu.cpu.bus.read(used + 16, WORD).unwrap());
+    assert_eq!(
+        1,
+        emu.cpu.bus.read(VIRTIO_CONSOLE_BASE + 0x60, WORD).unwrap()
+    );
+}
+
+#[test]
+fn virtio_console_returns_a_chain_out_of_the_memory() {
+    let mut emu = Emulator::new();
+    emu.enable_virtio_console();
+    let backend = BufferBackend::new();
+    let console = emu.cpu.bus.virtio_console.as_mut().unwrap();
+    console.set_backend(Box::new(backend.clone()));
+    let queue = setup_console_queue(&mut emu, 1, DRAM_BASE + 0x4000);
+    emu.cpu.bus.write(DATA_ADDR, 0x6968, HALFWORD).unwrap();
+    offer_console_buffer(&mut emu, queue, 0, 0, 0, 5, false);
+    offer_console_buffer(&mut emu, queue, 1, 1, DATA_ADDR, 2, false);
+    emu.cpu
+        .bus
+        .write(VIRTIO_CONSOLE_BASE + 0x50, 1, WORD)
+        .unwrap();
+
+    // The chain whose buffer is out of the memory fails, but it's used rather than lost.
+    assert!(VirtioConsole::process_queues(&mut emu.cpu.bus).is_err());
+    let (_, _, used) = queue;
+    assert_eq!(1, emu.cpu.bus.read(used + 2, HALFWORD).unwrap());
+    assert_eq!(0, emu.cpu.bus.read(used + 4, WORD).unwrap());
+
+    // The next chain is sent once the driver notifies the queue again.
+    emu.cpu
+        .bus
+        .write(VIRTIO_CONSOLE_BASE + 0x50, 1, WORD)
+        .unwrap();
+    assert!(VirtioConsole::process_queues(&mut emu.cpu.bus).unwrap());
+    assert_eq!("hi", backend.contents());
+    assert_eq!(2, emu.cpu.bus.read(used + 2, HALFWORD).unwrap());
+    assert_eq!(1, emu.cpu.bus.read(used + 12, WORD).unwrap());
+}
+
+#[test]
+fn virtio_console_rejects_a_head_out_of_the_table() {
+    let mut emu = Emulator::new();
+    emu.enable_virtio_console();
+    let backend = BufferBackend::new();
+    let console = emu.cpu.bus.virtio_console.as_mut().unwrap();
+    console.set_backend(Box::new(backend.clone()));
+    let queue = setup_console_queue(&mut emu, 1, DRAM_BASE + 0x4000);
+    emu.cpu.bus.write(DATA_ADDR, 0x6968, HALFWORD).unwrap();
+    offer_console_buffer(&mut emu, queue, 0, 1, DATA_ADDR, 2, false);
+    // The queue has 8 entries, so head 9 isn't descriptor 1.
+    let (_, avail, used) = queue;
+    emu.cpu.bus.write(avail + 4, 9, HALFWORD).unwrap();
+    offer_console_buffer(&mut emu, queue, 1, 1, DATA_ADDR, 2, false);
+    emu.cpu
+        .bus
+        .write(VIRTIO_CONSOLE_BASE + 0x50, 1, WORD)
+        .unwrap();
+
+    // The bad head is neither sent nor put in the used ring.
+    assert!(VirtioConsole::process_queues(&mut emu.cpu.bus).is_err());
+    assert_eq!("", backend.contents());
+    assert_eq!(0, emu.cpu.bus.read(used + 2, HALFWORD).unwrap());
+
+    emu.cpu
+        .bus
+        .write(VIRTIO_CONSOLE_BASE + 0x50, 1, WORD)
+        .unwrap();
+    assert!(VirtioConsole::process_queues(&mut emu.cpu.bus).unwrap());
+    assert_eq!("hi", backend.contents());
+    assert_eq!(1, emu.cpu.bus.read(used + 2, HALFWORD).unwrap());
+    assert_eq!(1, emu.cpu.bus.read(used + 4, WORD).unwrap());
+}
+
+#[test]
+fn perf_counters_read_as_the_csrs() {
+    let data = vec![
+        0xb7, 0x02, 0x00, 0x20, // lui t0, 0x20000
+        0x13, 0x05, 0x10, 0x00, // addi a0, zero, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x03, 0xb3, 0x82, 0x00, // ld t1, 8(t0)
+        0xf3, 0x23, 0x20, 0xb0, // csrrs t2, minstret, zero
+        0x03, 0xbe, 0x02, 0x00, // ld t3, 0(t0)
+        0x83, 0xbe, 0x02, 0x01, // ld t4, 16(t0)
+    ];
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data);
+    emu.initialize_pc(DRAM_BASE);
+    emu.enable_perf_counters(0x2000_0000).unwrap();
+    emu.cpu
+        .bus
+        .write(CLINT_BASE + 0xbff8, 1234, DOUBLEWORD)
+        .unwrap();
+
+    for _ in 0..7 {
+        emu.cpu.execute().unwrap();
+    }
+
+    // The load sees the 3 instructions before it, as the CSR read after it sees 4.
+    assert_eq!(3, emu.cpu.xregs.read(6));
+    assert_eq!(4, emu.cpu.xregs.read(7));
+    assert_eq!(7, emu.cpu.state.read(MINSTRET));
+    assert!(emu.cpu.xregs.read(28) >= 5);
+    assert!(emu.cpu.xregs.read(28) < emu.cpu.state.read(MCYCLE));
+    assert_eq!(1234, emu.cpu.xregs.read(29));
+    // The registers are read-only.
+    assert!(emu.cpu.bus.write(0x2000_0008, 0, DOUBLEWORD).is_err());
+}
+
+#[test]
+fn debug_ctl_traces_only_the_region_the_guest_selects() {
+    let data = vec![
+        0xb7, 0x02, 0x00, 0x20, // lui t0, 0x20000
+        0x13, 0x03, 0x10, 0x00, // addi t1, zero, 1
+        0x23, 0xb0, 0x62, 0x00, // sd t1, 0(t0)
+        0x13, 0x05, 0x70, 0x00, // addi a0, zero, 7
+        0x23, 0xb4, 0xa2, 0x00, // sd a0, 8(t0)
+        0x23, 0xb0, 0x02, 0x00, // sd zero, 0(t0)
+        0x13, 0x05, 0x80, 0x00, // addi a0, zero, 8
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data);
+    emu.initialize_pc(DRAM_BASE);
+    emu.enable_debug_ctl(0x2000_0000).unwrap();
+    let traced = Rc::new(RefCell::new(Vec::new()));
+    let hook_traced = traced.clone();
+    emu.set_trace_hook(move |entry| hook_traced.borrow_mut().push(entry.pc));
+
+    emu.run(8);
+
+    // The hook fires from the instruction after the store which enables the trace, through the
+    // one which disables it.
+    assert_eq!(
+        vec![DRAM_BASE + 0xc, DRAM_BASE + 0x10, DRAM_BASE + 0x14],
+        *traced.borrow()
+    );
+    assert_eq!(8, emu.cpu.xregs.read(10));
+    assert_eq!(0, emu.cpu.bus.read(0x2000_0000, DOUBLEWORD).unwrap());
+    assert_eq!(7, emu.cpu.bus.read(0x2000_0008, DOUBLEWORD).unwrap());
+    assert!(emu
+        .cpu
+        .bus
+        .memory_map()
+        .contains(&("debug_ctl".to_string(), 0x2000_0000, 0x10)));
+}
+
+#[test]
+fn watchdog_lapse_raises_interrupt() {
+    const WATCHDOG_BASE: u64 = 0x2000_0000;
+    const MTIME: u64 = CLINT_BASE + 0xbff8;
+    let mut emu = Emulator::new();
+    emu.enable_watchdog(WATCHDOG_BASE, WatchdogAction::Interrupt(5))
+        .unwrap();
+    let bus = &mut emu.cpu.bus;
+
+    // A timeout of 100 ticks, kept alive at 60.
+    bus.write(WATCHDOG_BASE, 100, DOUBLEWORD).unwrap();
+    bus.write(MTIME, 60, DOUBLEWORD).unwrap();
+    bus.write(WATCHDOG_BASE + 0x8, 1, DOUBLEWORD).unwrap();
+    bus.write(MTIME, 150, DOUBLEWORD).unwrap();
+    assert_eq!(10, bus.read(WATCHDOG_BASE + 0x8, DOUBLEWORD).unwrap());
+    emu.cpu.check_pending_interrupt();
+    assert_eq!(0, emu.cpu.bus.read(PLIC_BASE + 0x1000, WORD).unwrap());
+
+    // No keepalive arrives before the deadline.
+    emu.cpu.bus.write(MTIME, 160, DOUBLEWORD).unwrap();
+    emu.cpu.check_pending_interrupt();
+    assert_eq!(1 << 5, emu.cpu.bus.read(PLIC_BASE + 0x1000, WORD).unwrap());
+    let bus = &mut emu.cpu.bus;
+    assert_eq!(1, bus.read(WATCHDOG_BASE + 0x10, DOUBLEWORD).unwrap());
+    assert_eq!(0, bus.read(WATCHDOG_BASE + 0x8, DOUBLEWORD).unwrap());
+    bus.write(WATCHDOG_BASE + 0x10, 1, DOUBLEWORD).unwrap();
+    assert_eq!(0, bus.read(WATCHDOG_BASE + 0x10, DOUBLEWORD).unwrap());
+}
+
+#[test]
+fn watchdog_lapse_can_reset_the_machine() {
+    let data = vec![
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data);
+    emu.initialize_pc(DRAM_BASE);
+    emu.enable_watchdog(0x2000_0000, WatchdogAction::Reset)
+        .unwrap();
+    emu.cpu.bus.write(0x2000_0000, 10, DOUBLEWORD).unwrap();
+    emu.cpu.bus.write(DRAM_BASE + 0x100, 0xab, BYTE).unwrap();
+
+    emu.run(50);
+
+    // The memory has been loaded again and the watchdog is disabled.
+    assert_eq!(0, emu.cpu.bus.read(DRAM_BASE + 0x100, BYTE).unwrap());
+    assert_eq!(0, emu.cpu.bus.read(0x2000_0000, DOUBLEWORD).unwrap());
+}
+
+#[test]
+fn optional_devices_reject_invalid_irq_and_overlap() {
+    let mut emu = Emulator::new();
+    assert_eq!(
+        Err(ConfigError::InvalidIrq(4096)),
+        emu.enable_watchdog(0x2000_0000, WatchdogAction::Interrupt(4096))
+    );
+    assert!(matches!(
+        emu.enable_watchdog(DRAM_BASE + 0x1000, WatchdogAction::Reset),
+        Err(ConfigError::Overlap { ref name, .. }) if name == "dram"
+    ));
+    assert!(matches!(
+        emu.enable_perf_counters(u64::MAX - 0x10),
+        Err(ConfigError::Wraparound { .. })
+    ));
+    assert!(matches!(
+        emu.enable_debug_ctl(VIRTIO_BASE + 0x8),
+        Err(ConfigError::Overlap { ref name, .. }) if name == "virtio"
+    ));
+
+    emu.enable_watchdog(0x2000_0000, WatchdogAction::Reset)
+        .unwrap();
+    assert!(matches!(
+        emu.enable_perf_counters(0x2000_0010),
+        Err(ConfigError::Overlap { ref name, .. }) if name == "watchdog"
+    ));
+    emu.enable_perf_counters(0x2000_0018).unwrap();
+    emu.enable_debug_ctl(0x2000_0030).unwrap();
+}
+
+#[test]
+fn random_mmio_accesses_never_panic() {
+    let mut emu = Emulator::new();
+    emu.initialize_disk(vec![0; 512 * 4]);
+    emu.cpu.bus.uart.set_backend(Box::new(BufferBackend::new()));
+    setup_virtqueue(&mut emu);
+    write_request(&mut emu, 0, 0, 512, true);
+
+    // The devices, the virtqueue and the request, and the end of DRAM.
+    let regions = [
+        (MROM_BASE, 0xf008),
+        (CLINT_BASE, 0x10008),
+        (PLIC_BASE, 0x208),
+        (PLIC_BASE + 0x20_0000, 0x2008),
+        (UART_BASE, 0x108),
+        (VIRTIO_BASE, 0x208),
+        (QUEUE_ADDR, 0x3000),
+        (HEADER_ADDR, 0x1800),
+        (DRAM_BASE + DRAM_SIZE - 8, 16),
+    ];
+    let sizes = [BYTE, HALFWORD, WORD, DOUBLEWORD];
+
+    // A deterministic xorshift generator, so that a failure is reproducible.
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for _ in 0..100_000 {
+        let (base, len) = regions[(random() % regions.len() as u64) as usize];
+        let addr = base + random() % len;
+        let size = sizes[(random() % sizes.len() as u64) as usize];
+        let value = random();
+        if random() % 2 == 0 {
+            let _ = emu.cpu.bus.write(addr, value, size);
+        } else {
+            let _ = emu.cpu.bus.read(addr, size);
+        }
+        // Let the devices act on what was written, e.g., a notification of the virtqueue.
+        emu.cpu.check_pending_interrupt();
+    }
+}
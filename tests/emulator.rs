@@ -0,0 +1,843 @@
+extern crate rvemu;
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use rvemu::{
+    bus::{CLINT_BASE, DRAM_BASE, MROM_BASE},
+    cpu::{Mode, BYTE, DOUBLEWORD, HALFWORD, WORD},
+    csr::{DCSR, DCSR_EBREAKM, DPC, MCAUSE, MTVEC, TIME},
+    dram::DRAM_SIZE,
+    emulator::{Emulator, Halt, SigintBehavior, INITRD_BASE},
+    error::ConfigError,
+    rom::{chosen_property, has_root_node, DTB_OFFSET},
+    wasm::WasmEmulator,
+};
+
+/// Create an emulator which has `data` at the beginning of DRAM.
+fn setup(data: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data);
+    emu.initialize_pc(DRAM_BASE);
+    emu
+}
+
+#[test]
+fn run_and_capture_output_returns_uart_output() {
+    let data = vec![
+        0xb7, 0x02, 0x00, 0x10, // lui t0, 0x10000
+        0x13, 0x03, 0x80, 0x06, // addi t1, zero, 104
+        0x23, 0x80, 0x62, 0x00, // sb t1, 0(t0)
+        0x13, 0x03, 0x50, 0x06, // addi t1, zero, 101
+        0x23, 0x80, 0x62, 0x00, // sb t1, 0(t0)
+        0x13, 0x03, 0xc0, 0x06, // addi t1, zero, 108
+        0x23, 0x80, 0x62, 0x00, // sb t1, 0(t0)
+        0x23, 0x80, 0x62, 0x00, // sb t1, 0(t0)
+        0x13, 0x03, 0xf0, 0x06, // addi t1, zero, 111
+        0x23, 0x80, 0x62, 0x00, // sb t1, 0(t0)
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+
+    let (halt, output) = emu.run_and_capture_output(100);
+
+    assert_eq!(Halt::InstructionLimit, halt);
+    assert_eq!("hello", output);
+}
+
+#[test]
+fn replay_reproduces_recorded_input_timing() {
+    // Poll the UART, counting the iterations in t2, and sum the received bytes in a0 and count
+    // them in a2.
+    let data = vec![
+        0xb7, 0x02, 0x00, 0x10, // lui t0, 0x10000
+        0x93, 0x83, 0x13, 0x00, // addi t2, t2, 1
+        0x03, 0xc3, 0x52, 0x00, // lbu t1, 5(t0)
+        0x13, 0x73, 0x13, 0x00, // andi t1, t1, 1
+        0xe3, 0x0a, 0x03, 0xfe, // beq t1, zero, -12
+        0x03, 0xc3, 0x02, 0x00, // lbu t1, 0(t0)
+        0x33, 0x05, 0x65, 0x00, // add a0, a0, t1
+        0x13, 0x06, 0x16, 0x00, // addi a2, a2, 1
+        0x6f, 0xf0, 0x5f, 0xfe, // jal zero, -28
+    ];
+    let path = std::env::temp_dir().join("rvemu-replay-test.log");
+    let registers = |emu: &Emulator| (0..32).map(|i| emu.cpu.xregs.read(i)).collect::<Vec<_>>();
+
+    let mut recorded = setup(data.clone());
+    recorded.start_recording(&path).unwrap();
+    recorded.cpu.bus.uart.push_input(b"h");
+    recorded.run(30);
+    recorded.cpu.bus.uart.push_input(b"i");
+    recorded.run(70);
+    assert_eq!(2, recorded.cpu.xregs.read(12));
+    assert_eq!((b'h' + b'i') as u64, recorded.cpu.xregs.read(10));
+
+    let mut replayed = setup(data);
+    replayed.replay(&path).unwrap();
+    replayed.run(100);
+
+    assert_eq!(registers(&recorded), registers(&replayed));
+    assert_eq!(recorded.cpu.pc, replayed.cpu.pc);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn golden_trace_matches_and_reports_divergence() {
+    let data = vec![
+        0x13, 0x05, 0x30, 0x00, // addi a0, zero, 3
+        0x93, 0x05, 0x50, 0x00, // addi a1, zero, 5
+        0x33, 0x06, 0xb5, 0x00, // add a2, a0, a1
+        0x63, 0x04, 0x05, 0x00, // beq a0, zero, 8
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let path = std::env::temp_dir().join("rvemu-golden-test.trace");
+
+    let mut recorded = setup(data.clone());
+    assert_eq!(
+        Halt::InstructionLimit,
+        recorded.write_golden(&path, 5).unwrap()
+    );
+    let golden = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(
+        "80000000 00300513 x10 3\n\
+         80000004 00500593 x11 5\n\
+         80000008 00b50633 x12 8\n\
+         8000000c 00050463 - -\n\
+         80000010 0000006f - -\n",
+        golden
+    );
+
+    let mut matching = setup(data.clone());
+    assert_eq!(
+        Halt::InstructionLimit,
+        matching.run_with_golden(&path).unwrap()
+    );
+    assert_eq!(8, matching.cpu.xregs.read(12));
+
+    // A different result of `add` is reported at the instruction.
+    std::fs::write(&path, golden.replace("x12 8", "x12 9")).unwrap();
+    let mut diverging = setup(data);
+    let error = diverging.run_with_golden(&path).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidData, error.kind());
+    let message = error.to_string();
+    assert!(message.contains("at instruction 3"), "{}", message);
+    assert!(message.contains("- 80000008 00b50633 x12 9"), "{}", message);
+    assert!(message.contains("+ 80000008 00b50633 x12 8"), "{}", message);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn spike_log_matches_and_reports_divergence() {
+    let data = vec![
+        0x13, 0x05, 0x30, 0x00, // addi a0, zero, 3
+        0x93, 0x05, 0x50, 0x00, // addi a1, zero, 5
+        0x33, 0x06, 0xb5, 0x00, // add a2, a0, a1
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x23, 0xa0, 0xc2, 0x00, // sw a2, 0(t0)
+        0x95, 0x45, // c.li a1, 5
+        0x01, 0xa0, // c.j 0
+    ];
+    let mut root = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    root.push("tests/resources/spike/commit.log");
+
+    // The boot ROM of Spike is skipped, and `c.li` matches though it doesn't change a1.
+    let mut matching = setup(data.clone());
+    assert_eq!(
+        Halt::InstructionLimit,
+        matching.check_against_spike_log(&root).unwrap()
+    );
+    assert_eq!(0x8000_0016, matching.cpu.pc);
+    assert_eq!(8, matching.cpu.bus.read(0x8000_100c, WORD).unwrap());
+
+    // A different result of `add` is reported at the instruction with the lines before it.
+    let log = std::fs::read_to_string(&root).unwrap();
+    let path = std::env::temp_dir().join("rvemu-spike-test.log");
+    std::fs::write(
+        &path,
+        log.replace("x12 0x0000000000000008", "x12 0x0000000000000009"),
+    )
+    .unwrap();
+    let mut diverging = setup(data.clone());
+    let error = diverging.check_against_spike_log(&path).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidData, error.kind());
+    let message = error.to_string();
+    assert!(message.contains("at instruction 3 (line 9)"), "{}", message);
+    assert!(
+        message.contains("  core   0: 3 0x0000000080000004 (0x00500593)"),
+        "{}",
+        message
+    );
+    assert!(
+        message.contains("+ 0x0000000080000008 (0x00b50633) x12 0x0000000000000008"),
+        "{}",
+        message
+    );
+
+    // The emulator must not write a register which Spike doesn't.
+    std::fs::write(&path, log.replace(" x5  0x000000008000100c", "")).unwrap();
+    let mut diverging = setup(data);
+    let message = diverging
+        .check_against_spike_log(&path)
+        .unwrap_err()
+        .to_string();
+    assert!(message.contains("at instruction 4"), "{}", message);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn sigint_injects_the_configured_byte() {
+    // Poll the UART and copy the received byte to a0.
+    let data = vec![
+        0xb7, 0x02, 0x00, 0x10, // lui t0, 0x10000
+        0x03, 0xc3, 0x52, 0x00, // lbu t1, 5(t0)
+        0x13, 0x73, 0x13, 0x00, // andi t1, t1, 1
+        0xe3, 0x0c, 0x03, 0xfe, // beq t1, zero, -8
+        0x03, 0xc5, 0x02, 0x00, // lbu a0, 0(t0)
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    emu.set_sigint_behavior(SigintBehavior::InjectByte(0x03))
+        .unwrap();
+    let sigint = emu.sigint_flag();
+
+    assert_eq!(Halt::InstructionLimit, emu.run(20));
+    assert_eq!(0, emu.cpu.xregs.read(10));
+    // What the signal handler of the host does.
+    sigint.store(true, Ordering::SeqCst);
+    assert_eq!(Halt::InstructionLimit, emu.run(20));
+    assert_eq!(0x03, emu.cpu.xregs.read(10));
+
+    emu.set_sigint_behavior(SigintBehavior::Halt).unwrap();
+    sigint.store(true, Ordering::SeqCst);
+    assert_eq!(Halt::Interrupted, emu.run(20));
+}
+
+#[test]
+fn sigint_rejects_an_invalid_interrupt_source() {
+    let mut emu = setup(vec![0x6f, 0x00, 0x00, 0x00]); // jal zero, 0
+    for irq in [0, 1024, 4096] {
+        assert_eq!(
+            Err(ConfigError::InvalidIrq(irq)),
+            emu.set_sigint_behavior(SigintBehavior::RaiseInterrupt(irq))
+        );
+    }
+    // The rejected behavior isn't set, so SIGINT is still ignored.
+    emu.sigint_flag().store(true, Ordering::SeqCst);
+    assert_eq!(Halt::InstructionLimit, emu.run(4));
+
+    // The PLIC ignores an ID which isn't an interrupt source instead of indexing past its bits.
+    emu.cpu.bus.plic.set_pending_id(4096);
+    assert!(!emu.cpu.bus.plic.is_interrupting(0));
+}
+
+#[test]
+fn trap_checkpoint_records_the_faulting_address_and_cause() {
+    let data = vec![
+        0x73, 0x10, 0x05, 0x18, // csrw satp, a0
+        0x13, 0x05, 0x10, 0x00, // addi a0, zero, 1
+    ];
+    let mut emu = setup(data);
+    // The root page table is empty, so the fetch after enabling paging faults.
+    let root = DRAM_BASE + 0x10000;
+    emu.cpu.xregs.write(10, (8 << 60) | (root >> 12));
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.state.write(MTVEC, DRAM_BASE + 0x100);
+    emu.write_physical(DRAM_BASE + 0x100, &[0x6f, 0x00, 0x00, 0x00]) // jal zero, 0
+        .unwrap();
+    let checkpoints = Rc::new(RefCell::new(Vec::new()));
+    let hook_checkpoints = checkpoints.clone();
+    emu.set_trap_checkpoint_hook(move |checkpoint| {
+        hook_checkpoints.borrow_mut().push(checkpoint.clone())
+    });
+
+    // The run continues in the handler.
+    assert_eq!(Halt::InstructionLimit, emu.run(10));
+    assert_eq!(1, checkpoints.borrow().len());
+    let checkpoint = checkpoints.borrow()[0].clone();
+    assert_eq!(12, checkpoint.cause);
+    assert_eq!(DRAM_BASE + 4, checkpoint.tval);
+    assert_eq!(DRAM_BASE + 4, checkpoint.epc);
+    assert_eq!(Mode::Supervisor, checkpoint.mode);
+    assert_eq!((8 << 60) | (root >> 12), checkpoint.xregs[10]);
+    assert!(checkpoint.csrs.contains(&(MCAUSE, 12)));
+    // The window is cut at the start of DRAM.
+    assert_eq!(DRAM_BASE, checkpoint.memory_base);
+    assert_eq!(
+        &[0x13, 0x05, 0x10, 0x00],
+        &checkpoint.memory[4..8],
+        "the faulting word"
+    );
+    assert!(emu.take_fatal_checkpoint().is_none());
+
+    // A fetch from nowhere is fatal, and the checkpoint is kept for the embedder instead.
+    emu.cpu.pc = 0x4000_0000_0000;
+    assert_eq!(Halt::FatalTrap, emu.run(10));
+    assert_eq!(1, checkpoints.borrow().len());
+    let checkpoint = emu.take_fatal_checkpoint().unwrap();
+    assert_eq!(1, checkpoint.cause);
+    assert_eq!(0x4000_0000_0000, checkpoint.epc);
+    assert!(checkpoint.memory.is_empty());
+}
+
+#[test]
+fn wfi_fast_forwards_time_to_the_next_timer_interrupt() {
+    let data = vec![
+        0x97, 0x02, 0x00, 0x00, // auipc t0, 0
+        0x93, 0x82, 0x82, 0x01, // addi t0, t0, 24
+        0x73, 0x90, 0x52, 0x30, // csrw mtvec, t0
+        0x73, 0x10, 0x43, 0x30, // csrw mie, t1
+        0x73, 0x60, 0x04, 0x30, // csrsi mstatus, 8
+        0x73, 0x00, 0x50, 0x10, // wfi
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    emu.set_fast_forward_wfi(true);
+    // Enable the machine timer interrupt, and set it far in the future.
+    emu.cpu.xregs.write(6, 1 << 7);
+    emu.cpu
+        .bus
+        .write(CLINT_BASE + 0x4000, 100_000, DOUBLEWORD)
+        .unwrap();
+
+    // Stop at the trap handler right after the interrupt wakes the hart.
+    emu.add_breakpoint(DRAM_BASE + 24);
+    assert_eq!(Halt::Breakpoint(DRAM_BASE + 24), emu.run(100));
+    assert_eq!(
+        100_000,
+        emu.cpu.bus.read(CLINT_BASE + 0xbff8, DOUBLEWORD).unwrap()
+    );
+    assert_eq!(100_000, emu.cpu.state.read(TIME));
+    assert_eq!((1 << 63) | 7, emu.cpu.state.read(MCAUSE));
+    assert_eq!(DRAM_BASE + 24, emu.cpu.pc);
+}
+
+#[test]
+fn ebreak_enters_debug_mode_when_ebreakm_is_set() {
+    let data = vec![
+        0x13, 0x05, 0x10, 0x00, // addi a0, zero, 1
+        0x73, 0x00, 0x10, 0x00, // ebreak
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    emu.cpu
+        .state
+        .write(DCSR, emu.cpu.state.read(DCSR) | DCSR_EBREAKM);
+    let entered_at = Rc::new(Cell::new(0));
+    let hook_entered_at = entered_at.clone();
+    emu.set_debug_entry_hook(move |cpu| hook_entered_at.set(cpu.state.read(DPC)));
+
+    assert_eq!(Halt::Debug, emu.run(100));
+    assert_eq!(Mode::Debug, emu.cpu.mode);
+    assert_eq!(DRAM_BASE + 4, emu.cpu.state.read(DPC));
+    assert_eq!(DRAM_BASE + 4, entered_at.get());
+    // dcsr.cause is ebreak and dcsr.prv is M-mode.
+    assert_eq!(1, (emu.cpu.state.read(DCSR) >> 6) & 0b111);
+    assert_eq!(0b11, emu.cpu.state.read(DCSR) & 0b11);
+    // No trap handler runs.
+    assert_eq!(0, emu.cpu.state.read(MCAUSE));
+    // The hart stays halted until it's resumed.
+    assert_eq!(Halt::Debug, emu.run(100));
+    assert_eq!(1, emu.cpu.xregs.read(10));
+
+    // Resume after the breakpoint as a debugger does.
+    emu.cpu.state.write(DPC, DRAM_BASE + 8);
+    emu.cpu.leave_debug_mode();
+    assert_eq!(Halt::InstructionLimit, emu.run(100));
+    assert_eq!(Mode::Machine, emu.cpu.mode);
+    assert_eq!(2, emu.cpu.xregs.read(10));
+}
+
+#[test]
+fn sbi_system_reset_shuts_down_with_reason() {
+    let data = vec![
+        0xb7, 0x58, 0x52, 0x53, // lui a7, 0x53525
+        0x93, 0x88, 0x48, 0x35, // addi a7, a7, 0x354
+        0x13, 0x08, 0x00, 0x00, // addi a6, zero, 0
+        0x13, 0x05, 0x00, 0x00, // addi a0, zero, 0
+        0x93, 0x05, 0xa0, 0x02, // addi a1, zero, 42
+        0x73, 0x00, 0x00, 0x00, // ecall
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    emu.is_sbi = true;
+    emu.cpu.mode = Mode::Supervisor;
+
+    assert_eq!(Halt::Shutdown(42), emu.run(100));
+    // No trap handler runs.
+    assert_eq!(0, emu.cpu.state.read(MCAUSE));
+}
+
+#[test]
+fn unsupported_sbi_call_returns_error() {
+    let data = vec![
+        0x93, 0x08, 0x10, 0x00, // addi a7, zero, 1
+        0x73, 0x00, 0x00, 0x00, // ecall
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    emu.is_sbi = true;
+    emu.cpu.mode = Mode::Supervisor;
+
+    assert_eq!(Halt::InstructionLimit, emu.run(100));
+    // SBI_ERR_NOT_SUPPORTED
+    assert_eq!(-2i64 as u64, emu.cpu.xregs.read(10));
+    assert_eq!(DRAM_BASE + 8, emu.cpu.pc);
+    assert_eq!(Mode::Supervisor, emu.cpu.mode);
+}
+
+#[test]
+fn debug_call_dumps_registers_to_console() {
+    let data = vec![
+        0x13, 0x05, 0x30, 0x12, // addi a0, zero, 0x123
+        0xb7, 0x08, 0x00, 0x0a, // lui a7, 0xa000
+        0x73, 0x00, 0x00, 0x00, // ecall
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    emu.is_debug_call = true;
+
+    let (halt, output) = emu.run_and_capture_output(4);
+    assert_eq!(Halt::InstructionLimit, halt);
+    assert!(output.starts_with(&format!("pc={:#x}\n", DRAM_BASE + 12)));
+    assert!(output.contains("x10( a0 )=             0x123"));
+    // The machine-mode `ecall` returns without a trap and modifies no register.
+    assert_eq!(0, emu.cpu.state.read(MCAUSE));
+    assert_eq!(0x123, emu.cpu.xregs.read(10));
+    assert_eq!(DRAM_BASE + 12, emu.cpu.pc);
+}
+
+#[test]
+fn run_for_duration_yields_after_budget() {
+    let data = vec![
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    let budget = Duration::from_millis(50);
+
+    let start = Instant::now();
+    assert_eq!(Halt::TimeSliceExpired, emu.run_for_duration(budget));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= budget);
+    // The clock is checked often enough not to overrun the budget much.
+    assert!(elapsed < budget * 10, "took {:?}", elapsed);
+}
+
+#[test]
+fn search_memory_finds_every_occurrence() {
+    let mut emu = setup(Vec::new());
+    let marker = b"\xde\xad\xbe\xefMARK";
+    emu.write_physical(DRAM_BASE + 0x1234, marker).unwrap();
+    // The second one straddles a page boundary.
+    emu.write_physical(DRAM_BASE + 0x0100_0ffe, marker).unwrap();
+
+    assert_eq!(
+        vec![DRAM_BASE + 0x1234, DRAM_BASE + 0x0100_0ffe],
+        emu.search_memory(marker)
+    );
+    // A pattern which starts with zeros is also found where it begins in a page of zeros.
+    assert_eq!(
+        vec![DRAM_BASE + 0x1232, DRAM_BASE + 0x0100_0ffc],
+        emu.search_memory(b"\0\0\xde\xad")
+    );
+    assert!(emu.search_memory(b"\xde\xad\xbe\xefMARX").is_empty());
+}
+
+#[test]
+fn reset_restores_registers_and_dram() {
+    let data = vec![
+        0x97, 0x05, 0x00, 0x00, // auipc a1, 0
+        0x13, 0x05, 0xa0, 0x02, // addi a0, zero, 42
+        0x23, 0x80, 0xa5, 0x10, // sb a0, 256(a1)
+        0x23, 0xa0, 0x05, 0x00, // sw zero, 0(a1)
+    ];
+    let mut emu = setup(data);
+    let sp = emu.cpu.xregs.read(2);
+
+    for _ in 0..2 {
+        for _ in 0..4 {
+            emu.cpu.execute().unwrap();
+        }
+        assert_eq!(42, emu.cpu.xregs.read(10));
+        assert_eq!(42, emu.cpu.bus.read(DRAM_BASE + 256, BYTE).unwrap());
+        assert_eq!(0, emu.cpu.bus.read(DRAM_BASE, WORD).unwrap());
+
+        emu.reset();
+        assert_eq!(DRAM_BASE, emu.cpu.pc);
+        assert_eq!(0, emu.cpu.xregs.read(10));
+        assert_eq!(sp, emu.cpu.xregs.read(2));
+        // The byte stored by the guest is cleared and the program is loaded again.
+        assert_eq!(0, emu.cpu.bus.read(DRAM_BASE + 256, BYTE).unwrap());
+        assert_eq!(0x0000_0597, emu.cpu.bus.read(DRAM_BASE, WORD).unwrap());
+    }
+}
+
+#[test]
+fn load_initrd_places_it_in_dram_and_dtb() {
+    let mut emu = setup(Vec::new());
+    emu.initialize_dtb(include_bytes!("../rvemu.dtb").to_vec());
+    let initrd = b"070701 not really a cpio archive".to_vec();
+
+    emu.load_initrd(initrd.clone()).unwrap();
+
+    for (i, &byte) in initrd.iter().enumerate() {
+        let addr = INITRD_BASE + i as u64;
+        assert_eq!(byte as u64, emu.cpu.bus.read(addr, BYTE).unwrap());
+    }
+    let dtb = emu.cpu.bus.rom.dtb().to_vec();
+    let end = INITRD_BASE + initrd.len() as u64;
+    assert_eq!(
+        Some(INITRD_BASE.to_be_bytes().to_vec()),
+        chosen_property(&dtb, "linux,initrd-start")
+    );
+    assert_eq!(
+        Some(end.to_be_bytes().to_vec()),
+        chosen_property(&dtb, "linux,initrd-end")
+    );
+    // The other properties are kept.
+    assert_eq!(
+        Some(b"root=/dev/vda ro console=ttyS0\0".to_vec()),
+        chosen_property(&dtb, "bootargs")
+    );
+    // The guest sees the patched DTB.
+    for (i, &byte) in dtb.iter().enumerate() {
+        let addr = MROM_BASE + DTB_OFFSET + i as u64;
+        assert_eq!(byte as u64, emu.cpu.bus.read(addr, BYTE).unwrap());
+    }
+
+    // Loading another initrd replaces the range.
+    emu.initrd_base = DRAM_BASE + 0x0700_0000;
+    emu.load_initrd(vec![1, 2, 3]).unwrap();
+    let dtb = emu.cpu.bus.rom.dtb();
+    assert_eq!(
+        Some((DRAM_BASE + 0x0700_0003).to_be_bytes().to_vec()),
+        chosen_property(dtb, "linux,initrd-end")
+    );
+}
+
+#[test]
+fn enable_virtio_console_adds_it_to_dtb() {
+    let mut emu = Emulator::new();
+    emu.initialize_dtb(include_bytes!("../rvemu.dtb").to_vec());
+    assert!(!has_root_node(
+        emu.cpu.bus.rom.dtb(),
+        "virtio_mmio@10002000"
+    ));
+
+    emu.enable_virtio_console();
+
+    let dtb = emu.cpu.bus.rom.dtb().to_vec();
+    assert!(has_root_node(&dtb, "virtio_mmio@10002000"));
+    // The other nodes are kept.
+    assert!(has_root_node(&dtb, "virtio_mmio@10001000"));
+    assert_eq!(
+        Some(b"root=/dev/vda ro console=ttyS0\0".to_vec()),
+        chosen_property(&dtb, "bootargs")
+    );
+    // Enabling it again doesn't add another node.
+    emu.enable_virtio_console();
+    assert_eq!(dtb, emu.cpu.bus.rom.dtb());
+}
+
+#[test]
+fn load_initrd_rejects_one_which_does_not_fit_in_dram() {
+    let mut emu = setup(Vec::new());
+    emu.initrd_base = DRAM_BASE + DRAM_SIZE - 2;
+    assert_eq!(
+        Err(ConfigError::OutOfDram {
+            base: DRAM_BASE + DRAM_SIZE - 2,
+            size: 3
+        }),
+        emu.load_initrd(vec![1, 2, 3])
+    );
+    // The address doesn't wrap around.
+    emu.initrd_base = u64::MAX;
+    assert!(emu.load_initrd(vec![1, 2]).is_err());
+    // The initrd isn't placed on a device below DRAM.
+    emu.initrd_base = MROM_BASE;
+    assert!(emu.load_initrd(vec![1]).is_err());
+}
+
+#[test]
+fn write_physical_places_code_at_reset_vector() {
+    let reset_vector = DRAM_BASE + 0x1000;
+    let code = [
+        0x93, 0x02, 0xa0, 0x02, // addi t0, zero, 42
+        0x13, 0x83, 0x12, 0x00, // addi t1, t0, 1
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = Emulator::new();
+    emu.write_physical(reset_vector, &code).unwrap();
+    emu.initialize_pc(reset_vector);
+
+    assert_eq!(Halt::InstructionLimit, emu.run(10));
+    assert_eq!(42, emu.cpu.xregs.read(5));
+    assert_eq!(43, emu.cpu.xregs.read(6));
+    assert_eq!(reset_vector + 8, emu.cpu.pc);
+}
+
+#[test]
+fn dram_fill_poisons_memory_the_guest_never_wrote() {
+    // Zero 16 bytes like a loader clearing BSS, and read them and the bytes after them.
+    let data = vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x23, 0xb0, 0x02, 0x00, // sd zero, 0(t0)
+        0x23, 0xb4, 0x02, 0x00, // sd zero, 8(t0)
+        0x03, 0xb5, 0x02, 0x00, // ld a0, 0(t0)
+        0x83, 0xb5, 0x82, 0x00, // ld a1, 8(t0)
+        0x03, 0xe6, 0x02, 0x01, // lwu a2, 16(t0)
+        0x83, 0xc6, 0x12, 0x01, // lbu a3, 17(t0)
+    ];
+    let mut emu = Emulator::new();
+    emu.dram_fill(0xdeadbeef);
+    emu.initialize_dram(data);
+    emu.initialize_pc(DRAM_BASE);
+
+    emu.run(7);
+    assert_eq!(0, emu.cpu.xregs.read(10));
+    assert_eq!(0, emu.cpu.xregs.read(11));
+    assert_eq!(0xdeadbeef, emu.cpu.xregs.read(12));
+    assert_eq!(0xbe, emu.cpu.xregs.read(13));
+}
+
+#[test]
+fn software_breakpoints_stop_and_restore_instructions() {
+    let data = vec![
+        0x13, 0x05, 0x10, 0x00, // addi a0, zero, 1
+        0x05, 0x05, // c.addi a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    emu.insert_breakpoint(DRAM_BASE + 4).unwrap();
+    emu.insert_breakpoint(DRAM_BASE + 6).unwrap();
+    // c.ebreak and ebreak replace the instructions.
+    assert_eq!(0x9002, emu.cpu.bus.read(DRAM_BASE + 4, HALFWORD).unwrap());
+    assert_eq!(0x0010_0073, emu.cpu.bus.read(DRAM_BASE + 6, WORD).unwrap());
+
+    assert_eq!(Halt::Breakpoint(DRAM_BASE + 4), emu.run(10));
+    assert_eq!(DRAM_BASE + 4, emu.cpu.pc);
+    assert_eq!(1, emu.cpu.xregs.read(10));
+
+    // Continuing re-executes the original instruction.
+    assert!(emu.remove_breakpoint(DRAM_BASE + 4).unwrap());
+    assert_eq!(0x0505, emu.cpu.bus.read(DRAM_BASE + 4, HALFWORD).unwrap());
+    assert_eq!(Halt::Breakpoint(DRAM_BASE + 6), emu.run(10));
+    assert_eq!(2, emu.cpu.xregs.read(10));
+
+    assert!(emu.remove_breakpoint(DRAM_BASE + 6).unwrap());
+    assert!(!emu.remove_breakpoint(DRAM_BASE + 6).unwrap());
+    assert_eq!(Halt::InstructionLimit, emu.run(10));
+    assert_eq!(3, emu.cpu.xregs.read(10));
+}
+
+#[test]
+fn breakpoints_count_hits_across_a_loop() {
+    let data = vec![
+        0x13, 0x05, 0x30, 0x00, // addi a0, zero, 3
+        0x93, 0x86, 0x16, 0x00, // addi a3, a3, 1
+        0x13, 0x05, 0xf5, 0xff, // addi a0, a0, -1
+        0xe3, 0x1c, 0x05, 0xfe, // bne a0, zero, -8
+        0x13, 0x06, 0x70, 0x00, // addi a2, zero, 7
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    emu.add_breakpoint(DRAM_BASE + 16);
+    emu.add_breakpoint(DRAM_BASE + 4);
+    assert_eq!(
+        vec![(DRAM_BASE + 4, 0), (DRAM_BASE + 16, 0)],
+        emu.list_breakpoints()
+    );
+
+    // The memory isn't modified, and the emulator stops before the instruction at a breakpoint.
+    assert_eq!(0x0016_8693, emu.cpu.bus.read(DRAM_BASE + 4, WORD).unwrap());
+    assert_eq!(Halt::Breakpoint(DRAM_BASE + 4), emu.run(100));
+    assert_eq!(DRAM_BASE + 4, emu.cpu.pc);
+    assert_eq!(0, emu.cpu.xregs.read(13));
+
+    // Resuming executes the instruction at the breakpoint until the loop hits it again.
+    for i in 1..3 {
+        assert_eq!(Halt::Breakpoint(DRAM_BASE + 4), emu.resume(100));
+        assert_eq!(i, emu.cpu.xregs.read(13));
+    }
+    assert_eq!(Halt::Breakpoint(DRAM_BASE + 16), emu.resume(100));
+    assert_eq!(3, emu.cpu.xregs.read(13));
+    assert_eq!(0, emu.cpu.xregs.read(12));
+    assert_eq!(
+        vec![(DRAM_BASE + 4, 3), (DRAM_BASE + 16, 1)],
+        emu.list_breakpoints()
+    );
+
+    assert!(emu.remove_breakpoint(DRAM_BASE + 4).unwrap());
+    assert!(!emu.remove_breakpoint(DRAM_BASE + 4).unwrap());
+    assert_eq!(vec![(DRAM_BASE + 16, 1)], emu.list_breakpoints());
+    assert_eq!(Halt::InstructionLimit, emu.resume(10));
+    assert_eq!(7, emu.cpu.xregs.read(12));
+}
+
+#[test]
+fn repl_drives_breakpoints_and_inspection() {
+    let data = vec![
+        0x13, 0x05, 0x10, 0x00, // addi a0, zero, 1
+        0x05, 0x05, // c.addi a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    let script =
+        "break 0x80000006\ncontinue\nregs\nmem 0x80000000 8\ndisas 2\nstep\nfoo\nquit\nstep\n";
+    let mut output = Vec::new();
+
+    emu.repl(script.as_bytes(), &mut output).unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!("breakpoint at 0x80000006", lines[0]);
+    assert_eq!("breakpoint at 0x80000006", lines[1]);
+    assert!(output.contains("x10( a0 )=               0x2"));
+    assert!(output.contains("pc: 0x80000006\n"));
+    assert!(output.contains("0x80000000: 13 05 10 00 05 05 73 00\n"));
+    assert!(output.contains("0x80000006: 00100073\n0x8000000a: 0000006f\n"));
+    // The step executes the original instruction and stops before the rest of the script.
+    assert!(output.ends_with("pc: 0x8000000a\nunknown command: foo\n"));
+    assert_eq!(3, emu.cpu.xregs.read(10));
+}
+
+#[test]
+fn repl_translates_the_pc_and_shows_only_dram() {
+    let mut data = vec![
+        0x73, 0x90, 0x02, 0x18, // csrw satp, t0
+        0x73, 0x10, 0x13, 0x34, // csrw mepc, t1
+        0x73, 0x90, 0x03, 0x30, // csrw mstatus, t2
+        0x73, 0x00, 0x20, 0x30, // mret
+    ];
+    data.resize(0x100, 0);
+    data.extend_from_slice(&[
+        0x13, 0x05, 0x10, 0x00, // addi a0, zero, 1
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ]);
+    // The root page table maps the first gigabyte of the virtual address space to DRAM.
+    data.resize(0x1000, 0);
+    data.extend_from_slice(&0x2000_00cfu64.to_le_bytes());
+    let mut emu = setup(data);
+    emu.cpu
+        .xregs
+        .write(5, (8 << 60) | ((DRAM_BASE + 0x1000) >> 12));
+    emu.cpu.xregs.write(6, 0x100);
+    // MPP is S-mode.
+    emu.cpu.xregs.write(7, 1 << 11);
+    let script = "step 4\nhex 2\nmem 0x10000000 2\n";
+    let mut output = Vec::new();
+
+    emu.repl(script.as_bytes(), &mut output).unwrap();
+
+    assert_eq!(
+        "pc: 0x100\n0x100: 00100513\n0x104: 0000006f\n0x10000000: ?? ??\n",
+        String::from_utf8(output).unwrap()
+    );
+}
+
+#[test]
+fn wasm_emulator_exchanges_console_bytes() {
+    let kernel = vec![
+        0xb7, 0x02, 0x00, 0x10, // lui t0, 0x10000
+        0x13, 0x03, 0x80, 0x06, // addi t1, zero, 104
+        0x23, 0x80, 0x62, 0x00, // sb t1, 0(t0)
+        0x13, 0x03, 0x90, 0x06, // addi t1, zero, 105
+        0x23, 0x80, 0x62, 0x00, // sb t1, 0(t0)
+        0x03, 0xce, 0x52, 0x00, // lbu t3, 5(t0)
+        0x13, 0x7e, 0x1e, 0x00, // andi t3, t3, 1
+        0xe3, 0x0c, 0x0e, 0xfe, // beq t3, zero, -8
+        0x83, 0xc3, 0x02, 0x00, // lbu t2, 0(t0)
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = WasmEmulator::new(kernel);
+
+    assert!(emu.step(5));
+    assert_eq!("hi", emu.get_output());
+    // The output is taken only once.
+    assert_eq!("", emu.get_output());
+
+    // The guest polls LSR until the input arrives.
+    assert!(emu.step(10));
+    assert_eq!(0, emu.get_registers()[7]);
+    emu.send_input("x");
+    assert!(emu.step(10));
+    let registers = emu.get_registers();
+    assert_eq!(33, registers.len());
+    assert_eq!(b'x' as u64, registers[7]);
+    assert_eq!(DRAM_BASE + 36, registers[32]);
+}
+
+/// Return the tickets which the hart `hartid` took in s2, s3, and s4.
+fn tickets(emu: &mut Emulator, hartid: usize) -> (u64, u64, u64) {
+    emu.switch_to_hart(hartid);
+    let xregs = &emu.cpu.xregs;
+    (xregs.read(18), xregs.read(19), xregs.read(20))
+}
+
+#[test]
+fn harts_interleave_by_scheduling_quantum() {
+    // Each hart takes three tickets from the counter at DRAM_BASE + 0x1000.
+    let data = vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x93, 0x06, 0x10, 0x00, // addi a3, zero, 1
+        0x2f, 0xa9, 0xd2, 0x00, // amoadd.w s2, a3, (t0)
+        0xaf, 0xa9, 0xd2, 0x00, // amoadd.w s3, a3, (t0)
+        0x2f, 0xaa, 0xd2, 0x00, // amoadd.w s4, a3, (t0)
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    for &(quantum, hart0, hart1) in &[(1, (0, 2, 4), (1, 3, 5)), (2, (0, 1, 4), (2, 3, 5))] {
+        let mut emu = setup(data.clone());
+        emu.set_num_harts(2);
+        emu.set_scheduling_quantum(quantum);
+
+        assert_eq!(Halt::InstructionLimit, emu.run(20));
+        assert_eq!(hart0, tickets(&mut emu, 0));
+        assert_eq!(hart1, tickets(&mut emu, 1));
+    }
+}
+
+#[test]
+fn harts_switch_on_memory_barrier() {
+    let data = vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x93, 0x06, 0x10, 0x00, // addi a3, zero, 1
+        0x2f, 0xa9, 0xd2, 0x00, // amoadd.w s2, a3, (t0)
+        0x0f, 0x00, 0xf0, 0x0f, // fence
+        0xaf, 0xa9, 0xd2, 0x00, // amoadd.w s3, a3, (t0)
+        0x0f, 0x00, 0xf0, 0x0f, // fence
+        0x2f, 0xaa, 0xd2, 0x00, // amoadd.w s4, a3, (t0)
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    emu.set_num_harts(2);
+    emu.set_switch_on_barrier(true);
+
+    // Hart 0 spins for the rest of its quantum after taking the last ticket.
+    emu.run(2100);
+    assert_eq!((0, 2, 4), tickets(&mut emu, 0));
+    assert_eq!((1, 3, 5), tickets(&mut emu, 1));
+}
+
+#[test]
+fn each_hart_reads_its_own_mhartid() {
+    let data = vec![
+        0xf3, 0x22, 0x40, 0xf1, // csrr t0, mhartid
+        0x13, 0x03, 0x05, 0x00, // mv t1, a0
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    let mut emu = setup(data);
+    emu.set_num_harts(2);
+    emu.set_scheduling_quantum(1);
+
+    emu.run(6);
+    for hartid in 0..2 {
+        emu.switch_to_hart(hartid);
+        assert_eq!(hartid as u64, emu.cpu.hartid);
+        assert_eq!(hartid as u64, emu.cpu.xregs.read(5));
+        assert_eq!(hartid as u64, emu.cpu.xregs.read(6));
+    }
+}
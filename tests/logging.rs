@@ -0,0 +1,61 @@
+extern crate rvemu;
+
+use std::sync::Mutex;
+
+use log::{Level, Log, Metadata, Record};
+use rvemu::{
+    bus::DRAM_BASE,
+    cpu::Mode,
+    csr::{MEDELEG, STVEC},
+    emulator::Emulator,
+};
+
+/// A logger that keeps every record in memory so tests can inspect them.
+struct CaptureLogger {
+    records: Mutex<Vec<(Level, String)>>,
+}
+
+impl Log for CaptureLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.records
+            .lock()
+            .unwrap()
+            .push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CaptureLogger = CaptureLogger {
+    records: Mutex::new(Vec::new()),
+};
+
+#[test]
+fn delegated_trap_emits_debug_record() {
+    log::set_logger(&LOGGER).expect("failed to install the logger");
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let data = vec![
+        0x73, 0x00, 0x10, 0x00, // ebreak
+    ];
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data);
+    emu.initialize_pc(DRAM_BASE);
+    emu.cpu.mode = Mode::Supervisor;
+    // Delegate breakpoints (cause 3) to S-mode.
+    emu.cpu.state.write(MEDELEG, 1 << 3);
+    emu.cpu.state.write(STVEC, DRAM_BASE + 0x100);
+
+    let exception = emu.cpu.execute().expect_err("ebreak should trap");
+    exception.take_trap(&mut emu.cpu);
+    assert_eq!(DRAM_BASE + 0x100, emu.cpu.pc);
+
+    let records = LOGGER.records.lock().unwrap();
+    assert!(records.iter().any(|(level, message)| *level == Level::Debug
+        && message.contains("Breakpoint")
+        && message.contains("delegated from Supervisor to Supervisor mode")));
+}
@@ -170,9 +170,9 @@ add_test!(rv64ua_p_amoxor_w);
 add_test!(rv64ua_p_lrsc);
 
 // rv64ud-p-*
+add_test!(rv64ud_p_fclass);
 /*
 add_test!(rv64ud_p_fadd);
-add_test!(rv64ud_p_fclass);
 add_test!(rv64ud_p_fcmp);
 add_test!(rv64ud_p_fcvt);
 add_test!(rv64ud_p_fcvt_w);
@@ -186,9 +186,9 @@ add_test!(rv64ud_p_structural);
 */
 
 // rv64uf-p-*
+add_test!(rv64uf_p_fclass);
 /*
 add_test!(rv64uf_p_fadd);
-add_test!(rv64uf_p_fclass);
 add_test!(rv64uf_p_fcmp);
 add_test!(rv64uf_p_fcvt);
 add_test!(rv64uf_p_fcvt_w);
@@ -0,0 +1,90 @@
+//! The integration tests which boot xv6 and talk to its shell over the UART. They only build with
+//! the `xv6` feature, e.g., `cargo test --release --features xv6 --test xv6`.
+
+extern crate rvemu;
+
+use std::fs;
+use std::path::PathBuf;
+
+use rvemu::{
+    bus::DRAM_BASE,
+    devices::serial::BufferBackend,
+    emulator::{Emulator, Halt},
+};
+
+/// The number of instructions executed between checks of the console.
+const CHUNK: u64 = 1_000_000;
+
+/// The number of instructions after which a test gives up waiting for the console.
+const MAX_INSTRUCTIONS: u64 = 2_000_000_000;
+
+/// The prompt of the xv6 shell.
+const PROMPT: &str = "$ ";
+
+/// Create an emulator which boots xv6 from the images in `bin/xv6`, along with the backend which
+/// captures its console.
+fn setup() -> (Emulator, BufferBackend) {
+    let mut root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    root.push("bin/xv6");
+    let kernel = fs::read(root.join("kernel.bin")).expect("failed to read the xv6 kernel");
+    let disk = fs::read(root.join("fs.img")).expect("failed to read the xv6 file system");
+
+    let mut emu = Emulator::new();
+    emu.initialize_dram(kernel);
+    emu.initialize_disk(disk);
+    emu.initialize_pc(DRAM_BASE);
+    let console = BufferBackend::new();
+    emu.cpu.bus.uart.set_backend(Box::new(console.clone()));
+    (emu, console)
+}
+
+/// Run the emulator until the console has printed `expected` after the first `from` bytes, and
+/// return the end of the match. Panic with the console output if it doesn't within
+/// `MAX_INSTRUCTIONS`.
+fn run_until(emu: &mut Emulator, console: &BufferBackend, from: usize, expected: &str) -> usize {
+    let mut executed = 0;
+    while executed < MAX_INSTRUCTIONS {
+        match emu.run(CHUNK) {
+            Halt::InstructionLimit => {}
+            halt => panic!(
+                "xv6 stopped with {:?}. The console:\n{}",
+                halt,
+                console.contents()
+            ),
+        }
+        executed += CHUNK;
+        let contents = console.contents();
+        if let Some(index) = contents.get(from..).and_then(|rest| rest.find(expected)) {
+            return from + index + expected.len();
+        }
+    }
+    panic!(
+        "xv6 didn't print {:?} in {} instructions. The console:\n{}",
+        expected,
+        MAX_INSTRUCTIONS,
+        console.contents()
+    );
+}
+
+#[test]
+fn xv6_boots_to_the_shell_prompt() {
+    let (mut emu, console) = setup();
+    run_until(&mut emu, &console, 0, PROMPT);
+    assert!(console.contents().contains("xv6 kernel is booting"));
+}
+
+#[test]
+fn xv6_shell_runs_echo() {
+    let (mut emu, console) = setup();
+    let prompt = run_until(&mut emu, &console, 0, PROMPT);
+
+    emu.cpu.bus.uart.push_input(b"echo hi\n");
+    // The shell echoes the command back, then prints the output and the next prompt.
+    let output = run_until(&mut emu, &console, prompt, PROMPT);
+    assert_eq!(
+        "echo hi\nhi\n$ ",
+        &console.contents()[prompt..output],
+        "the console:\n{}",
+        console.contents()
+    );
+}